@@ -613,6 +613,29 @@ struct RawCommonConfig {
   // Identity for internal Mononoke services. Requests from these services
   // can be trusted to not have been done directly by users.
   10: RawAllowlistIdentity internal_identity;
+
+  // Cachelib settings for Mononoke services. Command line arguments take
+  // precedence over these.
+  11: optional RawCachelibConfig cachelib_config;
+} (rust.exhaustive)
+
+struct RawCachelibConfig {
+  // Size of the cachelib cache, in GiB
+  1: optional double cache_size_gb;
+  // Process size at which cachelib will shrink, in GiB
+  2: optional i32 max_process_size;
+  // Process size at which cachelib will grow back, in GiB
+  3: optional i32 min_process_size;
+  // Power for cachelib's hashtable buckets
+  4: optional i32 buckets_power;
+  // Overrides for the sizes of individual caches
+  5: optional i64 blob_cache_size;
+  6: optional i64 presence_cache_size;
+  7: optional i64 changesets_cache_size;
+  8: optional i64 filenodes_cache_size;
+  9: optional i64 filenodes_history_cache_size;
+  10: optional i64 idmapping_cache_size;
+  11: optional i64 phases_cache_size;
 } (rust.exhaustive)
 
 struct RawCacheWarmupConfig {
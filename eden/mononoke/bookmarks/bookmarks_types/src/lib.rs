@@ -100,6 +100,12 @@ impl fmt::Display for BookmarkName {
     }
 }
 
+impl serde::Serialize for BookmarkName {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 impl Arbitrary for BookmarkName {
     fn arbitrary(g: &mut Gen) -> Self {
         // NOTE: We use a specific large size here because our tests exercise DB Bookmarks, which
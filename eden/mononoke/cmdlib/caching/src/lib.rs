@@ -12,14 +12,20 @@ mod settings;
 
 use environment::Caching;
 use fbinit::FacebookInit;
+use metaconfig_types::CachelibConfig;
 
 pub use crate::args::CachelibArgs;
 pub use crate::settings::CachelibSettings;
 
+/// Initialize cachelib. Settings are taken from the command line arguments,
+/// then from the config for any argument where `is_explicit_arg` returns
+/// false, and finally from the defaults in `settings`.
 pub fn init_cachelib(
     fb: FacebookInit,
     settings: &CachelibSettings,
     args: &CachelibArgs,
+    config: &CachelibConfig,
+    is_explicit_arg: impl Fn(&str) -> bool,
 ) -> Caching {
     if args.skip_caching {
         return Caching::Disabled;
@@ -27,6 +33,7 @@ pub fn init_cachelib(
 
     let mut settings = settings.clone();
     settings.update_from_args(args);
+    settings.update_from_config(config, is_explicit_arg);
 
     #[cfg(not(fbcode_build))]
     {
@@ -7,6 +7,8 @@
 
 use std::time::Duration;
 
+use metaconfig_types::CachelibConfig;
+
 use super::args::CachelibArgs;
 
 const ONE_GIB: usize = 1 << 30; // 2 ^ 30 = 1 GiB
@@ -166,6 +168,77 @@ impl CachelibSettings {
         );
         replace(&mut self.sql_cache_size, &args.sql_cache_size);
     }
+
+    /// Update the settings from the cachelib section of the config.
+    /// Arguments for which `is_explicit_arg` returns true were given on the
+    /// command line, and take precedence over the config.
+    pub fn update_from_config(
+        &mut self,
+        config: &CachelibConfig,
+        is_explicit_arg: impl Fn(&str) -> bool,
+    ) {
+        fn replace<T: Clone>(explicit: bool, target: &mut Option<T>, value: &Option<T>) {
+            if !explicit && value.is_some() {
+                *target = value.as_ref().cloned();
+            }
+        }
+
+        if let Some(cache_size) = config.cache_size {
+            if !is_explicit_arg("cache-size-gb") {
+                self.cache_size = cache_size;
+            }
+        }
+        replace(
+            is_explicit_arg("max-process-size"),
+            &mut self.max_process_size_gib,
+            &config.max_process_size_gib,
+        );
+        replace(
+            is_explicit_arg("min-process-size"),
+            &mut self.min_process_size_gib,
+            &config.min_process_size_gib,
+        );
+        replace(
+            is_explicit_arg("buckets-power"),
+            &mut self.buckets_power,
+            &config.buckets_power,
+        );
+        replace(
+            is_explicit_arg("blob-cache-size"),
+            &mut self.blob_cache_size,
+            &config.blob_cache_size,
+        );
+        replace(
+            is_explicit_arg("presence-cache-size"),
+            &mut self.presence_cache_size,
+            &config.presence_cache_size,
+        );
+        replace(
+            is_explicit_arg("changesets-cache-size"),
+            &mut self.changesets_cache_size,
+            &config.changesets_cache_size,
+        );
+        replace(
+            is_explicit_arg("filenodes-cache-size"),
+            &mut self.filenodes_cache_size,
+            &config.filenodes_cache_size,
+        );
+        replace(
+            is_explicit_arg("filenodes-history-cache-size"),
+            &mut self.filenodes_history_cache_size,
+            &config.filenodes_history_cache_size,
+        );
+        replace(
+            is_explicit_arg("idmapping-cache-size"),
+            &mut self.idmapping_cache_size,
+            &config.idmapping_cache_size,
+        );
+        replace(
+            is_explicit_arg("phases-cache-size"),
+            &mut self.phases_cache_size,
+            &config.phases_cache_size,
+        );
+    }
 }
 
 impl Default for CachelibSettings {
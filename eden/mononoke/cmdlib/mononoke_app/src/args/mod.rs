@@ -31,6 +31,7 @@ pub use hooks::HooksAppExtension;
 pub use mcrouter::McrouterAppExtension;
 pub use mcrouter::McrouterArgs;
 pub use mysql::MysqlArgs;
+pub use readonly::ReadonlyAppExtension;
 pub use readonly::ReadonlyArgs;
 pub use repo::MultiRepoArgs;
 pub use repo::RepoArg;
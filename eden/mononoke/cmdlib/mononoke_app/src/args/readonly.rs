@@ -5,7 +5,12 @@
  * GNU General Public License version 2.
  */
 
+use anyhow::Result;
+use blobstore_factory::ReadOnlyStorage;
 use clap::Args;
+use environment::MononokeEnvironment;
+
+use crate::AppExtension;
 
 /// Commandline args to make the server read-only
 #[derive(Args, Debug)]
@@ -14,3 +19,19 @@ pub struct ReadonlyArgs {
     #[clap(long)]
     pub readonly: bool,
 }
+
+/// Use `ReadonlyArgs` through this extension to also open storage as with
+/// --with-readonly-storage when the server is readonly.
+pub struct ReadonlyAppExtension;
+
+impl AppExtension for ReadonlyAppExtension {
+    type Args = ReadonlyArgs;
+
+    fn environment_hook(&self, args: &Self::Args, env: &mut MononokeEnvironment) -> Result<()> {
+        if args.readonly {
+            env.readonly_storage = ReadOnlyStorage(true);
+        }
+
+        Ok(())
+    }
+}
@@ -12,7 +12,8 @@ use clap::Args;
 // https://fburl.com/diffusion/n5isd68j, last synced on 17/12/2020
 #[derive(Args, Debug)]
 pub struct RuntimeArgs {
-    /// Number of threads to use in the Tokio runtime
-    #[clap(long)]
+    /// Number of threads to use in the Tokio runtime, instead of one per
+    /// core
+    #[clap(long, alias = "worker-threads")]
     pub runtime_threads: Option<usize>,
 }
@@ -26,10 +26,12 @@ use blobstore_factory::ReadOnlyStorageArgs;
 use blobstore_factory::ThrottleOptions;
 use cached_config::ConfigHandle;
 use cached_config::ConfigStore;
+use clap::ArgMatches;
 use clap::Args;
 use clap::Command;
 use clap::FromArgMatches;
 use clap::IntoApp;
+use clap::ValueSource;
 use cmdlib_caching::init_cachelib;
 use cmdlib_caching::CachelibArgs;
 use cmdlib_caching::CachelibSettings;
@@ -41,12 +43,14 @@ use environment::WarmBookmarksCacheDerivedData;
 use fbinit::FacebookInit;
 use megarepo_config::MegarepoConfigsArgs;
 use megarepo_config::MononokeMegarepoConfigsOptions;
+use metaconfig_parser::load_common_config;
 use observability::DynamicLevelDrain;
 use permission_checker::AclProvider;
 use permission_checker::DefaultAclProvider;
 use permission_checker::InternalAclProvider;
 use rendezvous::RendezVousArgs;
 use slog::debug;
+use slog::info;
 use slog::o;
 use slog::Logger;
 use slog::Never;
@@ -245,6 +249,7 @@ impl MononokeAppBuilder {
         let env_args = EnvironmentArgs::from_arg_matches(&args)?;
         let config_mode = env_args.config_args.mode();
         let mut env = self.build_environment(
+            &args,
             env_args,
             extension_args.iter().map(|(_type_id, ext)| ext.as_ref()),
         )?;
@@ -264,6 +269,7 @@ impl MononokeAppBuilder {
 
     fn build_environment<'a>(
         &self,
+        args: &ArgMatches,
         env_args: EnvironmentArgs,
         extension_args: impl IntoIterator<Item = &'a dyn BoxedAppExtensionArgs> + Clone,
     ) -> Result<MononokeEnvironment> {
@@ -325,9 +331,25 @@ impl MononokeAppBuilder {
             .create_warm_bookmark_cache_scuba_sample_builder(self.fb)
             .context("Failed to create warm bookmark cache scuba sample builder")?;
 
-        let caching = init_cachelib(self.fb, &self.cachelib_settings, &cachelib_args);
+        let common_config = load_common_config(config_args.config_path(), &config_store)
+            .context("Failed to load common config")?;
 
-        let runtime = create_runtime(&runtime_args)?;
+        // Cachelib settings in the config apply unless the corresponding
+        // argument was given on the command line.
+        let caching = init_cachelib(
+            self.fb,
+            &self.cachelib_settings,
+            &cachelib_args,
+            &common_config.cachelib_config,
+            |arg| {
+                matches!(
+                    args.value_source(arg),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            },
+        );
+
+        let runtime = create_runtime(&runtime_args, &logger)?;
 
         let mysql_options =
             create_mysql_options(&mysql_args, create_mysql_pool_config(&mysql_args));
@@ -424,11 +446,12 @@ fn create_config_store(
     }
 }
 
-fn create_runtime(runtime_args: &RuntimeArgs) -> Result<Runtime> {
+fn create_runtime(runtime_args: &RuntimeArgs, logger: &Logger) -> Result<Runtime> {
     let mut builder = tokio::runtime::Builder::new_multi_thread();
     builder.enable_all();
     builder.thread_name("tk");
     if let Some(threads) = runtime_args.runtime_threads {
+        info!(logger, "Using {} runtime worker threads", threads);
         builder.worker_threads(threads);
     }
     let runtime = builder.build()?;
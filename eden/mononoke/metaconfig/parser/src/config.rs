@@ -33,6 +33,7 @@ use repos::RawRepoConfig;
 use repos::RawRepoConfigs;
 use repos::RawRepoDefinition;
 use repos::RawStorageConfig;
+use serde::Serialize;
 
 use crate::convert::Convert;
 use crate::errors::ConfigurationError;
@@ -52,7 +53,7 @@ pub fn load_common_config(
 }
 
 /// Holds configuration for repostories.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct RepoConfigs {
     /// Configs for all repositories
     pub repos: HashMap<String, RepoConfig>,
@@ -422,6 +423,11 @@ fn parse_common_config(
     let scuba_censored_table = common.scuba_censored_table;
     let scuba_censored_local_path = common.scuba_local_path_censored;
     let internal_identity = common.internal_identity.convert()?;
+    let cachelib_config = common
+        .cachelib_config
+        .map(Convert::convert)
+        .transpose()?
+        .unwrap_or_default();
 
     let censored_scuba_params = CensoredScubaParams {
         table: scuba_censored_table,
@@ -461,6 +467,7 @@ fn parse_common_config(
         censored_scuba_params,
         redaction_config,
         internal_identity,
+        cachelib_config,
     })
 }
 
@@ -496,6 +503,7 @@ mod test {
     use metaconfig_types::BookmarkParams;
     use metaconfig_types::BubbleDeletionMode;
     use metaconfig_types::CacheWarmupParams;
+    use metaconfig_types::CachelibConfig;
     use metaconfig_types::CommitIdentityScheme;
     use metaconfig_types::CommitSyncConfig;
     use metaconfig_types::CommitSyncConfigVersion;
@@ -936,6 +944,10 @@ mod test {
             blobstore="main"
             redaction_sets_location="loc"
 
+            [cachelib_config]
+            cache_size_gb=2.5
+            buckets_power=20
+
             [[global_allowlist]]
             identity_type = "username"
             identity_data = "user"
@@ -1367,7 +1379,12 @@ mod test {
                 internal_identity: Identity {
                     id_type: "SERVICE_IDENTITY".to_string(),
                     id_data: "internal".to_string(),
-                }
+                },
+                cachelib_config: CachelibConfig {
+                    cache_size: Some(5 << 29),
+                    buckets_power: Some(20),
+                    ..Default::default()
+                },
             }
         );
         assert_eq!(
@@ -6,8 +6,10 @@
  */
 
 use anyhow::Result;
+use metaconfig_types::CachelibConfig;
 use metaconfig_types::Identity;
 use repos::RawAllowlistIdentity;
+use repos::RawCachelibConfig;
 
 use crate::convert::Convert;
 use crate::errors::ConfigurationError;
@@ -28,3 +30,49 @@ impl Convert for RawAllowlistIdentity {
         })
     }
 }
+
+impl Convert for RawCachelibConfig {
+    type Output = CachelibConfig;
+
+    fn convert(self) -> Result<Self::Output> {
+        const ONE_GIB: f64 = (1 << 30) as f64;
+
+        let cache_size = match self.cache_size_gb {
+            Some(size) if !size.is_finite() || size < 0.0 => {
+                return Err(ConfigurationError::InvalidConfig(format!(
+                    "cachelib cache_size_gb must be a non-negative number, got {}",
+                    size
+                ))
+                .into());
+            }
+            Some(size) => Some((size * ONE_GIB) as usize),
+            None => None,
+        };
+
+        Ok(CachelibConfig {
+            cache_size,
+            max_process_size_gib: self.max_process_size.map(|v| v.try_into()).transpose()?,
+            min_process_size_gib: self.min_process_size.map(|v| v.try_into()).transpose()?,
+            buckets_power: self.buckets_power.map(|v| v.try_into()).transpose()?,
+            blob_cache_size: self.blob_cache_size.map(|v| v.try_into()).transpose()?,
+            presence_cache_size: self.presence_cache_size.map(|v| v.try_into()).transpose()?,
+            changesets_cache_size: self
+                .changesets_cache_size
+                .map(|v| v.try_into())
+                .transpose()?,
+            filenodes_cache_size: self
+                .filenodes_cache_size
+                .map(|v| v.try_into())
+                .transpose()?,
+            filenodes_history_cache_size: self
+                .filenodes_history_cache_size
+                .map(|v| v.try_into())
+                .transpose()?,
+            idmapping_cache_size: self
+                .idmapping_cache_size
+                .map(|v| v.try_into())
+                .transpose()?,
+            phases_cache_size: self.phases_cache_size.map(|v| v.try_into()).transpose()?,
+        })
+    }
+}
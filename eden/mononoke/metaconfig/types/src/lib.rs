@@ -39,6 +39,7 @@ use mysql_common::value::convert::ParseIr;
 use regex::Regex;
 use scuba::ScubaValue;
 use serde_derive::Deserialize;
+use serde_derive::Serialize;
 use sql::mysql;
 use sql::mysql_async::FromValueError;
 use sql::mysql_async::Value;
@@ -86,8 +87,45 @@ impl PartialEq for ComparableRegex {
 
 impl Eq for ComparableRegex {}
 
+impl serde::Serialize for ComparableRegex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Serialize a field as its `Display` representation.
+fn serialize_display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: fmt::Display,
+    S: serde::Serializer,
+{
+    serializer.collect_str(value)
+}
+
+/// Serialize a field as its `Debug` representation, for types that have no
+/// more readable form.
+fn serialize_debug<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: fmt::Debug,
+    S: serde::Serializer,
+{
+    serializer.collect_str(&format_args!("{:?}", value))
+}
+
+/// Serialize a map between paths with the paths as strings, as map keys
+/// can't be serialized as sequences of path elements in formats like JSON.
+fn serialize_path_map<S>(map: &HashMap<MPath, MPath>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let entries = map
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()));
+    serializer.collect_map(entries)
+}
+
 /// Structure representing general purpose identity.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct Identity {
     /// Type of this identity.
     pub id_type: String,
@@ -96,7 +134,7 @@ pub struct Identity {
 }
 
 /// Configuration for how blobs are redacted
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct RedactionConfig {
     /// Which blobstore should be used to fetch the redacted key lists
     pub blobstore: BlobConfig,
@@ -108,7 +146,7 @@ pub struct RedactionConfig {
 
 /// Configuration for all repos
 #[facet::facet]
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct CommonConfig {
     /// Hipster tier that is permitted to act as a trusted proxy.
     pub trusted_parties_hipster_tier: Option<String>,
@@ -127,10 +165,40 @@ pub struct CommonConfig {
     pub redaction_config: RedactionConfig,
     /// Service identity for interal Mononoke services.
     pub internal_identity: Identity,
+    /// Cachelib settings, overridden by command line arguments.
+    pub cachelib_config: CachelibConfig,
+}
+
+/// Cachelib settings that can be provided by the config instead of the
+/// command line. Unset values fall back to the command line defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CachelibConfig {
+    /// Size of the cachelib cache, in bytes
+    pub cache_size: Option<usize>,
+    /// Process size at which cachelib will shrink, in GiB
+    pub max_process_size_gib: Option<u32>,
+    /// Process size at which cachelib will grow back, in GiB
+    pub min_process_size_gib: Option<u32>,
+    /// Power for cachelib's hashtable buckets
+    pub buckets_power: Option<u32>,
+    /// Size of the blob cache
+    pub blob_cache_size: Option<usize>,
+    /// Size of the blob presence cache
+    pub presence_cache_size: Option<usize>,
+    /// Size of the changesets cache
+    pub changesets_cache_size: Option<usize>,
+    /// Size of the filenodes cache
+    pub filenodes_cache_size: Option<usize>,
+    /// Size of the filenodes history cache
+    pub filenodes_history_cache_size: Option<usize>,
+    /// Size of the bonsai-hg mapping cache
+    pub idmapping_cache_size: Option<usize>,
+    /// Size of the phases cache
+    pub phases_cache_size: Option<usize>,
 }
 
 /// Configuration for logging of censored blobstore accesses
-#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct CensoredScubaParams {
     /// Scuba table for logging redacted file accesses
     pub table: Option<String>,
@@ -140,7 +208,7 @@ pub struct CensoredScubaParams {
 
 /// Configuration of a single repository
 #[facet::facet]
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct RepoConfig {
     /// If false, this repo config is completely ignored.
     pub enabled: bool,
@@ -229,7 +297,7 @@ pub struct RepoConfig {
 }
 
 /// Indicates types of commit hashes used in a repo context.
-#[derive(Eq, Clone, Debug, PartialEq)]
+#[derive(Eq, Clone, Debug, PartialEq, Serialize)]
 pub enum CommitIdentityScheme {
     /// Hashes are Mercurial hashes.
     HG,
@@ -248,7 +316,7 @@ impl Default for CommitIdentityScheme {
 }
 
 /// Backup repo configuration
-#[derive(Eq, Clone, Default, Debug, PartialEq)]
+#[derive(Eq, Clone, Default, Debug, PartialEq, Serialize)]
 pub struct BackupRepoConfig {
     /// Name of the repo that's a "source" of the backup
     /// i.e. what we are actually backing up
@@ -256,14 +324,14 @@ pub struct BackupRepoConfig {
 }
 
 /// Configuration for repo_client module
-#[derive(Eq, Copy, Clone, Default, Debug, PartialEq)]
+#[derive(Eq, Copy, Clone, Default, Debug, PartialEq, Serialize)]
 pub struct RepoClientKnobs {
     /// Return shorter file history in getpack call
     pub allow_short_getpack_history: bool,
 }
 
 /// Config for derived data
-#[derive(Eq, Clone, Default, Debug, PartialEq)]
+#[derive(Eq, Clone, Default, Debug, PartialEq, Serialize)]
 pub struct DerivedDataConfig {
     /// Name of scuba table where all derivation will be logged to
     pub scuba_table: Option<String>,
@@ -306,7 +374,7 @@ impl DerivedDataConfig {
 }
 
 /// Config for derived data types
-#[derive(Eq, Clone, Default, Debug, PartialEq)]
+#[derive(Eq, Clone, Default, Debug, PartialEq, Serialize)]
 pub struct DerivedDataTypesConfig {
     /// The configured types.
     pub types: HashSet<String>,
@@ -337,7 +405,7 @@ pub struct DerivedDataTypesConfig {
 }
 
 /// What type of unode derived data to generate
-#[derive(Eq, Clone, Copy, Debug, PartialEq)]
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum UnodeVersion {
     /// Unodes v1
     V1,
@@ -352,7 +420,7 @@ impl Default for UnodeVersion {
 }
 
 /// What type of blame derived data to generate
-#[derive(Eq, Clone, Copy, Debug, PartialEq)]
+#[derive(Eq, Clone, Copy, Debug, PartialEq, Serialize)]
 pub enum BlameVersion {
     /// Blame v1
     V1,
@@ -373,7 +441,7 @@ impl RepoConfig {
     }
 }
 
-#[derive(Eq, Copy, Clone, Debug, PartialEq, Deserialize)]
+#[derive(Eq, Copy, Clone, Debug, PartialEq, Deserialize, Serialize)]
 /// Should the redaction verification be enabled?
 pub enum Redaction {
     /// Redacted files cannot be accessed
@@ -388,7 +456,7 @@ impl Default for Redaction {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
 /// Is the repo read-only?
 pub enum RepoReadOnly {
     /// This repo is read-only and should not accept pushes or other writes
@@ -404,7 +472,7 @@ impl Default for RepoReadOnly {
 }
 
 /// Configuration of warming up the Mononoke cache. This warmup happens on startup
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct CacheWarmupParams {
     /// Bookmark to warmup cache for at the startup. If not set then the cache will be cold.
     pub bookmark: BookmarkName,
@@ -416,7 +484,7 @@ pub struct CacheWarmupParams {
 }
 
 /// Configuration for the hook manager
-#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Default)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Default, Serialize)]
 pub struct HookManagerParams {
     /// Wether to disable the acl checker or not (intended for testing purposes)
     pub disable_acl_checker: bool,
@@ -427,7 +495,7 @@ pub struct HookManagerParams {
 }
 
 /// Configuration might be done for a single bookmark or for all bookmarks matching a regex
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum BookmarkOrRegex {
     /// Matches a single bookmark
     Bookmark(BookmarkName),
@@ -458,7 +526,7 @@ impl From<Regex> for BookmarkOrRegex {
 }
 
 /// Configuration for a bookmark
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct BookmarkParams {
     /// The bookmark
     pub bookmark: BookmarkOrRegex,
@@ -489,7 +557,7 @@ pub struct BookmarkParams {
 }
 
 /// The type of the hook
-#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum HookType {
     /// A hook that runs on the whole changeset
     PerChangeset,
@@ -510,7 +578,7 @@ impl FromStr for HookType {
 }
 
 /// Hook bypass
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct HookBypass {
     /// Bypass that checks that a string is in the commit message
     commit_message_bypass: Option<String>,
@@ -561,7 +629,7 @@ impl HookBypass {
 }
 
 /// Configs that are being passed to the hook during runtime
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct HookConfig {
     /// An optional way to bypass a hook
     pub bypass: Option<HookBypass>,
@@ -580,7 +648,7 @@ pub struct HookConfig {
 }
 
 /// Configuration for a hook
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct HookParams {
     /// The name of the hook
     pub name: String,
@@ -589,7 +657,7 @@ pub struct HookParams {
 }
 
 /// Push configuration options
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct PushParams {
     /// Whether normal non-pushrebase pushes are allowed
     pub pure_push_allowed: bool,
@@ -607,7 +675,7 @@ impl Default for PushParams {
 }
 
 /// Flags for the pushrebase inner loop
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct PushrebaseFlags {
     /// Update dates of rebased commits
     pub rewritedates: bool,
@@ -636,7 +704,7 @@ impl Default for PushrebaseFlags {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 /// Either an SMC tier or a host/port pair
 pub enum Address {
     /// An SMC tier
@@ -645,7 +713,7 @@ pub enum Address {
     HostPort(String),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 /// How to do pushrebase on Mononoke
 pub enum PushrebaseRemoteMode {
     /// Do pushrebase in the same process
@@ -661,7 +729,7 @@ pub enum PushrebaseRemoteMode {
 }
 
 /// Pushrebase configuration options
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct PushrebaseParams {
     /// Pushrebase processing flags
     pub flags: PushrebaseFlags,
@@ -701,7 +769,7 @@ impl Default for PushrebaseParams {
 }
 
 /// LFS configuration options
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct LfsParams {
     /// threshold in bytes, If None, Lfs is disabled
     pub threshold: Option<u64>,
@@ -713,7 +781,7 @@ pub struct LfsParams {
 
 /// Id used to discriminate diffirent underlying blobstore instances
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize)]
-#[derive(From, Into, mysql::OptTryFromRowField)]
+#[derive(From, Into, Serialize, mysql::OptTryFromRowField)]
 pub struct BlobstoreId(u64);
 sql::proxy_conv_ir!(BlobstoreId, ParseIr<u64>, u64);
 
@@ -737,7 +805,7 @@ impl From<BlobstoreId> for ScubaValue {
 }
 
 /// Id used to identify storage configuration for a multiplexed blobstore.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize)]
 #[derive(From, Into, mysql::OptTryFromRowField)]
 pub struct MultiplexId(i32);
 sql::proxy_conv_ir!(MultiplexId, ParseIr<i32>, i32);
@@ -766,7 +834,7 @@ impl From<MultiplexId> for ScubaValue {
 /// can be broadly classified as "local" and "remote". "Local" is primarily for testing, and is
 /// only suitable for single hosts. "Remote" is durable storage which can be shared by multiple
 /// BlobRepo instances on different hosts.
-#[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct StorageConfig {
     /// Blobstores. If the blobstore has a BlobstoreId then it can be used as a component of
     /// a Multiplexed blobstore.
@@ -780,7 +848,7 @@ pub struct StorageConfig {
 
 /// Whether we should read from this blobstore normally in a Multiplex,
 /// or only read from it in Scrub or when it's our last chance to find the blob
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash, Serialize)]
 pub enum MultiplexedStoreType {
     /// Normal operation, no special treatment
     Normal,
@@ -789,7 +857,7 @@ pub enum MultiplexedStoreType {
 }
 
 /// What format should data be in either Raw or a compressed form with compression options like level
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash, Serialize)]
 pub enum PackFormat {
     /// Uncompressed data is written by put
     Raw,
@@ -804,14 +872,14 @@ impl Default for PackFormat {
 }
 
 /// Configuration for packblob
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash, Serialize)]
 pub struct PackConfig {
     /// What format should put write in, either Raw or a compressed form.
     pub put_format: PackFormat,
 }
 
 /// Configuration for a blobstore
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum BlobConfig {
     /// Administratively disabled blobstore
     Disabled,
@@ -968,21 +1036,21 @@ impl Default for BlobConfig {
 }
 
 /// Configuration for a local SQLite database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct LocalDatabaseConfig {
     /// Path to the directory containing the SQLite databases
     pub path: PathBuf,
 }
 
 /// Configuration for a remote MySQL database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct RemoteDatabaseConfig {
     /// SQL database to connect to
     pub db_address: String,
 }
 
 /// Configuration for a sharded remote MySQL database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct ShardedRemoteDatabaseConfig {
     /// SQL database shard map to connect to
     pub shard_map: String,
@@ -991,7 +1059,7 @@ pub struct ShardedRemoteDatabaseConfig {
 }
 
 /// Configuration for a potentially sharded remote MySQL database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum ShardableRemoteDatabaseConfig {
     /// Database is not sharded.
     Unsharded(RemoteDatabaseConfig),
@@ -1000,7 +1068,7 @@ pub enum ShardableRemoteDatabaseConfig {
 }
 
 /// Configuration for a single database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum DatabaseConfig {
     /// Local SQLite database
     Local(LocalDatabaseConfig),
@@ -1019,7 +1087,7 @@ impl DatabaseConfig {
 }
 
 /// Configuration for a sharded database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum ShardedDatabaseConfig {
     /// Local SQLite database
     Local(LocalDatabaseConfig),
@@ -1028,7 +1096,7 @@ pub enum ShardedDatabaseConfig {
 }
 
 /// Configuration for the Metadata database when it is remote.
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct RemoteMetadataDatabaseConfig {
     /// Database for the primary metadata.
     pub primary: RemoteDatabaseConfig,
@@ -1041,7 +1109,7 @@ pub struct RemoteMetadataDatabaseConfig {
 }
 
 /// Configuration for the Metadata database
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum MetadataDatabaseConfig {
     /// Local SQLite database
     Local(LocalDatabaseConfig),
@@ -1077,7 +1145,7 @@ impl MetadataDatabaseConfig {
 
 /// Enum configuration representing the possible modes
 /// of deletion for expired bubbles.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
 pub enum BubbleDeletionMode {
     /// No marking or deletion
     Disabled,
@@ -1095,7 +1163,7 @@ impl Default for BubbleDeletionMode {
 
 /// Configuration for the ephemeral blobstore, which stores
 /// blobs for ephemeral changesets and snapshots.
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
 pub struct EphemeralBlobstoreConfig {
     /// The configuration of the blobstore where ephemeral blobs
     /// are stored.
@@ -1117,7 +1185,7 @@ pub struct EphemeralBlobstoreConfig {
 }
 
 /// Regex for valid branches that Infinite Pushes can be directed to.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct InfinitepushNamespace(ComparableRegex);
 
 impl InfinitepushNamespace {
@@ -1141,7 +1209,7 @@ impl InfinitepushNamespace {
 /// while still providing a namespace. Doing so will prevent regular pushes to the namespace, as
 /// well as allow the creation of Infinitepush scratchbookmarks through e.g. replicating them from
 /// Mercurial.
-#[derive(Debug, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Clone, Eq, PartialEq, Default, Serialize)]
 pub struct InfinitepushParams {
     /// Whether infinite push bundles are allowed on this server. If false, all infinitepush
     /// bundles will be rejected.
@@ -1159,7 +1227,7 @@ pub struct InfinitepushParams {
 }
 
 /// Filestore configuration.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct FilestoreParams {
     /// Chunk size for the Filestore, in bytes.
     pub chunk_size: u64,
@@ -1169,7 +1237,7 @@ pub struct FilestoreParams {
 
 /// Default path action to perform when syncing commits
 /// from small to large repos
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum DefaultSmallToLargeCommitSyncPathAction {
     /// Preserve as is
     Preserve,
@@ -1181,16 +1249,17 @@ pub enum DefaultSmallToLargeCommitSyncPathAction {
 /// Note: this configuration is always from the point of view
 /// of the small repo, meaning a key in the `map` is a path
 /// prefix in the small repo, and a value - in the large repo
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct SmallRepoCommitSyncConfig {
     /// Default action to take on a path
     pub default_action: DefaultSmallToLargeCommitSyncPathAction,
     /// A map of prefix replacements when syncing
+    #[serde(serialize_with = "serialize_path_map")]
     pub map: HashMap<MPath, MPath>,
 }
 
 /// Commit sync direction
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
 pub enum CommitSyncDirection {
     /// Syncing commits from large repo to small ones
     LargeToSmall,
@@ -1199,7 +1268,7 @@ pub enum CommitSyncDirection {
 }
 
 /// CommitSyncConfig version name
-#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
 #[derive(mysql::OptTryFromRowField)]
 pub struct CommitSyncConfigVersion(pub String);
 
@@ -1242,7 +1311,7 @@ impl FromValue for CommitSyncConfigVersion {
 }
 
 /// Commit sync configuration for a large repo
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct CommitSyncConfig {
     /// Large repository id
     pub large_repo_id: RepositoryId,
@@ -1255,7 +1324,7 @@ pub struct CommitSyncConfig {
 }
 
 /// Config that applies to all mapping versions
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct CommonCommitSyncConfig {
     /// Large repository id
     pub large_repo_id: RepositoryId,
@@ -1266,14 +1335,15 @@ pub struct CommonCommitSyncConfig {
 }
 
 /// Permanent config for a single small repo
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct SmallRepoPermanentConfig {
     /// Prefix of the bookmark
+    #[serde(serialize_with = "serialize_display")]
     pub bookmark_prefix: AsciiString,
 }
 
 /// Source Control Service options
-#[derive(Debug, Clone, Eq, PartialEq, Default)]
+#[derive(Debug, Clone, Eq, PartialEq, Default, Serialize)]
 pub struct SourceControlServiceParams {
     /// Whether writes are permitted.
     pub permit_writes: bool,
@@ -1374,12 +1444,13 @@ impl SourceControlServiceParams {
 }
 
 /// Restrictions on writes for services.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct ServiceWriteRestrictions {
     /// The service is permissed to call these methods
     pub permitted_methods: HashSet<String>,
 
     /// The service is permitted to modify files with these path prefixes.
+    #[serde(serialize_with = "serialize_debug")]
     pub permitted_path_prefixes: PrefixTrie,
 
     /// The service is permitted to modify these bookmarks.
@@ -1391,7 +1462,7 @@ pub struct ServiceWriteRestrictions {
 }
 
 /// Configuration for health monitoring of the Source Control Service
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct SourceControlServiceMonitoring {
     /// Bookmarks, for which we want our services to log
     /// age values to monitoring counters. For example,
@@ -1401,7 +1472,7 @@ pub struct SourceControlServiceMonitoring {
 }
 
 /// Represents the repository name for this repository in Hgsql.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct HgsqlName(pub String);
 
 impl AsRef<str> for HgsqlName {
@@ -1417,7 +1488,7 @@ impl AsRef<String> for HgsqlName {
 }
 
 /// Represents the repository name for Globalrevs for this repository in Hgsql.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct HgsqlGlobalrevsName(pub String);
 
 impl AsRef<str> for HgsqlGlobalrevsName {
@@ -1433,7 +1504,7 @@ impl AsRef<String> for HgsqlGlobalrevsName {
 }
 
 /// An unit of configuration for what should be indexed by Segmented Changelog.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum SegmentedChangelogHeadConfig {
     /// All public bookmarks with exceptions.
     AllPublicBookmarksExcept(Vec<BookmarkName>),
@@ -1471,7 +1542,7 @@ impl From<&ChangesetId> for SegmentedChangelogHeadConfig {
 }
 
 /// Configuration for Segmented Changelog.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct SegmentedChangelogConfig {
     /// Signals whether segmented changelog functionality is enabled for the current repository.
     /// This can mean that functionality is disabled to shed load, that the required data is not
@@ -1556,7 +1627,7 @@ impl Default for SegmentedChangelogConfig {
 ///
 /// If the roots and heads lists are both empty then this region covers the
 /// entire repo.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct AclRegion {
     /// List of roots that begin this region.  Any commit that is a descendant of any
     /// root, including the root itself, will be included in the region.  If this
@@ -1576,7 +1647,7 @@ pub struct AclRegion {
 }
 
 /// ACL region rule consisting of multiple regions and path prefixes
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct AclRegionRule {
     /// The name of this region rule.  This is used in error messages and diagnostics.
     pub name: String,
@@ -1590,14 +1661,14 @@ pub struct AclRegionRule {
 /// Describe ACL Regions for a repository.
 ///
 /// This is a set of rules which define regions of the repository (commits and paths)
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct AclRegionConfig {
     /// List of rules that grant access to regions of the repo.
     pub allow_rules: Vec<AclRegionRule>,
 }
 
 /// Walker parameters that are specific to type of job and repo.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct WalkerJobParams {
     /// Controls max concurrency for MySQL and other dependencies
     pub scheduled_max_concurrency: Option<i64>,
@@ -1612,7 +1683,7 @@ pub struct WalkerJobParams {
     pub error_as_node_data_type: Option<String>,
 }
 
-#[derive(Debug, Copy, clap::ArgEnum, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Copy, clap::ArgEnum, Clone, Eq, PartialEq, Hash, Serialize)]
 /// The type of walker jobs deployed in production
 pub enum WalkerJobType {
     /// Invalid value
@@ -1665,7 +1736,7 @@ impl fmt::Display for WalkerJobType {
 }
 
 /// Configuration relevant to walker job.
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct WalkerConfig {
     /// Determines if the walker should scrub blobs.
     pub scrub_enabled: bool,
@@ -1676,7 +1747,7 @@ pub struct WalkerConfig {
 }
 
 /// Configuration relevant to cross-repo commit validation
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct CrossRepoCommitValidation {
     /// A set of bookmarks whose changelog entries are deemed to be valid
     /// Commits that are only found via the changelog for this named bookmark
@@ -1685,7 +1756,7 @@ pub struct CrossRepoCommitValidation {
 }
 
 /// Configuration for sparse profile monitoring
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct SparseProfilesConfig {
     /// Location where sparse profiles are stored within the repo
     pub sparse_profiles_location: String,
@@ -1699,7 +1770,7 @@ pub struct SparseProfilesConfig {
 
 /// Repo-specific configuration parameters for hg sync job
 /// for a specific job variant
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct HgSyncConfig {
     /// Remote path to hg repo to replay to
     pub hg_repo_ssh_path: String,
@@ -1712,7 +1783,7 @@ pub struct HgSyncConfig {
 }
 
 /// Destination for telemetry logging.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum LoggingDestination {
     /// Logs should be sent to the default logger for this type.
     Logger,
@@ -1724,7 +1795,7 @@ pub enum LoggingDestination {
 }
 
 /// Configuration for logging updates to the repo to external telemetry
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct UpdateLoggingConfig {
     /// Destination where bookmark updates are logged to
     pub bookmark_logging_destination: Option<LoggingDestination>,
@@ -62,6 +62,9 @@ pub struct PermissionDenied {
 
 impl fmt::Display for PermissionDenied {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.context == AuthorizationContext::ReadOnlyIdentity {
+            f.write_str("server is read-only: ")?;
+        }
         write!(
             f,
             "{} is not permitted with {:?} for [",
@@ -144,6 +144,7 @@ use stats::prelude::*;
 use streaming_clone::RevlogStreamingChunks;
 use streaming_clone::StreamingCloneArc;
 use time_ext::DurationExt;
+use tracing::Span;
 use tunables::tunables;
 use unbundle::run_hooks;
 use unbundle::run_post_resolve_action;
@@ -165,6 +166,8 @@ use logging::debug_format_path;
 use logging::log_getpack_params_verbose;
 use logging::log_gettreepack_params_verbose;
 use logging::CommandLogger;
+pub use monitor::CommandBytes;
+pub use monitor::CommandRateLimiter;
 use monitor::Monitor;
 use session_bookmarks_cache::SessionBookmarkCache;
 
@@ -466,6 +469,10 @@ pub struct RepoClient {
     force_lfs: Arc<AtomicBool>,
     knobs: RepoClientKnobs,
     request_perf_counters: Arc<PerfCounters>,
+    // Bytes of the current command, counted by the request handler.
+    command_bytes: Arc<CommandBytes>,
+    // Checked before each command is started.
+    command_rate_limiter: Option<Arc<dyn CommandRateLimiter>>,
     // In case `repo` is a backup of another repository `maybe_backup_repo_source` points to
     // a source for this repository.
     maybe_backup_repo_source: Option<BackupSourceRepo>,
@@ -479,6 +486,7 @@ impl RepoClient {
         maybe_push_redirector_args: Option<PushRedirectorArgs<Repo>>,
         knobs: RepoClientKnobs,
         maybe_backup_repo_source: Option<BackupSourceRepo>,
+        command_rate_limiter: Option<Arc<dyn CommandRateLimiter>>,
     ) -> Self {
         let session_bookmarks_cache = Arc::new(SessionBookmarkCache::new(repo.clone()));
 
@@ -491,6 +499,8 @@ impl RepoClient {
             force_lfs: Arc::new(AtomicBool::new(false)),
             knobs,
             request_perf_counters: Arc::new(PerfCounters::default()),
+            command_bytes: Arc::new(CommandBytes::default()),
+            command_rate_limiter,
             maybe_backup_repo_source,
         }
     }
@@ -499,6 +509,10 @@ impl RepoClient {
         self.request_perf_counters.clone()
     }
 
+    pub fn command_bytes(&self) -> Arc<CommandBytes> {
+        self.command_bytes.clone()
+    }
+
     fn command_future<F, I, E, H>(
         &self,
         command: &str,
@@ -507,10 +521,18 @@ impl RepoClient {
     ) -> BoxFuture<I, E>
     where
         F: Future<Item = I, Error = E> + Send + 'static,
+        I: Send + 'static,
+        E: From<Error> + Send + 'static,
         H: FnOnce(CoreContext, CommandLogger) -> F,
     {
-        let (ctx, command_logger) = self.start_command(command, sampling_rate);
-        with_command_monitor(ctx.clone(), handler(ctx, command_logger)).boxify()
+        let span = command_span(command);
+        self.command_bytes.start(&span);
+        if let Err(err) = self.check_command_rate_limit(command) {
+            return future_old::err(err.into()).boxify();
+        }
+        let (ctx, command_logger) = span.in_scope(|| self.start_command(command, sampling_rate));
+        let inner = span.in_scope(|| handler(ctx.clone(), command_logger));
+        with_command_monitor(ctx, inner, span).boxify()
     }
 
     fn command_stream<S, I, E, H>(
@@ -521,10 +543,25 @@ impl RepoClient {
     ) -> BoxStream<I, E>
     where
         S: Stream<Item = I, Error = E> + Send + 'static,
+        I: Send + 'static,
+        E: From<Error> + Send + 'static,
         H: FnOnce(CoreContext, CommandLogger) -> S,
     {
-        let (ctx, command_logger) = self.start_command(command, sampling_rate);
-        with_command_monitor(ctx.clone(), handler(ctx, command_logger)).boxify()
+        let span = command_span(command);
+        self.command_bytes.start(&span);
+        if let Err(err) = self.check_command_rate_limit(command) {
+            return stream_old::once(Err(err.into())).boxify();
+        }
+        let (ctx, command_logger) = span.in_scope(|| self.start_command(command, sampling_rate));
+        let inner = span.in_scope(|| handler(ctx.clone(), command_logger));
+        with_command_monitor(ctx, inner, span).boxify()
+    }
+
+    fn check_command_rate_limit(&self, command: &str) -> Result<()> {
+        match &self.command_rate_limiter {
+            Some(command_rate_limiter) => command_rate_limiter.check(command),
+            None => Ok(()),
+        }
     }
 
     fn start_command(
@@ -2485,7 +2522,18 @@ fn serialize_getcommitdata(
     Ok(buffer.freeze())
 }
 
-fn with_command_monitor<T>(ctx: CoreContext, t: T) -> Monitor<T, Sender<()>> {
+/// Tracing span covering a single wireproto command. It nests under the span of
+/// the session the command was received on.
+fn command_span(command: &str) -> Span {
+    tracing::info_span!(
+        "wireproto_command",
+        command,
+        request_bytes = tracing::field::Empty,
+        response_bytes = tracing::field::Empty,
+    )
+}
+
+fn with_command_monitor<T>(ctx: CoreContext, t: T, span: Span) -> Monitor<T, Sender<()>> {
     let (sender, receiver) = oneshot::channel();
 
     let reporting_loop = async move {
@@ -2526,7 +2574,7 @@ fn with_command_monitor<T>(ctx: CoreContext, t: T) -> Monitor<T, Sender<()>> {
         let _ = future::select(reporting_loop, receiver).await;
     });
 
-    Monitor::new(t, sender)
+    Monitor::new(t, sender, span)
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -5,9 +5,15 @@
  * GNU General Public License version 2.
  */
 
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+
+use anyhow::Error;
 use futures_old::Future;
 use futures_old::Poll;
 use futures_old::Stream;
+use tracing::Span;
 
 pub struct Monitor<T, P> {
     inner: T,
@@ -15,11 +21,17 @@ pub struct Monitor<T, P> {
     // being dropped, P will be dropped.
     #[allow(dead_code)]
     payload: P,
+    // Entered whenever inner is polled, so that work done by inner is attributed to it.
+    span: Span,
 }
 
 impl<T, P> Monitor<T, P> {
-    pub fn new(inner: T, payload: P) -> Self {
-        Self { inner, payload }
+    pub fn new(inner: T, payload: P, span: Span) -> Self {
+        Self {
+            inner,
+            payload,
+            span,
+        }
     }
 }
 
@@ -28,6 +40,7 @@ impl<F: Future, P> Future for Monitor<F, P> {
     type Error = F::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let _guard = self.span.enter();
         self.inner.poll()
     }
 }
@@ -37,6 +50,61 @@ impl<S: Stream, P> Stream for Monitor<S, P> {
     type Error = S::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let _guard = self.span.enter();
         self.inner.poll()
     }
 }
+
+/// Bytes read from and sent to the client for the current wireproto command,
+/// recorded on its span as `request_bytes` and `response_bytes`.
+///
+/// The request handler counts bytes, and commands are delimited by their
+/// start. The request bytes of a command are those read since the previous
+/// command started, so the first command of a batch gets the whole batch.
+#[derive(Default)]
+pub struct CommandBytes {
+    request: AtomicU64,
+    response: AtomicU64,
+    span: Mutex<Option<Span>>,
+}
+
+impl CommandBytes {
+    pub fn add_request(&self, bytes: u64) {
+        self.request.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_response(&self, bytes: u64) {
+        self.response.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes sent so far in response to the current command.
+    pub fn response(&self) -> u64 {
+        self.response.load(Ordering::Relaxed)
+    }
+
+    /// Record the response of the previous command, and start counting for
+    /// the command of `span`.
+    pub(crate) fn start(&self, span: &Span) {
+        let mut current = self.span.lock().expect("lock poisoned");
+        let response = self.response.swap(0, Ordering::Relaxed);
+        if let Some(previous) = current.take() {
+            previous.record("response_bytes", response);
+        }
+        span.record("request_bytes", self.request.swap(0, Ordering::Relaxed));
+        *current = Some(span.clone());
+    }
+
+    /// Record the response of the last command, once the session is over.
+    pub fn finish(&self) {
+        if let Some(span) = self.span.lock().expect("lock poisoned").take() {
+            span.record("response_bytes", self.response.swap(0, Ordering::Relaxed));
+        }
+    }
+}
+
+/// Check run before each wireproto command, for rate limits that apply to
+/// commands rather than to connections.
+pub trait CommandRateLimiter: Send + Sync {
+    /// Returns an error if `command` should be rejected.
+    fn check(&self, command: &str) -> Result<(), Error>;
+}
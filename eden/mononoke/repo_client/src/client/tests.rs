@@ -407,6 +407,7 @@ async fn run_and_check_if_lfs(
         None, // No PushRedirectorArgs
         Default::default(),
         None, // No backup repo source
+        None, // No command rate limiter
     );
 
     let bytes = repo_client
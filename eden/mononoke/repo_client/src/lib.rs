@@ -14,6 +14,8 @@ mod errors;
 
 pub use client::fetch_treepack_part_input;
 pub use client::gettreepack_entries;
+pub use client::CommandBytes;
+pub use client::CommandRateLimiter;
 pub use client::RepoClient;
 pub use getbundle_response::find_commits_to_send;
 pub use getbundle_response::find_new_draft_commits_and_derive_filenodes_for_public_roots;
@@ -0,0 +1,173 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Tracking of the last read or write on a connection, so that connections
+//! whose clients went silent without closing them can be reaped.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::task::Context;
+use futures::task::Poll;
+use pin_project::pin_project;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::time::Instant;
+
+/// Time of the last read or write on a connection.
+pub struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`.
+    last: AtomicU64,
+    /// Whether writes to the socket count as activity.
+    count_writes: AtomicBool,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            count_writes: AtomicBool::new(true),
+        }
+    }
+
+    /// Record activity now.
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// Stop counting writes to the socket. This is for protocols that also
+    /// write keepalives, which must not keep a silent client's connection
+    /// open. They need to `touch` when they write anything else.
+    pub fn ignore_writes(&self) {
+        self.count_writes.store(false, Ordering::Relaxed);
+    }
+
+    /// Time since the last read or write.
+    pub fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// Resolves once there was no read or write for `timeout`.
+    pub async fn wait_until_idle(&self, timeout: Duration) {
+        loop {
+            let idle = self.idle();
+            if idle >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - idle).await;
+        }
+    }
+}
+
+/// A stream that records reads and writes that transfer data in an
+/// `Activity`. Writes are not recorded once `Activity::ignore_writes` was
+/// called.
+#[pin_project]
+pub struct ActivityStream<S> {
+    #[pin]
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> ActivityStream<S> {
+    pub fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let ret = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = ret {
+            if buf.filled().len() > filled {
+                this.activity.touch();
+            }
+        }
+        ret
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let ret = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = ret {
+            if written > 0 && this.activity.count_writes.load(Ordering::Relaxed) {
+                this.activity.touch();
+            }
+        }
+        ret
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_until_idle() {
+        tokio::time::pause();
+        let timeout = Duration::from_secs(10);
+        let activity = Arc::new(Activity::new());
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        activity.touch();
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(activity.idle(), Duration::from_secs(6));
+
+        let start = Instant::now();
+        activity.wait_until_idle(timeout).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+
+    #[tokio::test]
+    async fn test_ignore_writes() -> io::Result<()> {
+        tokio::time::pause();
+        let activity = Arc::new(Activity::new());
+        let mut stream = ActivityStream::new(Vec::new(), activity.clone());
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        stream.write_all(b"data").await?;
+        assert_eq!(activity.idle(), Duration::ZERO);
+
+        activity.ignore_writes();
+        tokio::time::advance(Duration::from_secs(5)).await;
+        stream.write_all(b"keepalive").await?;
+        assert_eq!(activity.idle(), Duration::from_secs(5));
+        Ok(())
+    }
+}
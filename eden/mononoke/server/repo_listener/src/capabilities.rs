@@ -0,0 +1,148 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Protocol version and compression negotiation for the repo listener.
+//!
+//! When upgrading a connection, the client may send the highest protocol
+//! version it speaks. The server replies with the version that will be used
+//! for the connection, along with the list of optional capabilities it
+//! supports, so that clients can gate optional features on them. Clients
+//! that do not send a version are assumed to speak the baseline version.
+//!
+//! Clients may also ask for stdout of the connection to be compressed. Only
+//! clients that ask for it get compressed output.
+
+use anyhow::anyhow;
+use anyhow::Result;
+use http::HeaderValue;
+
+/// Protocol version assumed for clients that don't take part in the handshake.
+pub const BASELINE_PROTOCOL_VERSION: u32 = 0;
+
+/// Highest protocol version spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The protocol version to use for a connection, given the version sent by
+/// the client, if any.
+pub fn negotiate_protocol_version(client_version: Option<&HeaderValue>) -> Result<u32> {
+    let client_version = match client_version {
+        Some(client_version) => client_version,
+        None => return Ok(BASELINE_PROTOCOL_VERSION),
+    };
+    let client_version = client_version
+        .to_str()
+        .ok()
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "'{}' is not a valid protocol version",
+                String::from_utf8_lossy(client_version.as_bytes())
+            )
+        })?;
+    Ok(std::cmp::min(client_version, PROTOCOL_VERSION))
+}
+
+/// The zstd level to compress stdout of a connection with, given the
+/// compression requested by the client, if any. `wire_compression_level` is
+/// `None` if wire compression is disabled, in which case requests for
+/// compression are rejected.
+pub fn negotiate_compression(
+    client_compression: Option<&HeaderValue>,
+    wire_compression_level: Option<i32>,
+) -> Result<Option<i32>> {
+    let client_compression = match client_compression {
+        Some(client_compression) => client_compression,
+        None => return Ok(None),
+    };
+    match (client_compression.as_bytes(), wire_compression_level) {
+        (b"zstd=stdin", Some(zstd_level)) if zstd_level > 0 => Ok(Some(zstd_level)),
+        (client_compression, _) => Err(anyhow!(
+            "'{}' is not a recognized compression value",
+            String::from_utf8_lossy(client_compression),
+        )),
+    }
+}
+
+/// Optional capabilities supported by this server.
+pub struct ServerCapabilities {
+    /// Stdout of the wireproto channel can be compressed with zstd.
+    pub zstd_compression: bool,
+    /// The HTTP control API is available.
+    pub control_api: bool,
+}
+
+impl ServerCapabilities {
+    /// Comma separated list of capabilities, suitable for a header value.
+    pub fn to_header_value(&self) -> String {
+        let mut capabilities = vec!["edenapi", "netspeedtest"];
+        if self.zstd_compression {
+            capabilities.push("zstd");
+        }
+        if self.control_api {
+            capabilities.push("control");
+        }
+        capabilities.join(",")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        assert_eq!(
+            negotiate_protocol_version(None).unwrap(),
+            BASELINE_PROTOCOL_VERSION
+        );
+        let version =
+            |v: &'static str| negotiate_protocol_version(Some(&HeaderValue::from_static(v)));
+        assert_eq!(version("0").unwrap(), 0);
+        assert_eq!(version(" 1 ").unwrap(), 1);
+        assert_eq!(version("1000").unwrap(), PROTOCOL_VERSION);
+        assert!(version("").is_err());
+        assert!(version("-1").is_err());
+        assert!(version("v1").is_err());
+    }
+
+    #[test]
+    fn test_capabilities_header_value() {
+        let capabilities = |zstd_compression, control_api| {
+            ServerCapabilities {
+                zstd_compression,
+                control_api,
+            }
+            .to_header_value()
+        };
+        assert_eq!(capabilities(false, false), "edenapi,netspeedtest");
+        assert_eq!(capabilities(true, false), "edenapi,netspeedtest,zstd");
+        assert_eq!(capabilities(false, true), "edenapi,netspeedtest,control");
+        assert_eq!(
+            capabilities(true, true),
+            "edenapi,netspeedtest,zstd,control"
+        );
+    }
+
+    #[test]
+    fn test_negotiate_compression() {
+        let zstd = HeaderValue::from_static("zstd=stdin");
+        assert_eq!(
+            negotiate_compression(Some(&zstd), Some(3)).unwrap(),
+            Some(3)
+        );
+
+        // Clients that don't ask for compression get uncompressed output,
+        // whether or not the server supports it.
+        assert_eq!(negotiate_compression(None, Some(3)).unwrap(), None);
+        assert_eq!(negotiate_compression(None, None).unwrap(), None);
+
+        assert!(negotiate_compression(Some(&zstd), None).is_err());
+        assert!(negotiate_compression(Some(&zstd), Some(0)).is_err());
+        let gzip = HeaderValue::from_static("gzip");
+        assert!(negotiate_compression(Some(&gzip), Some(3)).is_err());
+    }
+}
@@ -60,6 +60,8 @@ use slog::error;
 use slog::info;
 use slog::warn;
 use slog::Logger;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
 use sshrelay::IoStream;
 use sshrelay::SshDecoder;
 use sshrelay::SshEncoder;
@@ -69,14 +71,20 @@ use stats::prelude::*;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::net::TcpListener;
+use tokio::net::TcpSocket;
 use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
 use tokio_openssl::SslStream;
 use tokio_util::codec::FramedRead;
 use tokio_util::codec::FramedWrite;
 
+use crate::activity_stream::Activity;
+use crate::activity_stream::ActivityStream;
 use crate::errors::ErrorKind;
 use crate::http_service::MononokeHttpService;
+use crate::privileges::RunAs;
+use crate::repo_rate_limiter::RepoRateLimitConfig;
+use crate::repo_rate_limiter::RepoRateLimiter;
 use crate::request_handler::create_conn_logger;
 use crate::request_handler::request_handler;
 use crate::wireproto_sink::WireprotoSink;
@@ -84,6 +92,7 @@ use crate::wireproto_sink::WireprotoSink;
 define_stats! {
     prefix = "mononoke.connection_acceptor";
     http_accepted: timeseries(Sum),
+    idle_connections_reaped: timeseries(Sum),
 }
 
 pub trait MononokeStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {}
@@ -92,10 +101,63 @@ impl<T> MononokeStream for T where T: AsyncRead + AsyncWrite + Unpin + Send + Sy
 
 const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(5000);
 const CHUNK_SIZE: usize = 10000;
+// Same backlog as TcpListener::bind.
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
 lazy_static! {
     static ref OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
 }
 
+/// Socket options for the listening socket and the connections it accepts.
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    /// Maximum number of pending connections queued by the listening socket.
+    pub listen_backlog: u32,
+    /// Set TCP_NODELAY on accepted connections.
+    pub nodelay: bool,
+    /// Idle time before TCP keepalive probes are sent on accepted connections.
+    /// Keepalive is left disabled if this is not set.
+    pub keepalive: Option<Duration>,
+    /// Close accepted connections that had no reads or writes for this long.
+    /// Connections are never closed for being idle if this is not set.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            nodelay: false,
+            keepalive: None,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    fn bind(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        // TcpListener::bind also sets SO_REUSEADDR on unix.
+        #[cfg(unix)]
+        socket.set_reuseaddr(true)?;
+        socket.bind(*addr)?;
+        socket.listen(self.listen_backlog)
+    }
+
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
 pub async fn wait_for_connections_closed(logger: &Logger) {
     loop {
         let conns = OPEN_CONNECTIONS.load(Ordering::Relaxed);
@@ -125,6 +187,12 @@ pub async fn connection_acceptor(
     cslb_config: Option<String>,
     wireproto_scuba: MononokeScubaSampleBuilder,
     bound_addr_path: Option<PathBuf>,
+    bound_sender: oneshot::Sender<()>,
+    tcp_options: TcpOptions,
+    repo_rate_limit: Option<RepoRateLimitConfig>,
+    run_as: RunAs,
+    max_response_bytes: Option<u64>,
+    wire_compression_level: Option<i32>,
     acl_provider: &dyn AclProvider,
     readonly: bool,
 ) -> Result<()> {
@@ -134,8 +202,8 @@ pub async fn connection_acceptor(
     let addr: SocketAddr = sockname
         .parse()
         .with_context(|| format!("could not parse '{}'", sockname))?;
-    let listener = TcpListener::bind(&addr)
-        .await
+    let listener = tcp_options
+        .bind(&addr)
         .with_context(|| format!("could not bind mononoke on '{}'", sockname))?;
 
     let mut terminate_process = terminate_process.fuse();
@@ -159,6 +227,16 @@ pub async fn connection_acceptor(
         writer.write_all(bound_addr.as_bytes())?;
         writer.write_all(b"\n")?;
     }
+    // Tell the caller the listener is bound. The caller may not be waiting.
+    let _ = bound_sender.send(());
+
+    // Everything that needs privileges (binding, reading TLS keys) is done by now.
+    if !run_as.is_empty() {
+        run_as
+            .drop_privileges()
+            .context("could not drop privileges")?;
+        info!(root_log, "dropped privileges to {:?}", run_as);
+    }
 
     let acceptor = Arc::new(Acceptor {
         fb,
@@ -166,6 +244,9 @@ pub async fn connection_acceptor(
         mononoke,
         security_checker,
         rate_limiter,
+        repo_rate_limiter: repo_rate_limit.map(|config| Arc::new(RepoRateLimiter::new(config))),
+        max_response_bytes,
+        wire_compression_level,
         scribe,
         logger: root_log.clone(),
         edenapi,
@@ -187,8 +268,15 @@ pub async fn connection_acceptor(
             },
             sock_tuple = listener.accept().fuse() => match sock_tuple {
                 Ok((stream, addr)) => {
-                    let conn = PendingConnection { acceptor: acceptor.clone(), addr };
-                    let task = handle_connection(conn.clone(), stream);
+                    if let Err(err) = tcp_options.configure(&stream) {
+                        warn!(root_log, "Failed to set socket options for {}: {}", addr, err);
+                    }
+                    let conn = PendingConnection {
+                        acceptor: acceptor.clone(),
+                        addr,
+                        activity: Arc::new(Activity::new()),
+                    };
+                    let task = handle_connection(conn.clone(), stream, tcp_options.idle_timeout);
                     conn.spawn_task(task, "Failed to handle_connection");
                 }
                 Err(err) => {
@@ -206,6 +294,12 @@ pub struct Acceptor {
     pub mononoke: Arc<Mononoke>,
     pub security_checker: ConnectionSecurityChecker,
     pub rate_limiter: Option<RateLimitEnvironment>,
+    pub repo_rate_limiter: Option<Arc<RepoRateLimiter>>,
+    /// Abort responses to wireproto commands larger than this many bytes.
+    pub max_response_bytes: Option<u64>,
+    /// Zstd level of wireproto connections of clients that ask for
+    /// compression. Wire compression is disabled if not set.
+    pub wire_compression_level: Option<i32>,
     pub scribe: Scribe,
     pub logger: Logger,
     pub edenapi: EdenApi,
@@ -224,6 +318,8 @@ pub struct Acceptor {
 pub struct PendingConnection {
     pub acceptor: Arc<Acceptor>,
     pub addr: SocketAddr,
+    /// Reads and writes on the socket, for reaping idle connections.
+    pub activity: Arc<Activity>,
 }
 
 /// A connection where we completed the initial TLS handshake.
@@ -263,7 +359,34 @@ impl PendingConnection {
     }
 }
 
-async fn handle_connection(conn: PendingConnection, sock: TcpStream) -> Result<()> {
+async fn handle_connection(
+    conn: PendingConnection,
+    sock: TcpStream,
+    idle_timeout: Option<Duration>,
+) -> Result<()> {
+    let activity = conn.activity.clone();
+    let sock = ActivityStream::new(sock, activity.clone());
+    let idle_timeout = match idle_timeout {
+        Some(idle_timeout) => idle_timeout,
+        None => return serve_connection(conn, sock).await,
+    };
+
+    let logger = conn.acceptor.logger.clone();
+    let addr = conn.addr;
+    select_biased! {
+        res = serve_connection(conn, sock).fuse() => res,
+        _ = activity.wait_until_idle(idle_timeout).fuse() => {
+            STATS::idle_connections_reaped.add_value(1);
+            info!(
+                logger,
+                "Closing connection to {} after {:?} without activity", addr, idle_timeout
+            );
+            Ok(())
+        },
+    }
+}
+
+async fn serve_connection(conn: PendingConnection, sock: ActivityStream<TcpStream>) -> Result<()> {
     let ssl = Ssl::new(conn.acceptor.tls_acceptor.context()).context("Error creating Ssl")?;
     let ssl_socket = SslStream::new(ssl, sock).context("Error creating SslStream")?;
     let mut ssl_socket = Box::pin(ssl_socket);
@@ -361,6 +484,8 @@ where
         &conn.pending.acceptor.security_checker,
         stdio,
         conn.pending.acceptor.rate_limiter.clone(),
+        conn.pending.acceptor.repo_rate_limiter.clone(),
+        conn.pending.acceptor.max_response_bytes,
         conn.pending.acceptor.scribe.clone(),
         conn.pending.acceptor.qps.clone(),
         conn.pending.acceptor.readonly,
@@ -418,6 +543,12 @@ impl ChannelConn {
     {
         let FramedConn { rd, wr } = framed;
 
+        // Keepalives are written every few seconds whether or not the client is there, so only
+        // count what is written to stdout and stderr. Those are only pulled from their channels
+        // once the socket accepted what came before.
+        let activity = conn.pending.activity.clone();
+        activity.ignore_writes();
+
         let stdin = Box::new(rd.compat().filter_map(|s| {
             if s.stream() == IoStream::Stdin {
                 Some(s.data())
@@ -434,11 +565,20 @@ impl ChannelConn {
             let orx = orx
                 .map(|blob| split_bytes_in_chunk(blob, CHUNK_SIZE))
                 .flatten()
-                .map(|v| SshMsg::new(IoStream::Stdout, v));
+                .map({
+                    let activity = activity.clone();
+                    move |v| {
+                        activity.touch();
+                        SshMsg::new(IoStream::Stdout, v)
+                    }
+                });
             let erx = erx
                 .map(|blob| split_bytes_in_chunk(blob, CHUNK_SIZE))
                 .flatten()
-                .map(|v| SshMsg::new(IoStream::Stderr, v));
+                .map(move |v| {
+                    activity.touch();
+                    SshMsg::new(IoStream::Stderr, v)
+                });
             let krx = krx.map(|v| SshMsg::new(IoStream::Stderr, v));
 
             // Glue them together
@@ -16,4 +16,8 @@ pub enum ErrorKind {
     AuthorizationFailed,
     #[error("Large repo not found: {0}")]
     LargeRepoNotFound(RepositoryId),
+    #[error("Too many requests to repo {0}, please retry later")]
+    RepoRateLimited(String),
+    #[error("Response too large, exceeded the limit of {0} bytes")]
+    ResponseTooLarge(u64),
 }
@@ -45,6 +45,9 @@ use tokio::io::AsyncReadExt;
 use tunables::force_update_tunables;
 use tunables::tunables;
 
+use crate::capabilities::negotiate_compression;
+use crate::capabilities::negotiate_protocol_version;
+use crate::capabilities::ServerCapabilities;
 use crate::connection_acceptor;
 use crate::connection_acceptor::AcceptedConnection;
 use crate::connection_acceptor::Acceptor;
@@ -53,10 +56,13 @@ use crate::connection_acceptor::MononokeStream;
 
 const HEADER_CLIENT_COMPRESSION: &str = "x-client-compression";
 const HEADER_CLIENT_DEBUG: &str = "x-client-debug";
+const HEADER_CLIENT_PROTOCOL_VERSION: &str = "x-client-protocol-version";
 const HEADER_WEBSOCKET_KEY: &str = "sec-websocket-key";
 const HEADER_WEBSOCKET_ACCEPT: &str = "sec-websocket-accept";
 const HEADER_MONONOKE_ENCODING: &str = "x-mononoke-encoding";
 const HEADER_MONONOKE_HOST: &str = "x-mononoke-host";
+const HEADER_MONONOKE_PROTOCOL_VERSION: &str = "x-mononoke-protocol-version";
+const HEADER_MONONOKE_CAPABILITIES: &str = "x-mononoke-capabilities";
 const HEADER_REVPROXY_REGION: &str = "x-fb-revproxy-region";
 
 // See https://tools.ietf.org/html/rfc6455#section-1.3
@@ -234,20 +240,11 @@ where
             .context("Invalid metadata")
             .map_err(HttpError::BadRequest)?;
 
-        let zstd_level: i32 = tunables::tunables()
-            .get_zstd_compression_level()
-            .try_into()
-            .unwrap_or_default();
-        let compression = match req.headers().get(HEADER_CLIENT_COMPRESSION) {
-            Some(header_value) => match header_value.as_bytes() {
-                b"zstd=stdin" if zstd_level > 0 => Ok(Some(zstd_level)),
-                header_value_bytes => Err(anyhow!(
-                    "'{}' is not a recognized compression value",
-                    String::from_utf8_lossy(header_value_bytes),
-                )),
-            },
-            None => Ok(None),
-        }
+        let wire_compression_level = self.acceptor().wire_compression_level;
+        let compression = negotiate_compression(
+            req.headers().get(HEADER_CLIENT_COMPRESSION),
+            wire_compression_level,
+        )
         .map_err(HttpError::BadRequest)?;
 
         match compression {
@@ -257,6 +254,26 @@ where
             _ => {}
         };
 
+        // Clients that don't send a protocol version get the baseline one,
+        // and are free to ignore the headers below.
+        let protocol_version =
+            negotiate_protocol_version(req.headers().get(HEADER_CLIENT_PROTOCOL_VERSION))
+                .map_err(HttpError::BadRequest)?;
+        debug!(
+            self.logger(),
+            "Negotiated protocol version {} for {}", protocol_version, reponame
+        );
+        let capabilities = ServerCapabilities {
+            zstd_compression: wire_compression_level.is_some(),
+            control_api: self.acceptor().enable_http_control_api,
+        };
+        builder = builder
+            .header(
+                HEADER_MONONOKE_PROTOCOL_VERSION,
+                protocol_version.to_string(),
+            )
+            .header(HEADER_MONONOKE_CAPABILITIES, capabilities.to_header_value());
+
         let res = builder.body(Body::empty()).map_err(HttpError::internal)?;
 
         let this = self.clone();
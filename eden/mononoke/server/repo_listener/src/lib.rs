@@ -9,11 +9,15 @@
 #![feature(never_type)]
 #![recursion_limit = "256"]
 
+mod activity_stream;
+mod capabilities;
 mod connection_acceptor;
 mod errors;
 mod http_service;
 mod netspeedtest;
+mod privileges;
 mod repo_handlers;
+mod repo_rate_limiter;
 mod request_handler;
 mod wireproto_sink;
 
@@ -39,6 +43,10 @@ use slog::Logger;
 
 use crate::connection_acceptor::connection_acceptor;
 pub use crate::connection_acceptor::wait_for_connections_closed;
+pub use crate::connection_acceptor::TcpOptions;
+pub use crate::connection_acceptor::DEFAULT_LISTEN_BACKLOG;
+pub use crate::privileges::RunAs;
+pub use crate::repo_rate_limiter::RepoRateLimitConfig;
 
 const CONFIGERATOR_RATE_LIMITING_CONFIG: &str = "scm/mononoke/ratelimiting/ratelimits";
 
@@ -57,6 +65,12 @@ pub async fn create_repo_listeners<'a>(
     will_exit: Arc<AtomicBool>,
     cslb_config: Option<String>,
     bound_addr_file: Option<PathBuf>,
+    bound_sender: oneshot::Sender<()>,
+    tcp_options: TcpOptions,
+    repo_rate_limit: Option<RepoRateLimitConfig>,
+    run_as: RunAs,
+    max_response_bytes: Option<u64>,
+    wire_compression_level: Option<i32>,
     acl_provider: &dyn AclProvider,
     readonly: bool,
 ) -> Result<()> {
@@ -113,6 +127,12 @@ pub async fn create_repo_listeners<'a>(
             scuba
         },
         bound_addr_file,
+        bound_sender,
+        tcp_options,
+        repo_rate_limit,
+        run_as,
+        max_response_bytes,
+        wire_compression_level,
         acl_provider,
         readonly,
     )
@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Dropping root privileges once the listening socket is bound, so the
+//! server can bind privileged ports without serving requests as root.
+
+use std::io;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+/// User and group to switch to after binding. Nothing is changed for
+/// fields that are not set.
+#[derive(Clone, Debug, Default)]
+pub struct RunAs {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl RunAs {
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none()
+    }
+
+    /// Switch to the configured group and user, and check that the switch
+    /// cannot be undone. The group goes first since changing it requires
+    /// privileges that are lost when the user changes.
+    pub fn drop_privileges(&self) -> Result<()> {
+        if let Some(gid) = self.gid {
+            let groups = [gid as libc::gid_t];
+            // Supplementary groups would otherwise be inherited from root.
+            if unsafe { libc::setgroups(1, groups.as_ptr()) } != 0 {
+                let err = io::Error::last_os_error();
+                return Err(err).with_context(|| format!("could not set groups to {}", gid));
+            }
+            if unsafe { libc::setgid(gid as libc::gid_t) } != 0 {
+                let err = io::Error::last_os_error();
+                return Err(err).with_context(|| format!("could not set gid to {}", gid));
+            }
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid as libc::uid_t) } != 0 {
+                let err = io::Error::last_os_error();
+                return Err(err).with_context(|| format!("could not set uid to {}", uid));
+            }
+        }
+
+        if let Some(gid) = self.gid {
+            let (real, effective) = unsafe { (libc::getgid(), libc::getegid()) };
+            if real != gid || effective != gid {
+                bail!(
+                    "gid is {} (effective {}) after switching to {}",
+                    real,
+                    effective,
+                    gid
+                );
+            }
+        }
+        if let Some(uid) = self.uid {
+            let (real, effective) = unsafe { (libc::getuid(), libc::geteuid()) };
+            if real != uid || effective != uid {
+                bail!(
+                    "uid is {} (effective {}) after switching to {}",
+                    real,
+                    effective,
+                    uid
+                );
+            }
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                bail!(
+                    "root privileges could be regained after switching to uid {}",
+                    uid
+                );
+            }
+        }
+        Ok(())
+    }
+}
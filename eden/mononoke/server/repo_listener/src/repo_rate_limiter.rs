@@ -0,0 +1,215 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Token bucket rate limiting of wireproto commands, keyed by repo and
+//! optionally by client identity.
+//!
+//! Buckets are spread across independently locked shards so that commands to
+//! different repos rarely contend on the same lock.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::sync::Mutex;
+
+use permission_checker::MononokeIdentitySet;
+use tokio::time::Instant;
+
+const SHARDS: usize = 64;
+
+/// Shards holding more buckets than this drop the ones that are full, as
+/// those behave the same as freshly created buckets.
+const MAX_BUCKETS_PER_SHARD: usize = 1024;
+
+#[derive(Clone, Debug)]
+pub struct RepoRateLimitConfig {
+    /// Sustained number of commands allowed per second.
+    pub requests_per_second: f64,
+    /// Number of commands that can be run at once after being idle.
+    pub burst: u64,
+    /// Limit each client identity separately instead of the repo as a whole.
+    pub per_client: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct BucketKey {
+    repo: String,
+    client: Option<String>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant, config: &RepoRateLimitConfig) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.requests_per_second).min(capacity(config));
+        self.updated = now;
+    }
+}
+
+fn capacity(config: &RepoRateLimitConfig) -> f64 {
+    config.burst.max(1) as f64
+}
+
+pub struct RepoRateLimiter {
+    config: RepoRateLimitConfig,
+    shards: Vec<Mutex<HashMap<BucketKey, TokenBucket>>>,
+}
+
+impl RepoRateLimiter {
+    pub fn new(config: RepoRateLimitConfig) -> Self {
+        Self {
+            config,
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    /// Take a token for a command to `repo` from the given client. Returns
+    /// false if the command should be rejected.
+    pub fn try_acquire(&self, repo: &str, identities: &MononokeIdentitySet) -> bool {
+        let key = BucketKey {
+            repo: repo.to_string(),
+            client: self.config.per_client.then(|| {
+                identities
+                    .iter()
+                    .map(|identity| identity.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        };
+        let now = Instant::now();
+        let capacity = capacity(&self.config);
+
+        let mut buckets = self.shards[shard(&key)].lock().expect("lock poisoned");
+        if buckets.len() >= MAX_BUCKETS_PER_SHARD && !buckets.contains_key(&key) {
+            let config = &self.config;
+            buckets.retain(|_, bucket| {
+                bucket.refill(now, config);
+                bucket.tokens < capacity
+            });
+        }
+
+        let bucket = buckets.entry(key).or_insert_with(|| TokenBucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.refill(now, &self.config);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn shard(key: &BucketKey) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use permission_checker::MononokeIdentity;
+
+    use super::*;
+
+    fn config(requests_per_second: f64, burst: u64, per_client: bool) -> RepoRateLimitConfig {
+        RepoRateLimitConfig {
+            requests_per_second,
+            burst,
+            per_client,
+        }
+    }
+
+    fn identities(user: &str) -> MononokeIdentitySet {
+        [MononokeIdentity::new("USER", user)].into_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_burst() {
+        tokio::time::pause();
+        let limiter = RepoRateLimiter::new(config(1.0, 3, false));
+        let alice = identities("alice");
+        for _ in 0..3 {
+            assert!(limiter.try_acquire("repo", &alice));
+        }
+        assert!(!limiter.try_acquire("repo", &alice));
+        // Other repos have their own bucket.
+        assert!(limiter.try_acquire("other", &alice));
+    }
+
+    #[tokio::test]
+    async fn test_refill() {
+        tokio::time::pause();
+        let limiter = RepoRateLimiter::new(config(2.0, 2, false));
+        let alice = identities("alice");
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(!limiter.try_acquire("repo", &alice));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(!limiter.try_acquire("repo", &alice));
+
+        // Buckets don't fill beyond the burst.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(!limiter.try_acquire("repo", &alice));
+    }
+
+    #[tokio::test]
+    async fn test_per_client() {
+        tokio::time::pause();
+        let alice = identities("alice");
+        let bob = identities("bob");
+
+        let limiter = RepoRateLimiter::new(config(1.0, 1, true));
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(!limiter.try_acquire("repo", &alice));
+        assert!(limiter.try_acquire("repo", &bob));
+
+        let limiter = RepoRateLimiter::new(config(1.0, 1, false));
+        assert!(limiter.try_acquire("repo", &alice));
+        assert!(!limiter.try_acquire("repo", &bob));
+    }
+
+    #[tokio::test]
+    async fn test_shard_eviction() {
+        tokio::time::pause();
+        let limiter = RepoRateLimiter::new(config(1.0, 1, false));
+        let alice = identities("alice");
+        let key = |repo: String| BucketKey { repo, client: None };
+        let target = shard(&key("repo0".to_string()));
+        let repos: Vec<String> = (0..)
+            .map(|i| format!("repo{}", i))
+            .filter(|repo| shard(&key(repo.clone())) == target)
+            .take(MAX_BUCKETS_PER_SHARD + 2)
+            .collect();
+        let shard_len = || limiter.shards[target].lock().unwrap().len();
+
+        // Buckets that are not full are kept.
+        for repo in &repos[..=MAX_BUCKETS_PER_SHARD] {
+            assert!(limiter.try_acquire(repo, &alice));
+        }
+        assert_eq!(shard_len(), MAX_BUCKETS_PER_SHARD + 1);
+
+        // Once they are full again, they are dropped to make room.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.try_acquire(&repos[MAX_BUCKETS_PER_SHARD + 1], &alice));
+        assert_eq!(shard_len(), 1);
+    }
+}
@@ -5,6 +5,8 @@
  * GNU General Public License version 2.
  */
 
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -29,11 +31,14 @@ use hgproto::HgProtoHandler;
 use maplit::hashmap;
 use maplit::hashset;
 use mononoke_api::Mononoke;
+use permission_checker::MononokeIdentitySet;
 use qps::Qps;
 use rate_limiting::Metric;
 use rate_limiting::RateLimitEnvironment;
+use repo_client::CommandRateLimiter;
 use repo_client::RepoClient;
 use scribe_ext::Scribe;
+use scuba_ext::MononokeScubaSampleBuilder;
 use slog::error;
 use slog::o;
 use slog::Drain;
@@ -45,10 +50,12 @@ use sshrelay::SenderBytesWrite;
 use sshrelay::Stdio;
 use stats::prelude::*;
 use time_ext::DurationExt;
+use tracing::Instrument;
 
 use crate::errors::ErrorKind;
 use crate::repo_handlers::repo_handler;
 use crate::repo_handlers::RepoHandler;
+use crate::repo_rate_limiter::RepoRateLimiter;
 
 define_stats! {
     prefix = "mononoke.request_handler";
@@ -57,6 +64,8 @@ define_stats! {
     request_success: timeseries(Rate, Sum),
     request_failure: timeseries(Rate, Sum),
     request_outcome_permille: timeseries(Average),
+    request_repo_rate_limited: timeseries(Rate, Sum),
+    response_too_large: timeseries(Rate, Sum),
 }
 
 pub async fn request_handler(
@@ -66,6 +75,8 @@ pub async fn request_handler(
     _security_checker: &ConnectionSecurityChecker,
     stdio: Stdio,
     rate_limiter: Option<RateLimitEnvironment>,
+    repo_rate_limiter: Option<Arc<RepoRateLimiter>>,
+    max_response_bytes: Option<u64>,
     scribe: Scribe,
     qps: Option<Arc<Qps>>,
     readonly: bool,
@@ -79,6 +90,17 @@ pub async fn request_handler(
 
     let session_id = metadata.session_id();
 
+    // Commands handled on this connection nest under this span. At INFO level
+    // this is a no-op unless a subscriber is interested, and can be compiled
+    // out with the tracing `max_level_*` features.
+    let span = tracing::info_span!(
+        "wireproto_session",
+        repo = %reponame,
+        session_id = %session_id,
+        request_bytes = tracing::field::Empty,
+        response_bytes = tracing::field::Empty,
+    );
+
     // We don't have a repository yet, so create without server drain
     let conn_log = create_conn_logger(stderr.clone(), None, Some(session_id));
 
@@ -105,7 +127,7 @@ pub async fn request_handler(
     let conn_log = create_conn_logger(stderr.clone(), Some(logger), Some(session_id));
 
     scuba = scuba.with_seq("seq");
-    scuba.add("repo", reponame);
+    scuba.add("repo", reponame.clone());
     scuba.add_metadata(&metadata);
     scuba.sample_for_identities(metadata.identities());
 
@@ -145,6 +167,15 @@ pub async fn request_handler(
 
     let session = session_builder.build();
 
+    let command_rate_limiter = repo_rate_limiter.map(|repo_rate_limiter| {
+        Arc::new(RepoCommandRateLimiter {
+            repo_rate_limiter,
+            reponame: reponame.clone(),
+            identities: metadata.identities().clone(),
+            scuba: scuba.clone(),
+        }) as Arc<dyn CommandRateLimiter>
+    });
+
     let mut logging = LoggingContainer::new(fb, conn_log.clone(), scuba.clone());
     logging.with_scribe(scribe);
 
@@ -155,13 +186,26 @@ pub async fn request_handler(
         maybe_push_redirector_args,
         repo_client_knobs,
         maybe_backup_repo_source,
+        command_rate_limiter,
     );
     let request_perf_counters = repo_client.request_perf_counters();
+    let command_bytes = repo_client.command_bytes();
+
+    let request_bytes = Arc::new(AtomicU64::new(0));
+    let response_bytes = Arc::new(AtomicU64::new(0));
 
     // Construct a hg protocol handler
     let proto_handler = HgProtoHandler::new(
         conn_log.clone(),
-        stdin.map(|b| bytes_old::Bytes::from(b.as_ref())),
+        stdin.map({
+            let request_bytes = request_bytes.clone();
+            let command_bytes = command_bytes.clone();
+            move |b| {
+                request_bytes.fetch_add(b.len() as u64, Ordering::Relaxed);
+                command_bytes.add_request(b.len() as u64);
+                bytes_old::Bytes::from(b.as_ref())
+            }
+        }),
         repo_client,
         sshproto::HgSshCommandDecode,
         sshproto::HgSshCommandEncode,
@@ -172,14 +216,40 @@ pub async fn request_handler(
 
     // send responses back
     let endres = proto_handler
-        .inspect(move |bytes| session.bump_load(Metric::EgressBytes, bytes.len() as f64))
+        .inspect({
+            let response_bytes = response_bytes.clone();
+            let command_bytes = command_bytes.clone();
+            move |bytes| {
+                response_bytes.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                command_bytes.add_response(bytes.len() as u64);
+                session.bump_load(Metric::EgressBytes, bytes.len() as f64)
+            }
+        })
         .map_err(Error::from)
+        .and_then({
+            let command_bytes = command_bytes.clone();
+            let conn_log = conn_log.clone();
+            move |bytes| match max_response_bytes {
+                Some(max) if command_bytes.response() > max => {
+                    STATS::response_too_large.add_value(1);
+                    let err = ErrorKind::ResponseTooLarge(max);
+                    // Tell the client why its response stops short, as stdout is closed next.
+                    error!(conn_log, "{}", err; "remote" => "true");
+                    Err(err.into())
+                }
+                _ => Ok(bytes),
+            }
+        })
         .map(|b| Bytes::copy_from_slice(b.as_ref()))
         .forward(stdout)
         .map(|_| ());
 
     // If we got an error at this point, then catch it and print a message
-    let (stats, result) = endres.compat().timed().await;
+    let (stats, result) = endres.compat().timed().instrument(span.clone()).await;
+
+    span.record("request_bytes", request_bytes.load(Ordering::Relaxed));
+    span.record("response_bytes", response_bytes.load(Ordering::Relaxed));
+    command_bytes.finish();
 
     let wireproto_calls = {
         let mut wireproto_calls = wireproto_calls.lock().expect("lock poisoned");
@@ -220,15 +290,48 @@ pub async fn request_handler(
     }
 
     if let Err(err) = result {
+        // Oversized responses were already reported to the client above.
+        let remote = match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::ResponseTooLarge(_)) => "false",
+            _ => "true",
+        };
         error!(&conn_log, "Command failed";
             SlogKVError(err),
-            "remote" => "true"
+            "remote" => remote
         );
     }
 
     Ok(())
 }
 
+/// Applies the repo rate limit to each command of a connection.
+struct RepoCommandRateLimiter {
+    repo_rate_limiter: Arc<RepoRateLimiter>,
+    reponame: String,
+    identities: MononokeIdentitySet,
+    scuba: MononokeScubaSampleBuilder,
+}
+
+impl CommandRateLimiter for RepoCommandRateLimiter {
+    fn check(&self, command: &str) -> Result<()> {
+        if self
+            .repo_rate_limiter
+            .try_acquire(&self.reponame, &self.identities)
+        {
+            return Ok(());
+        }
+        STATS::request_repo_rate_limited.add_value(1);
+        let err = ErrorKind::RepoRateLimited(self.reponame.clone());
+        let mut scuba = self.scuba.clone();
+        scuba.add("command", command);
+        scuba.log_with_msg(
+            "Command rejected due to repo rate limit",
+            format!("{}", err),
+        );
+        Err(err.into())
+    }
+}
+
 pub fn create_conn_logger(
     stderr: mpsc::UnboundedSender<Bytes>,
     server_logger: Option<Logger>,
@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! One-shot integrity check of the data indexes of the configured repos, for
+//! on-call to run against a suspect repo.
+//!
+//! Data indexes live in the shared cache, laid out like the client's
+//! `remotefilelog.cachepath`: the packs of a repo are under
+//! `<cache path>/<repo name>/packs`, with manifest packs in a subdirectory.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use metaconfig_parser::RepoConfigs;
+use revisionstore::DataIndex;
+use slog::error;
+use slog::info;
+use slog::Logger;
+
+/// Check every data index of the enabled repos under `cache_path` and log
+/// problems per file. A repo that has a packs directory without any data
+/// index counts as a problem too. Return the number of problems.
+pub fn fsck(configs: &RepoConfigs, cache_path: &Path, logger: &Logger) -> Result<usize> {
+    let mut repo_names: Vec<&String> = configs.repos.keys().collect();
+    repo_names.sort();
+
+    let mut problems = 0;
+    for repo_name in repo_names {
+        if !configs.repos[repo_name].enabled {
+            continue;
+        }
+        let dir = cache_path.join(repo_name).join("packs");
+        if !dir.is_dir() {
+            info!(logger, "{}: no packs in {}", repo_name, dir.display());
+            continue;
+        }
+
+        let mut index_paths = Vec::new();
+        find_data_indexes(&dir, &mut index_paths)
+            .with_context(|| format!("Failed to list '{}'", dir.display()))?;
+        if index_paths.is_empty() {
+            error!(
+                logger,
+                "{}: no data indexes in {}",
+                repo_name,
+                dir.display()
+            );
+            problems += 1;
+            continue;
+        }
+        index_paths.sort();
+        info!(
+            logger,
+            "{}: checking {} data indexes",
+            repo_name,
+            index_paths.len()
+        );
+        for path in index_paths {
+            if let Err(err) = check_data_index(&path, logger) {
+                error!(logger, "{}: {}: {:#}", repo_name, path.display(), err);
+                problems += 1;
+            }
+        }
+    }
+    Ok(problems)
+}
+
+fn find_data_indexes(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_data_indexes(&path, paths)?;
+        } else if path.extension().map_or(false, |ext| ext == "dataidx") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn check_data_index(path: &Path, logger: &Logger) -> Result<()> {
+    let index: DataIndex = DataIndex::new(path)?;
+    index.verify_with_progress(|checked, total| {
+        if checked < total {
+            info!(
+                logger,
+                "{}: checked {} of {} entries",
+                path.display(),
+                checked,
+                total
+            );
+        }
+    })?;
+    index.check_fanout_consistency()?;
+    index.verify_checksums()?;
+    let duplicates = index.find_duplicates()?;
+    if !duplicates.is_empty() {
+        let duplicates: Vec<String> = duplicates.iter().map(|node| node.to_string()).collect();
+        bail!("duplicate nodes: {}", duplicates.join(", "));
+    }
+    Ok(())
+}
@@ -7,10 +7,14 @@
 
 #![feature(never_type)]
 
+mod fsck;
+
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
@@ -26,6 +30,7 @@ use executor_lib::RepoShardedProcess;
 use executor_lib::RepoShardedProcessExecutor;
 use fbinit::FacebookInit;
 use futures::channel::oneshot;
+use futures::future;
 use futures::stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
@@ -35,7 +40,7 @@ use mononoke_api::Mononoke;
 use mononoke_api::Repo;
 use mononoke_app::args::HooksAppExtension;
 use mononoke_app::args::McrouterAppExtension;
-use mononoke_app::args::ReadonlyArgs;
+use mononoke_app::args::ReadonlyAppExtension;
 use mononoke_app::args::RepoFilterAppExtension;
 use mononoke_app::args::ShutdownTimeoutArgs;
 use mononoke_app::fb303::Fb303AppExtension;
@@ -44,9 +49,14 @@ use mononoke_app::MononokeApp;
 use mononoke_app::MononokeAppBuilder;
 use mononoke_repos::MononokeRepos;
 use openssl::ssl::AlpnError;
+use repo_listener::RepoRateLimitConfig;
+use repo_listener::RunAs;
+use repo_listener::TcpOptions;
+use repo_listener::DEFAULT_LISTEN_BACKLOG;
 use slog::error;
 use slog::info;
 use slog::o;
+use slog::warn;
 use slog::Logger;
 
 const SM_CLEANUP_TIMEOUT_SECS: u64 = 120;
@@ -59,23 +69,73 @@ struct MononokeServerArgs {
     #[clap(flatten)]
     scribe_logging_args: ScribeLoggingArgs,
     /// TCP address to listen to in format `host:port
-    #[clap(long)]
-    listening_host_port: String,
+    #[clap(long, required_unless_present = "fsck")]
+    listening_host_port: Option<String>,
     /// Path for file in which to write the bound tcp address in rust std::net::SocketAddr format
     #[clap(long)]
     bound_address_file: Option<PathBuf>,
+    /// Maximum number of pending connections queued by the listening socket
+    #[clap(long, default_value_t = DEFAULT_LISTEN_BACKLOG)]
+    listen_backlog: u32,
+    /// Set TCP_NODELAY on accepted connections
+    #[clap(long)]
+    tcp_nodelay: bool,
+    /// Enable TCP keepalive on accepted connections, sending probes after
+    /// this many seconds of idleness
+    #[clap(long)]
+    tcp_keepalive_secs: Option<u64>,
+    /// Close connections that had no reads or writes for this many seconds,
+    /// not counting wireproto keepalives. Connections are not closed for
+    /// being idle if this is not set
+    #[clap(long)]
+    idle_timeout_secs: Option<u64>,
+    /// Maximum sustained number of wireproto commands per second to each
+    /// repo. Commands are not limited if this is not set
+    #[clap(long)]
+    repo_rate_limit: Option<f64>,
+    /// Number of commands to a repo allowed at once on top of
+    /// --repo-rate-limit. Defaults to one second worth of commands
+    #[clap(long, requires = "repo-rate-limit")]
+    repo_rate_limit_burst: Option<u64>,
+    /// Apply --repo-rate-limit to each client identity separately
+    #[clap(long, requires = "repo-rate-limit")]
+    repo_rate_limit_per_client: bool,
+    /// Abort the response to a wireproto command once it exceeds this many
+    /// bytes. Responses are not limited if this is not set
+    #[clap(long)]
+    max_response_bytes: Option<u64>,
+    /// Advertise the zstd capability and compress wireproto connections
+    /// with zstd for clients that ask for it. Clients that ask for
+    /// compression are rejected otherwise
+    #[clap(long)]
+    enable_wire_compression: bool,
+    /// Zstd level used with --enable-wire-compression
+    #[clap(long, default_value_t = 3, requires = "enable-wire-compression")]
+    wire_compression_level: i32,
+    /// Warm up the cache of this repo in the background after the server
+    /// has started, instead of before it accepts connections. Can be repeated
+    #[clap(long)]
+    prewarm_repo: Vec<String>,
+    /// Switch to this user id once the listening socket is bound. Allows
+    /// binding privileged ports without serving requests as root. Requires
+    /// --setgid, so that root's group is not kept
+    #[clap(long, requires = "setgid")]
+    setuid: Option<u32>,
+    /// Switch to this group id once the listening socket is bound
+    #[clap(long)]
+    setgid: Option<u32>,
     /// If provided the thrift server will start on this port
     #[clap(long, short = 'p')]
     thrift_port: Option<String>,
     /// Path to a file with server certificate
-    #[clap(long)]
-    cert: String,
+    #[clap(long, required_unless_present = "fsck")]
+    cert: Option<String>,
     /// Path to a file with server private key
-    #[clap(long)]
-    private_key: String,
+    #[clap(long, required_unless_present = "fsck")]
+    private_key: Option<String>,
     /// Path to a file with CA certificate
-    #[clap(long)]
-    ca_pem: String,
+    #[clap(long, required_unless_present = "fsck")]
+    ca_pem: Option<String>,
     /// Path to a file with SCS client certificate
     #[clap(long)]
     scs_client_cert: Option<String>,
@@ -89,8 +149,6 @@ struct MononokeServerArgs {
     #[clap(long)]
     cslb_config: Option<String>,
     #[clap(flatten)]
-    readonly: ReadonlyArgs,
-    #[clap(flatten)]
     sharded_executor_args: ShardedExecutorArgs,
     /// Path to a file with land service client certificate
     #[clap(long)]
@@ -98,6 +156,15 @@ struct MononokeServerArgs {
     /// Path to a file with land service client private key
     #[clap(long, requires = "land-service-client-cert")]
     land_service_client_private_key: Option<String>,
+    /// Print the repo configs the server would use as JSON, after all
+    /// config sources are merged, and exit without serving
+    #[clap(long)]
+    dump_config: bool,
+    /// Check the data indexes of each repo in the shared cache at this
+    /// path, under `<repo name>/packs`, report problems per file, and exit
+    /// without serving. Exits with an error if any problem is found
+    #[clap(long, value_name = "CACHE_PATH")]
+    fsck: Option<PathBuf>,
 }
 
 /// Struct representing the Mononoke API process.
@@ -216,6 +283,42 @@ impl RepoShardedProcessExecutor for MononokeApiProcessExecutor {
     }
 }
 
+/// Warm up the caches of `repo_names` one at a time, using the cache warmup
+/// configured for each repo.
+async fn prewarm_repos(
+    fb: FacebookInit,
+    mononoke: Arc<Mononoke>,
+    repo_names: Vec<String>,
+    root_log: Logger,
+) {
+    for repo_name in repo_names {
+        let logger = root_log.new(o!("repo" => repo_name.clone()));
+        let repo = match mononoke.raw_repo(&repo_name) {
+            Some(repo) => repo,
+            None => {
+                warn!(&logger, "Not prewarming repo {}: not served", repo_name);
+                continue;
+            }
+        };
+        let cache_warmup_params = repo.config().cache_warmup.clone();
+        if cache_warmup_params.is_none() {
+            warn!(
+                &logger,
+                "Not prewarming repo {}: no cache warmup configured", repo_name
+            );
+            continue;
+        }
+        let ctx = CoreContext::new_with_logger(fb, logger.clone());
+        match cache_warmup(&ctx, repo.blob_repo(), cache_warmup_params).await {
+            Ok(()) => info!(&logger, "Prewarming repo {} completed", repo_name),
+            Err(e) => error!(
+                &logger,
+                "Error while prewarming repo {}: {:?}", repo_name, e
+            ),
+        }
+    }
+}
+
 #[fbinit::main]
 fn main(fb: FacebookInit) -> Result<()> {
     let app = Arc::new(
@@ -226,10 +329,35 @@ fn main(fb: FacebookInit) -> Result<()> {
             .with_app_extension(Fb303AppExtension {})
             .with_app_extension(HooksAppExtension {})
             .with_app_extension(RepoFilterAppExtension {})
+            .with_app_extension(ReadonlyAppExtension {})
             .build::<MononokeServerArgs>()?,
     );
     let args: MononokeServerArgs = app.args()?;
 
+    if args.dump_config {
+        let configs = app.repo_configs();
+        let json = serde_json::to_string_pretty(&*configs)
+            .context("Failed to serialize the repo configs")?;
+        println!("{}", json);
+        return Ok(());
+    }
+
+    if let Some(cache_path) = &args.fsck {
+        let problems = fsck::fsck(&app.repo_configs(), cache_path, app.logger())?;
+        if problems > 0 {
+            bail!("fsck found {} problems", problems);
+        }
+        return Ok(());
+    }
+
+    // These are required unless --fsck is passed.
+    let host_port = args
+        .listening_host_port
+        .context("--listening-host-port is required")?;
+    let cert = args.cert.context("--cert is required")?;
+    let private_key = args.private_key.context("--private-key is required")?;
+    let ca_pem = args.ca_pem.context("--ca-pem is required")?;
+
     let root_log = app.logger();
     let runtime = app.runtime();
 
@@ -240,11 +368,7 @@ fn main(fb: FacebookInit) -> Result<()> {
     if let (Some(scs_cert_path), Some(scs_key_path)) =
         (&args.scs_client_cert, &args.scs_client_private_key)
     {
-        pushrebase_client::scs_override_certificate_paths(
-            scs_cert_path,
-            scs_key_path,
-            &args.ca_pem,
-        );
+        pushrebase_client::scs_override_certificate_paths(scs_cert_path, scs_key_path, &ca_pem);
     }
 
     #[cfg(fbcode_build)]
@@ -255,21 +379,17 @@ fn main(fb: FacebookInit) -> Result<()> {
         pushrebase_client::land_service_override_certificate_paths(
             land_service_cert_path,
             land_service_key_path,
-            &args.ca_pem,
+            &ca_pem,
         );
     }
 
     let configs = app.repo_configs();
 
     let acceptor = {
-        let mut builder = secure_utils::SslConfig::new(
-            args.ca_pem,
-            args.cert,
-            args.private_key,
-            args.ssl_ticket_seeds,
-        )
-        .tls_acceptor_builder(root_log.clone())
-        .context("Failed to instantiate TLS Acceptor builder")?;
+        let mut builder =
+            secure_utils::SslConfig::new(ca_pem, cert, private_key, args.ssl_ticket_seeds)
+                .tls_acceptor_builder(root_log.clone())
+                .context("Failed to instantiate TLS Acceptor builder")?;
 
         builder.set_alpn_select_callback(|_, protos| {
             // NOTE: Currently we do not support HTTP/2 here yet.
@@ -287,9 +407,37 @@ fn main(fb: FacebookInit) -> Result<()> {
     let (terminate_sender, terminate_receiver) = oneshot::channel::<()>();
 
     let scribe = args.scribe_logging_args.get_scribe(fb)?;
-    let host_port = args.listening_host_port;
 
     let bound_addr_file = args.bound_address_file;
+    let tcp_options = TcpOptions {
+        listen_backlog: args.listen_backlog,
+        nodelay: args.tcp_nodelay,
+        keepalive: args.tcp_keepalive_secs.map(Duration::from_secs),
+        idle_timeout: args.idle_timeout_secs.map(Duration::from_secs),
+    };
+    if args.repo_rate_limit.map_or(false, |limit| limit <= 0.0) {
+        bail!("--repo-rate-limit must be positive");
+    }
+    let repo_rate_limit = args
+        .repo_rate_limit
+        .map(|requests_per_second| RepoRateLimitConfig {
+            requests_per_second,
+            burst: args
+                .repo_rate_limit_burst
+                .unwrap_or_else(|| requests_per_second.ceil() as u64),
+            per_client: args.repo_rate_limit_per_client,
+        });
+    if args.enable_wire_compression && args.wire_compression_level <= 0 {
+        bail!("--wire-compression-level must be positive");
+    }
+    let wire_compression_level = args
+        .enable_wire_compression
+        .then(|| args.wire_compression_level);
+    let readonly = app.extension_args::<ReadonlyAppExtension>()?.readonly;
+    let run_as = RunAs {
+        uid: args.setuid,
+        gid: args.setgid,
+    };
 
     let env = app.environment();
 
@@ -297,6 +445,8 @@ fn main(fb: FacebookInit) -> Result<()> {
 
     let will_exit = Arc::new(AtomicBool::new(false));
 
+    let prewarm_repo_names = args.prewarm_repo;
+
     let repo_listeners = {
         cloned!(root_log, service, will_exit, env, runtime);
         let app = Arc::clone(&app);
@@ -306,7 +456,9 @@ fn main(fb: FacebookInit) -> Result<()> {
             info!(&root_log, "Built Mononoke");
 
             info!(&root_log, "Warming up cache");
+            let prewarmed: HashSet<String> = prewarm_repo_names.iter().cloned().collect();
             stream::iter(mononoke.repos())
+                .filter(|repo| future::ready(!prewarmed.contains(repo.name())))
                 .map(|repo| {
                     let repo_name = repo.name().to_string();
                     let blob_repo = repo.blob_repo().clone();
@@ -343,6 +495,22 @@ fn main(fb: FacebookInit) -> Result<()> {
                     async move { executor.block_and_execute(&logger).await }
                 });
             }
+            // Prewarm once the listener is bound, so that it competes with
+            // connections rather than delaying them.
+            let (bound_sender, bound_receiver) = oneshot::channel();
+            if !prewarm_repo_names.is_empty() {
+                let prewarm = prewarm_repos(
+                    fb,
+                    mononoke.clone(),
+                    prewarm_repo_names.clone(),
+                    root_log.clone(),
+                );
+                runtime.spawn(async move {
+                    if bound_receiver.await.is_ok() {
+                        prewarm.await;
+                    }
+                });
+            }
             repo_listener::create_repo_listeners(
                 fb,
                 common,
@@ -358,8 +526,14 @@ fn main(fb: FacebookInit) -> Result<()> {
                 will_exit,
                 cslb_config,
                 bound_addr_file,
+                bound_sender,
+                tcp_options,
+                repo_rate_limit,
+                run_as,
+                args.max_response_bytes,
+                wire_compression_level,
                 env.acl_provider.as_ref(),
-                args.readonly.readonly,
+                readonly,
             )
             .await
         }
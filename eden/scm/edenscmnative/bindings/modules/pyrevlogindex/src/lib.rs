@@ -9,11 +9,14 @@
 
 use std::cell::RefCell;
 use std::path::Path;
+use std::sync::Arc;
 
 use ::revlogindex::RevlogIndex;
+use cpython::_detail::ffi;
 use cpython::*;
 use cpython_ext::PyNone;
 use cpython_ext::ResultPyErrExt;
+use dag::Vertex;
 use pydag::Spans;
 
 // XXX: The revlogindex is a temporary solution before migrating to
@@ -34,10 +37,17 @@ pub fn init_module(py: Python, package: &str) -> PyResult<PyModule> {
 py_class!(class revlogindex |py| {
     data index: RefCell<RevlogIndex>;
 
-    def __new__(_cls, changelogipath: String, nodemappath: String) -> PyResult<Self> {
+    def __new__(
+        _cls,
+        changelogipath: String,
+        nodemappath: String,
+        filelog: bool = false
+    ) -> PyResult<Self> {
         let changelogipath = Path::new(&changelogipath);
         let nodemappath = Path::new(&nodemappath);
-        let index = RevlogIndex::new(&changelogipath, &nodemappath).map_pyerr(py)?;
+        let mut index = RevlogIndex::new(&changelogipath, &nodemappath).map_pyerr(py)?;
+        index.set_interrupt_check(Some(Arc::new(check_signals)));
+        index.set_filelog(filelog);
         Self::create_instance(py, RefCell::new(index))
     }
 
@@ -50,15 +60,305 @@ py_class!(class revlogindex |py| {
     /// Calculate `heads(ancestors(revs))`.
     def headsancestors(&self, revs: Vec<u32>) -> PyResult<Vec<u32>> {
         let revlog = self.index(py).borrow();
-        Ok(revlog.headsancestors(revs).map_pyerr(py)?)
+        map_interrupted(py, revlog.headsancestors(revs))
     }
 
-    /// Given public and draft head revision numbers, calculate the "phase sets".
-    /// Return (publicset, draftset).
-    def phasesets(&self, publicheads: Vec<u32>, draftheads: Vec<u32>) -> PyResult<(Spans, Spans)> {
+    /// Given public, draft and secret head revision numbers, calculate the
+    /// "phase sets". Return (publicset, draftset, secretset).
+    def phasesets(
+        &self,
+        publicheads: Vec<u32>,
+        draftheads: Vec<u32>,
+        secretheads: Vec<u32>
+    ) -> PyResult<(Spans, Spans, Spans)> {
         let revlog = self.index(py).borrow();
-        let (public_set, draft_set) = revlog.phasesets(publicheads, draftheads).map_pyerr(py)?;
-        Ok((Spans(public_set), Spans(draft_set)))
+        let (public_set, draft_set, secret_set) = revlog
+            .phasesets(publicheads, draftheads, secretheads)
+            .map_pyerr(py)?;
+        Ok((Spans(public_set), Spans(draft_set), Spans(secret_set)))
+    }
+
+    /// Like `phasesets`, but return lists of nodes, ordered by ascending
+    /// revision number.
+    def phasesets_nodes(
+        &self,
+        publicheads: Vec<u32>,
+        draftheads: Vec<u32>,
+        secretheads: Vec<u32>
+    ) -> PyResult<(Vec<PyBytes>, Vec<PyBytes>, Vec<PyBytes>)> {
+        let revlog = self.index(py).borrow();
+        let (public_nodes, draft_nodes, secret_nodes) = revlog
+            .phasesets_nodes(publicheads, draftheads, secretheads)
+            .map_pyerr(py)?;
+        let to_py = |nodes: Vec<Vertex>| -> Vec<PyBytes> {
+            nodes.iter().map(|node| PyBytes::new(py, node.as_ref())).collect()
+        };
+        Ok((to_py(public_nodes), to_py(draft_nodes), to_py(secret_nodes)))
+    }
+
+    /// Given draft and secret root revision numbers, calculate the "phase
+    /// sets" from roots. Return (draftset, secretset). Other revisions are
+    /// public.
+    def phasesets_from_roots(
+        &self,
+        draftroots: Vec<u32>,
+        secretroots: Vec<u32>
+    ) -> PyResult<(Spans, Spans)> {
+        let revlog = self.index(py).borrow();
+        let (draft_set, secret_set) =
+            map_interrupted(py, revlog.phasesets_from_roots(draftroots, secretroots))?;
+        Ok((Spans(draft_set), Spans(secret_set)))
+    }
+
+    /// Calculate the common and missing sets for discovery.
+    /// Return (common, missing), where common is `::localheads & ::remoteheads`,
+    /// and missing is `::remoteheads - common`.
+    def findmissingcommon(&self, localheads: Vec<u32>, remoteheads: Vec<u32>) -> PyResult<(Spans, Spans)> {
+        let revlog = self.index(py).borrow();
+        let (common, missing) = map_interrupted(py, revlog.findmissingcommon(localheads, remoteheads))?;
+        Ok((Spans(common), Spans(missing)))
+    }
+
+    /// Get revisions without children, in ascending order.
+    def headrevs(&self) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.headrevs())
+    }
+
+    /// Calculate `parents(revs) - revs`.
+    def parentsofset(&self, revs: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(revlog.parentsofset(revs).map_pyerr(py)?))
+    }
+
+    /// Calculate `::revs`, including `revs` themselves.
+    def ancestors(&self, revs: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.ancestorsofset(revs))?))
+    }
+
+    /// Calculate `revs::`, including `revs` themselves.
+    def descendants(&self, revs: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.descendantsofset(revs))?))
+    }
+
+    /// Calculate the greatest common ancestors of a and b, in descending
+    /// order.
+    def gca(&self, a: u32, b: u32) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.gca(a, b).map_pyerr(py)?)
+    }
+
+    /// Calculate the revisions reachable from `heads` going down to `roots`.
+    /// Return the reached roots, or `roots::heads` if `includepath` is True.
+    def reachableroots(&self, roots: Vec<u32>, heads: Vec<u32>, includepath: bool) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.reachableroots(roots, heads, includepath))?))
+    }
+
+    /// Return the highest-numbered common ancestor of revs, or None.
+    /// This is a heuristic, not the full set of greatest common ancestors.
+    def lowestcommonancestor_fast(&self, revs: Vec<u32>) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.lowestcommonancestor_fast(revs))
+    }
+
+    /// For each candidate, test whether it is a descendant of any reference revision.
+    def descendant_of_any(&self, candidates: Vec<u32>, reference: Vec<u32>) -> PyResult<Vec<bool>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.descendant_of_any(candidates, reference))
+    }
+
+    /// For each candidate, test whether it is an ancestor of, or equal to,
+    /// `target`.
+    def ancestors_or_equal(&self, target: u32, candidates: Vec<u32>) -> PyResult<Vec<bool>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.ancestors_or_equal(target, candidates))
+    }
+
+    /// Calculate `ancestors(revs)` and return their nodes, ordered by
+    /// descending revision number.
+    def ancestornodes(&self, revs: Vec<u32>) -> PyResult<Vec<PyBytes>> {
+        let revlog = self.index(py).borrow();
+        let nodes = map_interrupted(py, revlog.ancestornodes(revs))?;
+        Ok(nodes.iter().map(|node| PyBytes::new(py, node.as_ref())).collect())
+    }
+
+    /// For each ordered pair of revs, test whether the first is an ancestor of
+    /// the second. Return the matrix, indexed in the order of `revs`.
+    def reachability_matrix(&self, revs: Vec<u32>) -> PyResult<Vec<Vec<bool>>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.reachability_matrix(revs))
+    }
+
+    /// Calculate the subgraph induced by `revs`.
+    /// Return [(rev, internal_parents, external_parents)], sorted by rev.
+    def inducedsubgraph(&self, revs: Vec<u32>) -> PyResult<Vec<(u32, Vec<u32>, Vec<u32>)>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.inducedsubgraph(revs).map_pyerr(py)?)
+    }
+
+    /// Serialize spans, for example returned by `phasesets`, into compact bytes
+    /// for caching.
+    def serialize_spans(&self, spans: Spans) -> PyResult<PyBytes> {
+        let data = ::revlogindex::spans::serialize_spans(&spans.0);
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Deserialize spans from bytes returned by `serialize_spans`.
+    def deserialize_spans(&self, data: PyBytes) -> PyResult<Spans> {
+        let spans = ::revlogindex::spans::deserialize_spans(data.data(py)).map_pyerr(py)?;
+        Ok(Spans(spans))
+    }
+
+    /// Test whether `anc` is an ancestor of `desc`, or equal to it.
+    def isancestor(&self, anc: u32, desc: u32) -> PyResult<bool> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.isancestor(anc, desc))
+    }
+
+    /// Number of edges of the shortest path from `descendant` to `ancestor`.
+    /// Return None if `ancestor` is not an ancestor of `descendant`.
+    def ancestordistance(&self, ancestor: u32, descendant: u32) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.ancestordistance(ancestor, descendant).map_pyerr(py)?)
+    }
+
+    /// Follow the first parent of `rev` `n` times, like `rev~n`.
+    /// Return None if a root is reached first. Other parents of merges are
+    /// ignored.
+    def nth_ancestor(&self, rev: u32, n: u32) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.nth_ancestor(rev, n).map_pyerr(py)?)
+    }
+
+    /// Pick the next revision to test when bisecting, given known good and
+    /// bad revisions. Return None if there is nothing left to test.
+    def bisect_next(&self, good: Vec<u32>, bad: Vec<u32>) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.bisect_next(good, bad))
+    }
+
+    /// Revisions with a linkrev in `lo..=hi`, in ascending order.
+    /// This scans the whole index, since linkrevs are not sorted.
+    def revs_by_linkrev_range(&self, lo: i32, hi: i32) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.revs_by_linkrev_range(lo, hi))
+    }
+
+    /// Revisions above `watermark`, including inserted ones, as
+    /// `[(rev, parents)]` in ascending order. Empty if `watermark` is the
+    /// last revision or above.
+    def since(&self, watermark: u32) -> PyResult<Vec<(u32, Vec<u32>)>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.since(watermark).map_pyerr(py)?)
+    }
+
+    /// Calculate the greatest common ancestors of any number of heads, in
+    /// descending order. There can be more than one.
+    def commonancestors_multi(&self, heads: Vec<u32>) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.commonancestors_multi(heads))
+    }
+
+    /// Sort `revs` topologically, children first, ordering revisions that
+    /// are not ancestors of each other by `keys` descending. `keys[i]` is the
+    /// key of `revs[i]`, for example its commit date.
+    def toposort_with_key(&self, revs: Vec<u32>, keys: Vec<i64>) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.toposort_with_key(revs, keys))
+    }
+
+    /// Calculate `::aheads - ::bheads`.
+    def onlyfirst(&self, aheads: Vec<u32>, bheads: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.onlyfirst(aheads, bheads))?))
+    }
+
+    /// Calculate the revisions that are ancestors of exactly one of `aheads`
+    /// and `bheads`.
+    def ancestors_symmetric_difference(&self, aheads: Vec<u32>, bheads: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.ancestors_symmetric_difference(aheads, bheads))?))
+    }
+
+    /// Test whether the node of `rev` is `expected`.
+    def verifynode(&self, rev: u32, expected: PyBytes) -> PyResult<bool> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.verifynode(rev, expected.data(py)).map_pyerr(py)?)
+    }
+
+    /// Get the linkrev of `rev`, or None if it is not recorded. Raise an error
+    /// for revisions that are inserted but not flushed.
+    def linkrev(&self, rev: u32) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.linkrev(rev).map_pyerr(py)?)
+    }
+
+    /// Revisions in `revs` with more than one child, or all such revisions if
+    /// `revs` is empty.
+    def branchpoints(&self, revs: Vec<u32>) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.branchpoints(revs))
+    }
+
+    /// Revisions in `revs` that have no children anywhere in the DAG. Unlike
+    /// `heads(revs)`, children outside `revs` are also considered.
+    def external_leaves(&self, revs: Vec<u32>) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.external_leaves(revs))
+    }
+
+    /// Calculate `ancestors(heads) - hidden`. Hidden revisions are traversed,
+    /// so they don't hide their visible ancestors.
+    def ancestors_visible(&self, heads: Vec<u32>, hidden: Vec<u32>) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.ancestors_visible(heads, hidden))?))
+    }
+
+    /// Calculate `ancestors(heads) & lo:hi`. Heads above `hi` are ignored, and
+    /// the traversal stops at `lo`.
+    def ancestors_in_window(&self, heads: Vec<u32>, lo: u32, hi: u32) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.ancestors_in_window(heads, lo, hi))?))
+    }
+
+    /// Calculate the ancestors of `heads` reachable by crossing at most
+    /// `max_merges` merges.
+    def ancestors_merge_bounded(&self, heads: Vec<u32>, max_merges: u32) -> PyResult<Spans> {
+        let revlog = self.index(py).borrow();
+        Ok(Spans(map_interrupted(py, revlog.ancestors_merge_bounded(heads, max_merges))?))
+    }
+
+    /// Calculate the revisions within `depth` parent hops of `heads`, and the
+    /// parents just beyond them, for shallow clones.
+    /// Return (base, included).
+    def shallowbase(&self, heads: Vec<u32>, depth: u32) -> PyResult<(Spans, Spans)> {
+        let revlog = self.index(py).borrow();
+        let (base, included) = map_interrupted(py, revlog.shallowbase(heads, depth))?;
+        Ok((Spans(base), Spans(included)))
+    }
+
+    /// Generation numbers of all revisions, indexed by revision. Roots are 0.
+    def generations(&self) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        let generations = map_interrupted(py, revlog.generations())?;
+        Ok(generations.to_vec())
+    }
+
+    /// Generation number of `rev`. Roots are 0.
+    def generation(&self, rev: u32) -> PyResult<u32> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.generation(rev))
+    }
+
+    /// SHA-256 of the edge list of the ancestors of `rev`, renumbered by
+    /// position. This only covers topology, not nodes or content.
+    def dag_hash(&self, rev: u32) -> PyResult<PyBytes> {
+        let revlog = self.index(py).borrow();
+        let hash = map_interrupted(py, revlog.dag_hash(rev))?;
+        Ok(PyBytes::new(py, &hash))
     }
 
     /// Get parent revisions.
@@ -67,13 +367,108 @@ py_class!(class revlogindex |py| {
         Ok(revlog.parent_revs(rev).map_pyerr(py)?.as_revs().to_vec())
     }
 
+    /// Get parent revisions of many revisions, in the order of `revs`.
+    def parentrevs_batch(&self, revs: Vec<u32>) -> PyResult<Vec<Vec<u32>>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.parent_revs_batch(&revs).map_pyerr(py)?)
+    }
+
+    /// Get child revisions, in ascending order. Inserted revisions are
+    /// included.
+    def childrevs(&self, rev: u32) -> PyResult<Vec<u32>> {
+        let revlog = self.index(py).borrow();
+        map_interrupted(py, revlog.child_revs(rev))
+    }
+
+    /// Get parent revisions as `(p1, p2)`, using -1 for absent parents.
+    /// Unlike `parentrevs`, this does not allocate a list.
+    def parents2(&self, rev: u32) -> PyResult<(i64, i64)> {
+        let revlog = self.index(py).borrow();
+        let (p1, p2) = revlog.parents2(rev).map_pyerr(py)?;
+        Ok((p1 as i64, p2 as i64))
+    }
+
+    /// Get parent nodes.
+    def parentnodes(&self, rev: u32) -> PyResult<Vec<PyBytes>> {
+        let revlog = self.index(py).borrow();
+        let nodes = revlog.parent_nodes(rev).map_pyerr(py)?;
+        Ok(nodes.iter().map(|node| PyBytes::new(py, node.as_ref())).collect())
+    }
+
+    /// The last revision, or None if the index is empty.
+    def tip(&self) -> PyResult<Option<u32>> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.tip())
+    }
+
+    /// Node of the last revision, or None if the index is empty.
+    def tipnode(&self) -> PyResult<Option<PyBytes>> {
+        let revlog = self.index(py).borrow();
+        let node = revlog.tip_node().map_pyerr(py)?;
+        Ok(node.map(|node| PyBytes::new(py, node.as_ref())))
+    }
+
+    /// Revision of `node`, or None if the node is unknown.
+    def rev(&self, node: PyBytes) -> PyResult<Option<u32>> {
+        let node = node.data(py).to_vec().into();
+        let revlog = self.index(py).borrow();
+        Ok(revlog.rev_of_node(&node).map_pyerr(py)?)
+    }
+
+    /// Node of `rev`. Raise IndexError if `rev` is out of range.
+    def node(&self, rev: u32) -> PyResult<PyBytes> {
+        let revlog = self.index(py).borrow();
+        if rev as usize >= revlog.len() {
+            let msg = format!("revision {} is out of range", rev);
+            return Err(PyErr::new::<exc::IndexError, _>(py, msg));
+        }
+        let node = revlog.node_of_rev(rev).map_pyerr(py)?;
+        Ok(PyBytes::new(py, node.as_ref()))
+    }
+
     /// Insert a new revision that hasn't been written to disk.
     /// Used by revlog._addrevision.
+    /// Raise ValueError if a parent is not lower than the new revision.
     def insert(&self, node: PyBytes, parents: Vec<u32>, data: Option<PyBytes> = None) -> PyResult<PyNone> {
         let node = node.data(py).to_vec().into();
         let mut revlog = self.index(py).borrow_mut();
         let data = data.map(|p| p.data(py).to_vec()).unwrap_or_default();
-        revlog.insert(node, parents, data.into());
+        match revlog.insert(node, parents, data.into()) {
+            Err(::revlogindex::Error::Unsupported(msg)) => {
+                Err(PyErr::new::<exc::ValueError, _>(py, msg))
+            }
+            result => {
+                result.map_pyerr(py)?;
+                Ok(PyNone)
+            }
+        }
+    }
+
+    /// Write inserted revisions to the index and data files the index was
+    /// loaded from. Return the number of revisions written.
+    def flush(&self) -> PyResult<u32> {
+        let mut revlog = self.index(py).borrow_mut();
+        Ok(revlog.flush().map_pyerr(py)? as u32)
+    }
+
+    /// Replace the parents of an inserted revision that isn't flushed yet.
+    def set_inserted_parents(&self, rev: u32, parents: Vec<u32>) -> PyResult<PyNone> {
+        let mut revlog = self.index(py).borrow_mut();
+        revlog.set_inserted_parents(rev, parents).map_pyerr(py)?;
+        Ok(PyNone)
+    }
+
+    /// Return a marker of the current inserted revisions.
+    /// Pass it to `restore` to drop revisions inserted afterwards.
+    def snapshot(&self) -> PyResult<usize> {
+        let revlog = self.index(py).borrow();
+        Ok(revlog.pending_len())
+    }
+
+    /// Drop inserted revisions added after `snapshot` returned `marker`.
+    def restore(&self, marker: usize) -> PyResult<PyNone> {
+        let mut revlog = self.index(py).borrow_mut();
+        revlog.truncate_pending(marker).map_pyerr(py)?;
         Ok(PyNone)
     }
 
@@ -82,3 +477,20 @@ py_class!(class revlogindex |py| {
         Ok(revlog.len())
     }
 });
+
+/// Run Python signal handlers. Return true if a handler raised, for example
+/// `KeyboardInterrupt` on Ctrl-C. The exception is left pending, to be raised
+/// by `map_interrupted`.
+fn check_signals() -> bool {
+    let _gil = Python::acquire_gil();
+    unsafe { ffi::PyErr_CheckSignals() != 0 }
+}
+
+/// Convert the result of a traversal to a `PyResult`, raising the pending
+/// exception if the traversal was aborted by `check_signals`.
+fn map_interrupted<T>(py: Python, result: dag::Result<T>) -> PyResult<T> {
+    if result.is_err() && PyErr::occurred(py) {
+        return Err(PyErr::fetch(py));
+    }
+    result.map_pyerr(py)
+}
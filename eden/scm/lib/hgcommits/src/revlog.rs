@@ -72,7 +72,7 @@ impl AppendCommits for RevlogCommits {
                 parent_revs.push(self.revlog.vertex_id(parent.clone()).await?.0 as u32);
             }
             self.revlog
-                .insert(commit.vertex.clone(), parent_revs, commit.raw_text.clone())
+                .insert(commit.vertex.clone(), parent_revs, commit.raw_text.clone())?;
         }
         Ok(())
     }
@@ -5,57 +5,148 @@
  * GNU General Public License version 2.
  */
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs::File;
+use std::hash::Hash;
 use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
+use std::marker::PhantomData;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
+use async_runtime::spawn_blocking;
 use byteorder::BigEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
+use futures::stream;
+use futures::StreamExt;
 use memmap::Mmap;
+use memmap::MmapMut;
 use memmap::MmapOptions;
 use thiserror::Error;
 use types::HgId;
+use types::Sha256;
 
 use crate::fanouttable::FanoutTable;
 use crate::sliceext::SliceExt;
 
-const ENTRY_LEN: usize = 40;
+// Delta base offset, pack entry offset and pack entry size.
+const ENTRY_FIELDS_LEN: usize = 20;
 const SMALL_FANOUT_CUTOFF: usize = 8192; // 2^16 / 8
 
+// Highest format version that can be read. Bump it when adding a version.
+const MAX_VERSION: u8 = 2;
+
+// Set in the pack entry size of tombstones, in indexes that have them.
+const TOMBSTONE_FLAG: u64 = 1 << 63;
+// Length of the checksum of each entry, in indexes that have them.
+const CHECKSUM_LEN: usize = 4;
+// Nodes looked up by each task of `prefetch_index_entries`, and the number of such tasks
+// running at once.
+const PREFETCH_CHUNK_SIZE: usize = 256;
+const PREFETCH_PARALLELISM: usize = 8;
+
+// Entries checked by `verify_with_progress` between progress reports.
+const VERIFY_PROGRESS_INTERVAL: usize = 65536;
+
 #[derive(Debug, Error)]
 #[error("DataIndex Error: {0:?}")]
 struct DataIndexError(String);
 
+/// Returned when looking up a node that has a tombstone in the index.
+#[derive(Debug, Error)]
+#[error("node {0} is redacted")]
+pub struct RedactedNode(pub String);
+
+/// Node hash type stored in a `DataIndex`.
+///
+/// Each node length has its own format version, so an index can only be read
+/// with the node type it was written with.
+pub trait IndexNode: AsRef<[u8]> + Clone + fmt::Display + Eq + Hash + Ord {
+    /// Length of the node in bytes.
+    const LEN: usize;
+    /// Length of an index entry holding this node.
+    const ENTRY_LEN: usize = Self::LEN + ENTRY_FIELDS_LEN;
+    /// Format version of indexes holding this node.
+    const VERSION: u8;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self>;
+}
+
+impl IndexNode for HgId {
+    const LEN: usize = HgId::len();
+    const VERSION: u8 = 1;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(HgId::from_slice(bytes)?)
+    }
+}
+
+impl IndexNode for Sha256 {
+    const LEN: usize = Sha256::len();
+    const VERSION: u8 = 2;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(Sha256::from_slice(bytes)?)
+    }
+}
+
+/// Differences between two indexes, see [`diff_indexes`].
+#[derive(Debug, PartialEq)]
+pub struct IndexDiff<N = HgId> {
+    /// Nodes only in the first index.
+    pub only_in_a: Vec<N>,
+    /// Nodes only in the second index.
+    pub only_in_b: Vec<N>,
+    /// Nodes in both indexes whose pack entry offset, size, or tombstone
+    /// state differ.
+    pub changed: Vec<N>,
+}
+
 #[derive(Debug, PartialEq)]
 struct DataIndexOptions {
     version: u8,
     // Indicates whether to use the large fanout (2 bytes) or the small (1 byte)
     large: bool,
+    // Indicates whether entries may be tombstones. Only set if there are
+    // tombstones, so that other indexes stay readable by older readers.
+    tombstones: bool,
+    // Indicates whether the entries are followed by a checksum of each
+    // entry, in the same order.
+    checksums: bool,
 }
 
 #[derive(Debug)]
-pub struct DeltaLocation {
-    pub delta_base: Option<HgId>,
+pub struct DeltaLocation<N = HgId> {
+    pub delta_base: Option<N>,
     pub offset: u64,
     pub size: u64,
+    /// The node was deliberately removed, for example because it was
+    /// redacted. The other fields are ignored for tombstones.
+    pub tombstone: bool,
 }
 
 #[derive(Debug)]
-pub struct IndexEntry {
-    hgid: HgId,
+pub struct IndexEntry<N = HgId> {
+    hgid: N,
     delta_base_offset: u32,
     pack_entry_offset: u64,
     pack_entry_size: u64,
+    tombstone: bool,
 }
 
-impl IndexEntry {
+impl<N: IndexNode> IndexEntry<N> {
     pub fn new(
-        hgid: HgId,
+        hgid: N,
         delta_base_offset: DeltaBaseOffset,
         pack_entry_offset: u64,
         pack_entry_size: u64,
@@ -69,13 +160,32 @@ impl IndexEntry {
             },
             pack_entry_offset,
             pack_entry_size,
+            tombstone: false,
+        }
+    }
+
+    /// An entry recording that `hgid` was deliberately removed.
+    pub fn tombstone(hgid: N) -> Self {
+        IndexEntry {
+            tombstone: true,
+            ..IndexEntry::new(hgid, DeltaBaseOffset::FullText, 0, 0)
         }
     }
 
-    pub fn hgid(&self) -> &HgId {
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    pub fn hgid(&self) -> &N {
         &self.hgid
     }
 
+    /// The raw bytes of the node, for copying it verbatim. Prefer `hgid`
+    /// otherwise.
+    pub fn node_bytes(&self) -> &[u8] {
+        self.hgid.as_ref()
+    }
+
     pub fn delta_base_offset(&self) -> DeltaBaseOffset {
         if self.delta_base_offset == 0xffffffff {
             DeltaBaseOffset::FullText
@@ -124,12 +234,23 @@ impl DeltaBaseOffset {
     }
 }
 
-impl IndexEntry {
+/// Pack entry sizes summed by the kind of delta base of each entry.
+#[derive(Debug, Default, PartialEq)]
+pub struct DeltaBaseSizes {
+    /// Entries stored as full texts.
+    pub fulltext: u64,
+    /// Entries stored as deltas against another entry in the same pack.
+    pub delta: u64,
+    /// Entries stored as deltas against a base that is not in the pack.
+    pub missing: u64,
+}
+
+impl<N: IndexNode> IndexEntry<N> {
     pub fn read(buf: &[u8]) -> Result<Self> {
         let mut cur = Cursor::new(buf);
-        cur.set_position(20);
-        let hgid_slice: &[u8] = buf.get_err(0..20)?;
-        let hgid = HgId::from_slice(hgid_slice)?;
+        cur.set_position(N::LEN as u64);
+        let hgid_slice: &[u8] = buf.get_err(0..N::LEN)?;
+        let hgid = N::from_bytes(hgid_slice)?;
         let delta_base_offset = cur.read_i32::<BigEndian>()?;
         let delta_base_offset = DeltaBaseOffset::new(delta_base_offset)?;
         let pack_entry_offset = cur.read_u64::<BigEndian>()?;
@@ -146,7 +267,11 @@ impl IndexEntry {
         writer.write_all(self.hgid().as_ref())?;
         writer.write_i32::<BigEndian>(self.delta_base_offset().to_i32())?;
         writer.write_u64::<BigEndian>(self.pack_entry_offset())?;
-        writer.write_u64::<BigEndian>(self.pack_entry_size())?;
+        let mut pack_entry_size = self.pack_entry_size();
+        if self.tombstone {
+            pack_entry_size |= TOMBSTONE_FLAG;
+        }
+        writer.write_u64::<BigEndian>(pack_entry_size)?;
         Ok(())
     }
 }
@@ -154,76 +279,650 @@ impl IndexEntry {
 impl DataIndexOptions {
     pub fn read<T: Read>(reader: &mut T) -> Result<DataIndexOptions> {
         let version = reader.read_u8()?;
-        if version > 1 {
-            return Err(DataIndexError(format!("unsupported version '{:?}'", version)).into());
+        if version > MAX_VERSION {
+            return Err(DataIndexError(format!(
+                "index is version {}, but this binary only supports up to version {}; upgrade the client",
+                version, MAX_VERSION
+            ))
+            .into());
         };
 
         let raw_config = reader.read_u8()?;
-        let large = match raw_config {
-            0b10000000 => true,
-            0 => false,
-            _ => {
-                return Err(
-                    DataIndexError(format!("invalid data index '{:?}'", raw_config)).into(),
-                );
-            }
-        };
-        Ok(DataIndexOptions { version, large })
+        if raw_config & !0b11100000 != 0 {
+            return Err(DataIndexError(format!("invalid data index '{:?}'", raw_config)).into());
+        }
+        let large = raw_config & 0b10000000 != 0;
+        let tombstones = raw_config & 0b01000000 != 0;
+        let checksums = raw_config & 0b00100000 != 0;
+        Ok(DataIndexOptions {
+            version,
+            large,
+            tombstones,
+            checksums,
+        })
+    }
+
+    /// Length of the nodes. Version two stores 32-byte nodes, earlier
+    /// versions 20-byte nodes.
+    fn node_len(&self) -> usize {
+        match self.version {
+            0 | 1 => HgId::len(),
+            _ => Sha256::len(),
+        }
     }
 
     pub fn write<T: Write>(&self, writer: &mut T) -> Result<()> {
         writer.write_u8(self.version)?;
-        writer.write_u8(if self.large { 0b10000000 } else { 0 })?;
+        let mut raw_config = 0;
+        if self.large {
+            raw_config |= 0b10000000;
+        }
+        if self.tombstones {
+            raw_config |= 0b01000000;
+        }
+        if self.checksums {
+            raw_config |= 0b00100000;
+        }
+        writer.write_u8(raw_config)?;
         Ok(())
     }
 }
 
-pub struct DataIndex {
+/// Expected access pattern of a `DataIndex`, used as a hint for the kernel.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmapAccess {
+    /// Binary-search lookups. Readahead is mostly wasted.
+    Random,
+    /// Scanning the whole index in file order.
+    Sequential,
+}
+
+/// Compression codec of a `DataIndex` file, see `DataIndex::open_compressed`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+impl Codec {
+    /// Detect the codec from the magic bytes at the start of a compressed
+    /// file, if it is one of the supported codecs.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Codec::Zstd)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Codec::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Size of the fanout table of a written `DataIndex`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FanoutMode {
+    /// Use the large fanout if there are more than `SMALL_FANOUT_CUTOFF` entries.
+    Auto,
+    /// Always use the small (1 byte) fanout.
+    Small,
+    /// Always use the large (2 bytes) fanout.
+    Large,
+}
+
+/// Output format of `DataIndex::dump`. Both write one entry per line, in
+/// node order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// Space-separated node, kind (`fulltext`, `delta`, `missing` or
+    /// `tombstone`), pack entry offset and size, followed by the delta base
+    /// node for deltas.
+    Lines,
+    /// A JSON object per entry with the same fields, where the base is null
+    /// for entries that are not deltas.
+    Json,
+}
+
+/// Delta base of an entry that is about to be written, before the final
+/// entry offsets are known.
+enum PendingDeltaBase<N> {
+    FullText,
+    Node(N),
+    Missing,
+    /// The entry is a tombstone, which has no delta base.
+    Tombstone,
+}
+
+/// A read-only, memory mapped data pack index, holding nodes of type `N`.
+///
+/// `DataIndex` is `Send` and `Sync`. Lookups only read from the mapping, so
+/// an index can be opened once and shared between threads with an
+/// `Arc<DataIndex>`, without any locking.
+pub struct DataIndex<N = HgId> {
     mmap: Mmap,
     fanout_size: usize,
     index_start: usize,
+    tombstones: bool,
+    checksums: bool,
+    /// File the index was loaded from, if any. Only used for error messages.
+    path: Option<PathBuf>,
+    node: PhantomData<N>,
 }
 
-impl DataIndex {
+impl<N: IndexNode> DataIndex<N> {
     pub fn new(path: &Path) -> Result<Self> {
-        let file = File::open(path)?;
+        let file = File::open(path).with_context(|| {
+            DataIndexError(format!("failed to open dataidx '{}'", path.display()))
+        })?;
         let len = file.metadata()?.len();
         if len < 1 {
+            return Err(
+                DataIndexError(format!("empty dataidx '{}' is invalid", path.display())).into(),
+            );
+        }
+
+        let mmap = unsafe { MmapOptions::new().len(len as usize).map(&file)? };
+        Self::from_mmap(mmap, Some(path.to_path_buf()))
+    }
+
+    /// Open an index file compressed with `codec`.
+    ///
+    /// The whole file is decompressed into anonymous memory. Unlike with
+    /// `new`, the index is not backed by the page cache, so it can't be
+    /// evicted under memory pressure and isn't shared between processes. This
+    /// is appropriate for archived indexes that are rarely read, prefer
+    /// uncompressed files for indexes that are read often.
+    pub fn open_compressed(path: &Path, codec: Codec) -> Result<Self> {
+        let file = File::open(path).with_context(|| {
+            DataIndexError(format!("failed to open dataidx '{}'", path.display()))
+        })?;
+        let mut buf = Vec::new();
+        match codec {
+            Codec::Zstd => zstd::stream::read::Decoder::new(file)?.read_to_end(&mut buf),
+            Codec::Gzip => flate2::read::GzDecoder::new(file).read_to_end(&mut buf),
+        }
+        .with_context(|| {
+            DataIndexError(format!("failed to decompress dataidx '{}'", path.display()))
+        })?;
+        Self::from_buf(&buf, Some(path.to_path_buf()))
+    }
+
+    /// Like `open_compressed`, but detect the codec from the start of the
+    /// file.
+    pub fn open_compressed_auto(path: &Path) -> Result<Self> {
+        let mut header = Vec::with_capacity(4);
+        File::open(path)
+            .and_then(|file| file.take(4).read_to_end(&mut header))
+            .with_context(|| {
+                DataIndexError(format!("failed to open dataidx '{}'", path.display()))
+            })?;
+        match Codec::detect(&header) {
+            Some(codec) => Self::open_compressed(path, codec),
+            None => Err(DataIndexError(format!(
+                "dataidx '{}' is not compressed with a known codec",
+                path.display()
+            ))
+            .into()),
+        }
+    }
+
+    /// Load an index from an in-memory buffer holding the serialized index.
+    /// The buffer is copied into an anonymous mapping.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Self::from_buf(buf, None)
+    }
+
+    fn from_buf(buf: &[u8], path: Option<PathBuf>) -> Result<Self> {
+        if buf.is_empty() {
             return Err(DataIndexError(format!(
-                "empty dataidx '{:?}' is invalid",
-                path.to_str().unwrap_or("<unknown>")
+                "empty dataidx '{}' is invalid",
+                display_path(path.as_deref())
             ))
             .into());
         }
 
-        let mmap = unsafe { MmapOptions::new().len(len as usize).map(&file)? };
-        let options = DataIndexOptions::read(&mut Cursor::new(&mmap))?;
+        let mut mmap = MmapMut::map_anon(buf.len())?;
+        mmap.copy_from_slice(buf);
+        Self::from_mmap(mmap.make_read_only()?, path)
+    }
+
+    /// Build an index from `entries` without going through the filesystem.
+    pub fn from_entries(entries: Vec<(N, DeltaLocation<N>)>) -> Result<Self> {
+        let values: HashMap<N, DeltaLocation<N>> = entries.into_iter().collect();
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values)?;
+        Self::from_bytes(&buf)
+    }
+
+    fn from_mmap(mmap: Mmap, path: Option<PathBuf>) -> Result<Self> {
+        let options = DataIndexOptions::read(&mut Cursor::new(&mmap)).with_context(|| {
+            DataIndexError(format!(
+                "invalid header in dataidx '{}'",
+                display_path(path.as_deref())
+            ))
+        })?;
+        if options.node_len() != N::LEN {
+            return Err(DataIndexError(format!(
+                "dataidx '{}' has {}-byte nodes, expected {}-byte nodes",
+                display_path(path.as_deref()),
+                options.node_len(),
+                N::LEN
+            ))
+            .into());
+        }
         let fanout_size = FanoutTable::get_size(options.large);
         let mut index_start = 2 + fanout_size;
 
-        // Version one records the number of entries in the index
-        if options.version == 1 {
+        // Version one and later record the number of entries in the index
+        if options.version >= 1 {
             index_start += 8;
         }
 
-        Ok(DataIndex {
+        let index = DataIndex {
             mmap,
             fanout_size,
             index_start,
-        })
+            tombstones: options.tombstones,
+            checksums: options.checksums,
+            path,
+            node: PhantomData,
+        };
+        // Lookups are the dominant access pattern. This is only a hint, so
+        // failing to apply it is not an error.
+        let _ = index.advise(MmapAccess::Random);
+        Ok(index)
+    }
+
+    /// Tell the kernel how the index is going to be accessed, so it can tune
+    /// readahead accordingly. This is a no-op on platforms without `madvise`.
+    pub fn advise(&self, access: MmapAccess) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let advice = match access {
+                MmapAccess::Random => libc::MADV_RANDOM,
+                MmapAccess::Sequential => libc::MADV_SEQUENTIAL,
+            };
+            // safety: the range is exactly the (page aligned) mapped region.
+            let ret = unsafe {
+                libc::madvise(
+                    self.mmap.as_ptr() as *mut libc::c_void,
+                    self.mmap.len(),
+                    advice,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = access;
+        Ok(())
+    }
+
+    /// Lock the mapped index in memory with `mlock`, so lookups don't fault
+    /// pages in from disk once they have been touched.
+    ///
+    /// Locked pages can't be reclaimed when the system is low on memory, so
+    /// this is only for small, hot indexes. The whole index counts against
+    /// `RLIMIT_MEMLOCK`, and this fails if that would be exceeded. Pages stay
+    /// locked until `unlock_memory` is called or the index is dropped.
+    pub fn lock_in_memory(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let addr = self.mmap.as_ptr() as *const libc::c_void;
+            // safety: the range is exactly the (page aligned) mapped region.
+            let ret = unsafe { libc::mlock(addr, self.mmap.len()) };
+            if ret != 0 {
+                let err = std::io::Error::last_os_error();
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if matches!(err.raw_os_error(), Some(libc::ENOMEM) | Some(libc::EPERM))
+                    && unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } == 0
+                {
+                    return Err(err).with_context(|| {
+                        self.error(format!(
+                            "cannot lock {} bytes in memory, RLIMIT_MEMLOCK is {} bytes",
+                            self.mmap.len(),
+                            limit.rlim_cur
+                        ))
+                    });
+                }
+                return Err(err).with_context(|| self.error("cannot lock in memory".to_string()));
+            }
+            Ok(())
+        }
+        #[cfg(not(unix))]
+        {
+            let message = "locking in memory is not supported on this platform";
+            Err(self.error(message.to_string()).into())
+        }
+    }
+
+    /// Undo `lock_in_memory`. This is a no-op if the index isn't locked, or
+    /// on platforms without `mlock`.
+    pub fn unlock_memory(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let addr = self.mmap.as_ptr() as *const libc::c_void;
+            // safety: the range is exactly the (page aligned) mapped region.
+            let ret = unsafe { libc::munlock(addr, self.mmap.len()) };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write<T: Write>(writer: &mut T, values: &HashMap<N, DeltaLocation<N>>) -> Result<()> {
+        Self::write_with_fanout(writer, values, FanoutMode::Auto)
+    }
+
+    /// Like `write`, but compress the index with `codec`, to be read with
+    /// `open_compressed`, or with `open_compressed_auto` which detects the
+    /// codec from the zstd or gzip magic bytes.
+    ///
+    /// Mostly the offset and size fields shrink, node hashes don't compress.
+    /// In exchange, every open decompresses the whole index, so keep using
+    /// `write` for indexes on latency-sensitive paths.
+    pub fn write_compressed<T: Write>(
+        writer: &mut T,
+        values: &HashMap<N, DeltaLocation<N>>,
+        codec: Codec,
+    ) -> Result<()> {
+        let mut buf = Vec::new();
+        Self::write(&mut buf, values)?;
+        match codec {
+            Codec::Zstd => zstd::stream::copy_encode(&buf[..], writer, 0)?,
+            Codec::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                encoder.write_all(&buf)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `write`, but with control over the size of the fanout table.
+    pub fn write_with_fanout<T: Write>(
+        writer: &mut T,
+        values: &HashMap<N, DeltaLocation<N>>,
+        fanout: FanoutMode,
+    ) -> Result<()> {
+        Self::write_with_fanout_by(writer, values, fanout, byte_order, false)
+    }
+
+    /// Like `write`, but also write a checksum of each entry, which
+    /// `verify_checksums` checks. Indexes appended to or repacked from an
+    /// index with checksums keep them.
+    pub fn write_with_checksums<T: Write>(
+        writer: &mut T,
+        values: &HashMap<N, DeltaLocation<N>>,
+    ) -> Result<()> {
+        Self::write_with_fanout_by(writer, values, FanoutMode::Auto, byte_order, true)
+    }
+
+    /// Like `write`, but order the nodes with `cmp` instead of by their
+    /// bytes, to be read with `get_entry_by` with the same `cmp`.
+    ///
+    /// The fanout table still groups nodes by their first bytes, so `cmp`
+    /// only orders the nodes within each group. Other lookups, `append` and
+    /// `verify_sorted` assume byte order.
+    pub fn write_by<T, F>(
+        writer: &mut T,
+        values: &HashMap<N, DeltaLocation<N>>,
+        cmp: F,
+    ) -> Result<()>
+    where
+        T: Write,
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        Self::write_with_fanout_by(writer, values, FanoutMode::Auto, cmp, false)
+    }
+
+    fn write_with_fanout_by<T, F>(
+        writer: &mut T,
+        values: &HashMap<N, DeltaLocation<N>>,
+        fanout: FanoutMode,
+        cmp: F,
+        checksums: bool,
+    ) -> Result<()>
+    where
+        T: Write,
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        let values = values
+            .iter()
+            .map(|(hgid, value)| {
+                let delta_base = if value.tombstone {
+                    PendingDeltaBase::Tombstone
+                } else {
+                    value
+                        .delta_base
+                        .clone()
+                        .map_or(PendingDeltaBase::FullText, PendingDeltaBase::Node)
+                };
+                (hgid.clone(), (delta_base, value.offset, value.size))
+            })
+            .collect();
+        Self::write_pending(writer, values, fanout, cmp, checksums)
+    }
+
+    /// Write an index containing all of the entries of `base` followed by
+    /// `new_entries`. Delta bases of the new entries may refer to nodes in
+    /// either. Delta base offsets are recomputed for the combined layout. A
+    /// new entry replaces a base entry for the same node.
+    ///
+    /// The output is the same as writing all entries with `write`, but the
+    /// base entries are not re-sorted or hashed. The only difference is that
+    /// the output is marked as having tombstones if `base` is, even if they
+    /// are all replaced. Each new entry is located in
+    /// `base` with a lookup, which gives where it is inserted and how far the
+    /// base entries after it shift. If the fanout table keeps its size, it is
+    /// derived from the bucket sizes of the base fanout table.
+    ///
+    /// The entries still have to be copied: every entry after the first
+    /// insertion moves, and delta base offsets are absolute entry offsets, so
+    /// there is no part of the index that stays the same in general. This
+    /// makes appending linear in the size of `base`, but just a sequential
+    /// copy.
+    pub fn append<T: Write>(
+        base: &DataIndex<N>,
+        new_entries: &[(N, DeltaLocation<N>)],
+        writer: &mut T,
+    ) -> Result<()> {
+        // Later entries for the same node win, like with `write`.
+        let new_entries: BTreeMap<&N, &DeltaLocation<N>> = new_entries
+            .iter()
+            .map(|(hgid, value)| (hgid, value))
+            .collect();
+        // Base index each new entry is inserted at, and whether it replaces
+        // the base entry there.
+        let mut insertions: Vec<(usize, bool)> = Vec::with_capacity(new_entries.len());
+        for hgid in new_entries.keys() {
+            insertions.push(match base.search(hgid)? {
+                Ok(index) => (index, true),
+                Err(index) => (index, false),
+            });
+        }
+        // Base indexes new entries are inserted at without replacing an entry.
+        let inserted: Vec<usize> = insertions
+            .iter()
+            .filter(|(_, replaces)| !replaces)
+            .map(|(index, _)| *index)
+            .collect();
+
+        // Final position of each new entry: its insertion index, plus the new
+        // entries before it, minus the base entries those replaced.
+        let new_nodes: Vec<&N> = new_entries.keys().copied().collect();
+        let new_values: Vec<&DeltaLocation<N>> = new_entries.values().copied().collect();
+        let mut new_positions: Vec<usize> = Vec::with_capacity(insertions.len());
+        let mut replaced_before = 0;
+        // Base index of replaced entries, to the index of the new entry.
+        let mut replaced: HashMap<usize, usize> = HashMap::new();
+        for (i, &(index, replaces)) in insertions.iter().enumerate() {
+            new_positions.push(index - replaced_before + i);
+            if replaces {
+                replaced.insert(index, i);
+                replaced_before += 1;
+            }
+        }
+        // Final position of the new entry `i`, or `None` for tombstones, as
+        // deltas against tombstones have a missing base.
+        let new_position =
+            |i: usize| -> Option<usize> { (!new_values[i].tombstone).then(|| new_positions[i]) };
+        // Final position of the base entry `index`, or `None` for tombstones.
+        let base_position = |index: usize| -> Result<Option<usize>> {
+            if let Some(&i) = replaced.get(&index) {
+                return Ok(new_position(i));
+            }
+            if base.read_entry(index * N::ENTRY_LEN)?.is_tombstone() {
+                return Ok(None);
+            }
+            Ok(Some(index + inserted.partition_point(|&i| i <= index)))
+        };
+        // Final position of the entry for `hgid`, or `None` if there is no
+        // such entry or it is a tombstone.
+        let position_of = |hgid: &N| -> Result<Option<usize>> {
+            if let Ok(i) = new_nodes.binary_search(&hgid) {
+                return Ok(new_position(i));
+            }
+            match base.search(hgid)? {
+                Ok(index) => base_position(index),
+                Err(_) => Ok(None),
+            }
+        };
+
+        let len = base.len() + inserted.len();
+        let large = len > SMALL_FANOUT_CUTOFF;
+        let options = DataIndexOptions {
+            version: N::VERSION,
+            large,
+            tombstones: base.tombstones || new_values.iter().any(|value| value.tombstone),
+            checksums: base.checksums,
+        };
+        options.write(writer)?;
+        let mut checksums = base.checksums.then(Vec::new);
+
+        let mut bucket_sizes = base.bucket_sizes(large)?;
+        for (hgid, (_, replaces)) in new_entries.keys().zip(insertions.iter()) {
+            if !replaces {
+                bucket_sizes[fanout_key(hgid.as_ref(), large)] += 1;
+            }
+        }
+        // Buckets point to their first entry, empty buckets repeat the
+        // previous bucket, like `FanoutTable::write`.
+        let mut offset: u32 = 0;
+        let mut last_offset: u32 = 0;
+        for size in bucket_sizes {
+            if size > 0 {
+                last_offset = offset;
+            }
+            writer.write_u32::<BigEndian>(last_offset)?;
+            offset += size * N::ENTRY_LEN as u32;
+        }
+
+        writer.write_u64::<BigEndian>(len as u64)?;
+        let mut pending = new_entries.iter().zip(insertions.iter()).peekable();
+        for index in 0..=base.len() {
+            let mut is_replaced = false;
+            while let Some(((hgid, value), &(_, replaces))) =
+                pending.next_if(|(_, (at, _))| *at == index)
+            {
+                let entry = if value.tombstone {
+                    IndexEntry::tombstone((*hgid).clone())
+                } else {
+                    let delta_base_offset = match value.delta_base.as_ref() {
+                        None => DeltaBaseOffset::FullText,
+                        Some(delta_base) => match position_of(delta_base)? {
+                            Some(position) => {
+                                DeltaBaseOffset::Offset((position * N::ENTRY_LEN) as u32)
+                            }
+                            None => DeltaBaseOffset::Missing,
+                        },
+                    };
+                    IndexEntry::new((*hgid).clone(), delta_base_offset, value.offset, value.size)
+                };
+                write_entry(writer, &entry, &mut checksums)?;
+                is_replaced |= replaces;
+            }
+            if is_replaced || index == base.len() {
+                continue;
+            }
+
+            let entry = base.read_entry(index * N::ENTRY_LEN)?;
+            if entry.is_tombstone() {
+                write_entry(writer, &entry, &mut checksums)?;
+                continue;
+            }
+            let delta_base_offset = match entry.delta_base_offset() {
+                DeltaBaseOffset::Offset(offset) => {
+                    let offset = offset as usize;
+                    if offset % N::ENTRY_LEN != 0 || offset / N::ENTRY_LEN >= base.len() {
+                        return Err(base
+                            .error(format!(
+                                "entry {} has invalid delta base offset {}",
+                                entry.hgid(),
+                                offset
+                            ))
+                            .into());
+                    }
+                    match base_position(offset / N::ENTRY_LEN)? {
+                        Some(position) => DeltaBaseOffset::Offset((position * N::ENTRY_LEN) as u32),
+                        None => DeltaBaseOffset::Missing,
+                    }
+                }
+                other => other,
+            };
+            let entry = IndexEntry::new(
+                entry.hgid().clone(),
+                delta_base_offset,
+                entry.pack_entry_offset(),
+                entry.pack_entry_size(),
+            );
+            write_entry(writer, &entry, &mut checksums)?;
+        }
+        write_checksums(writer, checksums)
     }
 
-    pub fn write<T: Write>(writer: &mut T, values: &HashMap<HgId, DeltaLocation>) -> Result<()> {
+    fn write_pending<T, F>(
+        writer: &mut T,
+        values: HashMap<N, (PendingDeltaBase<N>, u64, u64)>,
+        fanout: FanoutMode,
+        cmp: F,
+        checksums: bool,
+    ) -> Result<()>
+    where
+        T: Write,
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
         // Write header
+        let large = match fanout {
+            FanoutMode::Auto => values.len() > SMALL_FANOUT_CUTOFF,
+            FanoutMode::Small => false,
+            FanoutMode::Large => true,
+        };
+        let tombstones = values
+            .values()
+            .any(|(delta_base, _, _)| matches!(delta_base, PendingDeltaBase::Tombstone));
         let options = DataIndexOptions {
-            version: 1,
-            large: values.len() > SMALL_FANOUT_CUTOFF,
+            version: N::VERSION,
+            large,
+            tombstones,
+            checksums,
         };
         options.write(writer)?;
 
-        let mut values: Vec<(&HgId, &DeltaLocation)> = values.iter().collect();
-        // They must be written in sorted order
-        values.sort_by_key(|x| x.0);
+        let mut values: Vec<(N, (PendingDeltaBase<N>, u64, u64))> = values.into_iter().collect();
+        // They must be written in sorted order, grouped by fanout key. With
+        // byte order, that is the same as sorting by node.
+        values.sort_by(|a, b| {
+            let (a, b) = (a.0.as_ref(), b.0.as_ref());
+            fanout_key(a, large)
+                .cmp(&fanout_key(b, large))
+                .then_with(|| cmp(a, b))
+        });
 
         // Write fanout
         // `locations` will contain the eventual offset that each value will be written to.
@@ -232,167 +931,2074 @@ impl DataIndex {
         FanoutTable::write(
             writer,
             if options.large { 2 } else { 1 },
-            &mut values.iter().map(|x| x.0),
-            ENTRY_LEN,
+            &mut values.iter().map(|x| &x.0),
+            N::ENTRY_LEN,
             Some(&mut locations),
         )?;
 
         // Map from hgid to location
-        let mut nodelocations: HashMap<HgId, u32> = HashMap::new();
-        for (i, &(hgid, _value)) in values.iter().enumerate() {
-            nodelocations.insert(hgid.clone(), locations[i]);
+        let mut nodelocations: HashMap<N, u32> = HashMap::new();
+        // Deltas against tombstones have a missing base.
+        for (i, (hgid, (delta_base, _, _))) in values.iter().enumerate() {
+            if !matches!(delta_base, PendingDeltaBase::Tombstone) {
+                nodelocations.insert(hgid.clone(), locations[i]);
+            }
         }
 
         // Write index
         writer.write_u64::<BigEndian>(values.len() as u64)?;
-        for &(hgid, value) in values.iter() {
-            let delta_base_offset =
-                value
-                    .delta_base
-                    .map_or(DeltaBaseOffset::FullText, |delta_base| {
-                        nodelocations
-                            .get(&delta_base)
-                            .map_or(DeltaBaseOffset::Missing, |x| {
-                                DeltaBaseOffset::Offset(*x as u32)
-                            })
-                    });
+        let mut checksums = checksums.then(Vec::new);
+        for (hgid, (delta_base, offset, size)) in values.iter() {
+            let delta_base_offset = match delta_base {
+                PendingDeltaBase::Tombstone => {
+                    write_entry(writer, &IndexEntry::tombstone(hgid.clone()), &mut checksums)?;
+                    continue;
+                }
+                PendingDeltaBase::FullText => DeltaBaseOffset::FullText,
+                PendingDeltaBase::Missing => DeltaBaseOffset::Missing,
+                PendingDeltaBase::Node(delta_base) => nodelocations
+                    .get(delta_base)
+                    .map_or(DeltaBaseOffset::Missing, |x| {
+                        DeltaBaseOffset::Offset(*x as u32)
+                    }),
+            };
 
-            let entry = IndexEntry::new(hgid.clone(), delta_base_offset, value.offset, value.size);
+            let entry = IndexEntry::new(hgid.clone(), delta_base_offset, *offset, *size);
 
-            entry.write(writer)?;
+            write_entry(writer, &entry, &mut checksums)?;
         }
 
-        Ok(())
+        write_checksums(writer, checksums)
     }
 
-    pub fn get_entry(&self, hgid: &HgId) -> Result<Option<IndexEntry>> {
-        let (start, end) = FanoutTable::get_bounds(self.get_fanout_slice(), hgid)?;
-        let start = start + self.index_start;
-        let end = match end {
-            Option::None => self.mmap.len(),
-            Option::Some(pos) => pos + self.index_start,
-        };
-
-        let entry_offset = match self.binary_search(hgid, &self.mmap[start..end]) {
-            None => return Ok(None),
-            Some(offset) => offset,
-        };
-        self.read_entry((start + entry_offset) - self.index_start)
-            .map(Some)
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        (self.mmap.len() - self.index_start) / self.stored_entry_len()
     }
 
-    pub fn read_entry(&self, offset: usize) -> Result<IndexEntry> {
-        let offset = offset + self.index_start;
-        let raw_entry = self.mmap.get_err(offset..offset + ENTRY_LEN)?;
-        IndexEntry::read(raw_entry)
+    /// Bytes stored per entry, including its checksum if there is one.
+    fn stored_entry_len(&self) -> usize {
+        if self.checksums {
+            N::ENTRY_LEN + CHECKSUM_LEN
+        } else {
+            N::ENTRY_LEN
+        }
     }
 
-    fn binary_search(&self, key: &HgId, slice: &[u8]) -> Option<usize> {
-        let size = slice.len() / ENTRY_LEN;
-        // Cast the slice into an array of entry buffers so we can bisect across them
-        let slice: &[[u8; ENTRY_LEN]] =
-            unsafe { ::std::slice::from_raw_parts(slice.as_ptr() as *const [u8; ENTRY_LEN], size) };
-        match slice.binary_search_by(|entry| entry[0..20].cmp(key.as_ref())) {
-            Ok(offset) => Some(offset * ENTRY_LEN),
-            Err(_offset) => None,
-        }
+    /// End of the entries, where checksums start if there are any.
+    fn entries_end(&self) -> usize {
+        self.index_start + self.len() * N::ENTRY_LEN
     }
 
-    fn get_fanout_slice(&self) -> &[u8] {
-        self.mmap[2..2 + self.fanout_size].as_ref()
+    /// Whether the index was written with a checksum of each entry.
+    pub fn has_checksums(&self) -> bool {
+        self.checksums
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use quickcheck::quickcheck;
-    use rand::SeedableRng;
-    use rand_chacha::ChaChaRng;
-    use tempfile::NamedTempFile;
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-    use super::*;
+    /// Iterate over all entries, in node order.
+    pub fn entries(&self) -> impl Iterator<Item = Result<IndexEntry<N>>> + '_ {
+        (0..self.len()).map(move |i| self.read_entry(i * N::ENTRY_LEN))
+    }
 
-    fn make_index(values: &HashMap<HgId, DeltaLocation>) -> DataIndex {
-        let mut file = NamedTempFile::new().expect("file");
-        DataIndex::write(&mut file, &values).expect("write dataindex");
-        let path = file.into_temp_path();
+    /// Write the entries in a readable format, to inspect or diff indexes.
+    /// Entries are written as they are read, so this works on indexes of
+    /// any size.
+    pub fn dump<W: Write>(&self, writer: &mut W, format: DumpFormat) -> Result<()> {
+        for entry in self.entries() {
+            let entry = entry?;
+            let (kind, base) = if entry.is_tombstone() {
+                ("tombstone", None)
+            } else {
+                match entry.delta_base_offset() {
+                    DeltaBaseOffset::FullText => ("fulltext", None),
+                    DeltaBaseOffset::Missing => ("missing", None),
+                    DeltaBaseOffset::Offset(offset) => {
+                        let base = self.read_entry(offset as usize)?;
+                        ("delta", Some(base.hgid().to_string()))
+                    }
+                }
+            };
+            let (offset, size) = (entry.pack_entry_offset(), entry.pack_entry_size());
+            match format {
+                DumpFormat::Lines => {
+                    write!(writer, "{} {} {} {}", entry.hgid(), kind, offset, size)?;
+                    if let Some(base) = base {
+                        write!(writer, " {}", base)?;
+                    }
+                    writeln!(writer)?;
+                }
+                DumpFormat::Json => {
+                    let value = serde_json::json!({
+                        "node": entry.hgid().to_string(),
+                        "kind": kind,
+                        "offset": offset,
+                        "size": size,
+                        "base": base,
+                    });
+                    serde_json::to_writer(&mut *writer, &value)?;
+                    writeln!(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        DataIndex::new(&path).expect("dataindex")
+    /// All entries, sorted by their offset in the pack file.
+    ///
+    /// Useful to read the pack sequentially, for example when verifying it.
+    pub fn entries_by_pack_offset(&self) -> Result<Vec<IndexEntry<N>>> {
+        let mut entries = self.entries().collect::<Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.pack_entry_offset());
+        Ok(entries)
     }
 
-    #[test]
-    fn test_header_invalid() {
-        let buf: Vec<u8> = vec![2, 0];
-        DataIndexOptions::read(&mut Cursor::new(buf)).expect_err("invalid read");
+    /// Entries with a node in `lo..hi`, in node order.
+    ///
+    /// The range is found with lookups of both ends, so this only reads the
+    /// entries in it. Splitting the node space into ranges allows processing
+    /// an index in parallel.
+    pub fn entries_in_prefix_range(&self, lo: &N, hi: &N) -> Result<Vec<IndexEntry<N>>> {
+        if lo >= hi {
+            return Ok(Vec::new());
+        }
+        let start = self.search(lo)?.unwrap_or_else(|index| index);
+        let end = self.search(hi)?.unwrap_or_else(|index| index);
+        (start..end)
+            .map(|i| self.read_entry(i * N::ENTRY_LEN))
+            .collect()
+    }
 
-        let buf: Vec<u8> = vec![0, 1];
-        DataIndexOptions::read(&mut Cursor::new(buf)).expect_err("invalid read");
+    /// Nodes that appear more than once in the index.
+    ///
+    /// Entries are written sorted, so duplicates are adjacent. A valid index
+    /// returns an empty list.
+    pub fn find_duplicates(&self) -> Result<Vec<N>> {
+        let mut duplicates = Vec::new();
+        let mut prev: Option<N> = None;
+        for entry in self.entries() {
+            let hgid = entry?.hgid().clone();
+            if prev.as_ref() == Some(&hgid) && duplicates.last() != Some(&hgid) {
+                duplicates.push(hgid);
+            }
+            prev = Some(hgid);
+        }
+        Ok(duplicates)
     }
 
-    #[test]
-    fn test_missing_delta_base() {
-        let mut rng = ChaChaRng::from_seed([0u8; 32]);
-        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
-        let hgid = HgId::random(&mut rng);
-        let base = HgId::random(&mut rng);
-        values.insert(
-            hgid.clone(),
-            DeltaLocation {
-                delta_base: Some(base),
-                offset: 1,
-                size: 2,
-            },
-        );
-        let index = make_index(&values);
+    /// Check that nodes are strictly increasing, which lookups rely on.
+    pub fn verify_sorted(&self) -> Result<()> {
+        let mut prev: Option<N> = None;
+        for (i, entry) in self.entries().enumerate() {
+            let hgid = entry?.hgid().clone();
+            if let Some(prev) = prev {
+                if prev >= hgid {
+                    return Err(self
+                        .error(format!(
+                            "entry {} ({}) is not sorted after {}",
+                            i, hgid, prev
+                        ))
+                        .into());
+                }
+            }
+            prev = Some(hgid);
+        }
+        Ok(())
+    }
 
-        let delta = index.get_entry(&hgid).unwrap().unwrap();
-        assert_eq!(delta.delta_base_offset(), DeltaBaseOffset::Missing);
+    /// Check the integrity of the index: the entry count recorded in the
+    /// header matches the size of the index, nodes are sorted, and delta
+    /// base offsets point to entries in the index.
+    pub fn verify(&self) -> Result<()> {
+        self.verify_with_progress(|_, _| {})
     }
 
-    #[test]
-    fn test_missing_key() {
-        let mut rng = ChaChaRng::from_seed([0u8; 32]);
-        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
-        let hgid = HgId::random(&mut rng);
-        values.insert(
-            hgid.clone(),
-            DeltaLocation {
-                delta_base: None,
-                offset: 1,
-                size: 2,
-            },
-        );
-        let index = make_index(&values);
+    /// Like `verify`, but call `callback` with the number of entries checked
+    /// and the total number of entries periodically, and once at the end,
+    /// so that checking a large index can report progress.
+    pub fn verify_with_progress(&self, mut callback: impl FnMut(usize, usize)) -> Result<()> {
+        let total = self.len();
+        // Version zero does not record the number of entries.
+        if self.index_start > 2 + self.fanout_size {
+            let mut header = &self.mmap[self.index_start - 8..self.index_start];
+            let count = header.read_u64::<BigEndian>()?;
+            if count != total as u64 {
+                return Err(self
+                    .error(format!(
+                        "header records {} entries, but the index has {}",
+                        count, total
+                    ))
+                    .into());
+            }
+        }
 
-        let other = HgId::random(&mut rng);
-        assert!(index.get_entry(&other).unwrap().is_none());
+        let mut prev: Option<N> = None;
+        for (i, entry) in self.entries().enumerate() {
+            if i % VERIFY_PROGRESS_INTERVAL == 0 {
+                callback(i, total);
+            }
+            let entry = entry?;
+            let hgid = entry.hgid();
+            if let Some(prev) = &prev {
+                if prev >= hgid {
+                    return Err(self
+                        .error(format!(
+                            "entry {} ({}) is not sorted after {}",
+                            i, hgid, prev
+                        ))
+                        .into());
+                }
+            }
+            if !entry.is_tombstone() {
+                if let DeltaBaseOffset::Offset(offset) = entry.delta_base_offset() {
+                    let offset = offset as usize;
+                    if offset % N::ENTRY_LEN != 0 || offset / N::ENTRY_LEN >= total {
+                        return Err(self
+                            .error(format!(
+                                "entry {} ({}) has invalid delta base offset {}",
+                                i, hgid, offset
+                            ))
+                            .into());
+                    }
+                }
+            }
+            prev = Some(entry.hgid);
+        }
+        callback(total, total);
+        Ok(())
     }
 
-    quickcheck! {
-        fn test_header_serialization(version: u8, large: bool) -> bool {
-            let version = version % 2;
-            let options = DataIndexOptions { version, large };
-            let mut buf: Vec<u8> = vec![];
-            options.write(&mut buf).expect("write");
-            let parsed_options = DataIndexOptions::read(&mut Cursor::new(buf)).expect("read");
-            options == parsed_options
+    /// Check that the fanout table matches the entries: each bucket points
+    /// to the first entry whose node starts with its key, and empty buckets
+    /// repeat the bucket before them, like `FanoutTable::write`.
+    pub fn check_fanout_consistency(&self) -> Result<()> {
+        let large = self.fanout_size == FanoutTable::get_size(true);
+        let mut expected: Vec<Option<u32>> = vec![None; self.fanout_size / 4];
+        let mut prev_key = 0;
+        for i in 0..self.len() {
+            let offset = self.index_start + i * N::ENTRY_LEN;
+            let key = fanout_key(&self.mmap[offset..offset + N::LEN], large);
+            if key < prev_key {
+                return Err(self
+                    .error(format!(
+                        "entry {} is in fanout bucket {} after bucket {}",
+                        i, key, prev_key
+                    ))
+                    .into());
+            }
+            expected[key].get_or_insert((i * N::ENTRY_LEN) as u32);
+            prev_key = key;
         }
 
-        fn test_roundtrip_index(nodes: Vec<(HgId, u32)>) -> bool {
-            let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let mut last_offset = 0;
+        for (bucket, bytes) in self.get_fanout_slice().chunks_exact(4).enumerate() {
+            let offset = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let expected_offset = expected[bucket].unwrap_or(last_offset);
+            if offset != expected_offset {
+                return Err(self
+                    .error(format!(
+                        "fanout bucket {} points to offset {}, expected {}",
+                        bucket, offset, expected_offset
+                    ))
+                    .into());
+            }
+            last_offset = expected_offset;
+        }
+        Ok(())
+    }
 
-            let mut nodes = nodes;
-            let last_hgid = nodes.pop();
+    /// Check each entry against its checksum, for indexes written with
+    /// `write_with_checksums`. There is nothing to check in other indexes.
+    pub fn verify_checksums(&self) -> Result<()> {
+        if !self.checksums {
+            return Ok(());
+        }
+        if (self.mmap.len() - self.index_start) % self.stored_entry_len() != 0 {
+            return Err(self.error("checksums are truncated".to_string()).into());
+        }
+        let mut stored = &self.mmap[self.entries_end()..];
+        for i in 0..self.len() {
+            let offset = self.index_start + i * N::ENTRY_LEN;
+            let checksum = stored.read_u32::<BigEndian>()?;
+            if entry_checksum(&self.mmap[offset..offset + N::ENTRY_LEN]) != checksum {
+                let entry = self.read_entry(i * N::ENTRY_LEN)?;
+                return Err(self
+                    .error(format!(
+                        "entry {} ({}) does not match its checksum",
+                        i,
+                        entry.hgid()
+                    ))
+                    .into());
+            }
+        }
+        Ok(())
+    }
 
-            let mut offset = 0;
-            for &(hgid, size) in nodes.iter() {
-                let size = (size as u64) + 1;
+    /// Total size of the pack data covered by this index.
+    pub fn total_pack_bytes(&self) -> Result<u64> {
+        let mut total: u64 = 0;
+        for entry in self.entries() {
+            total = total
+                .checked_add(entry?.pack_entry_size())
+                .ok_or_else(|| self.error("total pack entry size overflows".to_string()))?;
+        }
+        Ok(total)
+    }
+
+    /// Sum of pack entry sizes, grouped by whether entries are full texts,
+    /// deltas, or deltas against a missing base.
+    pub fn base_size_histogram(&self) -> Result<DeltaBaseSizes> {
+        let mut sizes = DeltaBaseSizes::default();
+        for entry in self.entries() {
+            let entry = entry?;
+            let total = match entry.delta_base_offset() {
+                DeltaBaseOffset::FullText => &mut sizes.fulltext,
+                DeltaBaseOffset::Offset(_) => &mut sizes.delta,
+                DeltaBaseOffset::Missing => &mut sizes.missing,
+            };
+            *total = total
+                .checked_add(entry.pack_entry_size())
+                .ok_or_else(|| self.error("total pack entry size overflows".to_string()))?;
+        }
+        Ok(sizes)
+    }
+
+    /// Number of entries that are deltas against each delta base node.
+    /// Entries with a missing base are not counted.
+    pub fn base_reference_counts(&self) -> Result<HashMap<N, u32>> {
+        let mut counts = HashMap::new();
+        for entry in self.entries() {
+            if let DeltaBaseOffset::Offset(offset) = entry?.delta_base_offset() {
+                let base = self.read_entry(offset as usize)?;
+                *counts.entry(base.hgid().clone()).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// The entry with the longest delta chain and the length of its chain,
+    /// or `None` if the index is empty.
+    ///
+    /// The length of a chain is the number of entries that are read to
+    /// reconstruct its first entry, including itself. Chain lengths are
+    /// memoized, so shared tails are walked only once.
+    pub fn max_delta_chain(&self) -> Result<Option<(N, usize)>> {
+        // 0 means unknown, `IN_PROGRESS` marks entries of the chain being
+        // walked. Reaching one of those again means the chain has a cycle.
+        const IN_PROGRESS: usize = usize::MAX;
+        let mut lengths = vec![0; self.len()];
+        let mut chain = Vec::new();
+        let mut max: Option<(usize, usize)> = None;
+        for start in 0..self.len() {
+            let mut index = start;
+            // Length of the chain after the last entry of `chain`.
+            let mut length = loop {
+                match lengths[index] {
+                    0 => {}
+                    IN_PROGRESS => {
+                        return Err(self
+                            .error(format!("delta chain of entry {} has a cycle", index))
+                            .into());
+                    }
+                    length => break length,
+                }
+                lengths[index] = IN_PROGRESS;
+                chain.push(index);
+                match self.read_entry(index * N::ENTRY_LEN)?.delta_base_offset() {
+                    DeltaBaseOffset::Offset(offset) => {
+                        let offset = offset as usize;
+                        if offset % N::ENTRY_LEN != 0 || offset / N::ENTRY_LEN >= self.len() {
+                            return Err(self
+                                .error(format!(
+                                    "entry {} has invalid delta base offset {}",
+                                    index, offset
+                                ))
+                                .into());
+                        }
+                        index = offset / N::ENTRY_LEN;
+                    }
+                    DeltaBaseOffset::FullText | DeltaBaseOffset::Missing => break 0,
+                }
+            };
+            while let Some(index) = chain.pop() {
+                length += 1;
+                lengths[index] = length;
+            }
+            let is_max = match max {
+                Some((_, max_length)) => lengths[start] > max_length,
+                None => true,
+            };
+            if is_max {
+                max = Some((start, lengths[start]));
+            }
+        }
+        match max {
+            Some((index, length)) => {
+                let entry = self.read_entry(index * N::ENTRY_LEN)?;
+                Ok(Some((entry.hgid().clone(), length)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Look up the entry for `hgid`. Fails with `RedactedNode` if the entry
+    /// is a tombstone, to tell it apart from nodes that are not in the index.
+    pub fn get_entry(&self, hgid: &N) -> Result<Option<IndexEntry<N>>> {
+        self.get_entry_by(hgid, byte_order)
+    }
+
+    /// Like `get_entry`, for indexes written by `write_by` with `cmp`.
+    pub fn get_entry_by<F>(&self, hgid: &N, cmp: F) -> Result<Option<IndexEntry<N>>>
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        let entry = match self.search_by(hgid, cmp)? {
+            Ok(index) => self.read_entry(index * N::ENTRY_LEN)?,
+            Err(_) => return Ok(None),
+        };
+        if entry.is_tombstone() {
+            return Err(RedactedNode(hgid.to_string()).into());
+        }
+        Ok(Some(entry))
+    }
+
+    /// Index of the entry for `hgid`, or the index it would be inserted at
+    /// if it is not in the index.
+    fn search(&self, hgid: &N) -> Result<Result<usize, usize>> {
+        self.search_by(hgid, byte_order)
+    }
+
+    fn search_by<F>(&self, hgid: &N, cmp: F) -> Result<Result<usize, usize>>
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        let (start, end) = FanoutTable::get_bounds(self.get_fanout_slice(), hgid)
+            .with_context(|| self.error(format!("invalid fanout for {}", hgid)))?;
+        let first = start / N::ENTRY_LEN;
+        let start = start + self.index_start;
+        let end = match end {
+            Option::None => self.entries_end(),
+            Option::Some(pos) => pos + self.index_start,
+        };
+
+        let slice = self
+            .mmap
+            .get_err(start..end)
+            .with_context(|| self.error(format!("invalid bounds for {}", hgid)))?;
+        Ok(match self.binary_search(hgid, slice, cmp) {
+            Ok(index) => Ok(first + index),
+            Err(index) => Err(first + index),
+        })
+    }
+
+    /// Number of entries in each bucket of a fanout table of the given
+    /// size. The buckets are read from the fanout table if it has that size.
+    fn bucket_sizes(&self, large: bool) -> Result<Vec<u32>> {
+        let fanout_size = FanoutTable::get_size(large);
+        let mut sizes = vec![0u32; fanout_size / 4];
+        if self.fanout_size != fanout_size {
+            for i in 0..self.len() {
+                let offset = self.index_start + i * N::ENTRY_LEN;
+                sizes[fanout_key(&self.mmap[offset..offset + N::LEN], large)] += 1;
+            }
+            return Ok(sizes);
+        }
+        if self.is_empty() {
+            return Ok(sizes);
+        }
+
+        let starts: Vec<u32> = self
+            .get_fanout_slice()
+            .chunks_exact(4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+        let size = |from: u32, to: u32| -> Result<u32> {
+            match to.checked_sub(from) {
+                Some(bytes) => Ok(bytes / N::ENTRY_LEN as u32),
+                None => Err(self.error("fanout table is not sorted".to_string()).into()),
+            }
+        };
+        // Empty buckets repeat the start of the bucket before them. The
+        // first non-empty bucket starts at 0, like the empty buckets before
+        // it, so find it from the first entry instead.
+        let first = fanout_key(
+            &self.mmap[self.index_start..self.index_start + N::LEN],
+            large,
+        );
+        let mut prev = first;
+        for bucket in first + 1..starts.len() {
+            if starts[bucket] != starts[bucket - 1] {
+                sizes[prev] = size(starts[prev], starts[bucket])?;
+                prev = bucket;
+            }
+        }
+        sizes[prev] = size(starts[prev], (self.len() * N::ENTRY_LEN) as u32)?;
+        Ok(sizes)
+    }
+
+    pub fn read_entry(&self, offset: usize) -> Result<IndexEntry<N>> {
+        let offset = offset + self.index_start;
+        let raw_entry = self.mmap.get_err(offset..offset + N::ENTRY_LEN);
+        let mut entry = raw_entry
+            .and_then(IndexEntry::read)
+            .with_context(|| self.error(format!("invalid entry at offset {}", offset)))?;
+        if self.tombstones && entry.pack_entry_size & TOMBSTONE_FLAG != 0 {
+            entry.pack_entry_size &= !TOMBSTONE_FLAG;
+            entry.tombstone = true;
+        }
+        Ok(entry)
+    }
+
+    fn error(&self, message: String) -> DataIndexError {
+        DataIndexError(format!(
+            "{} in dataidx '{}'",
+            message,
+            display_path(self.path.as_deref())
+        ))
+    }
+
+    /// Binary search the entries in `slice`, ordered by `cmp`, returning
+    /// entry indexes like `slice::binary_search`.
+    fn binary_search<F>(&self, key: &N, slice: &[u8], cmp: F) -> Result<usize, usize>
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering,
+    {
+        let size = slice.len() / N::ENTRY_LEN;
+        // The entry length depends on `N`, so bisect over entry indexes
+        // rather than casting the slice into an array of entry buffers.
+        let (mut low, mut high) = (0, size);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = mid * N::ENTRY_LEN;
+            match cmp(&slice[offset..offset + N::LEN], key.as_ref()) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    fn get_fanout_slice(&self) -> &[u8] {
+        self.mmap[2..2 + self.fanout_size].as_ref()
+    }
+}
+
+/// Nodes of delta entries in `indexes` that can't be read from any of them:
+/// the base is `Missing`, or the base offset doesn't point to an entry, and
+/// no other index has a readable entry for the same node.
+///
+/// Delta base offsets only refer to entries of the same index. A delta
+/// whose base is in another pack is written with a `Missing` base, as the
+/// index doesn't record the base node.
+pub fn find_orphaned_bases<N: IndexNode>(indexes: &[&DataIndex<N>]) -> Result<Vec<N>> {
+    let mut orphans = Vec::new();
+    let mut readable = HashSet::new();
+    for index in indexes {
+        for entry in index.entries() {
+            let entry = entry?;
+            if entry.is_tombstone() {
+                continue;
+            }
+            let is_orphan = match entry.delta_base_offset() {
+                DeltaBaseOffset::FullText => false,
+                DeltaBaseOffset::Missing => true,
+                DeltaBaseOffset::Offset(offset) => {
+                    let offset = offset as usize;
+                    offset % N::ENTRY_LEN != 0 || offset / N::ENTRY_LEN >= index.len()
+                }
+            };
+            if is_orphan {
+                orphans.push(entry.hgid().clone());
+            } else {
+                readable.insert(entry.hgid().clone());
+            }
+        }
+    }
+    orphans.retain(|hgid| !readable.contains(hgid));
+    orphans.sort();
+    orphans.dedup();
+    Ok(orphans)
+}
+
+/// Compare the entries of two indexes, for example to check that a
+/// rewritten index has the same nodes as the original. Delta bases are not
+/// compared, since their offsets depend on the layout of each index.
+///
+/// Both indexes are sorted, so this is a single merge of their entries.
+pub fn diff_indexes<N: IndexNode>(a: &DataIndex<N>, b: &DataIndex<N>) -> Result<IndexDiff<N>> {
+    let mut result = IndexDiff {
+        only_in_a: Vec::new(),
+        only_in_b: Vec::new(),
+        changed: Vec::new(),
+    };
+    let mut a_entries = a.entries().peekable();
+    let mut b_entries = b.entries().peekable();
+    loop {
+        let order = match (a_entries.peek(), b_entries.peek()) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(Ok(a_entry)), Some(Ok(b_entry))) => a_entry.hgid().cmp(b_entry.hgid()),
+            // Pick the failed side so the error is returned below.
+            (Some(Err(_)), Some(_)) => Ordering::Less,
+            (Some(_), Some(Err(_))) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => result.only_in_a.push(a_entries.next().unwrap()?.hgid),
+            Ordering::Greater => result.only_in_b.push(b_entries.next().unwrap()?.hgid),
+            Ordering::Equal => {
+                let a_entry = a_entries.next().unwrap()?;
+                let b_entry = b_entries.next().unwrap()?;
+                if a_entry.is_tombstone() != b_entry.is_tombstone()
+                    || (!a_entry.is_tombstone()
+                        && (a_entry.pack_entry_offset() != b_entry.pack_entry_offset()
+                            || a_entry.pack_entry_size() != b_entry.pack_entry_size()))
+                {
+                    result.changed.push(a_entry.hgid);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Write an index with the entries of `source` for the nodes in
+/// `referenced`, and the delta bases those entries need, transitively.
+/// Other entries are dropped, such as full texts that only served as bases
+/// of deltas that were removed. Delta base offsets are recomputed for the
+/// new layout, and pack entry offsets are kept.
+///
+/// Nodes in `referenced` without an entry in `source` are ignored.
+pub fn repack_drop_orphans<N: IndexNode, T: Write>(
+    source: &DataIndex<N>,
+    referenced: &HashSet<N>,
+    writer: &mut T,
+) -> Result<()> {
+    let mut values = HashMap::new();
+    for hgid in referenced {
+        // Tombstones are looked up too, so they stay redacted.
+        let mut next = match source.search(hgid)? {
+            Ok(index) => Some(source.read_entry(index * N::ENTRY_LEN)?),
+            Err(_) => None,
+        };
+        // Stop at entries that are already retained, along with their bases.
+        while let Some(entry) = next.take() {
+            if values.contains_key(entry.hgid()) {
+                break;
+            }
+            let delta_base = if entry.is_tombstone() {
+                PendingDeltaBase::Tombstone
+            } else {
+                match entry.delta_base_offset() {
+                    DeltaBaseOffset::FullText => PendingDeltaBase::FullText,
+                    DeltaBaseOffset::Missing => PendingDeltaBase::Missing,
+                    DeltaBaseOffset::Offset(offset) => {
+                        let base = source.read_entry(offset as usize)?;
+                        if base.is_tombstone() {
+                            PendingDeltaBase::Missing
+                        } else {
+                            let base_hgid = base.hgid().clone();
+                            next = Some(base);
+                            PendingDeltaBase::Node(base_hgid)
+                        }
+                    }
+                }
+            };
+            let (offset, size) = (entry.pack_entry_offset(), entry.pack_entry_size());
+            values.insert(entry.hgid, (delta_base, offset, size));
+        }
+    }
+    DataIndex::write_pending(
+        writer,
+        values,
+        FanoutMode::Auto,
+        byte_order,
+        source.checksums,
+    )
+}
+
+/// Look up `nodes` in `indexes`, in parallel. The result for each node is
+/// from the first index that has an entry for it, or `None` if no index
+/// has one, and the results are in the same order as `nodes`.
+///
+/// Lookups fault in pages of the mappings, so they run on the blocking
+/// pool, in chunks of nodes and with at most `PREFETCH_PARALLELISM` chunks
+/// at a time so other users of the pool are not starved.
+pub async fn prefetch_index_entries<N>(
+    indexes: &[Arc<DataIndex<N>>],
+    nodes: &[N],
+) -> Vec<Result<Option<IndexEntry<N>>>>
+where
+    N: IndexNode + Send + Sync + 'static,
+{
+    let chunks = nodes.chunks(PREFETCH_CHUNK_SIZE).map(|chunk| {
+        let indexes = indexes.to_vec();
+        let chunk = chunk.to_vec();
+        spawn_blocking(move || {
+            chunk
+                .iter()
+                .map(|hgid| {
+                    for index in &indexes {
+                        if let Some(entry) = index.get_entry(hgid)? {
+                            return Ok(Some(entry));
+                        }
+                    }
+                    Ok(None)
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+    let mut chunks = stream::iter(chunks).buffered(PREFETCH_PARALLELISM);
+
+    let mut results = Vec::with_capacity(nodes.len());
+    while let Some(chunk) = chunks.next().await {
+        match chunk {
+            Ok(chunk) => results.extend(chunk),
+            Err(err) => {
+                // Keep the results aligned to `nodes` if the task panicked.
+                let len = nodes.len().min(results.len() + PREFETCH_CHUNK_SIZE) - results.len();
+                let err = format!("prefetch task failed: {}", err);
+                results.extend((0..len).map(|_| Err(anyhow!(err.clone()))));
+            }
+        }
+    }
+    results
+}
+
+/// The default order of nodes in an index.
+fn byte_order(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+/// Fanout bucket of `hgid`, given by its first one or two bytes.
+/// Write `entry`, and collect its checksum if `checksums` is set.
+fn write_entry<N: IndexNode, T: Write>(
+    writer: &mut T,
+    entry: &IndexEntry<N>,
+    checksums: &mut Option<Vec<u32>>,
+) -> Result<()> {
+    let mut buf = Vec::with_capacity(N::ENTRY_LEN);
+    entry.write(&mut buf)?;
+    writer.write_all(&buf)?;
+    if let Some(checksums) = checksums {
+        checksums.push(entry_checksum(&buf));
+    }
+    Ok(())
+}
+
+/// Write the checksums collected by `write_entry`, after the entries.
+fn write_checksums<T: Write>(writer: &mut T, checksums: Option<Vec<u32>>) -> Result<()> {
+    for checksum in checksums.into_iter().flatten() {
+        writer.write_u32::<BigEndian>(checksum)?;
+    }
+    Ok(())
+}
+
+/// Checksum of a serialized entry: the first bytes of its SHA-256.
+fn entry_checksum(entry: &[u8]) -> u32 {
+    use sha2::Digest;
+
+    let digest = sha2::Sha256::digest(entry);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+fn fanout_key(hgid: &[u8], large: bool) -> usize {
+    if large {
+        u16::from_be_bytes([hgid[0], hgid[1]]) as usize
+    } else {
+        hgid[0] as usize
+    }
+}
+
+fn display_path(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => "<in-memory>".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use async_runtime::block_on;
+    use quickcheck::quickcheck;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+
+    fn make_index(values: &HashMap<HgId, DeltaLocation>) -> DataIndex {
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).expect("write dataindex");
+
+        DataIndex::from_bytes(&buf).expect("dataindex")
+    }
+
+    #[test]
+    fn test_header_invalid() {
+        let buf: Vec<u8> = vec![MAX_VERSION + 1, 0];
+        let err = DataIndexOptions::read(&mut Cursor::new(buf)).expect_err("invalid read");
+        assert!(err.to_string().contains("supports up to version 2"));
+
+        let buf: Vec<u8> = vec![0, 1];
+        DataIndexOptions::read(&mut Cursor::new(buf)).expect_err("invalid read");
+    }
+
+    #[test]
+    fn test_missing_delta_base() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgid = HgId::random(&mut rng);
+        let base = HgId::random(&mut rng);
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: Some(base),
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let index = make_index(&values);
+
+        let delta = index.get_entry(&hgid).unwrap().unwrap();
+        assert_eq!(delta.delta_base_offset(), DeltaBaseOffset::Missing);
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgid = HgId::random(&mut rng);
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let index = make_index(&values);
+
+        let other = HgId::random(&mut rng);
+        assert!(index.get_entry(&other).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_new_from_file() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgid = HgId::random(&mut rng);
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let mut file = NamedTempFile::new().expect("file");
+        DataIndex::write(&mut file, &values).expect("write dataindex");
+        let path = file.into_temp_path();
+        let index = DataIndex::new(&path).expect("dataindex");
+
+        let entry = index.get_entry(&hgid).unwrap().unwrap();
+        assert_eq!(entry.pack_entry_offset(), 1);
+        assert_eq!(entry.pack_entry_size(), 2);
+    }
+
+    #[test]
+    fn test_from_entries() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let base = HgId::random(&mut rng);
+        let hgid = HgId::random(&mut rng);
+        let index = DataIndex::from_entries(vec![
+            (
+                base.clone(),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: 0,
+                    size: 10,
+                    tombstone: false,
+                },
+            ),
+            (
+                hgid.clone(),
+                DeltaLocation {
+                    delta_base: Some(base.clone()),
+                    offset: 10,
+                    size: 5,
+                    tombstone: false,
+                },
+            ),
+        ])
+        .unwrap();
+
+        let base_entry = index.get_entry(&base).unwrap().unwrap();
+        assert_eq!(base_entry.delta_base_offset(), DeltaBaseOffset::FullText);
+        let entry = index.get_entry(&hgid).unwrap().unwrap();
+        assert_eq!(entry.pack_entry_offset(), 10);
+        assert_eq!(entry.pack_entry_size(), 5);
+        match entry.delta_base_offset() {
+            DeltaBaseOffset::Offset(offset) => {
+                assert_eq!(index.read_entry(offset as usize).unwrap().hgid(), &base)
+            }
+            other => panic!("unexpected delta base {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let base_full = HgId::random(&mut rng);
+        let base_delta = HgId::random(&mut rng);
+        let base_missing = HgId::random(&mut rng);
+        let new_on_base = HgId::random(&mut rng);
+        let new_full = HgId::random(&mut rng);
+        let new_on_new = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>, offset| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+
+        let base = DataIndex::from_entries(vec![
+            (base_full, location(None, 0)),
+            (base_delta, location(Some(&base_full), 1)),
+            (base_missing, location(Some(&HgId::random(&mut rng)), 2)),
+        ])
+        .unwrap();
+        let mut buf = Vec::new();
+        DataIndex::append(
+            &base,
+            &[
+                (new_on_base, location(Some(&base_delta), 3)),
+                (new_full, location(None, 4)),
+                (new_on_new, location(Some(&new_full), 5)),
+            ],
+            &mut buf,
+        )
+        .unwrap();
+        let index = DataIndex::from_bytes(&buf).unwrap();
+        assert_eq!(index.len(), 6);
+
+        let delta_base = |hgid: &HgId| -> Option<HgId> {
+            match index.get_entry(hgid).unwrap().unwrap().delta_base_offset() {
+                DeltaBaseOffset::Offset(offset) => {
+                    Some(index.read_entry(offset as usize).unwrap().hgid().clone())
+                }
+                DeltaBaseOffset::FullText => None,
+                DeltaBaseOffset::Missing => panic!("missing delta base"),
+            }
+        };
+        assert_eq!(delta_base(&base_full), None);
+        assert_eq!(delta_base(&base_delta), Some(base_full));
+        assert_eq!(delta_base(&new_on_base), Some(base_delta));
+        assert_eq!(delta_base(&new_full), None);
+        assert_eq!(delta_base(&new_on_new), Some(new_full));
+        assert_eq!(
+            index
+                .get_entry(&base_missing)
+                .unwrap()
+                .unwrap()
+                .delta_base_offset(),
+            DeltaBaseOffset::Missing
+        );
+        assert_eq!(
+            index
+                .get_entry(&new_on_new)
+                .unwrap()
+                .unwrap()
+                .pack_entry_offset(),
+            5
+        );
+    }
+
+    #[test]
+    fn test_append_matches_write() {
+        let mut missing_rng = ChaChaRng::from_seed([1u8; 32]);
+        // Deltas against an earlier node, full texts and missing bases.
+        let mut location = |nodes: &[HgId], offset: u64| DeltaLocation {
+            delta_base: match offset % 3 {
+                0 => None,
+                1 => Some(nodes[offset as usize / 2]),
+                _ => Some(HgId::random(&mut missing_rng)),
+            },
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+
+        // Small and large fanouts, and an append that switches between them.
+        for base_len in [100, SMALL_FANOUT_CUTOFF - 5, SMALL_FANOUT_CUTOFF + 100] {
+            let mut nodes: Vec<HgId> = Vec::new();
+            let mut values = HashMap::new();
+            for offset in 0..base_len as u64 {
+                let value = location(&nodes, offset);
+                nodes.push(HgId::random(&mut rng));
+                values.insert(*nodes.last().unwrap(), value);
+            }
+            let base = make_index(&values);
+
+            let mut new_entries = Vec::new();
+            for i in 0..20 {
+                // Replace some of the base entries.
+                let hgid = if i % 4 == 0 {
+                    nodes[i * 3]
+                } else {
+                    HgId::random(&mut rng)
+                };
+                let offset = (base_len + i) as u64;
+                new_entries.push((hgid, location(&nodes, offset)));
+                values.insert(hgid, location(&nodes, offset));
+                nodes.push(hgid);
+            }
+
+            let mut appended = Vec::new();
+            DataIndex::append(&base, &new_entries, &mut appended).unwrap();
+            let mut expected = Vec::new();
+            DataIndex::write(&mut expected, &values).unwrap();
+            assert!(appended == expected, "base of {} entries", base_len);
+        }
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let redacted = HgId::random(&mut rng);
+        let delta = HgId::random(&mut rng);
+        let mut values = HashMap::new();
+        values.insert(
+            redacted,
+            DeltaLocation {
+                delta_base: None,
+                offset: 0,
+                size: 1,
+                tombstone: true,
+            },
+        );
+        values.insert(
+            delta,
+            DeltaLocation {
+                delta_base: Some(redacted),
+                offset: 1,
+                size: 1,
+                tombstone: false,
+            },
+        );
+
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+        let options = DataIndexOptions::read(&mut Cursor::new(&buf)).unwrap();
+        assert!(options.tombstones);
+        let index = DataIndex::from_bytes(&buf).unwrap();
+        let err = index.get_entry(&redacted).unwrap_err();
+        assert!(err.downcast_ref::<RedactedNode>().is_some());
+        let entry = index.get_entry(&delta).unwrap().unwrap();
+        assert!(!entry.is_tombstone());
+        assert_eq!(entry.delta_base_offset(), DeltaBaseOffset::Missing);
+        let tombstones: Vec<HgId> = index
+            .entries()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.is_tombstone())
+            .map(|entry| *entry.hgid())
+            .collect();
+        assert_eq!(tombstones, vec![redacted]);
+
+        // Appending keeps existing tombstones.
+        let new = HgId::random(&mut rng);
+        let location = DeltaLocation {
+            delta_base: Some(delta),
+            offset: 2,
+            size: 1,
+            tombstone: false,
+        };
+        let mut appended = Vec::new();
+        DataIndex::append(&index, &[(new, location)], &mut appended).unwrap();
+        let appended = DataIndex::from_bytes(&appended).unwrap();
+        assert!(appended.get_entry(&redacted).is_err());
+        assert!(appended.get_entry(&new).unwrap().is_some());
+
+        // Indexes without tombstones keep the old header.
+        values.remove(&redacted);
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+        assert_eq!(buf[1], 0);
+    }
+
+    #[test]
+    fn test_write_by() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let nodes: Vec<HgId> = (0..1000).map(|_| HgId::random(&mut rng)).collect();
+        for (i, hgid) in nodes.iter().enumerate() {
+            values.insert(
+                *hgid,
+                DeltaLocation {
+                    delta_base: (i > 0).then(|| nodes[i - 1]),
+                    offset: i as u64,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+        let reversed = |a: &[u8], b: &[u8]| b.cmp(a);
+
+        let mut buf = Vec::new();
+        DataIndex::write_by(&mut buf, &values, reversed).unwrap();
+        let index = DataIndex::from_bytes(&buf).unwrap();
+        for (i, hgid) in nodes.iter().enumerate() {
+            let entry = index.get_entry_by(hgid, reversed).unwrap().unwrap();
+            assert_eq!(entry.hgid(), hgid);
+            assert_eq!(entry.pack_entry_offset(), i as u64);
+            if i > 0 {
+                let offset = match entry.delta_base_offset() {
+                    DeltaBaseOffset::Offset(offset) => offset as usize,
+                    other => panic!("unexpected delta base {:?}", other),
+                };
+                assert_eq!(index.read_entry(offset).unwrap().hgid(), &nodes[i - 1]);
+            }
+        }
+        let absent = HgId::random(&mut rng);
+        assert!(index.get_entry_by(&absent, reversed).unwrap().is_none());
+
+        // Byte order is the same as `write`.
+        let mut expected = Vec::new();
+        DataIndex::write(&mut expected, &values).unwrap();
+        let mut buf = Vec::new();
+        DataIndex::write_by(&mut buf, &values, |a, b| a.cmp(b)).unwrap();
+        assert!(buf == expected);
+    }
+
+    #[test]
+    fn test_write_with_fanout() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..100 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+
+        let indexes: Vec<DataIndex> = [FanoutMode::Small, FanoutMode::Large]
+            .iter()
+            .map(|&mode| {
+                let mut buf = Vec::new();
+                DataIndex::write_with_fanout(&mut buf, &values, mode).unwrap();
+                let options = DataIndexOptions::read(&mut Cursor::new(&buf)).unwrap();
+                assert_eq!(options.large, mode == FanoutMode::Large);
+                DataIndex::from_bytes(&buf).unwrap()
+            })
+            .collect();
+        assert_eq!(indexes[0].fanout_size, FanoutTable::get_size(false));
+        assert_eq!(indexes[1].fanout_size, FanoutTable::get_size(true));
+
+        for (hgid, location) in values.iter() {
+            for index in indexes.iter() {
+                let entry = index.get_entry(hgid).unwrap().unwrap();
+                assert_eq!(entry.pack_entry_offset(), location.offset);
+            }
+        }
+        let missing = HgId::random(&mut rng);
+        for index in indexes.iter() {
+            assert!(index.get_entry(&missing).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_open_compressed() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let hgid = HgId::random(&mut rng);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+
+        let zstd_buf = zstd::stream::encode_all(&buf[..], 0).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(&buf).unwrap();
+        let gzip_buf = gzip.finish().unwrap();
+
+        for (codec, compressed) in [(Codec::Zstd, zstd_buf), (Codec::Gzip, gzip_buf)] {
+            let mut file = NamedTempFile::new().expect("file");
+            file.write_all(&compressed).unwrap();
+            let path = file.into_temp_path();
+            let index = DataIndex::open_compressed(&path, codec).unwrap();
+            let entry = index.get_entry(&hgid).unwrap().unwrap();
+            assert_eq!(entry.pack_entry_offset(), 1);
+            assert_eq!(entry.pack_entry_size(), 2);
+
+            // The uncompressed file is not a valid compressed index.
+            let mut file = NamedTempFile::new().expect("file");
+            file.write_all(&buf).unwrap();
+            let path = file.into_temp_path();
+            assert!(DataIndex::<HgId>::open_compressed(&path, codec).is_err());
+            assert!(DataIndex::<HgId>::open_compressed_auto(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_compressed() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..100 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i * 10,
+                    size: 10,
+                    tombstone: false,
+                },
+            );
+        }
+
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let mut file = NamedTempFile::new().expect("file");
+            DataIndex::write_compressed(&mut file, &values, codec).unwrap();
+            let path = file.into_temp_path();
+            let index = DataIndex::open_compressed(&path, codec).unwrap();
+            assert_eq!(index.len(), values.len());
+            let detected = DataIndex::<HgId>::open_compressed_auto(&path).unwrap();
+            assert_eq!(detected.len(), values.len());
+            for (hgid, location) in values.iter() {
+                let entry = index.get_entry(hgid).unwrap().unwrap();
+                assert_eq!(entry.pack_entry_offset(), location.offset);
+                assert_eq!(entry.pack_entry_size(), location.size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_orphaned_bases() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let absent = HgId::random(&mut rng);
+        let base = HgId::random(&mut rng);
+        let delta = HgId::random(&mut rng);
+        let moved = HgId::random(&mut rng);
+        let orphan = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>, offset| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+
+        let index1 = DataIndex::from_entries(vec![
+            (base.clone(), location(None, 0)),
+            (delta.clone(), location(Some(&base), 1)),
+            (moved.clone(), location(Some(&absent), 2)),
+        ])
+        .unwrap();
+        // `moved` is readable from the second index.
+        let index2 = DataIndex::from_entries(vec![
+            (moved.clone(), location(None, 0)),
+            (orphan.clone(), location(Some(&absent), 1)),
+        ])
+        .unwrap();
+
+        assert_eq!(find_orphaned_bases(&[&index1]).unwrap(), vec![moved]);
+        assert_eq!(
+            find_orphaned_bases(&[&index1, &index2]).unwrap(),
+            vec![orphan]
+        );
+        assert!(find_orphaned_bases::<HgId>(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_diff_indexes() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut nodes: Vec<HgId> = (0..100).map(|_| HgId::random(&mut rng)).collect();
+        nodes.sort();
+        let location = |offset, size| DeltaLocation {
+            delta_base: None,
+            offset,
+            size,
+            tombstone: false,
+        };
+
+        // Every 10th node is removed, every 10th node from the 5th is only
+        // added, and every 7th node has a different offset or size.
+        let mut a_entries = Vec::new();
+        let mut b_entries = Vec::new();
+        let mut expected = IndexDiff {
+            only_in_a: Vec::new(),
+            only_in_b: Vec::new(),
+            changed: Vec::new(),
+        };
+        for (i, hgid) in nodes.iter().enumerate() {
+            let i = i as u64;
+            if i % 10 == 0 {
+                a_entries.push((hgid.clone(), location(i, 1)));
+                expected.only_in_a.push(hgid.clone());
+            } else if i % 10 == 5 {
+                b_entries.push((hgid.clone(), location(i, 1)));
+                expected.only_in_b.push(hgid.clone());
+            } else {
+                a_entries.push((hgid.clone(), location(i, 1)));
+                let b_location = match i % 7 {
+                    0 => location(i + 1000, 1),
+                    1 => location(i, 2),
+                    _ => location(i, 1),
+                };
+                if i % 7 < 2 {
+                    expected.changed.push(hgid.clone());
+                }
+                b_entries.push((hgid.clone(), b_location));
+            }
+        }
+        let a = DataIndex::from_entries(a_entries).unwrap();
+        let b = DataIndex::from_entries(b_entries).unwrap();
+
+        assert_eq!(diff_indexes(&a, &b).unwrap(), expected);
+        let reversed = diff_indexes(&b, &a).unwrap();
+        assert_eq!(reversed.only_in_a, expected.only_in_b);
+        assert_eq!(reversed.only_in_b, expected.only_in_a);
+        assert_eq!(reversed.changed, expected.changed);
+
+        let same = diff_indexes(&a, &a).unwrap();
+        assert!(same.only_in_a.is_empty() && same.only_in_b.is_empty() && same.changed.is_empty());
+    }
+
+    #[test]
+    fn test_repack_drop_orphans() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let base = HgId::random(&mut rng);
+        let middle = HgId::random(&mut rng);
+        let delta = HgId::random(&mut rng);
+        let orphan = HgId::random(&mut rng);
+        let unrelated = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>, offset| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+        let source = DataIndex::from_entries(vec![
+            (base.clone(), location(None, 0)),
+            (middle.clone(), location(Some(&base), 1)),
+            (delta.clone(), location(Some(&middle), 2)),
+            (orphan.clone(), location(None, 3)),
+            (unrelated.clone(), location(Some(&orphan), 4)),
+        ])
+        .unwrap();
+
+        // Only `delta` is referenced, but its base chain is still needed.
+        let referenced = vec![delta.clone(), HgId::random(&mut rng)]
+            .into_iter()
+            .collect();
+        let mut buf = Vec::new();
+        repack_drop_orphans(&source, &referenced, &mut buf).unwrap();
+        let index = DataIndex::from_bytes(&buf).unwrap();
+
+        assert_eq!(index.len(), 3);
+        assert!(index.get_entry(&orphan).unwrap().is_none());
+        assert!(index.get_entry(&unrelated).unwrap().is_none());
+        let mut hgid = delta;
+        for expected_offset in (0..3).rev() {
+            let entry = index.get_entry(&hgid).unwrap().unwrap();
+            assert_eq!(entry.pack_entry_offset(), expected_offset);
+            match entry.delta_base_offset() {
+                DeltaBaseOffset::Offset(offset) => {
+                    hgid = index.read_entry(offset as usize).unwrap().hgid().clone();
+                }
+                DeltaBaseOffset::FullText => assert_eq!(hgid, base),
+                DeltaBaseOffset::Missing => panic!("missing delta base for {}", hgid),
+            }
+        }
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut nodes: Vec<HgId> = (0..3).map(|_| HgId::random(&mut rng)).collect();
+        nodes.sort();
+        let absent = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>, offset| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset,
+            size: 10,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (nodes[0].clone(), location(None, 0)),
+            (nodes[1].clone(), location(Some(&nodes[0]), 10)),
+            (nodes[2].clone(), location(Some(&absent), 20)),
+        ])
+        .unwrap();
+
+        let mut buf = Vec::new();
+        index.dump(&mut buf, DumpFormat::Lines).unwrap();
+        let expected = format!(
+            "{} fulltext 0 10\n{} delta 10 10 {}\n{} missing 20 10\n",
+            nodes[0], nodes[1], nodes[0], nodes[2]
+        );
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+
+        let mut buf = Vec::new();
+        index.dump(&mut buf, DumpFormat::Json).unwrap();
+        let values: Vec<serde_json::Value> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1]["node"], nodes[1].to_string());
+        assert_eq!(values[1]["kind"], "delta");
+        assert_eq!(values[1]["offset"], 10);
+        assert_eq!(values[1]["base"], nodes[0].to_string());
+        assert!(values[2]["base"].is_null());
+    }
+
+    #[test]
+    fn test_prefetch_index_entries() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        // More nodes than fit in one chunk, spread across the indexes.
+        let nodes: Vec<HgId> = (0..1000).map(|_| HgId::random(&mut rng)).collect();
+        let mut entries = vec![Vec::new(), Vec::new(), Vec::new()];
+        for (i, hgid) in nodes.iter().enumerate() {
+            let location = |index: usize| DeltaLocation {
+                delta_base: None,
+                offset: i as u64,
+                size: index as u64,
+                tombstone: false,
+            };
+            entries[i % 3].push((*hgid, location(i % 3)));
+            // Nodes in several indexes are resolved by the first one.
+            if i % 10 == 0 && i % 3 != 2 {
+                entries[2].push((*hgid, location(2)));
+            }
+        }
+        let indexes: Vec<Arc<DataIndex>> = entries
+            .into_iter()
+            .map(|entries| Arc::new(DataIndex::from_entries(entries).unwrap()))
+            .collect();
+
+        let mut lookup = nodes.clone();
+        lookup.push(HgId::random(&mut rng));
+        let results = block_on(prefetch_index_entries(&indexes, &lookup));
+        assert_eq!(results.len(), lookup.len());
+        for (i, result) in results.into_iter().enumerate() {
+            let entry = result.unwrap();
+            if i == nodes.len() {
+                assert!(entry.is_none());
+                continue;
+            }
+            let entry = entry.unwrap();
+            assert_eq!(entry.hgid(), &nodes[i]);
+            assert_eq!(entry.pack_entry_offset(), i as u64);
+            assert_eq!(entry.pack_entry_size(), (i % 3) as u64);
+        }
+
+        assert!(block_on(prefetch_index_entries(&indexes, &[])).is_empty());
+    }
+
+    #[test]
+    fn test_total_pack_bytes() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let location = |size| DeltaLocation {
+            delta_base: None,
+            offset: 0,
+            size,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (HgId::random(&mut rng), location(10)),
+            (HgId::random(&mut rng), location(32)),
+        ])
+        .unwrap();
+        assert_eq!(index.total_pack_bytes().unwrap(), 42);
+
+        let index = DataIndex::from_entries(vec![
+            (HgId::random(&mut rng), location(u64::MAX)),
+            (HgId::random(&mut rng), location(1)),
+        ])
+        .unwrap();
+        assert!(index.total_pack_bytes().is_err());
+    }
+
+    #[test]
+    fn test_base_size_histogram() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let base = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>, size| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset: 0,
+            size,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (base.clone(), location(None, 100)),
+            (HgId::random(&mut rng), location(None, 50)),
+            (HgId::random(&mut rng), location(Some(&base), 10)),
+            (HgId::random(&mut rng), location(Some(&base), 5)),
+            (
+                HgId::random(&mut rng),
+                location(Some(&HgId::random(&mut rng)), 7),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(
+            index.base_size_histogram().unwrap(),
+            DeltaBaseSizes {
+                fulltext: 150,
+                delta: 15,
+                missing: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn test_base_reference_counts() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let base = HgId::random(&mut rng);
+        let delta = HgId::random(&mut rng);
+        let location = |delta_base: Option<&HgId>| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset: 0,
+            size: 1,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (base.clone(), location(None)),
+            (delta.clone(), location(Some(&base))),
+            (HgId::random(&mut rng), location(Some(&base))),
+            (HgId::random(&mut rng), location(Some(&delta))),
+            (
+                HgId::random(&mut rng),
+                location(Some(&HgId::random(&mut rng))),
+            ),
+        ])
+        .unwrap();
+        let mut expected = HashMap::new();
+        expected.insert(base, 2);
+        expected.insert(delta, 1);
+        assert_eq!(index.base_reference_counts().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_max_delta_chain() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let location = |delta_base: Option<&HgId>| DeltaLocation {
+            delta_base: delta_base.cloned(),
+            offset: 0,
+            size: 1,
+            tombstone: false,
+        };
+        let index: DataIndex = DataIndex::from_entries(vec![]).unwrap();
+        assert_eq!(index.max_delta_chain().unwrap(), None);
+
+        let base = HgId::random(&mut rng);
+        let delta1 = HgId::random(&mut rng);
+        let delta2 = HgId::random(&mut rng);
+        let index = DataIndex::from_entries(vec![
+            (base.clone(), location(None)),
+            (delta1.clone(), location(Some(&base))),
+            (delta2.clone(), location(Some(&delta1))),
+            (HgId::random(&mut rng), location(Some(&delta1))),
+            (HgId::random(&mut rng), location(None)),
+            (
+                HgId::random(&mut rng),
+                location(Some(&HgId::random(&mut rng))),
+            ),
+        ])
+        .unwrap();
+        let (hgid, length) = index.max_delta_chain().unwrap().unwrap();
+        assert_eq!(length, 3);
+        assert_ne!(hgid, base);
+        assert_ne!(hgid, delta1);
+
+        let index = DataIndex::from_entries(vec![
+            (base.clone(), location(Some(&delta1))),
+            (delta1.clone(), location(Some(&base))),
+        ])
+        .unwrap();
+        assert!(index.max_delta_chain().is_err());
+    }
+
+    #[test]
+    fn test_entries_by_pack_offset() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let location = |offset| DeltaLocation {
+            delta_base: None,
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (HgId::random(&mut rng), location(30)),
+            (HgId::random(&mut rng), location(0)),
+            (HgId::random(&mut rng), location(20)),
+            (HgId::random(&mut rng), location(10)),
+        ])
+        .unwrap();
+        let offsets = index
+            .entries_by_pack_offset()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.pack_entry_offset())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, vec![0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn test_entries_in_prefix_range() {
+        let node = |byte: u8| HgId::from_byte_array([byte; 20]);
+        let location = |offset| DeltaLocation {
+            delta_base: None,
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(
+            [0x10, 0x20, 0x30, 0x40]
+                .iter()
+                .map(|&byte| (node(byte), location(byte as u64)))
+                .collect(),
+        )
+        .unwrap();
+        let range = |lo: u8, hi: u8| -> Vec<u64> {
+            index
+                .entries_in_prefix_range(&node(lo), &node(hi))
+                .unwrap()
+                .iter()
+                .map(|entry| entry.pack_entry_offset())
+                .collect()
+        };
+
+        // `lo` is included, `hi` is not.
+        assert_eq!(range(0x20, 0x40), vec![0x20, 0x30]);
+        assert_eq!(range(0x11, 0x31), vec![0x20, 0x30]);
+        assert_eq!(range(0x00, 0xff), vec![0x10, 0x20, 0x30, 0x40]);
+        assert_eq!(range(0x40, 0xff), vec![0x40]);
+        assert!(range(0x21, 0x30).is_empty());
+        assert!(range(0x30, 0x20).is_empty());
+        assert!(range(0x30, 0x30).is_empty());
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..3 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).expect("write dataindex");
+        let index = DataIndex::<HgId>::from_bytes(&buf).expect("dataindex");
+        assert!(index.find_duplicates().unwrap().is_empty());
+
+        // `write` dedupes, so corrupt the buffer directly: overwrite the last
+        // node with the one before it. Entries stay sorted.
+        let (len, entry_len) = (buf.len(), HgId::ENTRY_LEN);
+        let duplicate = buf[len - 2 * entry_len..len - 2 * entry_len + HgId::len()].to_vec();
+        buf[len - entry_len..len - entry_len + HgId::len()].copy_from_slice(&duplicate);
+        let index = DataIndex::from_bytes(&buf).expect("dataindex");
+        assert_eq!(
+            index.find_duplicates().unwrap(),
+            vec![HgId::from_slice(&duplicate).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_verify_sorted() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..3 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).expect("write dataindex");
+        let index = DataIndex::<HgId>::from_bytes(&buf).expect("dataindex");
+        index.verify_sorted().expect("sorted");
+
+        // Swap the last two entries.
+        let (len, entry_len) = (buf.len(), HgId::ENTRY_LEN);
+        let last = buf[len - entry_len..].to_vec();
+        buf.copy_within(len - 2 * entry_len..len - entry_len, len - entry_len);
+        buf[len - 2 * entry_len..len - entry_len].copy_from_slice(&last);
+        let index = DataIndex::<HgId>::from_bytes(&buf).expect("dataindex");
+        let err = index.verify_sorted().expect_err("out of order");
+        assert!(err.to_string().contains("entry 2"));
+    }
+
+    #[test]
+    fn test_verify_with_progress() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let mut prev = None;
+        for i in 0..3 {
+            let hgid = HgId::random(&mut rng);
+            values.insert(
+                hgid,
+                DeltaLocation {
+                    delta_base: prev,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+            prev = Some(hgid);
+        }
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).expect("write dataindex");
+        let index = DataIndex::<HgId>::from_bytes(&buf).expect("dataindex");
+        let mut progress = Vec::new();
+        index
+            .verify_with_progress(|checked, total| progress.push((checked, total)))
+            .expect("valid");
+        assert_eq!(progress, vec![(0, 3), (3, 3)]);
+
+        // Point the delta base of the last entry past the end.
+        let (len, entry_len) = (buf.len(), HgId::ENTRY_LEN);
+        let mut corrupt = buf.clone();
+        let field = len - entry_len + HgId::LEN;
+        corrupt[field..field + 4].copy_from_slice(&(3 * entry_len as u32).to_be_bytes());
+        let index = DataIndex::<HgId>::from_bytes(&corrupt).expect("dataindex");
+        let err = index.verify().expect_err("invalid delta base");
+        assert!(err.to_string().contains("invalid delta base offset"));
+
+        // Drop the last entry without updating the entry count.
+        let index = DataIndex::<HgId>::from_bytes(&buf[..len - entry_len]).expect("dataindex");
+        let err = index.verify().expect_err("wrong entry count");
+        assert!(err.to_string().contains("header records 3 entries"));
+    }
+
+    #[test]
+    fn test_check_fanout_consistency() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..100 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+        for mode in [FanoutMode::Small, FanoutMode::Large] {
+            let mut buf = Vec::new();
+            DataIndex::write_with_fanout(&mut buf, &values, mode).unwrap();
+            let index = DataIndex::<HgId>::from_bytes(&buf).unwrap();
+            index.check_fanout_consistency().expect("consistent");
+
+            // Point the last bucket one entry further.
+            let fanout_end = 2 + index.fanout_size;
+            let mut last = &buf[fanout_end - 4..fanout_end];
+            let offset = last.read_u32::<BigEndian>().unwrap() + HgId::ENTRY_LEN as u32;
+            buf[fanout_end - 4..fanout_end].copy_from_slice(&offset.to_be_bytes());
+            let index = DataIndex::<HgId>::from_bytes(&buf).unwrap();
+            let err = index.check_fanout_consistency().expect_err("inconsistent");
+            assert!(err.to_string().contains("fanout bucket"));
+        }
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        for i in 0..10 {
+            values.insert(
+                HgId::random(&mut rng),
+                DeltaLocation {
+                    delta_base: None,
+                    offset: i,
+                    size: 1,
+                    tombstone: false,
+                },
+            );
+        }
+
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+        let index = DataIndex::<HgId>::from_bytes(&buf).unwrap();
+        assert!(!index.has_checksums());
+        index.verify_checksums().expect("nothing to check");
+
+        let mut buf = Vec::new();
+        DataIndex::write_with_checksums(&mut buf, &values).unwrap();
+        let index = DataIndex::<HgId>::from_bytes(&buf).unwrap();
+        assert!(index.has_checksums());
+        assert_eq!(index.len(), values.len());
+        index.verify().expect("valid");
+        index.verify_checksums().expect("checksums match");
+        for (hgid, location) in values.iter() {
+            let entry = index.get_entry(hgid).unwrap().unwrap();
+            assert_eq!(entry.pack_entry_offset(), location.offset);
+        }
+
+        // Appending keeps the checksums.
+        let new = DeltaLocation {
+            delta_base: None,
+            offset: 10,
+            size: 1,
+            tombstone: false,
+        };
+        let mut appended = Vec::new();
+        DataIndex::append(&index, &[(HgId::random(&mut rng), new)], &mut appended).unwrap();
+        let appended = DataIndex::<HgId>::from_bytes(&appended).unwrap();
+        assert!(appended.has_checksums());
+        assert_eq!(appended.len(), values.len() + 1);
+        appended.verify_checksums().expect("checksums match");
+
+        // Flip a bit in the pack entry size of the last entry.
+        let entries_end = index.entries_end();
+        buf[entries_end - 1] ^= 1;
+        let index = DataIndex::<HgId>::from_bytes(&buf).unwrap();
+        let err = index.verify_checksums().expect_err("corrupt");
+        assert!(err.to_string().contains("entry 9"));
+    }
+
+    #[test]
+    fn test_sha256_nodes() {
+        let node = |byte| Sha256::from([byte; 32]);
+        let location = |delta_base, offset| DeltaLocation {
+            delta_base,
+            offset,
+            size: 1,
+            tombstone: false,
+        };
+        let index = DataIndex::from_entries(vec![
+            (node(3), location(Some(node(1)), 0)),
+            (node(1), location(None, 1)),
+            (node(2), location(Some(node(1)), 2)),
+        ])
+        .unwrap();
+        assert_eq!(index.len(), 3);
+        index.verify_sorted().unwrap();
+
+        let entry = index.get_entry(&node(3)).unwrap().unwrap();
+        assert_eq!(entry.hgid(), &node(3));
+        assert_eq!(entry.node_bytes(), &[3; 32]);
+        assert_eq!(entry.pack_entry_offset(), 0);
+        let base_offset = match entry.delta_base_offset() {
+            DeltaBaseOffset::Offset(offset) => offset,
+            _ => panic!("expected a delta base"),
+        };
+        assert_eq!(
+            index.read_entry(base_offset as usize).unwrap().hgid(),
+            &node(1)
+        );
+        assert!(index.get_entry(&node(4)).unwrap().is_none());
+
+        // Indexes can only be read with the node length they were written with.
+        let mut buf = Vec::new();
+        let values: HashMap<Sha256, DeltaLocation<Sha256>> =
+            vec![(node(1), location(None, 1))].into_iter().collect();
+        DataIndex::write(&mut buf, &values).unwrap();
+        assert_eq!(buf[0], 2);
+        assert!(DataIndex::<HgId>::from_bytes(&buf).is_err());
+
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        values.insert(
+            HgId::random(&mut rng),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 1,
+                tombstone: false,
+            },
+        );
+        let mut buf = Vec::new();
+        DataIndex::write(&mut buf, &values).unwrap();
+        assert_eq!(buf[0], 1);
+        assert!(DataIndex::<Sha256>::from_bytes(&buf).is_err());
+    }
+
+    #[test]
+    fn test_error_path() {
+        let mut file = NamedTempFile::new().expect("file");
+        file.write_all(&[3, 0]).expect("write");
+        let path = file.into_temp_path();
+        let err = DataIndex::<HgId>::new(&path).err().expect("invalid header");
+        assert!(err.to_string().contains(&path.display().to_string()));
+
+        let err = DataIndex::<HgId>::from_bytes(&[3, 0])
+            .err()
+            .expect("invalid header");
+        assert!(err.to_string().contains("<in-memory>"));
+    }
+
+    #[test]
+    fn test_from_bytes_empty() {
+        assert!(DataIndex::<HgId>::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn test_advise() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgid = HgId::random(&mut rng);
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let index = make_index(&values);
+
+        index.advise(MmapAccess::Sequential).unwrap();
+        assert!(index.get_entry(&hgid).unwrap().is_some());
+        index.advise(MmapAccess::Random).unwrap();
+        assert!(index.get_entry(&hgid).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_in_memory() {
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+        let hgid = HgId::random(&mut rng);
+        values.insert(
+            hgid.clone(),
+            DeltaLocation {
+                delta_base: None,
+                offset: 1,
+                size: 2,
+                tombstone: false,
+            },
+        );
+        let index = make_index(&values);
+
+        index.lock_in_memory().unwrap();
+        assert!(index.get_entry(&hgid).unwrap().is_some());
+        index.unlock_memory().unwrap();
+        // Unlocking an index that isn't locked is fine.
+        index.unlock_memory().unwrap();
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn test_lock_in_memory_unsupported() {
+        let index = make_index(&HashMap::new());
+        assert!(index.lock_in_memory().is_err());
+    }
+
+    #[test]
+    fn test_concurrent_get_entry() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<DataIndex>();
+
+        let mut rng = ChaChaRng::from_seed([0u8; 32]);
+        let entries: Vec<(HgId, DeltaLocation)> = (0..1000)
+            .map(|i| {
+                (
+                    HgId::random(&mut rng),
+                    DeltaLocation {
+                        delta_base: None,
+                        offset: i,
+                        size: 1,
+                        tombstone: false,
+                    },
+                )
+            })
+            .collect();
+        let hgids: Arc<Vec<(HgId, u64)>> = Arc::new(
+            entries
+                .iter()
+                .map(|(hgid, location)| (hgid.clone(), location.offset))
+                .collect(),
+        );
+        let index = Arc::new(DataIndex::from_entries(entries).unwrap());
+
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let index = index.clone();
+                let hgids = hgids.clone();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        for (hgid, offset) in hgids.iter().skip(t) {
+                            let entry = index.get_entry(hgid).unwrap().unwrap();
+                            assert_eq!(entry.pack_entry_offset(), *offset);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    quickcheck! {
+        fn test_header_serialization(
+            version: u8,
+            large: bool,
+            tombstones: bool,
+            checksums: bool
+        ) -> bool {
+            let version = version % 3;
+            let options = DataIndexOptions { version, large, tombstones, checksums };
+            let mut buf: Vec<u8> = vec![];
+            options.write(&mut buf).expect("write");
+            let parsed_options = DataIndexOptions::read(&mut Cursor::new(buf)).expect("read");
+            options == parsed_options
+        }
+
+        fn test_roundtrip_index(nodes: Vec<(HgId, u32)>) -> bool {
+            let mut values: HashMap<HgId, DeltaLocation> = HashMap::new();
+
+            let mut nodes = nodes;
+            let last_hgid = nodes.pop();
+
+            let mut offset = 0;
+            for &(hgid, size) in nodes.iter() {
+                let size = (size as u64) + 1;
                 values.insert(
                     hgid.clone(),
                     DeltaLocation {
                         delta_base: Default::default(),
                         offset: offset,
                         size: size,
+                        tombstone: false,
                     },
                 );
 
@@ -416,5 +3022,29 @@ mod tests {
             let last_hgid = last_hgid.unwrap_or((HgId::random(&mut rng), 0)).0;
             index.get_entry(&last_hgid).unwrap().is_none()
         }
+
+        fn test_roundtrip_index_sha256(nodes: HashMap<Sha256, u32>) -> bool {
+            let values: HashMap<Sha256, DeltaLocation<Sha256>> = nodes
+                .iter()
+                .map(|(node, &offset)| {
+                    let location = DeltaLocation {
+                        delta_base: None,
+                        offset: offset as u64,
+                        size: 1,
+                        tombstone: false,
+                    };
+                    (node.clone(), location)
+                })
+                .collect();
+            let mut buf = Vec::new();
+            DataIndex::write(&mut buf, &values).expect("write dataindex");
+            let index = DataIndex::<Sha256>::from_bytes(&buf).expect("dataindex");
+
+            index.len() == values.len()
+                && values.iter().all(|(node, location)| {
+                    let entry = index.get_entry(node).expect("get_entry").unwrap();
+                    entry.hgid() == node && entry.pack_entry_offset() == location.offset
+                })
+        }
     }
 }
@@ -289,8 +289,8 @@ impl DataPack {
         let len = file.metadata()?.len();
         if len < 1 {
             return Err(format_err!(
-                "empty datapack '{:?}' is invalid",
-                path.to_str().unwrap_or("<unknown>")
+                "empty datapack '{}' is invalid",
+                path.display()
             ));
         }
 
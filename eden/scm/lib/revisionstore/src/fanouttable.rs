@@ -13,7 +13,6 @@ use byteorder::BigEndian;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use thiserror::Error;
-use types::HgId;
 
 const SMALL_FANOUT_FACTOR: u8 = 1;
 const LARGE_FANOUT_FACTOR: u8 = 2;
@@ -26,8 +25,8 @@ const LARGE_RAW_SIZE: usize = 262144; // LARGE_FANOUT_LENGTH * sizeof(u32)
 #[error("Fanout Table Error: {0:?}")]
 struct FanoutTableError(String);
 
-fn get_fanout_index(table_size: usize, hgid: &HgId) -> Result<u64> {
-    let mut cursor = Cursor::new(hgid.as_ref());
+fn get_fanout_index(table_size: usize, hgid: &[u8]) -> Result<u64> {
+    let mut cursor = Cursor::new(hgid);
     match table_size {
         SMALL_RAW_SIZE => Ok(cursor.read_u8()? as u64),
         LARGE_RAW_SIZE => Ok(cursor.read_u16::<BigEndian>()? as u64),
@@ -40,9 +39,9 @@ pub struct FanoutTable {}
 impl FanoutTable {
     /// Returns the (start, end) search bounds indicated by the fanout table. If end is None, then
     /// search to the end of the index.
-    pub fn get_bounds(table: &[u8], hgid: &HgId) -> Result<(usize, Option<usize>)> {
+    pub fn get_bounds<H: AsRef<[u8]>>(table: &[u8], hgid: &H) -> Result<(usize, Option<usize>)> {
         // Get the integer equivalent of the first few bytes of the hgid.
-        let index = get_fanout_index(table.len(), hgid)?;
+        let index = get_fanout_index(table.len(), hgid.as_ref())?;
 
         // Read the start bound at the index location.
         let mut cur = Cursor::new(table);
@@ -74,7 +73,7 @@ impl FanoutTable {
     ///
     /// `locations` - A presized, mutable vector where the offset for each hgid index value will be
     /// written.
-    pub fn write<'b, T: Write, I: Iterator<Item = &'b HgId>>(
+    pub fn write<'b, T: Write, H: AsRef<[u8]> + 'b, I: Iterator<Item = &'b H>>(
         writer: &mut T,
         fanout_factor: u8,
         hgid_iter: &mut I,
@@ -109,7 +108,7 @@ impl FanoutTable {
         // Fill in the fanout table with the offset of the first entry for each prefix.
         let mut offset: u32 = 0;
         for (i, hgid) in hgid_iter.enumerate() {
-            let fanout_key = get_fanout_index(fanout_raw_size, &hgid)?;
+            let fanout_key = get_fanout_index(fanout_raw_size, hgid.as_ref())?;
             if fanout_table[fanout_key as usize].is_none() {
                 fanout_table[fanout_key as usize] = Some(offset);
             }
@@ -151,6 +150,7 @@ mod tests {
     use std::mem::size_of;
 
     use quickcheck::quickcheck;
+    use types::HgId;
 
     use super::*;
 
@@ -169,8 +169,8 @@ impl HistoryIndex {
         let len = file.metadata()?.len();
         if len < 1 {
             return Err(HistoryIndexError(format!(
-                "empty histidx '{:?}' is invalid",
-                path.to_str().unwrap_or("<unknown>")
+                "empty histidx '{}' is invalid",
+                path.display()
             ))
             .into());
         }
@@ -276,8 +276,8 @@ impl HistoryPack {
         let len = file.metadata()?.len();
         if len < 1 {
             return Err(format_err!(
-                "empty histpack '{:?}' is invalid",
-                path.to_str().unwrap_or("<unknown>")
+                "empty histpack '{}' is invalid",
+                path.display()
             ));
         }
 
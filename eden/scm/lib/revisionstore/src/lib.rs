@@ -167,6 +167,22 @@ pub use revisionstore_types::*;
 
 pub use crate::contentstore::ContentStore;
 pub use crate::contentstore::ContentStoreBuilder;
+pub use crate::dataindex::diff_indexes;
+pub use crate::dataindex::find_orphaned_bases;
+pub use crate::dataindex::prefetch_index_entries;
+pub use crate::dataindex::repack_drop_orphans;
+pub use crate::dataindex::Codec;
+pub use crate::dataindex::DataIndex;
+pub use crate::dataindex::DeltaBaseOffset;
+pub use crate::dataindex::DeltaBaseSizes;
+pub use crate::dataindex::DeltaLocation;
+pub use crate::dataindex::DumpFormat;
+pub use crate::dataindex::FanoutMode;
+pub use crate::dataindex::IndexDiff;
+pub use crate::dataindex::IndexEntry;
+pub use crate::dataindex::IndexNode;
+pub use crate::dataindex::MmapAccess;
+pub use crate::dataindex::RedactedNode;
 pub use crate::datapack::DataEntry;
 pub use crate::datapack::DataPack;
 pub use crate::datapack::DataPackVersion;
@@ -164,6 +164,7 @@ impl MutableDataPackInner {
             delta_base: delta.base.as_ref().map(|k| k.hgid.clone()),
             offset,
             size: buf.len() as u64,
+            tombstone: false,
         };
         self.mem_index
             .insert(delta.key.hgid.clone(), delta_location);
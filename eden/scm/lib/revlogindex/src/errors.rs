@@ -31,6 +31,9 @@ pub enum RevlogIndexError {
 
     #[error("unsupported: {0}")]
     Unsupported(String),
+
+    #[error("interrupted")]
+    Interrupted,
 }
 
 #[derive(Debug, Error)]
@@ -137,6 +140,7 @@ impl From<RevlogIndexError> for dag::Error {
             }
             .into(),
             R::Unsupported(message) => dag::Error::Programming(message),
+            R::Interrupted => BackendError::Generic("interrupted".to_string()).into(),
         }
     }
 }
@@ -10,9 +10,12 @@
 pub mod errors;
 pub mod nodemap;
 mod revlogindex;
+pub mod spans;
 
 pub use crate::errors::RevlogIndexError as Error;
 pub use crate::nodemap::NodeRevMap;
+pub use crate::revlogindex::InterruptCheck;
 pub use crate::revlogindex::RevlogEntry;
 pub use crate::revlogindex::RevlogIndex;
+pub use crate::revlogindex::SpillOptions;
 pub type Result<T> = std::result::Result<T, Error>;
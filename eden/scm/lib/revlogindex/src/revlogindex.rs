@@ -6,6 +6,7 @@
  */
 
 use std::collections::BTreeMap;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::collections::VecDeque;
 use std::fs;
@@ -53,12 +54,15 @@ use minibytes::Bytes;
 use nonblocking::non_blocking_result;
 use parking_lot::Mutex;
 use parking_lot::RwLock;
+use sha2::Digest;
+use sha2::Sha256;
 use util::path::atomic_write_symlink;
 use util::path::remove_file;
 
 use crate::errors::corruption;
 use crate::errors::unsupported;
 use crate::errors::CorruptionError;
+use crate::errors::RevNotFound;
 use crate::nodemap;
 use crate::Error;
 use crate::NodeRevMap;
@@ -66,6 +70,16 @@ use crate::Result;
 
 const REVIDX_OCTOPUS_MERGE: u16 = 1 << 12;
 
+/// Maximum number of revisions accepted by `reachability_matrix`.
+const MAX_REACHABILITY_MATRIX_REVS: usize = 64;
+
+/// Long traversals call the interrupt check once per this many revisions.
+const INTERRUPT_CHECK_INTERVAL: usize = 4096;
+
+/// Returns `true` if a long traversal should be aborted, for example because
+/// the user pressed Ctrl-C.
+pub type InterruptCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
 impl RevlogIndex {
     /// Calculate `heads(ancestors(revs))`.
     pub fn headsancestors(&self, revs: Vec<u32>) -> dag::Result<Vec<u32>> {
@@ -82,7 +96,10 @@ impl RevlogIndex {
         }
 
         let min_rev = *revs.iter().min().unwrap();
-        assert!(self.len() > min_rev as usize);
+        let max_rev = *revs.iter().max().unwrap();
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
         let mut states = vec![State::Unspecified; self.len() - min_rev as usize];
         let mut result = Vec::with_capacity(revs.len());
@@ -91,6 +108,7 @@ impl RevlogIndex {
         }
 
         for rev in (min_rev as usize..self.len()).rev() {
+            self.check_interrupted(rev)?;
             let state = states[rev - min_rev as usize];
             match state {
                 State::Unspecified => {}
@@ -109,15 +127,20 @@ impl RevlogIndex {
         Ok(result)
     }
 
-    /// Given public and draft head revision numbers, calculate the "phase sets".
-    /// Return (publicset, draftset).
+    /// Given public, draft and secret head revision numbers, calculate the
+    /// "phase sets". Return (publicset, draftset, secretset).
+    ///
+    /// A revision reachable from heads of different phases gets the most
+    /// public of them.
     ///
     /// (only used when narrow-heads is disabled).
     pub fn phasesets(
         &self,
         publicheads: Vec<u32>,
         draftheads: Vec<u32>,
-    ) -> dag::Result<(IdSet, IdSet)> {
+        secretheads: Vec<u32>,
+    ) -> dag::Result<(IdSet, IdSet, IdSet)> {
+        let mut secret_set = IdSet::empty();
         let mut draft_set = IdSet::empty();
         let mut public_set = IdSet::empty();
 
@@ -126,6 +149,7 @@ impl RevlogIndex {
         #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
         enum Phase {
             Unspecified,
+            Secret,
             Draft,
             Public,
         }
@@ -135,7 +159,19 @@ impl RevlogIndex {
             }
         }
 
+        if let Some(&rev) = publicheads
+            .iter()
+            .chain(draftheads.iter())
+            .chain(secretheads.iter())
+            .find(|&&rev| rev as usize >= self.len())
+        {
+            return Id(rev as _).not_found();
+        }
+
         let mut phases = vec![Phase::Unspecified; self.len()];
+        for rev in secretheads {
+            phases[rev as usize] = Phase::Secret;
+        }
         for rev in draftheads {
             phases[rev as usize] = Phase::Draft;
         }
@@ -148,6 +184,7 @@ impl RevlogIndex {
             match phase {
                 Phase::Public => public_set.push(Id(rev as u64)),
                 Phase::Draft => draft_set.push(Id(rev as u64)),
+                Phase::Secret => secret_set.push(Id(rev as u64)),
                 // Do not track "unknown" explicitly. This is future-proof,
                 // since tracking "unknown" explicitly is quite expensive
                 // with the new "dag" abstraction.
@@ -158,2183 +195,5276 @@ impl RevlogIndex {
                 phases[parent_rev as usize] = phases[parent_rev as usize].max(phase);
             }
         }
-        Ok((public_set, draft_set))
+        Ok((public_set, draft_set, secret_set))
     }
 
-    /// GCA based on linear scan.
-    ///
-    /// Ported from Mercurial's C code `find_gca_candidates()`.
-    ///
-    /// The algorithm was written by Bryan O'Sullivan on 2013-04-16.
-    /// He provided both a Python [1] and a C version [2]. Since then, the only real
-    /// logic change is removing an unnecessary "if" branch by Mads Kiilerich on
-    /// 2014-02-24 [4].
-    ///
-    /// The C implementation is quite competitive among linear algorithms on
-    /// performance. It is cache-efficient, has fast paths to exit early, and
-    /// takes up to 62 (if bitmask is u64) revs at once. Other implementations
-    /// might just take 2 revs at most. For example, the older implemenation
-    /// by Olivia Mackall in 2006 [3] takes 2 revs explicitly.
+    /// Like `phasesets`, but return the nodes of each set, ordered by
+    /// ascending revision number.
+    pub fn phasesets_nodes(
+        &self,
+        publicheads: Vec<u32>,
+        draftheads: Vec<u32>,
+        secretheads: Vec<u32>,
+    ) -> dag::Result<(Vec<Vertex>, Vec<Vertex>, Vec<Vertex>)> {
+        let (public_set, draft_set, secret_set) =
+            self.phasesets(publicheads, draftheads, secretheads)?;
+        let to_nodes = |set: IdSet| -> dag::Result<Vec<Vertex>> {
+            set.iter_asc()
+                .map(|id| self.node_of_rev(id.0 as u32))
+                .collect()
+        };
+        Ok((
+            to_nodes(public_set)?,
+            to_nodes(draft_set)?,
+            to_nodes(secret_set)?,
+        ))
+    }
+
+    /// Given draft and secret root revision numbers, calculate the "phase
+    /// sets" by propagating phases to descendants. Return (draftset,
+    /// secretset). Revisions in neither set are public.
     ///
-    /// Changes in this Rust implementation:
-    /// - Change `bitmask` from `u64` to `u8` for smaller memory footage, at the
-    ///   cost of losing support for more than 6 revs.
-    /// - Adopted to RevlogIndex.
-    /// - Run `gca` recursively on large input.
+    /// A revision that descends from roots of different phases gets the
+    /// least public of them. This is for repos that still track phase
+    /// roots instead of heads.
+    pub fn phasesets_from_roots(
+        &self,
+        draftroots: Vec<u32>,
+        secretroots: Vec<u32>,
+    ) -> dag::Result<(IdSet, IdSet)> {
+        let mut draft_set = IdSet::empty();
+        let mut secret_set = IdSet::empty();
+
+        // Used internally. Different from "phases.py".
+        #[repr(u8)]
+        #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+        enum Phase {
+            Public,
+            Draft,
+            Secret,
+        }
+
+        let len = self.len();
+        if let Some(&rev) = draftroots
+            .iter()
+            .chain(secretroots.iter())
+            .find(|&&rev| rev as usize >= len)
+        {
+            return Id(rev as _).not_found();
+        }
+        let min_rev = match draftroots.iter().chain(secretroots.iter()).min() {
+            Some(&rev) => rev as usize,
+            None => return Ok((draft_set, secret_set)),
+        };
+
+        let mut phases = vec![Phase::Public; len];
+        for rev in draftroots {
+            phases[rev as usize] = Phase::Draft;
+        }
+        for rev in secretroots {
+            phases[rev as usize] = Phase::Secret;
+        }
+
+        // Parents have lower revision numbers, so a revision has its final
+        // phase when it is reached.
+        let children = self.children()?;
+        let mut draft_revs = Vec::new();
+        let mut secret_revs = Vec::new();
+        for rev in min_rev..len {
+            self.check_interrupted(rev)?;
+            let phase = phases[rev];
+            match phase {
+                Phase::Public => continue,
+                Phase::Draft => draft_revs.push(rev),
+                Phase::Secret => secret_revs.push(rev),
+            }
+            for &child_rev in children[rev].iter() {
+                phases[child_rev as usize] = phases[child_rev as usize].max(phase);
+            }
+        }
+        for rev in draft_revs.into_iter().rev() {
+            draft_set.push(Id(rev as u64));
+        }
+        for rev in secret_revs.into_iter().rev() {
+            secret_set.push(Id(rev as u64));
+        }
+        Ok((draft_set, secret_set))
+    }
+
+    /// Calculate `(common, missing)` used by discovery, where `common` is
+    /// `::localheads & ::remoteheads`, and `missing` is `::remoteheads - common`.
     ///
-    /// [1]: https://www.mercurial-scm.org/repo/hg/rev/2f7186400a07
-    /// [2]: https://www.mercurial-scm.org/repo/hg/rev/5bae936764bb
-    /// [3]: https://www.mercurial-scm.org/repo/hg/rev/b1db258e875c
-    /// [4]: https://www.mercurial-scm.org/repo/hg/rev/4add43865a9b
-    pub fn gca_revs(&self, revs: &[u32], limit: usize) -> dag::Result<Vec<u32>> {
-        type BitMask = u8;
-        let revcount = revs.len();
-        assert!(revcount < 7);
-        if revcount == 0 {
-            return Ok(Vec::new());
+    /// Both sets are calculated in a single scan.
+    pub fn findmissingcommon(
+        &self,
+        localheads: Vec<u32>,
+        remoteheads: Vec<u32>,
+    ) -> dag::Result<(IdSet, IdSet)> {
+        let mut common = IdSet::empty();
+        let mut missing = IdSet::empty();
+        if remoteheads.is_empty() {
+            // Nothing is missing or common.
+            return Ok((common, missing));
+        }
+        let max_rev = *remoteheads.iter().chain(localheads.iter()).max().unwrap();
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
         }
 
-        let allseen: BitMask = (1 << revcount) - 1;
-        let poison: BitMask = 1 << revcount;
-        let maxrev = revs.iter().max().cloned().unwrap();
-        let mut interesting = revcount;
-        let mut gca = Vec::new();
-        let mut seen: Vec<BitMask> = vec![0; maxrev as usize + 1];
+        const LOCAL: u8 = 1;
+        const REMOTE: u8 = 2;
+        const BOTH: u8 = LOCAL | REMOTE;
 
-        for (i, &rev) in revs.iter().enumerate() {
-            seen[rev as usize] = 1 << i;
+        let mut states = vec![0u8; max_rev as usize + 1];
+        for rev in localheads {
+            states[rev as usize] |= LOCAL;
+        }
+        for rev in remoteheads {
+            states[rev as usize] |= REMOTE;
         }
 
-        for v in (0..=maxrev).rev() {
-            if interesting == 0 {
-                break;
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev as usize)?;
+            let state = states[rev as usize];
+            match state {
+                0 => continue,
+                BOTH => common.push(Id(rev as _)),
+                REMOTE => missing.push(Id(rev as _)),
+                // Local only. Still need to propagate to find common revs.
+                _ => {}
             }
-            let mut sv = seen[v as usize];
-            if sv == 0 {
-                continue;
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                states[parent_rev as usize] |= state;
             }
-            if sv < poison {
-                interesting -= 1;
-                if sv == allseen {
-                    gca.push(v);
-                    if gca.len() >= limit {
-                        return Ok(gca);
-                    }
-                    sv |= poison;
-                    if revs.iter().any(|&r| r == v) {
-                        break;
-                    }
-                }
+        }
+
+        Ok((common, missing))
+    }
+
+    /// Get revisions that are not a parent of any other revision, in
+    /// ascending order. Inserted revisions are included.
+    pub fn headrevs(&self) -> dag::Result<Vec<u32>> {
+        let len = self.len();
+        let mut has_child = vec![false; len];
+        for rev in 0..len {
+            self.check_interrupted(rev)?;
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                has_child[parent_rev as usize] = true;
             }
-            for &p in self.parent_revs(v)?.as_revs() {
-                let sp = seen[p as usize];
-                if sv < poison {
-                    if sp == 0 {
-                        seen[p as usize] = sv;
-                        interesting += 1
-                    } else if sp != sv {
-                        seen[p as usize] |= sv
-                    }
-                } else {
-                    if sp != 0 && sp < poison {
-                        interesting -= 1
-                    }
-                    seen[p as usize] = sv
+        }
+        Ok((0..len as u32)
+            .filter(|&rev| !has_child[rev as usize])
+            .collect())
+    }
+
+    /// Calculate `parents(revs) - revs`, the one-step boundary of `revs`.
+    pub fn parentsofset(&self, revs: Vec<u32>) -> dag::Result<IdSet> {
+        let len = self.len();
+        let mut in_set = vec![false; len];
+        for &rev in revs.iter() {
+            if rev as usize >= len {
+                return Id(rev as _).not_found();
+            }
+            in_set[rev as usize] = true;
+        }
+
+        let mut frontier = Vec::new();
+        for &rev in revs.iter() {
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if !in_set[parent_rev as usize] {
+                    frontier.push(parent_rev);
                 }
             }
         }
+        frontier.sort_unstable_by(|a, b| b.cmp(a));
+        frontier.dedup();
 
-        Ok(gca)
+        let mut result = IdSet::empty();
+        for rev in frontier {
+            result.push(Id(rev as _));
+        }
+        Ok(result)
     }
 
-    /// Range based on linear scan.
-    ///
-    /// Ported from Mercurial's C code `reachableroots2()`.
-    ///
-    /// The C implementation was added by Laurent Charignon on 2015-08-06 [1].
-    /// It was based on the a Python implementation added by Bryan O'Sullivan on
-    /// 2012-06-01 [2], which is similar to an older implementation by Eric Hopper
-    /// on 2005-10-07 [3], but faster and shorter.
-    ///
-    /// The C code was then revised by others. The most significant change was
-    /// switching the "contains" check of "roots" and "reachable" from Python sets
-    /// to bits in the pure C "revstates" array for easier error handling and
-    /// better performance, by Yuya Nishihara on 2015-08-13 [4] [5].
-    ///
-    /// Improvements in this Rust implementation:
-    /// - Use `VecDeque` for `tovisit` (roughly O(len(result)) -> O(len(heads))).
-    /// - Truncate `revstates` (O(len(changelog)) -> O(max_head - min_root)).
-    /// - Add `reachable.is_empty()` fast path that existed in the Python code.
-    /// - Support octopus merge.
-    ///
-    /// [1]: https://www.mercurial-scm.org/repo/hg/rev/ff89383a97db
-    /// [2]: https://www.mercurial-scm.org/repo/hg/rev/b6efeb27e733
-    /// [3]: https://www.mercurial-scm.org/repo/hg/rev/518da3c3b6ce
-    /// [4]: https://www.mercurial-scm.org/repo/hg/rev/b68c9d232db6
-    /// [5]: https://www.mercurial-scm.org/repo/hg/rev/b3ad349d0e50
-    pub fn range_revs(&self, roots: &[u32], heads: &[u32]) -> dag::Result<Vec<u32>> {
-        if roots.is_empty() || heads.is_empty() {
-            return Ok(Vec::new());
+    /// Calculate `::revs`, including `revs` themselves.
+    pub fn ancestorsofset(&self, revs: Vec<u32>) -> dag::Result<IdSet> {
+        let len = self.len();
+        let mut visited = vec![false; len];
+        let mut tovisit = VecDeque::with_capacity(revs.len());
+        for &rev in revs.iter() {
+            if rev as usize >= len {
+                return Id(rev as _).not_found();
+            }
+            if !visited[rev as usize] {
+                visited[rev as usize] = true;
+                tovisit.push_back(rev);
+            }
         }
-        let min_root = *roots.iter().min().unwrap();
-        let max_head = *heads.iter().max().unwrap();
-        let len = (max_head.max(min_root) - min_root + 1) as usize;
-        let mut reachable = Vec::with_capacity(len);
-        let mut tovisit = VecDeque::new();
-        let mut revstates = vec![0u8; len];
 
-        const RS_SEEN: u8 = 1;
-        const RS_ROOT: u8 = 2;
-        const RS_REACHABLE: u8 = 4;
+        while let Some(rev) = tovisit.pop_front() {
+            self.check_interrupted(rev as usize)?;
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if !visited[parent_rev as usize] {
+                    visited[parent_rev as usize] = true;
+                    tovisit.push_back(parent_rev);
+                }
+            }
+        }
 
-        for &rev in roots {
-            if rev <= max_head {
-                revstates[(rev - min_root) as usize] |= RS_ROOT;
+        let mut result = IdSet::empty();
+        for rev in (0..len).rev() {
+            if visited[rev] {
+                result.push(Id(rev as _));
             }
         }
+        Ok(result)
+    }
 
-        for &rev in heads {
-            if rev >= min_root && revstates[(rev - min_root) as usize] & RS_SEEN == 0 {
+    /// Calculate `revs::`, including `revs` themselves.
+    ///
+    /// Children of all revisions are cached, see `child_revs`.
+    pub fn descendantsofset(&self, revs: Vec<u32>) -> dag::Result<IdSet> {
+        let len = self.len();
+        let mut visited = vec![false; len];
+        let mut tovisit = VecDeque::with_capacity(revs.len());
+        for &rev in revs.iter() {
+            if rev as usize >= len {
+                return Id(rev as _).not_found();
+            }
+            if !visited[rev as usize] {
+                visited[rev as usize] = true;
                 tovisit.push_back(rev);
-                revstates[(rev - min_root) as usize] |= RS_SEEN;
             }
         }
+        if tovisit.is_empty() {
+            return Ok(IdSet::empty());
+        }
 
-        // Visit the tovisit list and find the reachable roots
+        let children = self.children()?;
         while let Some(rev) = tovisit.pop_front() {
-            // Add the node to reachable if it is a root
-            if revstates[(rev - min_root) as usize] & RS_ROOT != 0 {
-                revstates[(rev - min_root) as usize] |= RS_REACHABLE;
-                reachable.push(rev);
+            self.check_interrupted(rev as usize)?;
+            for &child_rev in children[rev as usize].iter() {
+                if !visited[child_rev as usize] {
+                    visited[child_rev as usize] = true;
+                    tovisit.push_back(child_rev);
+                }
             }
+        }
 
-            // Add its parents to the list of nodes to visit
-            for &p in self.parent_revs(rev)?.as_revs() {
-                if p >= min_root && revstates[(p - min_root) as usize] & RS_SEEN == 0 {
-                    tovisit.push_back(p);
-                    revstates[(p - min_root) as usize] |= RS_SEEN;
-                }
+        let mut result = IdSet::empty();
+        for rev in (0..len).rev() {
+            if visited[rev] {
+                result.push(Id(rev as _));
             }
         }
+        Ok(result)
+    }
 
-        if reachable.is_empty() {
-            return Ok(Vec::new());
+    /// Return the highest-numbered revision that is an ancestor of all `revs`,
+    /// or `None` if they have no common ancestor.
+    ///
+    /// This is a heuristic, not a replacement for `gca_revs`. There might be
+    /// multiple greatest common ancestors, and only the one with the highest
+    /// revision number is returned. It is useful when a single base is needed,
+    /// for example for a three-way merge.
+    pub fn lowestcommonancestor_fast(&self, revs: Vec<u32>) -> dag::Result<Option<u32>> {
+        if let Some(&max_rev) = revs.iter().max() {
+            if max_rev as usize >= self.len() {
+                return Id(max_rev as _).not_found();
+            }
         }
 
-        // Find all the nodes in between the roots we found and the heads
-        // and add them to the reachable set
-        for rev in min_root..=max_head {
-            if revstates[(rev - min_root) as usize] & RS_SEEN == 0 {
-                continue;
+        let mut common: Option<Vec<bool>> = None;
+        for rev in revs {
+            let mut ancestors = vec![false; rev as usize + 1];
+            ancestors[rev as usize] = true;
+            for rev in (0..=rev).rev() {
+                self.check_interrupted(rev as usize)?;
+                if !ancestors[rev as usize] {
+                    continue;
+                }
+                for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                    ancestors[parent_rev as usize] = true;
+                }
             }
-            if self
-                .parent_revs(rev)?
-                .as_revs()
-                .iter()
-                .any(|&p| p >= min_root && revstates[(p - min_root) as usize] & RS_REACHABLE != 0)
-                && revstates[(rev - min_root) as usize] & RS_REACHABLE == 0
-            {
-                revstates[(rev - min_root) as usize] |= RS_REACHABLE;
-                reachable.push(rev);
+            let intersection: Vec<bool> = match common {
+                None => ancestors,
+                Some(common) => common
+                    .into_iter()
+                    .zip(ancestors)
+                    .map(|(a, b)| a && b)
+                    .collect(),
+            };
+            if !intersection.contains(&true) {
+                return Ok(None);
             }
+            common = Some(intersection);
         }
 
-        Ok(reachable)
+        Ok(common.and_then(|common| common.iter().rposition(|&c| c).map(|rev| rev as u32)))
     }
 
-    /// Whether "general delta" is enabled for this revlog.
-    ///
-    /// - If general_delta is true: `RevlogEntry.base` specifies the delta base.
-    /// - If general_delta is false: `RevlogEntry.base` specifies the delta chain
-    ///   `base..=rev`.
+    /// For each of `candidates`, test whether it is a descendant of (or equal
+    /// to) any revision in `reference`.
     ///
-    /// Revlog written by this crate won't use deltas. This is only useful for
-    /// reading ancient revisions in ancient revlogs.
-    fn is_general_delta(&self) -> bool {
-        match self.changelogi_data.get(0..4) {
-            None => {
-                // Empty revlog. Does not matter if it's general delta or not.
-                true
+    /// The descendants of `reference` are calculated once, in a single forward
+    /// scan, and shared by all candidates.
+    pub fn descendant_of_any(
+        &self,
+        candidates: Vec<u32>,
+        reference: Vec<u32>,
+    ) -> dag::Result<Vec<bool>> {
+        let len = self.len();
+        if let Some(&rev) = candidates.iter().chain(reference.iter()).max() {
+            if rev as usize >= len {
+                return Id(rev as _).not_found();
             }
-            Some(mut entry) => {
-                // First 4 bytes: Revlog flags.
-                let flags = entry.read_u32::<BE>().unwrap();
-                const FLAG_GENERALDELTA: u32 = 1 << 17;
-                flags & FLAG_GENERALDELTA != 0
+        }
+        let min_rev = match reference.iter().min() {
+            Some(&rev) => rev as usize,
+            None => return Ok(vec![false; candidates.len()]),
+        };
+
+        let mut descendants = vec![false; len];
+        for &rev in reference.iter() {
+            descendants[rev as usize] = true;
+        }
+        for rev in min_rev + 1..len {
+            self.check_interrupted(rev)?;
+            if descendants[rev] {
+                continue;
             }
+            let parent_revs = self.parent_revs(rev as u32)?;
+            descendants[rev] = parent_revs
+                .as_revs()
+                .iter()
+                .any(|&p| descendants[p as usize]);
         }
+
+        Ok(candidates
+            .into_iter()
+            .map(|rev| descendants[rev as usize])
+            .collect())
     }
-}
-
-/// Minimal code to read the DAG (i.e. parents) stored in non-inlined revlog.
-pub struct RevlogIndex {
-    pub changelogi_data: Bytes,
 
-    /// Inserted entries that are not flushed to disk.
-    pub pending_parents: Vec<ParentRevs>,
+    /// For each of `candidates`, test whether it is an ancestor of, or equal
+    /// to, `target`.
+    ///
+    /// The ancestors of `target` are calculated once, and only down to the
+    /// lowest candidate. Candidates above `target` can't be its ancestors,
+    /// so they don't extend the scan.
+    pub fn ancestors_or_equal(&self, target: u32, candidates: Vec<u32>) -> dag::Result<Vec<bool>> {
+        if let Some(&rev) = candidates.iter().chain(Some(&target)).max() {
+            if rev as usize >= self.len() {
+                return Id(rev as _).not_found();
+            }
+        }
+        let min_rev = match candidates.iter().filter(|&&rev| rev <= target).min() {
+            Some(&rev) => rev,
+            None => return Ok(vec![false; candidates.len()]),
+        };
 
-    /// Inserted node -> index of pending_parents.
-    pub pending_nodes: Vec<Vertex>,
-    pub pending_nodes_index: BTreeMap<Vertex, usize>,
-    pub pending_raw_data: Vec<Bytes>,
+        let mut ancestors = vec![false; (target - min_rev) as usize + 1];
+        ancestors[(target - min_rev) as usize] = true;
+        for rev in (min_rev..=target).rev() {
+            self.check_interrupted(rev as usize)?;
+            if !ancestors[(rev - min_rev) as usize] {
+                continue;
+            }
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if parent_rev >= min_rev {
+                    ancestors[(parent_rev - min_rev) as usize] = true;
+                }
+            }
+        }
 
-    /// Snapshot used to construct Set.
-    snapshot: RwLock<Option<Arc<RevlogIndex>>>,
+        Ok(candidates
+            .into_iter()
+            .map(|rev| rev <= target && ancestors[(rev - min_rev) as usize])
+            .collect())
+    }
 
-    /// File handler to the revlog data.
-    data_handler: Mutex<Option<File>>,
+    /// Calculate `ancestors(revs)` and return their nodes, ordered by
+    /// descending revision number.
+    pub fn ancestornodes(&self, revs: Vec<u32>) -> dag::Result<Vec<Vertex>> {
+        let max_rev = match revs.iter().max() {
+            Some(&rev) => rev as usize,
+            None => return Ok(Vec::new()),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-    /// Index to convert node to rev.
-    pub nodemap: NodeRevMap<BytesSlice<RevlogEntry>, BytesSlice<u32>>,
+        let mut is_ancestor = vec![false; max_rev + 1];
+        for &rev in revs.iter() {
+            is_ancestor[rev as usize] = true;
+        }
+        let mut result = Vec::new();
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            if !is_ancestor[rev] {
+                continue;
+            }
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                is_ancestor[parent_rev as usize] = true;
+            }
+            result.push(non_blocking_result(self.vertex_name(Id(rev as _)))?);
+        }
+        Ok(result)
+    }
 
-    /// Paths to the on-disk files.
-    index_path: PathBuf,
-    nodemap_path: PathBuf,
+    /// For each ordered pair `(revs[i], revs[j])`, test whether `revs[i]` is an
+    /// ancestor of (or equal to) `revs[j]`. Return the matrix where
+    /// `matrix[i][j]` is the answer.
+    ///
+    /// The size of the matrix is quadratic, so at most
+    /// `MAX_REACHABILITY_MATRIX_REVS` (64) revisions are accepted.
+    pub fn reachability_matrix(&self, revs: Vec<u32>) -> dag::Result<Vec<Vec<bool>>> {
+        if revs.len() > MAX_REACHABILITY_MATRIX_REVS {
+            return dag::errors::programming(format!(
+                "reachability_matrix supports at most {} revs, got {}",
+                MAX_REACHABILITY_MATRIX_REVS,
+                revs.len()
+            ));
+        }
+        let (min_rev, max_rev) = match (revs.iter().min(), revs.iter().max()) {
+            (Some(&min_rev), Some(&max_rev)) => (min_rev as usize, max_rev as usize),
+            _ => return Ok(Vec::new()),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-    /// Identity of the revlog.
-    id: String,
+        // Bit `j` of `masks[rev - min_rev]` is set if `rev` is an ancestor of
+        // `revs[j]`. A single scan from `max_rev` calculates the ancestors of
+        // all `revs` at once.
+        let mut masks = vec![0u64; max_rev - min_rev + 1];
+        for (j, &rev) in revs.iter().enumerate() {
+            masks[rev as usize - min_rev] |= 1 << j;
+        }
+        for rev in (min_rev..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            let mask = masks[rev - min_rev];
+            if mask == 0 {
+                continue;
+            }
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                if parent_rev as usize >= min_rev {
+                    masks[parent_rev as usize - min_rev] |= mask;
+                }
+            }
+        }
 
-    /// Version of the revlog.
-    version: VerLink,
-}
+        Ok(revs
+            .iter()
+            .map(|&rev| {
+                let mask = masks[rev as usize - min_rev];
+                (0..revs.len()).map(|j| mask & (1 << j) != 0).collect()
+            })
+            .collect())
+    }
 
-/// "smallvec" optimization
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub enum ParentRevs {
-    Compact([i32; 2]),
-    // Box<Vec<i32>> takes 8 bytes. Box<[i32]> takes 16 bytes.
-    // Using smaller struct for better memory efficiency.
-    Octopus(Box<Vec<i32>>),
-}
+    /// For each of `revs`, split its parents into those in `revs` and those
+    /// outside of it. Return `(rev, internal_parents, external_parents)`,
+    /// ordered by ascending revision number.
+    ///
+    /// This is the graph induced by `revs`. The external parents are the
+    /// bases the subgraph applies on top of.
+    pub fn inducedsubgraph(
+        &self,
+        mut revs: Vec<u32>,
+    ) -> dag::Result<Vec<(u32, Vec<u32>, Vec<u32>)>> {
+        revs.sort_unstable();
+        revs.dedup();
+        let max_rev = match revs.last() {
+            Some(&rev) => rev as usize,
+            None => return Ok(Vec::new()),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-impl ParentRevs {
-    fn from_p1p2(p1: i32, p2: i32) -> Self {
-        Self::Compact([p1, p2])
+        let mut in_set = vec![false; max_rev + 1];
+        for &rev in revs.iter() {
+            in_set[rev as usize] = true;
+        }
+        let mut result = Vec::with_capacity(revs.len());
+        for rev in revs {
+            let (internal, external): (Vec<u32>, Vec<u32>) = self
+                .parent_revs(rev)?
+                .as_revs()
+                .iter()
+                .partition(|&&parent_rev| in_set[parent_rev as usize]);
+            result.push((rev, internal, external));
+        }
+        Ok(result)
     }
 
-    fn from_vec(v: Vec<i32>) -> Self {
-        Self::Octopus(Box::new(v))
-    }
+    /// Return the number of edges of the shortest path from `descendant` to
+    /// `ancestor` following parents, or `None` if `ancestor` is not an
+    /// ancestor of `descendant`.
+    ///
+    /// Parents have lower revision numbers, so revisions lower than
+    /// `ancestor` are not visited.
+    pub fn ancestordistance(&self, ancestor: u32, descendant: u32) -> dag::Result<Option<u32>> {
+        let max_rev = ancestor.max(descendant);
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
+        if ancestor > descendant {
+            return Ok(None);
+        }
 
-    pub fn as_revs(&self) -> &[u32] {
-        let slice: &[i32] = match self {
-            ParentRevs::Compact(s) => {
-                if s[0] == -1 {
-                    &s[0..0]
-                } else if s[1] == -1 {
-                    &s[0..1]
-                } else {
-                    &s[..]
+        let mut visited = vec![false; (descendant - ancestor) as usize + 1];
+        let mut tovisit = VecDeque::new();
+        tovisit.push_back((descendant, 0));
+        visited[(descendant - ancestor) as usize] = true;
+        while let Some((rev, distance)) = tovisit.pop_front() {
+            if rev == ancestor {
+                return Ok(Some(distance));
+            }
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if parent_rev >= ancestor && !visited[(parent_rev - ancestor) as usize] {
+                    visited[(parent_rev - ancestor) as usize] = true;
+                    tovisit.push_back((parent_rev, distance + 1));
                 }
             }
-            ParentRevs::Octopus(s) => &s[..],
-        };
-        let ptr = (slice as *const [i32]) as *const [u32];
-        unsafe { &*ptr }
+        }
+        Ok(None)
     }
-}
-
-/// Revlog entry. See "# index ng" in revlog.py.
-#[repr(packed)]
-#[derive(Copy, Clone, Debug)]
-pub struct RevlogEntry {
-    offset_flags: u64,
-    compressed: i32,
-    len: i32,
-    base: i32,
-    link: i32,
-    p1: i32,
-    p2: i32,
-    pub node: [u8; 20],
-    _padding: [u8; 12],
-}
 
-/// Bytes that can be converted to &[T].
-#[derive(Clone)]
-pub struct BytesSlice<T>(Bytes, PhantomData<T>);
-pub trait UnsafeSliceCast {}
+    /// Test whether `ancestor` is an ancestor of `descendant`, or equal to it.
+    ///
+    /// Only revisions between the two are visited, since parents have
+    /// smaller revision numbers than their children. Revisions with a
+    /// generation number not above the one of `ancestor` are not visited
+    /// either, since they cannot have `ancestor` as an ancestor.
+    pub fn isancestor(&self, ancestor: u32, descendant: u32) -> dag::Result<bool> {
+        let max_rev = ancestor.max(descendant);
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
+        if ancestor > descendant {
+            return Ok(false);
+        }
+        if ancestor == descendant {
+            return Ok(true);
+        }
 
-impl<T> From<Bytes> for BytesSlice<T> {
-    fn from(bytes: Bytes) -> Self {
-        Self(bytes, PhantomData)
+        let generations = self.generations()?;
+        let min_generation = generations[ancestor as usize];
+        let is_candidate = |rev: u32| {
+            rev == ancestor || (rev > ancestor && generations[rev as usize] > min_generation)
+        };
+        let mut visited = vec![false; (descendant - ancestor) as usize + 1];
+        let mut tovisit = vec![descendant];
+        visited[(descendant - ancestor) as usize] = true;
+        while let Some(rev) = tovisit.pop() {
+            if rev == ancestor {
+                return Ok(true);
+            }
+            self.check_interrupted(rev as usize)?;
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if is_candidate(parent_rev) && !visited[(parent_rev - ancestor) as usize] {
+                    visited[(parent_rev - ancestor) as usize] = true;
+                    tovisit.push(parent_rev);
+                }
+            }
+        }
+        Ok(false)
     }
-}
 
-impl<T: UnsafeSliceCast> AsRef<[T]> for BytesSlice<T> {
-    fn as_ref(&self) -> &[T] {
-        let u8_slice: &[u8] = self.0.as_ref();
-        // safety: buffer length matches. Bytes is expected to be mmap-ed or
-        // static buffer and should be aligned.
-        let ptr = u8_slice.as_ptr() as *const T;
-        unsafe { slice::from_raw_parts(ptr, u8_slice.len() / mem::size_of::<T>()) }
+    /// Follow the first parent of `rev` `n` times, like `rev~n` in revsets.
+    /// Return `None` if a root is reached first.
+    ///
+    /// Only the first parent of merges is followed, other parents are
+    /// ignored.
+    pub fn nth_ancestor(&self, rev: u32, n: u32) -> dag::Result<Option<u32>> {
+        if rev as usize >= self.len() {
+            return Id(rev as _).not_found();
+        }
+        let mut rev = rev;
+        for _ in 0..n {
+            match self.parent_revs(rev)?.as_revs().first() {
+                Some(&p1) => rev = p1,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(rev))
     }
-}
-impl UnsafeSliceCast for u32 {}
-impl UnsafeSliceCast for RevlogEntry {}
 
-impl RevlogEntry {
-    fn flags(&self) -> u16 {
-        (self.offset_flags.to_be() & 0xffff) as u16
-    }
+    /// Pick the next revision to test when bisecting. The remaining
+    /// candidates are `ancestors(bad) - ancestors(good)`. Return the
+    /// candidate whose ancestors split the candidates most evenly, the only
+    /// candidate if one is left, or `None` if there are no candidates.
+    ///
+    /// Ancestor sets are kept only until all children of a candidate are
+    /// visited, so a mostly linear history uses little memory.
+    pub fn bisect_next(&self, good: Vec<u32>, bad: Vec<u32>) -> dag::Result<Option<u32>> {
+        if let Some(&rev) = good.iter().chain(bad.iter()).max() {
+            if rev as usize >= self.len() {
+                return Id(rev as _).not_found();
+            }
+        }
+        let max_rev = match bad.iter().max() {
+            Some(&rev) => rev,
+            None => return Ok(None),
+        };
 
-    fn is_octopus_merge(&self) -> bool {
-        (self.flags() & REVIDX_OCTOPUS_MERGE) != 0
-    }
-}
+        const GOOD: u8 = 1;
+        const BAD: u8 = 2;
+        let scan_max = good.iter().copied().fold(max_rev, u32::max) as usize;
+        let mut states = vec![0u8; scan_max + 1];
+        for &rev in good.iter() {
+            states[rev as usize] |= GOOD;
+        }
+        for &rev in bad.iter() {
+            states[rev as usize] |= BAD;
+        }
+        let mut candidates = Vec::new();
+        for rev in (0..=scan_max).rev() {
+            self.check_interrupted(rev)?;
+            let state = states[rev];
+            if state == 0 {
+                continue;
+            }
+            if state == BAD {
+                candidates.push(rev as u32);
+            }
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                states[parent_rev as usize] |= state;
+            }
+        }
+        candidates.reverse();
+        match candidates.len() {
+            0 => return Ok(None),
+            1 => return Ok(Some(candidates[0])),
+            _ => {}
+        }
 
-impl RevlogIndex {
-    /// Constructs a RevlogIndex. The NodeRevMap is automatically manage>
-    pub fn new(changelogi_path: &Path, nodemap_path: &Path) -> Result<Self> {
-        // 20000 is chosen as it takes a few milliseconds to build up nodemap.
-        Self::new_advanced(changelogi_path, nodemap_path, 20000)
-    }
+        // Ancestor sets use indexes of `candidates`, not revs, so they only
+        // count candidates.
+        let index: HashMap<u32, usize> = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, &rev)| (rev, i))
+            .collect();
+        let parent_indexes = candidates
+            .iter()
+            .map(|&rev| -> dag::Result<Vec<usize>> {
+                let parent_revs = self.parent_revs(rev)?;
+                Ok(parent_revs
+                    .as_revs()
+                    .iter()
+                    .filter_map(|p| index.get(p).copied())
+                    .collect())
+            })
+            .collect::<dag::Result<Vec<_>>>()?;
+        let mut child_counts = vec![0usize; candidates.len()];
+        for &j in parent_indexes.iter().flatten() {
+            child_counts[j] += 1;
+        }
 
-    /// Constructs a RevlogIndex with customized nodemap lag threshold.
-    pub fn new_advanced(
-        changelogi_path: &Path,
-        nodemap_path: &Path,
-        nodemap_lag_threshold: usize,
-    ) -> Result<Self> {
-        let empty_nodemap_data = Bytes::from(nodemap::empty_index_buffer());
-        let nodemap_data = read_path(nodemap_path, None, empty_nodemap_data.clone())?;
-        let changelogi_len = read_usize(&changelogi_path.with_extension("len"))?;
-        let changelogi_data = read_path(changelogi_path, changelogi_len, Bytes::default())?;
-        let nodemap = NodeRevMap::new(changelogi_data.clone().into(), nodemap_data.into())
-            .or_else(|_| {
-                // Attempt to rebuild the index (in-memory) automatically.
-                NodeRevMap::new(changelogi_data.clone().into(), empty_nodemap_data.into())
-            })?;
-        if nodemap.lag() as usize > nodemap_lag_threshold {
-            // The index is lagged, and less efficient. Update it.
-            // Building is incremental. Writing to disk is not.
-            if let Ok(buf) = nodemap.build_incrementally() {
-                // Cast [u32] to [u8] for writing.
-                let slice =
-                    unsafe { slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 4) };
-                // nodemap_path should be a symlink. Older repos might have the non-symlink
-                // file. Attempt to delete it.
-                // This is useful on Windows to prevent atomic_write_symlink failures when
-                // the nodemap file was non-symlink and mmaped.
-                if let Ok(meta) = nodemap_path.symlink_metadata() {
-                    if !meta.file_type().is_symlink() {
-                        remove_file(nodemap_path)?;
-                    }
+        let total = candidates.len() as u64;
+        let mut ancestor_sets: Vec<Option<IdSet>> = vec![None; candidates.len()];
+        let mut best: Option<(u64, u32)> = None;
+        for (i, parents) in parent_indexes.iter().enumerate() {
+            let mut set = IdSet::empty();
+            for &j in parents {
+                child_counts[j] -= 1;
+                let parent_set = if child_counts[j] == 0 {
+                    ancestor_sets[j].take()
+                } else {
+                    ancestor_sets[j].clone()
+                };
+                if let Some(parent_set) = parent_set {
+                    set = set.union(&parent_set);
                 }
-                // Not fatal if we cannot update the on-disk index.
-                let _ = atomic_write_symlink(nodemap_path, slice);
+            }
+            set.push(Id(i as u64));
+
+            let count = set.count();
+            let score = count.min(total - count);
+            let is_better = match best {
+                Some((best_score, _)) => score > best_score,
+                None => true,
+            };
+            if is_better {
+                best = Some((score, candidates[i]));
+            }
+            if child_counts[i] > 0 {
+                ancestor_sets[i] = Some(set);
             }
         }
-        let result = Self {
-            nodemap,
-            changelogi_data,
-            pending_parents: Default::default(),
-            pending_nodes: Default::default(),
-            pending_nodes_index: Default::default(),
-            pending_raw_data: Default::default(),
-            snapshot: Default::default(),
-            data_handler: Default::default(),
-            index_path: changelogi_path.to_path_buf(),
-            nodemap_path: nodemap_path.to_path_buf(),
-            id: format!("rlog:{}", &nodemap_path.display()),
-            version: VerLink::new(),
-        };
-        Ok(result)
+        Ok(best.map(|(_, rev)| rev))
     }
 
-    /// Revisions in total.
-    pub fn len(&self) -> usize {
-        self.data_len() + self.pending_parents.len()
+    /// Revisions above `watermark` and their parents, in ascending order,
+    /// for consumers that process new revisions incrementally. Inserted
+    /// revisions are included. The result is empty if `watermark` is the
+    /// last revision or above.
+    pub fn since(&self, watermark: u32) -> dag::Result<Vec<(u32, Vec<u32>)>> {
+        let start = watermark as usize + 1;
+        let mut result = Vec::with_capacity(self.len().saturating_sub(start));
+        for rev in start..self.len() {
+            let parent_revs = self.parent_revs(rev as u32)?;
+            result.push((rev as u32, parent_revs.as_revs().to_vec()));
+        }
+        Ok(result)
     }
 
-    /// Revisions stored in the original revlog index.
-    pub fn data_len(&self) -> usize {
-        self.data().len()
+    /// Revisions with a linkrev in `lo..=hi`, in ascending order.
+    ///
+    /// Linkrevs are not sorted, so this scans the whole index. Inserted
+    /// revisions that are not flushed yet are linked to themselves, like
+    /// `flush` writes them.
+    pub fn revs_by_linkrev_range(&self, lo: i32, hi: i32) -> Vec<u32> {
+        let in_range = |link: i32| lo <= link && link <= hi;
+        let data = self.data();
+        let mut result: Vec<u32> = (0..data.len())
+            .filter(|&rev| in_range(i32::from_be(data[rev].link)))
+            .map(|rev| rev as u32)
+            .collect();
+        result.extend(
+            (data.len()..self.len())
+                .filter(|&rev| in_range(rev as i32))
+                .map(|rev| rev as u32),
+        );
+        result
     }
 
-    #[inline]
-    fn data(&self) -> &[RevlogEntry] {
-        self.nodemap.changelogi.as_ref()
-    }
+    /// Calculate `::aheads - ::bheads`.
+    ///
+    /// Ancestors of `bheads` are pruned as they are found, and the scan stops
+    /// once no revisions that are only reachable from `aheads` are left.
+    pub fn onlyfirst(&self, aheads: Vec<u32>, bheads: Vec<u32>) -> dag::Result<IdSet> {
+        let mut result = IdSet::empty();
+        let max_rev = match aheads.iter().chain(bheads.iter()).max() {
+            Some(&rev) => rev,
+            None => return Ok(result),
+        };
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-    /// Get parent revisions.
-    pub fn parent_revs(&self, rev: u32) -> dag::Result<ParentRevs> {
-        let data_len = self.data_len();
-        if rev >= data_len as u32 {
-            return match self.pending_parents.get(rev as usize - data_len) {
-                Some(revs) => Ok(revs.clone()),
-                None => Id(rev as _).not_found(),
-            };
+        const A: u8 = 1;
+        const B: u8 = 2;
+
+        let mut states = vec![0u8; max_rev as usize + 1];
+        for rev in bheads {
+            states[rev as usize] |= B;
+        }
+        // Number of unvisited revisions with state `A`.
+        let mut remaining = 0;
+        for rev in aheads {
+            if states[rev as usize] == 0 {
+                remaining += 1;
+            }
+            states[rev as usize] |= A;
         }
 
-        let data = self.data();
-        let entry = &data[rev as usize];
-        let p1 = i32::from_be(entry.p1);
-        let p2 = i32::from_be(entry.p2);
-        if entry.is_octopus_merge() {
-            let mut parents = Vec::with_capacity(3);
-            if p1 >= 0 {
-                parents.push(p1);
+        for rev in (0..=max_rev).rev() {
+            if remaining == 0 {
+                break;
             }
-            if p2 >= 0 {
-                parents.push(p2);
+            self.check_interrupted(rev as usize)?;
+            let state = states[rev as usize];
+            if state == 0 {
+                continue;
+            }
+            if state == A {
+                remaining -= 1;
+                result.push(Id(rev as _));
+            }
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                let old_state = states[parent_rev as usize];
+                let new_state = old_state | state;
+                if old_state != A && new_state == A {
+                    remaining += 1;
+                } else if old_state == A && new_state != A {
+                    remaining -= 1;
+                }
+                states[parent_rev as usize] = new_state;
             }
-            // Read from the "stepparents" extra.
-            let data = self.raw_data(rev)?;
-            let stepparents = self.get_stepparents(&data)?;
-            parents.extend(stepparents);
-            Ok(ParentRevs::from_vec(parents))
-        } else {
-            Ok(ParentRevs::from_p1p2(p1, p2))
         }
+        Ok(result)
     }
 
-    /// Get the extra parents from raw data of a revision.
-    fn get_stepparents(&self, data: &[u8]) -> Result<Vec<i32>> {
-        // `data` format:
-        // <manifest sha1> + '\n'
-        // author + '\n'
-        // date + ' ' + timezone + ' ' + (... + '\0')* + 'stepparents:' + (hexnode + ',')+
+    /// Calculate `(::aheads - ::bheads) | (::bheads - ::aheads)`, the
+    /// revisions that are ancestors of exactly one side.
+    ///
+    /// Both sides are followed in the same scan, which stops once no
+    /// revisions that are only reachable from one side are left.
+    pub fn ancestors_symmetric_difference(
+        &self,
+        aheads: Vec<u32>,
+        bheads: Vec<u32>,
+    ) -> dag::Result<IdSet> {
+        let mut result = IdSet::empty();
+        let max_rev = match aheads.iter().chain(bheads.iter()).max() {
+            Some(&rev) => rev,
+            None => return Ok(result),
+        };
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-        let mut parents = Vec::new();
+        const A: u8 = 1;
+        const B: u8 = 2;
+        let is_one_side = |state: u8| state == A || state == B;
 
-        #[derive(Copy, Clone)]
-        enum State {
-            ManifestLine,
-            AuthorLine,
-            Date,
-            Timezone,
-            Extras,
+        let mut states = vec![0u8; max_rev as usize + 1];
+        for rev in aheads {
+            states[rev as usize] |= A;
+        }
+        for rev in bheads {
+            states[rev as usize] |= B;
         }
+        // Number of unvisited revisions with state `A` or `B`.
+        let mut remaining = states.iter().filter(|&&state| is_one_side(state)).count();
 
-        let mut state: State = State::ManifestLine;
-        let mut extra_start = 0;
-        for (i, b) in data.iter().enumerate() {
-            match (state, b) {
-                (State::ManifestLine, b'\n') => {
-                    state = State::AuthorLine;
-                }
-                (State::AuthorLine, b'\n') => {
-                    state = State::Date;
-                }
-                (State::Date, b' ') => {
-                    state = State::Timezone;
-                }
-                (State::Timezone, b' ') => {
-                    state = State::Extras;
-                    extra_start = i + 1;
-                }
-                (State::Extras, b'\0') | (State::Extras, b'\n') => {
-                    let extra = &data[extra_start..i]; // name:value
-                    if let Some(value) = extra.strip_prefix(b"stepparents:") {
-                        // Parse the stepparents field.
-                        if let Ok(value) = std::str::from_utf8(value) {
-                            for hex_node in value.split(',') {
-                                let node = Vertex::from_hex(hex_node.as_bytes())?;
-                                if let Some(id) = self.nodemap.node_to_rev(node.as_ref())? {
-                                    parents.push(id as i32);
-                                } else {
-                                    return Err(crate::errors::CommitNotFound(node).into());
-                                }
-                            }
-                        }
-                    }
-                    if *b == b'\n' {
-                        break;
-                    } else {
-                        extra_start = i + 1;
-                    }
+        for rev in (0..=max_rev).rev() {
+            if remaining == 0 {
+                break;
+            }
+            self.check_interrupted(rev as usize)?;
+            let state = states[rev as usize];
+            if state == 0 {
+                continue;
+            }
+            if is_one_side(state) {
+                remaining -= 1;
+                result.push(Id(rev as _));
+            }
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                let old_state = states[parent_rev as usize];
+                let new_state = old_state | state;
+                if !is_one_side(old_state) && is_one_side(new_state) {
+                    remaining += 1;
+                } else if is_one_side(old_state) && !is_one_side(new_state) {
+                    remaining -= 1;
                 }
-                (_, _) => {}
+                states[parent_rev as usize] = new_state;
             }
         }
-        Ok(parents)
+        Ok(result)
     }
 
-    /// Get raw content from a revision.
-    pub fn raw_data(&self, rev: u32) -> Result<Bytes> {
+    /// Test whether the node of `rev` is `expected`.
+    pub fn verifynode(&self, rev: u32, expected: &[u8]) -> dag::Result<bool> {
+        let node = non_blocking_result(self.vertex_name(Id(rev as _)))?;
+        Ok(node.as_ref() == expected)
+    }
+
+    /// Get the linkrev of `rev`, the changelog revision that introduced it.
+    /// Return `None` if the index does not record one (`link` is -1).
+    ///
+    /// Inserted revisions do not have linkrevs until they are flushed.
+    pub fn linkrev(&self, rev: u32) -> dag::Result<Option<u32>> {
+        if rev as usize >= self.len() {
+            return Id(rev as _).not_found();
+        }
         if rev as usize >= self.data_len() {
-            let result = &self.pending_raw_data[rev as usize - self.data_len()];
-            return Ok(result.clone());
+            let msg = format!("linkrev of rev {} is not available before flush", rev);
+            return Err(crate::Error::Unsupported(msg).into());
         }
-        let entry = self.data()[rev as usize];
-        let offset = if rev == 0 {
-            0
-        } else {
-            u64::from_be(entry.offset_flags) >> 16
-        };
-
-        let compressed_size = i32::from_be(entry.compressed) as usize;
-        let mut chunk = vec![0; compressed_size];
-
-        let mut locked = self.data_handler.lock();
-        if locked.is_none() {
-            let revlog_data_path = self.index_path.with_extension("d");
-            let file = fs::OpenOptions::new().read(true).open(revlog_data_path)?;
-            *locked = Some(file);
+        match i32::from_be(self.data()[rev as usize].link) {
+            -1 => Ok(None),
+            link if link < 0 => Ok(corruption(format!("rev {} has linkrev {}", rev, link))?),
+            link => Ok(Some(link as u32)),
         }
-        let file = locked.as_mut().unwrap();
+    }
 
-        file.seek(io::SeekFrom::Start(offset))?;
-        file.read_exact(&mut chunk)?;
-        drop(locked);
+    /// Revisions with more than one child, in ascending order. Only
+    /// revisions in `revs` are returned, or all revisions if `revs` is empty.
+    ///
+    /// Children are counted in a single pass over all revisions, including
+    /// inserted ones.
+    pub fn branchpoints(&self, mut revs: Vec<u32>) -> dag::Result<Vec<u32>> {
+        revs.sort_unstable();
+        revs.dedup();
+        if let Some(&max_rev) = revs.last() {
+            if max_rev as usize >= self.len() {
+                return Id(max_rev as _).not_found();
+            }
+        }
 
-        let decompressed = match chunk.get(0) {
-            None => chunk,
-            Some(b'\0') => chunk,
-            Some(b'u') => {
-                chunk.drain(0..1);
-                chunk
+        let mut child_counts = vec![0u8; self.len()];
+        for rev in 0..self.len() as u32 {
+            self.check_interrupted(rev as usize)?;
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                let count = &mut child_counts[parent_rev as usize];
+                *count = count.saturating_add(1);
             }
-            Some(b'4') => lz4_pyframe::decompress(&chunk[1..])?,
-            Some(&c) => return unsupported(format!("unsupported header: {:?}", c as char)),
-        };
+        }
 
-        let base = i32::from_be(entry.base);
-        let result = if base != rev as i32 && base >= 0 {
-            // Has a delta base. Load it recursively.
-            // PERF: this is very inefficient (no caching, no folding delta chains), but is only
-            // used for ancient data.
-            let base = if self.is_general_delta() {
-                base as u32
-            } else {
-                rev - 1
-            };
-            let base_text = self.raw_data(base)?;
-            apply_deltas(&base_text, &decompressed)?
+        let is_branchpoint = |rev: &u32| child_counts[*rev as usize] > 1;
+        let result = if revs.is_empty() {
+            (0..self.len() as u32).filter(is_branchpoint).collect()
         } else {
-            decompressed
+            revs.into_iter().filter(is_branchpoint).collect()
         };
-
-        Ok(Bytes::from(result))
+        Ok(result)
     }
 
-    /// Insert a new revision with given parents at the end.
-    pub fn insert(&mut self, node: Vertex, parents: Vec<u32>, raw_data: Bytes) {
-        if non_blocking_result(self.contains_vertex_name(&node)).unwrap_or(false) {
-            return;
-        }
-        let parent_revs = if parents.len() <= 2 {
-            let p1 = parents.get(0).map(|r| *r as i32).unwrap_or(-1);
-            let p2 = parents.get(1).map(|r| *r as i32).unwrap_or(-1);
-            ParentRevs::from_p1p2(p1, p2)
-        } else {
-            ParentRevs::from_vec(parents.into_iter().map(|i| i as i32).collect())
+    /// Revisions in `revs` that have no children in the whole DAG, in
+    /// ascending order.
+    ///
+    /// Unlike `heads(revs)`, a revision is not a leaf if it has a child
+    /// outside `revs`.
+    pub fn external_leaves(&self, mut revs: Vec<u32>) -> dag::Result<Vec<u32>> {
+        revs.sort_unstable();
+        revs.dedup();
+        let min_rev = match revs.first() {
+            Some(&rev) => rev,
+            None => return Ok(Vec::new()),
         };
-        let idx = self.pending_parents.len();
-        self.pending_parents.push(parent_revs);
-        self.pending_nodes.push(node.clone());
-
-        self.pending_nodes_index.insert(node, idx);
-        *self.snapshot.write() = None;
+        let max_rev = *revs.last().unwrap();
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-        self.pending_raw_data.push(raw_data);
+        // Children always have larger revision numbers than their parents.
+        let mut is_parent = vec![false; self.len() - min_rev as usize];
+        for rev in min_rev as usize + 1..self.len() {
+            self.check_interrupted(rev)?;
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                if parent_rev >= min_rev {
+                    is_parent[(parent_rev - min_rev) as usize] = true;
+                }
+            }
+        }
 
-        self.version.bump();
+        let result = revs
+            .into_iter()
+            .filter(|&rev| !is_parent[(rev - min_rev) as usize])
+            .collect();
+        Ok(result)
     }
 
-    fn pending_parent_map(&self) -> dag::Result<HashMap<Vec<u8>, Vec<Vec<u8>>>> {
-        let mut result = HashMap::new();
-        for i in 0..self.pending_nodes.len() {
-            let parent_revs = &self.pending_parents[i];
-            let parent_nodes = parent_revs
-                .as_revs()
-                .iter()
-                .map(|&rev| {
-                    non_blocking_result(self.vertex_name(Id(rev as _)))
-                        .unwrap()
-                        .as_ref()
-                        .to_vec()
-                })
-                .collect::<Vec<_>>();
-            let rev = self.data_len() + i;
-            let node = non_blocking_result(self.vertex_name(Id(rev as _))).unwrap();
-            result.insert(node.as_ref().to_vec(), parent_nodes);
+    /// Nodes of the parents of `rev`, in the same order as `parent_revs`.
+    pub fn parent_nodes(&self, rev: u32) -> dag::Result<Vec<Vertex>> {
+        let mut result = Vec::new();
+        for &parent_rev in self.parent_revs(rev)?.as_revs() {
+            result.push(non_blocking_result(self.vertex_name(Id(parent_rev as _)))?);
         }
         Ok(result)
     }
 
-    /// Write pending commits to disk.
-    pub fn flush(&mut self) -> Result<()> {
-        // Convert parent revs to parent nodes. This is because revs are
-        // easy to get wrong: ex. some nodes already exist in updated revlog.
-        let parent_map = self.pending_parent_map().map_err(|e| {
-            CorruptionError::Generic(format!("cannot calculate pending graph: {}", e))
-        })?;
-
-        let _lock = ScopedDirLock::new(
-            self.index_path
-                .parent()
-                .expect("index_path should not be root dir"),
-        )?;
+    /// The last revision, or `None` if the index is empty.
+    pub fn tip(&self) -> Option<u32> {
+        self.len().checked_sub(1).map(|rev| rev as u32)
+    }
 
-        let revlog_data_path = self.index_path.with_extension("d");
-        let mut revlog_data = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .append(true)
-            .write(true)
-            .open(&revlog_data_path)?;
-        let mut revlog_index = fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .open(&self.index_path)?;
+    /// Node of the last revision, or `None` if the index is empty.
+    pub fn tip_node(&self) -> dag::Result<Option<Vertex>> {
+        match self.tip() {
+            Some(rev) => Ok(Some(non_blocking_result(self.vertex_name(Id(rev as _)))?)),
+            None => Ok(None),
+        }
+    }
 
-        let meta_len_path = self.index_path.with_extension("len");
-        let meta_len = read_usize(&meta_len_path)?;
-        let revlog_index_size = match meta_len {
-            // Use the logic length.
-            Some(len) => revlog_index.seek(io::SeekFrom::Start(len as _))? as usize,
-            // No explicit length. Use the physical length of the file.
-            None => revlog_index.seek(io::SeekFrom::End(0))? as usize,
-        };
+    /// Revision of `node`, or `None` if it is unknown. Inserted revisions
+    /// are included.
+    pub fn rev_of_node(&self, node: &Vertex) -> dag::Result<Option<u32>> {
+        let id = non_blocking_result(self.vertex_id_with_max_group(node, Group::MAX))?;
+        Ok(id.map(|id| id.0 as u32))
+    }
 
-        let old_rev_len = revlog_index_size / mem::size_of::<RevlogEntry>();
-        let old_offset = revlog_data.seek(io::SeekFrom::End(0))?;
+    /// Node of `rev`. Inserted revisions are included.
+    pub fn node_of_rev(&self, rev: u32) -> dag::Result<Vertex> {
+        non_blocking_result(self.vertex_name(Id(rev as _)))
+    }
 
-        if revlog_index_size % mem::size_of::<RevlogEntry>() != 0 {
-            return corruption("changelog index length is not a multiple of 64");
+    /// Calculate `ancestors(heads) - hidden`.
+    ///
+    /// Hidden revisions are still traversed, so their visible ancestors are
+    /// included. This is for the transition away from filtered revisions.
+    pub fn ancestors_visible(&self, heads: Vec<u32>, hidden: Vec<u32>) -> dag::Result<IdSet> {
+        let mut result = IdSet::empty();
+        if let Some(&rev) = hidden.iter().max() {
+            if rev as usize >= self.len() {
+                return Id(rev as _).not_found();
+            }
         }
-
-        if old_rev_len < self.data_len() {
-            return corruption("changelog was truncated unexpectedly");
+        let max_rev = match heads.iter().max() {
+            Some(&rev) => rev as usize,
+            None => return Ok(result),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
         }
 
-        // Read from disk about new nodes. Do not write them again.
-        // References to them will use new revs.
-        // `existing_nodes` contain rev -> node relationship not known
-        // to the current revlog snapshot.
-        let mut existing_nodes = HashMap::new();
-        if old_rev_len > self.data_len() {
-            let data = BytesSlice::<RevlogEntry>::from(read_path(
-                &self.index_path,
-                Some(revlog_index_size),
-                Bytes::new(),
-            )?);
-            for (i, entry) in data.as_ref()[self.data_len()..].iter().enumerate() {
-                let rev = (self.data_len() + i) as u32;
-                existing_nodes.insert(entry.node.as_ref().to_vec(), rev);
+        let mut is_ancestor = vec![false; max_rev + 1];
+        for &rev in heads.iter() {
+            is_ancestor[rev as usize] = true;
+        }
+        let mut is_hidden = vec![false; max_rev + 1];
+        for &rev in hidden.iter() {
+            if let Some(is_hidden) = is_hidden.get_mut(rev as usize) {
+                *is_hidden = true;
             }
         }
-
-        let get_rev = |existing_nodes: &HashMap<Vec<u8>, u32>, node: &[u8]| -> Result<u32> {
-            match existing_nodes.get(node) {
-                Some(&rev) => Ok(rev),
-                None => Ok(self.nodemap.node_to_rev(node)?.unwrap()),
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            if !is_ancestor[rev] {
+                continue;
             }
-        };
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                is_ancestor[parent_rev as usize] = true;
+            }
+            if !is_hidden[rev] {
+                result.push(Id(rev as _));
+            }
+        }
+        Ok(result)
+    }
 
-        let mut new_data = Vec::new();
-        let mut new_index = Vec::new();
-        let mut i = 0;
+    /// Calculate `ancestors(heads) & lo:hi`, for processing revisions in
+    /// windows.
+    ///
+    /// Heads above `hi` are ignored, and the traversal stops at `lo`. As
+    /// parents have smaller revision numbers than their children, this only
+    /// visits revisions within the window.
+    pub fn ancestors_in_window(&self, heads: Vec<u32>, lo: u32, hi: u32) -> dag::Result<IdSet> {
+        let mut result = IdSet::empty();
+        if let Some(&rev) = heads.iter().max() {
+            if rev as usize >= self.len() {
+                return Id(rev as _).not_found();
+            }
+        }
+        let max_rev = match heads.iter().filter(|&&rev| rev >= lo && rev <= hi).max() {
+            Some(&rev) => rev as usize,
+            None => return Ok(result),
+        };
+        let lo = lo as usize;
 
-        for (raw, node) in self.pending_raw_data.iter().zip(self.pending_nodes.iter()) {
-            if existing_nodes.contains_key(node.as_ref()) {
+        let mut is_ancestor = vec![false; max_rev + 1 - lo];
+        for &rev in heads.iter() {
+            if let Some(is_ancestor) = (rev as usize)
+                .checked_sub(lo)
+                .and_then(|i| is_ancestor.get_mut(i))
+            {
+                *is_ancestor = true;
+            }
+        }
+        for rev in (lo..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            if !is_ancestor[rev - lo] {
                 continue;
             }
-            let raw_len = raw.len();
-            let compressed = lz4_pyframe::compress(&raw)?;
-            let chunk = if compressed.len() < raw.len() {
-                // Use LZ4 compression ('4' header).
-                let mut chunk = vec![b'4'];
-                chunk.extend(compressed);
-                chunk
-            } else {
-                // Do not use compression ('u' header).
-                let mut chunk = vec![b'u'];
-                chunk.extend(raw.as_ref().to_vec());
-                chunk
-            };
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                if parent_rev as usize >= lo {
+                    is_ancestor[parent_rev as usize - lo] = true;
+                }
+            }
+            result.push(Id(rev as _));
+        }
+        Ok(result)
+    }
 
-            let offset = old_offset + new_data.len() as u64;
-            let rev = old_rev_len + i;
-            i += 1;
-            existing_nodes.insert(node.as_ref().to_vec(), rev as u32);
+    /// Calculate the ancestors of `heads` that can be reached by crossing at
+    /// most `max_merges` merges, where a merge is crossed by going from it to
+    /// its parents.
+    ///
+    /// Revisions reachable through several paths use the path crossing the
+    /// fewest merges.
+    pub fn ancestors_merge_bounded(&self, heads: Vec<u32>, max_merges: u32) -> dag::Result<IdSet> {
+        let mut result = IdSet::empty();
+        let max_rev = match heads.iter().max() {
+            Some(&rev) => rev as usize,
+            None => return Ok(result),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-            let mut parent_revs: [i32; 2] = [-1, -1];
-            let parents = &parent_map[node.as_ref()];
-            for (p_id, p_node) in parents.iter().take(2).enumerate() {
-                parent_revs[p_id] = get_rev(&existing_nodes, p_node)? as i32;
+        // Keep `max_merges` below the marker of unreached revisions.
+        let max_merges = max_merges.min(u32::MAX - 1);
+        let mut merges = vec![u32::MAX; max_rev + 1];
+        for &rev in heads.iter() {
+            merges[rev as usize] = 0;
+        }
+        // Parents have lower revision numbers, so the merge count of a
+        // revision is final once all revisions above it are visited.
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            let count = merges[rev];
+            if count == u32::MAX {
+                continue;
+            }
+            result.push(Id(rev as _));
+            let parent_revs = self.parent_revs(rev as u32)?;
+            let parent_revs = parent_revs.as_revs();
+            let parent_count = count + (parent_revs.len() > 1) as u32;
+            if parent_count > max_merges {
+                continue;
             }
+            for &parent_rev in parent_revs {
+                let parent_merges = &mut merges[parent_rev as usize];
+                *parent_merges = (*parent_merges).min(parent_count);
+            }
+        }
+        Ok(result)
+    }
 
-            let mut flags = 0;
-            if parents.len() > 2 || find_bytes_in_bytes(&raw, b"stepparents:").is_some() {
-                flags |= REVIDX_OCTOPUS_MERGE;
-            };
-            let entry = RevlogEntry {
-                offset_flags: u64::to_be((offset << 16) | (flags as u64)),
-                compressed: i32::to_be(chunk.len() as i32),
-                len: i32::to_be(raw_len as i32),
-                base: i32::to_be(rev as i32),
-                link: i32::to_be(rev as i32),
-                p1: i32::to_be(parent_revs[0]),
-                p2: i32::to_be(parent_revs[1]),
-                node: <[u8; 20]>::try_from(node.as_ref()).map_err(|_| {
-                    crate::Error::Unsupported(format!("node is not 20-char long: {:?}", &node))
-                })?,
-                _padding: [0u8; 12],
-            };
+    /// Calculate the revisions within `depth` parent hops of `heads`, and the
+    /// parents just beyond that, for shallow clones. Return (base, included),
+    /// where `base` is the set of revisions at exactly `depth + 1` hops.
+    ///
+    /// With `depth` 0, only the heads are included and their parents are the
+    /// base.
+    pub fn shallowbase(&self, heads: Vec<u32>, depth: u32) -> dag::Result<(IdSet, IdSet)> {
+        let mut base = IdSet::empty();
+        let mut included = IdSet::empty();
+        let max_rev = match heads.iter().max() {
+            Some(&rev) => rev as usize,
+            None => return Ok((base, included)),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
 
-            let entry_bytes: [u8; 64] = unsafe { std::mem::transmute(entry) };
+        // Distances can't exceed the number of revisions, so this only keeps
+        // `depth + 1` from overflowing or matching unreached revisions.
+        let depth = depth.min(u32::MAX - 2);
+        let mut distances = vec![u32::MAX; max_rev + 1];
+        for &rev in heads.iter() {
+            distances[rev as usize] = 0;
+        }
+        // Parents have lower revision numbers, so the distance of a revision
+        // is final once all revisions above it are visited.
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            let distance = distances[rev];
+            if distance > depth {
+                if distance == depth + 1 {
+                    base.push(Id(rev as _));
+                }
+                continue;
+            }
+            included.push(Id(rev as _));
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                let parent_distance = &mut distances[parent_rev as usize];
+                *parent_distance = (*parent_distance).min(distance + 1);
+            }
+        }
+        Ok((base, included))
+    }
 
-            new_index.write_all(&entry_bytes)?;
-            new_data.write_all(&chunk)?;
+    /// Hash of the shape of the ancestors of `rev`, for keying caches of data
+    /// derived from the DAG.
+    ///
+    /// The ancestors of `rev`, including `rev`, are renumbered by their
+    /// position in ascending revision order. The hash is the SHA-256 of their
+    /// edge list in that order: for each ancestor, its number of parents
+    /// followed by the positions of its parents, all as big-endian `u64`s.
+    ///
+    /// It only covers topology, not nodes or content. Ancestor graphs with
+    /// the same shape and the same ancestor order have the same hash,
+    /// whatever their revision numbers are. Since parents are referred to by
+    /// position, shared ancestors are part of the hash.
+    ///
+    /// Hashes are memoized per revision.
+    pub fn dag_hash(&self, rev: u32) -> dag::Result<[u8; 32]> {
+        if rev as usize >= self.len() {
+            return Id(rev as _).not_found();
+        }
+        if let Some(hash) = self.dag_hashes.read().get(&rev) {
+            return Ok(*hash);
         }
 
-        // Special case. First 6 bytes of the index file are not the offset but
-        // specify the version and flags.
-        if old_rev_len == 0 && new_index.len() > 4 {
-            new_index[3] = 1; // revlog v1
+        let max_rev = rev as usize;
+        let mut is_ancestor = BitVec::from_elem(max_rev + 1, false);
+        is_ancestor.set(max_rev, true);
+        for rev in (0..=max_rev).rev() {
+            self.check_interrupted(rev)?;
+            if is_ancestor[rev] {
+                for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                    is_ancestor.set(parent_rev as usize, true);
+                }
+            }
         }
 
-        // Write revlog data before revlog index.
-        revlog_data.write_all(&new_data)?;
-        drop(revlog_data);
+        // Parents have lower revision numbers, so their positions are known
+        // when a revision is visited in ascending order.
+        let mut positions = vec![0u64; max_rev + 1];
+        let mut next_position = 0;
+        let mut hasher = Sha256::new();
+        for rev in 0..=max_rev {
+            self.check_interrupted(rev)?;
+            if !is_ancestor[rev] {
+                continue;
+            }
+            positions[rev] = next_position;
+            next_position += 1;
+            let parent_revs = self.parent_revs(rev as u32)?;
+            let parent_revs = parent_revs.as_revs();
+            hasher.update((parent_revs.len() as u64).to_be_bytes());
+            for &parent_rev in parent_revs {
+                hasher.update(positions[parent_rev as usize].to_be_bytes());
+            }
+        }
+        let hash = hasher.finalize().into();
+        self.dag_hashes.write().insert(rev, hash);
+        Ok(hash)
+    }
 
-        // Write revlog index.
-        revlog_index.write_all(&new_index)?;
-        drop(revlog_index);
+    /// Parents of `rev` as `(p1, p2)`, using -1 for absent parents like the
+    /// revlog does. Octopus merges are not supported.
+    pub fn parents2(&self, rev: u32) -> dag::Result<(i32, i32)> {
+        match self.parent_revs(rev)? {
+            ParentRevs::Compact([p1, p2]) => Ok((p1, p2)),
+            ParentRevs::Octopus(parents) => dag::errors::programming(format!(
+                "revision {} has {} parents, which parents2 does not support",
+                rev,
+                parents.len()
+            )),
+        }
+    }
 
-        // Update meta file with the new logical length.
-        let new_len = revlog_index_size + new_index.len();
-        atomic_write_plain(&meta_len_path, format!("{}", new_len).as_bytes(), false)?;
+    /// Generation numbers of all revisions, indexed by revision: roots are
+    /// 0, and other revisions are one more than their highest parent.
+    ///
+    /// The result is cached until revisions are inserted or changed.
+    pub fn generations(&self) -> dag::Result<Arc<Vec<u32>>> {
+        if let Some(generations) = self.generations.read().as_ref() {
+            return Ok(generations.clone());
+        }
+        let mut generations: Vec<u32> = Vec::with_capacity(self.len());
+        for rev in 0..self.len() {
+            self.check_interrupted(rev)?;
+            let generation = self
+                .parent_revs(rev as u32)?
+                .as_revs()
+                .iter()
+                .map(|&parent_rev| generations[parent_rev as usize] + 1)
+                .max()
+                .unwrap_or(0);
+            generations.push(generation);
+        }
+        let generations = Arc::new(generations);
+        *self.generations.write() = Some(generations.clone());
+        Ok(generations)
+    }
 
-        // Reload.
-        *self = Self::new(&self.index_path, &self.nodemap_path)?;
+    /// Generation number of `rev`. See `generations`.
+    pub fn generation(&self, rev: u32) -> dag::Result<u32> {
+        if rev as usize >= self.len() {
+            return Id(rev as _).not_found();
+        }
+        Ok(self.generations()?[rev as usize])
+    }
 
-        Ok(())
+    /// Revisions that have `rev` as a parent, in ascending order.
+    ///
+    /// Children of all revisions are calculated on the first call, and
+    /// cached until revisions are inserted or changed.
+    pub fn child_revs(&self, rev: u32) -> dag::Result<Vec<u32>> {
+        if rev as usize >= self.len() {
+            return Id(rev as _).not_found();
+        }
+        Ok(self.children()?[rev as usize].clone())
     }
 
-    /// Create a Arc snapshot of IdConvert trait object on demand.
-    pub fn get_snapshot(&self) -> Arc<Self> {
-        let mut snapshot = self.snapshot.write();
-        // Create snapshot on demand.
-        match snapshot.deref() {
-            Some(s) => s.clone(),
-            None => {
-                let result = Arc::new(self.clone());
-                *snapshot = Some(result.clone());
-                result
+    /// Children of all revisions, indexed by revision. See `child_revs`.
+    fn children(&self) -> dag::Result<Arc<Vec<Vec<u32>>>> {
+        if let Some(children) = self.children.read().as_ref() {
+            return Ok(children.clone());
+        }
+        let mut children: Vec<Vec<u32>> = vec![Vec::new(); self.len()];
+        for child_rev in 0..self.len() {
+            self.check_interrupted(child_rev)?;
+            for &parent_rev in self.parent_revs(child_rev as u32)?.as_revs() {
+                children[parent_rev as usize].push(child_rev as u32);
             }
         }
+        let children = Arc::new(children);
+        *self.children.write() = Some(children.clone());
+        Ok(children)
     }
-}
 
-impl Clone for RevlogIndex {
-    fn clone(&self) -> Self {
-        Self {
-            pending_parents: self.pending_parents.clone(),
-            pending_nodes: self.pending_nodes.clone(),
-            pending_nodes_index: self.pending_nodes_index.clone(),
-            pending_raw_data: self.pending_raw_data.clone(),
-            snapshot: Default::default(),
-            data_handler: Default::default(),
-            nodemap: self.nodemap.clone(),
-            changelogi_data: self.changelogi_data.clone(),
-            index_path: self.index_path.clone(),
-            nodemap_path: self.nodemap_path.clone(),
-            id: self.id.clone(),
-            version: self.version.clone(),
+    /// Calculate `heads(::heads[0] & ::heads[1] & ...)`, the greatest common
+    /// ancestors of any number of heads, in descending order.
+    ///
+    /// Unlike `gca_revs`, the number of heads is not limited. The ancestors
+    /// of each head are walked separately, counting how many heads reach each
+    /// revision, so the time is proportional to the number of heads.
+    pub fn commonancestors_multi(&self, mut heads: Vec<u32>) -> dag::Result<Vec<u32>> {
+        heads.sort_unstable();
+        heads.dedup();
+        let max_rev = match heads.last() {
+            Some(&rev) => rev as usize,
+            None => return Ok(Vec::new()),
+        };
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
         }
-    }
-}
 
-fn apply_deltas(base_text: &[u8], delta_text: &[u8]) -> Result<Vec<u8>> {
-    struct Delta {
-        start: usize,
-        end: usize,
-        data: Vec<u8>,
-    }
-    let mut deltas = Vec::new();
-    let mut cursor = &delta_text[..];
-    while !cursor.is_empty() {
-        let start = cursor.read_u32::<BE>()?;
-        let end = cursor.read_u32::<BE>()?;
-        let len = cursor.read_u32::<BE>()?;
-        let mut data = vec![0u8; len as usize];
-        cursor.read_exact(&mut data)?;
-        deltas.push(Delta {
-            start: start as _,
-            end: end as _,
-            data,
-        });
-    }
-    let mut result: Vec<u8> = Vec::new();
-    let mut base_text_pos = 0;
-    for delta in deltas {
-        if base_text_pos < delta.start {
-            result.extend_from_slice(&base_text[base_text_pos..delta.start]);
+        let mut counts = vec![0u32; max_rev + 1];
+        let mut is_ancestor = BitVec::from_elem(max_rev + 1, false);
+        for &head in heads.iter() {
+            is_ancestor.clear();
+            is_ancestor.set(head as usize, true);
+            for rev in (0..=head as usize).rev() {
+                self.check_interrupted(rev)?;
+                if !is_ancestor[rev] {
+                    continue;
+                }
+                counts[rev] += 1;
+                for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                    is_ancestor.set(parent_rev as usize, true);
+                }
+            }
         }
-        if !delta.data.is_empty() {
-            result.extend(delta.data);
+
+        // Parents of common ancestors are common ancestors too, so the
+        // common ancestors without a common child are the heads.
+        let all = heads.len() as u32;
+        let mut result = Vec::new();
+        let mut has_child = BitVec::from_elem(max_rev + 1, false);
+        for rev in (0..=max_rev).rev() {
+            if counts[rev] != all {
+                continue;
+            }
+            if !has_child[rev] {
+                result.push(rev as u32);
+            }
+            for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                has_child.set(parent_rev as usize, true);
+            }
         }
-        base_text_pos = delta.end;
-    }
-    if base_text_pos < base_text.len() {
-        result.extend_from_slice(&base_text[base_text_pos..]);
+        Ok(result)
     }
-    Ok(result)
-}
 
-/// Read an integer from a `path`. If the file does not exist, or is empty,
-/// return None.
-fn read_usize(path: &Path) -> io::Result<Option<usize>> {
-    match fs::OpenOptions::new().read(true).open(path) {
-        Err(err) => {
-            if err.kind() == io::ErrorKind::NotFound {
-                Ok(None)
+    /// Sort `revs` topologically, children before their parents like `log`,
+    /// ordering revisions that are not ancestors of each other by `keys`,
+    /// descending. `keys[i]` is the key of `revs[i]`, for example its commit
+    /// date. Ties are broken by revision number, descending. If a revision is
+    /// given more than once, its first key is used.
+    ///
+    /// Ancestry is followed through revisions that are not in `revs`. This
+    /// is a Kahn-style traversal, where revisions whose descendants in `revs`
+    /// are all sorted wait in a heap ordered by key.
+    pub fn toposort_with_key(&self, revs: Vec<u32>, keys: Vec<i64>) -> dag::Result<Vec<u32>> {
+        if revs.len() != keys.len() {
+            return dag::errors::programming(format!(
+                "toposort_with_key got {} revs but {} keys",
+                revs.len(),
+                keys.len()
+            ));
+        }
+        let (min_rev, max_rev) = match (revs.iter().min(), revs.iter().max()) {
+            (Some(&min_rev), Some(&max_rev)) => (min_rev, max_rev),
+            _ => return Ok(Vec::new()),
+        };
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
+
+        let mut key_of: HashMap<u32, i64> = HashMap::with_capacity(revs.len());
+        for (rev, key) in revs.into_iter().zip(keys) {
+            key_of.entry(rev).or_insert(key);
+        }
+
+        // Revisions in `revs` that reach each revision without passing
+        // through other revisions in `revs`. For revisions in `revs`, those
+        // are their children in the order.
+        let mut reached: HashMap<u32, Vec<u32>> = HashMap::new();
+        let mut parents: HashMap<u32, Vec<u32>> = HashMap::new();
+        let mut child_counts: HashMap<u32, usize> = HashMap::new();
+        for rev in (min_rev..=max_rev).rev() {
+            self.check_interrupted(rev as usize)?;
+            let mut children = reached.remove(&rev).unwrap_or_default();
+            children.sort_unstable();
+            children.dedup();
+            let propagated = if key_of.contains_key(&rev) {
+                child_counts.insert(rev, children.len());
+                for child in children {
+                    parents.entry(child).or_default().push(rev);
+                }
+                vec![rev]
+            } else if children.is_empty() {
+                continue;
             } else {
-                Err(err)
+                children
+            };
+            for &parent_rev in self.parent_revs(rev)?.as_revs() {
+                if parent_rev >= min_rev {
+                    reached
+                        .entry(parent_rev)
+                        .or_default()
+                        .extend_from_slice(&propagated);
+                }
             }
         }
-        Ok(mut file) => {
-            let mut s = String::new();
-            file.read_to_string(&mut s)?;
-            if s.is_empty() {
-                // This might happen with some file systems.
-                // Just treat it as if the file does not exist.
-                Ok(None)
-            } else {
-                s.parse::<usize>()
-                    .map(Some)
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+
+        let mut ready: BinaryHeap<(i64, u32)> = key_of
+            .iter()
+            .filter(|(rev, _)| child_counts[*rev] == 0)
+            .map(|(&rev, &key)| (key, rev))
+            .collect();
+        let mut result = Vec::with_capacity(key_of.len());
+        while let Some((_, rev)) = ready.pop() {
+            result.push(rev);
+            for &parent_rev in parents.get(&rev).into_iter().flatten() {
+                let count = child_counts.get_mut(&parent_rev).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push((key_of[&parent_rev], parent_rev));
+                }
             }
         }
+        Ok(result)
     }
-}
 
-fn read_path(path: &Path, length: Option<usize>, fallback: Bytes) -> io::Result<Bytes> {
-    match fs::OpenOptions::new().read(true).open(path) {
-        Err(err) => {
-            if err.kind() == io::ErrorKind::NotFound {
-                Ok(fallback)
-            } else {
-                Err(err)
-            }
+    /// Calculate the greatest common ancestors of `a` and `b`, in descending
+    /// order. There can be more than one after criss-cross merges.
+    pub fn gca(&self, a: u32, b: u32) -> dag::Result<Vec<u32>> {
+        let max_rev = a.max(b);
+        if max_rev as usize >= self.len() {
+            return Id(max_rev as _).not_found();
         }
-        Ok(file) => mmap_bytes(&file, length.map(|size| size as u64)),
+        if a == b {
+            // gca_revs expects distinct revs.
+            return Ok(vec![a]);
+        }
+        self.gca_revs(&[a, b], usize::max_value())
     }
-}
 
-#[async_trait::async_trait]
-impl PrefixLookup for RevlogIndex {
-    async fn vertexes_by_hex_prefix(
-        &self,
-        hex_prefix: &[u8],
-        limit: usize,
-    ) -> dag::Result<Vec<Vertex>> {
-        // Search through the BTreeMap
-        let start = Vertex::from_hex(hex_prefix)?;
-        let mut result = Vec::new();
-        for (vertex, _) in self.pending_nodes_index.range(start..) {
-            if !vertex.to_hex().as_bytes().starts_with(hex_prefix) {
+    /// GCA based on linear scan.
+    ///
+    /// Ported from Mercurial's C code `find_gca_candidates()`.
+    ///
+    /// The algorithm was written by Bryan O'Sullivan on 2013-04-16.
+    /// He provided both a Python [1] and a C version [2]. Since then, the only real
+    /// logic change is removing an unnecessary "if" branch by Mads Kiilerich on
+    /// 2014-02-24 [4].
+    ///
+    /// The C implementation is quite competitive among linear algorithms on
+    /// performance. It is cache-efficient, has fast paths to exit early, and
+    /// takes up to 62 (if bitmask is u64) revs at once. Other implementations
+    /// might just take 2 revs at most. For example, the older implemenation
+    /// by Olivia Mackall in 2006 [3] takes 2 revs explicitly.
+    ///
+    /// Changes in this Rust implementation:
+    /// - Change `bitmask` from `u64` to `u8` for smaller memory footage, at the
+    ///   cost of losing support for more than 6 revs.
+    /// - Adopted to RevlogIndex.
+    /// - Run `gca` recursively on large input.
+    ///
+    /// [1]: https://www.mercurial-scm.org/repo/hg/rev/2f7186400a07
+    /// [2]: https://www.mercurial-scm.org/repo/hg/rev/5bae936764bb
+    /// [3]: https://www.mercurial-scm.org/repo/hg/rev/b1db258e875c
+    /// [4]: https://www.mercurial-scm.org/repo/hg/rev/4add43865a9b
+    pub fn gca_revs(&self, revs: &[u32], limit: usize) -> dag::Result<Vec<u32>> {
+        type BitMask = u8;
+        let revcount = revs.len();
+        assert!(revcount < 7);
+        if revcount == 0 {
+            return Ok(Vec::new());
+        }
+
+        let allseen: BitMask = (1 << revcount) - 1;
+        let poison: BitMask = 1 << revcount;
+        let maxrev = revs.iter().max().cloned().unwrap();
+        let mut interesting = revcount;
+        let mut gca = Vec::new();
+        let mut seen: Vec<BitMask> = vec![0; maxrev as usize + 1];
+
+        for (i, &rev) in revs.iter().enumerate() {
+            seen[rev as usize] = 1 << i;
+        }
+
+        for v in (0..=maxrev).rev() {
+            if interesting == 0 {
                 break;
             }
-            result.push(vertex.clone());
-            if result.len() >= limit {
-                break;
+            let mut sv = seen[v as usize];
+            if sv == 0 {
+                continue;
             }
-        }
-        // Search through the NodeRevMap
-        match self.nodemap.hex_prefix_to_node(hex_prefix) {
-            Ok(Some(node)) => {
-                if limit > 0 {
-                    result.push(node.to_vec().into());
+            if sv < poison {
+                interesting -= 1;
+                if sv == allseen {
+                    gca.push(v);
+                    if gca.len() >= limit {
+                        return Ok(gca);
+                    }
+                    sv |= poison;
+                    if revs.iter().any(|&r| r == v) {
+                        break;
+                    }
                 }
             }
-            Ok(None) => {}
-            Err(crate::Error::AmbiguousPrefix) => {
-                // Convert AmbiguousPrefix to a non-error with multiple vertex pushed to
-                // result.  That's what the Python code base expects.
-                while result.len() < limit {
-                    result.push(Vertex::from(Bytes::from_static(b"")));
+            for &p in self.parent_revs(v)?.as_revs() {
+                let sp = seen[p as usize];
+                if sv < poison {
+                    if sp == 0 {
+                        seen[p as usize] = sv;
+                        interesting += 1
+                    } else if sp != sv {
+                        seen[p as usize] |= sv
+                    }
+                } else {
+                    if sp != 0 && sp < poison {
+                        interesting -= 1
+                    }
+                    seen[p as usize] = sv
                 }
-                return Ok(result);
             }
-            Err(e) => return Err(e.into()),
         }
-        Ok(result)
+
+        Ok(gca)
     }
-}
 
-#[async_trait::async_trait]
-impl IdConvert for RevlogIndex {
-    async fn vertex_id(&self, vertex: Vertex) -> dag::Result<Id> {
-        if let Some(pending_id) = self.pending_nodes_index.get(&vertex) {
-            Ok(Id((pending_id + self.data_len()) as _))
-        } else if let Some(id) = self.nodemap.node_to_rev(vertex.as_ref())? {
-            Ok(Id(id as _))
-        } else {
-            vertex.not_found()
+    /// Range based on linear scan.
+    ///
+    /// Ported from Mercurial's C code `reachableroots2()`.
+    ///
+    /// The C implementation was added by Laurent Charignon on 2015-08-06 [1].
+    /// It was based on the a Python implementation added by Bryan O'Sullivan on
+    /// 2012-06-01 [2], which is similar to an older implementation by Eric Hopper
+    /// on 2005-10-07 [3], but faster and shorter.
+    ///
+    /// The C code was then revised by others. The most significant change was
+    /// switching the "contains" check of "roots" and "reachable" from Python sets
+    /// to bits in the pure C "revstates" array for easier error handling and
+    /// better performance, by Yuya Nishihara on 2015-08-13 [4] [5].
+    ///
+    /// Improvements in this Rust implementation:
+    /// - Use `VecDeque` for `tovisit` (roughly O(len(result)) -> O(len(heads))).
+    /// - Truncate `revstates` (O(len(changelog)) -> O(max_head - min_root)).
+    /// - Add `reachable.is_empty()` fast path that existed in the Python code.
+    /// - Support octopus merge.
+    ///
+    /// [1]: https://www.mercurial-scm.org/repo/hg/rev/ff89383a97db
+    /// [2]: https://www.mercurial-scm.org/repo/hg/rev/b6efeb27e733
+    /// [3]: https://www.mercurial-scm.org/repo/hg/rev/518da3c3b6ce
+    /// [4]: https://www.mercurial-scm.org/repo/hg/rev/b68c9d232db6
+    /// [5]: https://www.mercurial-scm.org/repo/hg/rev/b3ad349d0e50
+    pub fn range_revs(&self, roots: &[u32], heads: &[u32]) -> dag::Result<Vec<u32>> {
+        if roots.is_empty() || heads.is_empty() {
+            return Ok(Vec::new());
+        }
+        let min_root = *roots.iter().min().unwrap();
+        let max_head = *heads.iter().max().unwrap();
+        let len = (max_head.max(min_root) - min_root + 1) as usize;
+        let mut reachable = Vec::with_capacity(len);
+        let mut tovisit = VecDeque::new();
+        let mut revstates = vec![0u8; len];
+
+        const RS_SEEN: u8 = 1;
+        const RS_ROOT: u8 = 2;
+        const RS_REACHABLE: u8 = 4;
+
+        for &rev in roots {
+            if rev <= max_head {
+                revstates[(rev - min_root) as usize] |= RS_ROOT;
+            }
+        }
+
+        for &rev in heads {
+            if rev >= min_root && revstates[(rev - min_root) as usize] & RS_SEEN == 0 {
+                tovisit.push_back(rev);
+                revstates[(rev - min_root) as usize] |= RS_SEEN;
+            }
+        }
+
+        // Visit the tovisit list and find the reachable roots
+        while let Some(rev) = tovisit.pop_front() {
+            // Add the node to reachable if it is a root
+            if revstates[(rev - min_root) as usize] & RS_ROOT != 0 {
+                revstates[(rev - min_root) as usize] |= RS_REACHABLE;
+                reachable.push(rev);
+            }
+
+            // Add its parents to the list of nodes to visit
+            for &p in self.parent_revs(rev)?.as_revs() {
+                if p >= min_root && revstates[(p - min_root) as usize] & RS_SEEN == 0 {
+                    tovisit.push_back(p);
+                    revstates[(p - min_root) as usize] |= RS_SEEN;
+                }
+            }
+        }
+
+        if reachable.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Find all the nodes in between the roots we found and the heads
+        // and add them to the reachable set
+        for rev in min_root..=max_head {
+            if revstates[(rev - min_root) as usize] & RS_SEEN == 0 {
+                continue;
+            }
+            if self
+                .parent_revs(rev)?
+                .as_revs()
+                .iter()
+                .any(|&p| p >= min_root && revstates[(p - min_root) as usize] & RS_REACHABLE != 0)
+                && revstates[(rev - min_root) as usize] & RS_REACHABLE == 0
+            {
+                revstates[(rev - min_root) as usize] |= RS_REACHABLE;
+                reachable.push(rev);
+            }
         }
+
+        Ok(reachable)
     }
-    async fn vertex_id_with_max_group(
+
+    /// Calculate the revisions reachable from `heads` going down to `roots`,
+    /// like `reachableroots2` in Mercurial.
+    ///
+    /// If `includepath` is false, only the reached `roots` are returned.
+    /// Otherwise, `roots::heads` is returned, which also includes the
+    /// revisions connecting them.
+    pub fn reachableroots(
         &self,
-        vertex: &Vertex,
-        _max_group: Group,
-    ) -> dag::Result<Option<Id>> {
-        // RevlogIndex stores everything in the master group. So max_gorup is ignored.
-        if let Some(pending_id) = self.pending_nodes_index.get(vertex) {
-            Ok(Some(Id((pending_id + self.data_len()) as _)))
-        } else if let Some(id) = self.nodemap.node_to_rev(vertex.as_ref())? {
-            Ok(Some(Id(id as _)))
+        roots: Vec<u32>,
+        heads: Vec<u32>,
+        includepath: bool,
+    ) -> dag::Result<IdSet> {
+        if let Some(&rev) = roots
+            .iter()
+            .chain(heads.iter())
+            .find(|&&rev| rev as usize >= self.len())
+        {
+            return Id(rev as _).not_found();
+        }
+
+        let mut revs = if includepath {
+            self.range_revs(&roots, &heads)?
         } else {
-            Ok(None)
+            self.reached_roots(&roots, &heads)?
+        };
+        revs.sort_unstable_by(|a, b| b.cmp(a));
+        let mut result = IdSet::empty();
+        for rev in revs {
+            result.push(Id(rev as _));
         }
+        Ok(result)
     }
-    async fn vertex_name(&self, id: Id) -> dag::Result<Vertex> {
-        let rev = id.0 as usize;
-        if rev < self.data_len() {
-            Ok(Vertex::from(self.data()[rev].node.as_ref().to_vec()))
-        } else {
-            match self.pending_nodes.get(rev - self.data_len()) {
-                Some(node) => Ok(node.clone()),
-                None => id.not_found(),
+
+    /// `roots & ::heads`, visiting only revisions above the lowest root.
+    fn reached_roots(&self, roots: &[u32], heads: &[u32]) -> dag::Result<Vec<u32>> {
+        let min_root = match roots.iter().min() {
+            Some(&rev) => rev,
+            None => return Ok(Vec::new()),
+        };
+        let max_head = match heads.iter().filter(|&&rev| rev >= min_root).max() {
+            Some(&rev) => rev,
+            None => return Ok(Vec::new()),
+        };
+        let len = (max_head - min_root + 1) as usize;
+        let mut is_root = vec![false; len];
+        for &rev in roots {
+            if rev <= max_head {
+                is_root[(rev - min_root) as usize] = true;
             }
         }
-    }
-    async fn contains_vertex_name(&self, vertex: &Vertex) -> dag::Result<bool> {
-        if let Some(_pending_id) = self.pending_nodes_index.get(vertex) {
-            Ok(true)
-        } else if let Some(_id) = self.nodemap.node_to_rev(vertex.as_ref())? {
-            Ok(true)
-        } else {
-            Ok(false)
+        let mut roots_left = is_root.iter().filter(|&&is_root| is_root).count();
+
+        let mut seen = vec![false; len];
+        let mut tovisit = Vec::new();
+        for &rev in heads {
+            if rev >= min_root && !seen[(rev - min_root) as usize] {
+                seen[(rev - min_root) as usize] = true;
+                tovisit.push(rev);
+            }
+        }
+        let mut reached = Vec::new();
+        while let Some(rev) = tovisit.pop() {
+            self.check_interrupted(rev as usize)?;
+            if is_root[(rev - min_root) as usize] {
+                reached.push(rev);
+                roots_left -= 1;
+                if roots_left == 0 {
+                    break;
+                }
+            }
+            for &p in self.parent_revs(rev)?.as_revs() {
+                if p >= min_root && !seen[(p - min_root) as usize] {
+                    seen[(p - min_root) as usize] = true;
+                    tovisit.push(p);
+                }
+            }
         }
+        Ok(reached)
     }
-    async fn contains_vertex_id_locally(&self, ids: &[Id]) -> dag::Result<Vec<bool>> {
-        let mut list = Vec::with_capacity(ids.len());
-        for id in ids {
-            let rev = id.0 as usize;
-            list.push(
-                rev < self.data_len() || self.pending_nodes.get(rev - self.data_len()).is_some(),
-            );
-        }
-        Ok(list)
-    }
-    async fn contains_vertex_name_locally(&self, names: &[Vertex]) -> dag::Result<Vec<bool>> {
-        let mut list = Vec::with_capacity(names.len());
-        for name in names {
-            list.push(
-                self.pending_nodes_index.contains_key(name)
-                    || self.nodemap.node_to_rev(name.as_ref())?.is_some(),
-            )
-        }
-        Ok(list)
-    }
-    fn map_id(&self) -> &str {
-        &self.id
-    }
-    fn map_version(&self) -> &VerLink {
-        &self.version
-    }
-}
 
-#[async_trait::async_trait]
-impl DagAlgorithm for RevlogIndex {
-    /// Sort a `Set` topologically.
-    async fn sort(&self, set: &Set) -> dag::Result<Set> {
-        let hints = set.hints();
-        if hints.contains(Flags::TOPO_DESC)
-            && matches!(hints.dag_version(), Some(v) if v <= self.dag_version())
-            && matches!(hints.id_map_version(), Some(v) if v <= self.map_version())
-        {
-            Ok(set.clone())
-        } else {
-            let mut spans = IdSet::empty();
-            for name in set.iter()? {
-                let id = self.vertex_id(name?).await?;
-                spans.push(id);
+    /// Whether "general delta" is enabled for this revlog.
+    ///
+    /// - If general_delta is true: `RevlogEntry.base` specifies the delta base.
+    /// - If general_delta is false: `RevlogEntry.base` specifies the delta chain
+    ///   `base..=rev`.
+    ///
+    /// Revlog written by this crate won't use deltas. This is only useful for
+    /// reading ancient revisions in ancient revlogs.
+    fn is_general_delta(&self) -> bool {
+        match self.changelogi_data.get(0..4) {
+            None => {
+                // Empty revlog. Does not matter if it's general delta or not.
+                true
+            }
+            Some(mut entry) => {
+                // First 4 bytes: Revlog flags.
+                let flags = entry.read_u32::<BE>().unwrap();
+                const FLAG_GENERALDELTA: u32 = 1 << 17;
+                flags & FLAG_GENERALDELTA != 0
             }
-            let result = Set::from_spans_dag(spans, self)?;
-            Ok(result)
         }
     }
+}
 
-    /// Get ordered parent vertexes.
-    async fn parent_names(&self, name: Vertex) -> dag::Result<Vec<Vertex>> {
-        let rev = self.vertex_id(name).await?.0 as u32;
-        let parent_revs = self.parent_revs(rev)?;
-        let parent_revs = parent_revs.as_revs();
-        let mut result = Vec::with_capacity(parent_revs.len());
-        for &rev in parent_revs {
-            result.push(self.vertex_name(Id(rev as _)).await?);
-        }
-        Ok(result)
-    }
+/// Minimal code to read the DAG (i.e. parents) stored in non-inlined revlog.
+pub struct RevlogIndex {
+    pub changelogi_data: Bytes,
 
-    /// Returns a set that covers all vertexes tracked by this DAG.
-    async fn all(&self) -> dag::Result<Set> {
-        let id_set = if self.len() == 0 {
-            IdSet::empty()
-        } else {
-            IdSet::from(Id(0)..=Id(self.len() as u64 - 1))
-        };
-        let result = Set::from_spans_dag(id_set, self)?;
-        result.hints().add_flags(Flags::FULL);
-        Ok(result)
-    }
+    /// Inserted entries that are not flushed to disk, excluding the first
+    /// `spilled_len` entries.
+    pub pending_parents: Vec<ParentRevs>,
 
-    /// Returns a set that covers all vertexes in the master group.
-    async fn master_group(&self) -> dag::Result<Set> {
-        self.all().await
-    }
+    /// Inserted node -> index of pending_parents.
+    pub pending_nodes: Vec<Vertex>,
+    pub pending_nodes_index: BTreeMap<Vertex, usize>,
+    pub pending_raw_data: Vec<Bytes>,
 
-    /// Vertexes buffered, not persisted.
-    async fn dirty(&self) -> dag::Result<Set> {
-        let low = Id(self.data_len() as _);
-        let next = Id(self.len() as _);
-        let mut id_set = IdSet::empty();
-        if next > low {
-            id_set.push(low..=(next - 1));
-        }
-        let set = Set::from_spans_dag(id_set, self)?;
-        Ok(set)
-    }
+    /// Snapshot used to construct Set.
+    snapshot: RwLock<Option<Arc<RevlogIndex>>>,
 
-    /// Calculates all ancestors reachable from any name from the given set.
-    async fn ancestors(&self, set: Set) -> dag::Result<Set> {
-        if set.hints().contains(Flags::ANCESTORS) {
-            return Ok(set);
-        }
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
-        }
+    /// Generation numbers calculated by `generations`, cleared whenever
+    /// revisions change.
+    generations: RwLock<Option<Arc<Vec<u32>>>>,
 
-        let dag = self.get_snapshot();
-        let max_id = id_set.max().unwrap();
-        let max_rev = max_id.0 as u32;
+    /// Children of each revision calculated by `child_revs`, cleared
+    /// whenever revisions change.
+    children: RwLock<Option<Arc<Vec<Vec<u32>>>>>,
 
-        let mut included = BitVec::from_elem(max_rev as usize + 1, false);
-        for id in id_set.into_iter() {
-            included.set(id.0 as usize, true);
-        }
+    /// Hashes calculated by `dag_hash`. Inserting revisions does not change
+    /// the ancestors of existing ones, so this is only cleared when existing
+    /// revisions change.
+    dag_hashes: RwLock<HashMap<u32, [u8; 32]>>,
 
-        let iter = (0..=max_rev).rev().filter_map(move |rev| {
-            let should_include = included[rev as usize];
-            if should_include {
-                let parent_revs = match dag.parent_revs(rev) {
-                    Ok(revs) => revs,
-                    Err(e) => return Some(Err(e)),
-                };
-                for &p in parent_revs.as_revs() {
-                    included.set(p as usize, true);
-                }
-            }
-            if should_include {
-                Some(Ok(Id(rev as _)))
-            } else {
-                None
-            }
-        });
+    /// File handler to the revlog data.
+    data_handler: Mutex<Option<File>>,
 
-        let set = Set::from_id_iter_dag(iter, self)?;
-        set.hints()
-            .add_flags(Flags::ID_DESC | Flags::TOPO_DESC | Flags::ANCESTORS)
-            .set_max_id(max_id);
-        Ok(set)
-    }
+    /// Index to convert node to rev.
+    pub nodemap: NodeRevMap<BytesSlice<RevlogEntry>, BytesSlice<u32>>,
 
-    /// Calculates parents.
-    async fn parents(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
-        }
+    /// Paths to the on-disk files.
+    index_path: PathBuf,
+    nodemap_path: PathBuf,
 
-        let max_id = id_set.max().unwrap();
-        let dag = self.get_snapshot();
-        let min_parent = id_set.iter_desc().fold(max_id.0 as u32, |min, id| {
-            let rev = id.0 as u32;
-            if let Ok(parent_revs) = dag.parent_revs(rev) {
-                parent_revs.as_revs().iter().fold(min, |min, &p| p.min(min))
-            } else {
-                min
-            }
-        }) as usize;
+    /// Identity of the revlog.
+    id: String,
 
-        let mut included = BitVec::from_elem(max_id.0 as usize - min_parent + 1, false);
-        for id in id_set {
-            let rev = id.0 as u32;
-            for &p in dag.parent_revs(rev)?.as_revs() {
-                included.set(p as usize - min_parent, true);
-            }
-        }
+    /// Version of the revlog.
+    version: VerLink,
 
-        // IdSet::push is O(1) if pushed in DESC order, otherwise it's O(N).
-        let mut id_spans: IdSet = IdSet::empty();
-        for rev in (min_parent..=max_id.0 as usize).rev() {
-            if included[rev - min_parent] {
-                id_spans.push(Id(rev as _));
-            }
-        }
+    /// Checked periodically by long traversals.
+    interrupt_check: Option<InterruptCheck>,
 
-        let idmap = dag.clone();
-        let result = Set::from_spans_idmap_dag(id_spans, idmap, dag);
-        Ok(result)
-    }
+    /// The index is a filelog, where revisions with copy information can
+    /// have p2 without p1.
+    filelog: bool,
 
-    /// Calculates children of the given set.
-    async fn children(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
-        }
+    /// Where to move inserted parents once there are too many in memory.
+    spill_options: Option<SpillOptions>,
 
-        let min_id = id_set.min().unwrap();
-        let dag = self.get_snapshot();
-        // Children: scan to the highest Id. Check parents.
-        let iter = ((min_id.0 as u32)..(dag.len() as u32)).filter_map(move |rev| {
-            let parent_revs = match dag.parent_revs(rev) {
-                Ok(revs) => revs,
-                Err(err) => return Some(Err(err)),
-            };
-            let should_include = parent_revs
-                .as_revs()
-                .iter()
-                .any(|&p| id_set.contains(Id(p as _)));
-            if should_include {
-                Some(Ok(Id(rev as _)))
-            } else {
-                None
-            }
-        });
+    /// Parents of the first `spilled_len` inserted entries.
+    spill_file: Option<Arc<Mutex<SpillFile>>>,
+    spilled_len: usize,
 
-        let set = Set::from_id_iter_dag(iter, self)?;
-        set.hints().add_flags(Flags::ID_ASC).set_min_id(min_id);
-        Ok(set)
+    /// Parents of spilled entries that do not match `spill_file`: octopus
+    /// merges, and entries changed by `set_inserted_parents`.
+    spilled_overrides: BTreeMap<usize, ParentRevs>,
+}
+
+/// Options to move parents of inserted revisions to a temporary file, see
+/// `RevlogIndex::new_with_spill`.
+#[derive(Clone, Debug)]
+pub struct SpillOptions {
+    /// Spill once this many inserted revisions have their parents in memory.
+    pub threshold: usize,
+    /// Directory of the temporary file.
+    pub dir: PathBuf,
+}
+
+/// Spilled parents of inserted revisions, as `(p1, p2)` records.
+///
+/// Records are only appended. The file is shared with snapshots, each of
+/// them reading the records for the revisions it knows about.
+struct SpillFile {
+    file: File,
+    /// Number of records in `file`.
+    len: usize,
+}
+
+impl SpillFile {
+    const RECORD_LEN: u64 = 8;
+
+    fn new(dir: &Path) -> io::Result<Self> {
+        let file = tempfile::tempfile_in(dir)?;
+        Ok(Self { file, len: 0 })
     }
 
-    /// Calculates roots of the given set.
-    async fn roots(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
-        }
-        let min_id = id_set.min().unwrap();
-        let max_id = id_set.max().unwrap();
-        let dag = self.get_snapshot();
-        // Roots: [x for x in set if (parents(x) & set) is empty]
-        let iter = id_set.clone().into_iter().filter_map(move |i| {
-            let parent_revs = match dag.parent_revs(i.0 as _) {
-                Ok(revs) => revs,
-                Err(err) => return Some(Err(err)),
-            };
-            let should_include = parent_revs
-                .as_revs()
-                .iter()
-                .all(|&p| !id_set.contains(Id(p as _)));
-            if should_include { Some(Ok(i)) } else { None }
-        });
-        let set = Set::from_id_iter_dag(iter, self)?;
-        set.hints()
-            .add_flags(Flags::ID_DESC | Flags::TOPO_DESC)
-            .set_min_id(min_id)
-            .set_max_id(max_id);
-        Ok(set)
+    fn read(&mut self, idx: usize) -> io::Result<ParentRevs> {
+        let mut buf = [0u8; Self::RECORD_LEN as usize];
+        self.file
+            .seek(io::SeekFrom::Start(idx as u64 * Self::RECORD_LEN))?;
+        self.file.read_exact(&mut buf)?;
+        let p1 = i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let p2 = i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Ok(ParentRevs::from_p1p2(p1, p2))
     }
 
-    /// Calculates one "greatest common ancestor" of the given set.
-    ///
-    /// If there are no common ancestors, return None.
-    /// If there are multiple greatest common ancestors, pick one arbitrarily.
-    /// Use `gca_all` to get all of them.
-    async fn gca_one(&self, set: Set) -> dag::Result<Option<Vertex>> {
-        let id_set = self.to_id_set(&set).await?;
-        let mut revs: Vec<u32> = id_set.iter_desc().map(|id| id.0 as u32).collect();
-        while revs.len() > 1 {
-            let mut new_revs = Vec::new();
-            // gca_revs takes at most 6 revs at one.
-            for revs in revs.chunks(6) {
-                let gcas = self.gca_revs(revs, 1)?;
-                if gcas.is_empty() {
-                    return Ok(None);
-                } else {
-                    new_revs.extend(gcas);
-                }
-            }
-            // gca_revs needs de-duplicated revs to work.
-            new_revs.sort_unstable();
-            new_revs.dedup();
-            revs = new_revs;
-        }
-        if revs.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(self.vertex_name(Id(revs[0] as _)).await?))
+    /// Append records for `parents`. Octopus merges are written as root
+    /// revisions and need to be overridden by the caller.
+    fn append(&mut self, parents: &[ParentRevs]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(parents.len() * Self::RECORD_LEN as usize);
+        for parent_revs in parents {
+            let [p1, p2] = match parent_revs {
+                ParentRevs::Compact(revs) => *revs,
+                ParentRevs::Octopus(_) => [-1, -1],
+            };
+            buf.extend_from_slice(&p1.to_le_bytes());
+            buf.extend_from_slice(&p2.to_le_bytes());
         }
+        self.file
+            .seek(io::SeekFrom::Start(self.len as u64 * Self::RECORD_LEN))?;
+        self.file.write_all(&buf)?;
+        self.len += parents.len();
+        Ok(())
     }
+}
 
-    /// Calculates all "greatest common ancestor"s of the given set.
-    /// `gca_one` is faster if an arbitrary answer is ok.
-    async fn gca_all(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        // XXX: Limited by gca_revs implementation detail.
-        if id_set.count() > 6 {
-            return Err(Error::Unsupported(format!(
-                "RevlogIndex::gca_all does not support set with > 6 items, got {} items",
-                id_set.count()
-            ))
-            .into());
-        }
-        let revs: Vec<u32> = id_set.iter_desc().map(|id| id.0 as u32).collect();
-        let gcas = self.gca_revs(&revs, usize::max_value())?;
-        let spans = IdSet::from_spans(gcas.into_iter().map(|r| Id(r as _)));
-        let result = Set::from_spans_dag(spans, self)?;
-        Ok(result)
+/// "smallvec" optimization
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ParentRevs {
+    Compact([i32; 2]),
+    // Box<Vec<i32>> takes 8 bytes. Box<[i32]> takes 16 bytes.
+    // Using smaller struct for better memory efficiency.
+    Octopus(Box<Vec<i32>>),
+}
+
+impl ParentRevs {
+    fn from_p1p2(p1: i32, p2: i32) -> Self {
+        Self::Compact([p1, p2])
     }
 
-    /// Tests if `ancestor` is an ancestor of `descendant`.
-    async fn is_ancestor(&self, ancestor: Vertex, descendant: Vertex) -> dag::Result<bool> {
-        let ancestor_rev = self.vertex_id(ancestor).await?.0 as u32;
-        let descendant_rev = self.vertex_id(descendant).await?.0 as u32;
-        if ancestor_rev == descendant_rev {
-            return Ok(true);
-        }
-        Ok(self.gca_revs(&[ancestor_rev, descendant_rev], 1)?.get(0) == Some(&ancestor_rev))
+    fn from_vec(v: Vec<i32>) -> Self {
+        Self::Octopus(Box::new(v))
     }
 
-    /// Calculates "heads" of the ancestors of the given set. That is,
-    /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
-    /// `ancestors(X)`.
-    async fn heads_ancestors(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
+    fn from_revs(parents: Vec<u32>) -> Self {
+        if parents.len() <= 2 {
+            let p1 = parents.get(0).map(|r| *r as i32).unwrap_or(-1);
+            let p2 = parents.get(1).map(|r| *r as i32).unwrap_or(-1);
+            Self::from_p1p2(p1, p2)
+        } else {
+            Self::from_vec(parents.into_iter().map(|i| i as i32).collect())
         }
+    }
 
-        let min_rev = id_set.min().unwrap().0 as usize;
-        let max_rev = id_set.max().unwrap().0 as usize;
-        assert!(self.len() > min_rev);
-        assert!(self.len() > max_rev);
-        let state_len = max_rev - min_rev + 1;
-
-        #[repr(u8)]
-        #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-        enum State {
-            Unspecified,
-            PotentialHead,
-            NotHead,
-        }
-        let mut states = if id_set.count() as usize == state_len {
-            // Fast path: entire range of states are "Unspecified".
-            vec![State::PotentialHead; state_len]
-        } else {
-            let mut states = vec![State::Unspecified; state_len];
-            for id in id_set {
-                states[id.0 as usize - min_rev] = State::PotentialHead;
+    pub fn as_revs(&self) -> &[u32] {
+        let slice: &[i32] = match self {
+            ParentRevs::Compact(s) => {
+                if s[0] == -1 {
+                    &s[0..0]
+                } else if s[1] == -1 {
+                    &s[0..1]
+                } else {
+                    &s[..]
+                }
             }
-            states
+            ParentRevs::Octopus(s) => &s[..],
         };
+        let ptr = (slice as *const [i32]) as *const [u32];
+        unsafe { &*ptr }
+    }
+}
 
-        let mut result_id_set = IdSet::empty();
-        for i in (0..states.len()).rev() {
-            let state = states[i];
-            match state {
-                State::Unspecified => {}
-                State::PotentialHead | State::NotHead => {
-                    let rev = i + min_rev;
-                    if state == State::PotentialHead {
-                        result_id_set.push(Id(rev as _));
-                    }
-                    for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
-                        if parent_rev as usize >= min_rev {
-                            states[parent_rev as usize - min_rev] = State::NotHead;
-                        }
+/// Revlog entry. See "# index ng" in revlog.py.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct RevlogEntry {
+    offset_flags: u64,
+    compressed: i32,
+    len: i32,
+    base: i32,
+    link: i32,
+    p1: i32,
+    p2: i32,
+    pub node: [u8; 20],
+    _padding: [u8; 12],
+}
+
+/// Bytes that can be converted to &[T].
+#[derive(Clone)]
+pub struct BytesSlice<T>(Bytes, PhantomData<T>);
+pub trait UnsafeSliceCast {}
+
+impl<T> From<Bytes> for BytesSlice<T> {
+    fn from(bytes: Bytes) -> Self {
+        Self(bytes, PhantomData)
+    }
+}
+
+impl<T: UnsafeSliceCast> AsRef<[T]> for BytesSlice<T> {
+    fn as_ref(&self) -> &[T] {
+        let u8_slice: &[u8] = self.0.as_ref();
+        // safety: buffer length matches. Bytes is expected to be mmap-ed or
+        // static buffer and should be aligned.
+        let ptr = u8_slice.as_ptr() as *const T;
+        unsafe { slice::from_raw_parts(ptr, u8_slice.len() / mem::size_of::<T>()) }
+    }
+}
+impl UnsafeSliceCast for u32 {}
+impl UnsafeSliceCast for RevlogEntry {}
+
+impl RevlogEntry {
+    fn flags(&self) -> u16 {
+        (self.offset_flags.to_be() & 0xffff) as u16
+    }
+
+    fn is_octopus_merge(&self) -> bool {
+        (self.flags() & REVIDX_OCTOPUS_MERGE) != 0
+    }
+}
+
+impl RevlogIndex {
+    /// Constructs a RevlogIndex. The NodeRevMap is automatically manage>
+    pub fn new(changelogi_path: &Path, nodemap_path: &Path) -> Result<Self> {
+        // 20000 is chosen as it takes a few milliseconds to build up nodemap.
+        Self::new_advanced(changelogi_path, nodemap_path, 20000)
+    }
+
+    /// Constructs a RevlogIndex with customized nodemap lag threshold.
+    pub fn new_advanced(
+        changelogi_path: &Path,
+        nodemap_path: &Path,
+        nodemap_lag_threshold: usize,
+    ) -> Result<Self> {
+        let empty_nodemap_data = Bytes::from(nodemap::empty_index_buffer());
+        let nodemap_data = read_path(nodemap_path, None, empty_nodemap_data.clone())?;
+        let changelogi_len = read_usize(&changelogi_path.with_extension("len"))?;
+        let changelogi_data = read_path(changelogi_path, changelogi_len, Bytes::default())?;
+        let entry_size = mem::size_of::<RevlogEntry>();
+        if changelogi_data.len() % entry_size != 0 {
+            return corruption(format!(
+                "{}: length {} is not a multiple of the entry size {} ({} extra bytes)",
+                changelogi_path.display(),
+                changelogi_data.len(),
+                entry_size,
+                changelogi_data.len() % entry_size
+            ));
+        }
+        let nodemap = NodeRevMap::new(changelogi_data.clone().into(), nodemap_data.into())
+            .or_else(|_| {
+                // Attempt to rebuild the index (in-memory) automatically.
+                NodeRevMap::new(changelogi_data.clone().into(), empty_nodemap_data.into())
+            })?;
+        if nodemap.lag() as usize > nodemap_lag_threshold {
+            // The index is lagged, and less efficient. Update it.
+            // Building is incremental. Writing to disk is not.
+            if let Ok(buf) = nodemap.build_incrementally() {
+                // Cast [u32] to [u8] for writing.
+                let slice =
+                    unsafe { slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 4) };
+                // nodemap_path should be a symlink. Older repos might have the non-symlink
+                // file. Attempt to delete it.
+                // This is useful on Windows to prevent atomic_write_symlink failures when
+                // the nodemap file was non-symlink and mmaped.
+                if let Ok(meta) = nodemap_path.symlink_metadata() {
+                    if !meta.file_type().is_symlink() {
+                        remove_file(nodemap_path)?;
                     }
                 }
+                // Not fatal if we cannot update the on-disk index.
+                let _ = atomic_write_symlink(nodemap_path, slice);
             }
         }
-        let result = Set::from_spans_dag(result_id_set, self)?;
+        let result = Self {
+            nodemap,
+            changelogi_data,
+            pending_parents: Default::default(),
+            pending_nodes: Default::default(),
+            pending_nodes_index: Default::default(),
+            pending_raw_data: Default::default(),
+            snapshot: Default::default(),
+            generations: Default::default(),
+            children: Default::default(),
+            dag_hashes: Default::default(),
+            data_handler: Default::default(),
+            index_path: changelogi_path.to_path_buf(),
+            nodemap_path: nodemap_path.to_path_buf(),
+            id: format!("rlog:{}", &nodemap_path.display()),
+            version: VerLink::new(),
+            interrupt_check: None,
+            filelog: false,
+            spill_options: None,
+            spill_file: None,
+            spilled_len: 0,
+            spilled_overrides: Default::default(),
+        };
         Ok(result)
     }
 
-    /// Calculate the heads of the set.
-    async fn heads(&self, set: Set) -> dag::Result<Set> {
-        if set.hints().contains(Flags::ANCESTORS) {
-            self.heads_ancestors(set).await
-        } else {
-            Ok(set.clone() - self.parents(set).await?)
-        }
+    /// Like `new`, but move parents of inserted revisions to a temporary file
+    /// in `spill.dir` once `spill.threshold` of them are held in memory. This
+    /// limits memory usage when inserting many revisions before `flush`.
+    pub fn new_with_spill(
+        changelogi_path: &Path,
+        nodemap_path: &Path,
+        spill: SpillOptions,
+    ) -> Result<Self> {
+        let mut index = Self::new(changelogi_path, nodemap_path)?;
+        index.spill_options = Some(spill);
+        Ok(index)
     }
 
-    /// Calculates the "dag range" - vertexes reachable from both sides.
-    async fn range(&self, roots: Set, heads: Set) -> dag::Result<Set> {
-        let root_ids = self.to_id_set(&roots).await?;
-        let head_ids = self.to_id_set(&heads).await?;
-        let root_revs: Vec<u32> = root_ids.into_iter().map(|i| i.0 as u32).collect();
-        let head_revs: Vec<u32> = head_ids.into_iter().map(|i| i.0 as u32).collect();
-        let result_revs = self.range_revs(&root_revs, &head_revs)?;
-        let result_id_set = IdSet::from_spans(result_revs.into_iter().map(|r| Id(r as _)));
-        let result = Set::from_spans_dag(result_id_set, self)?;
-        Ok(result)
+    /// Revisions in total.
+    pub fn len(&self) -> usize {
+        self.data_len() + self.pending_len()
+    }
+
+    /// Revisions stored in the original revlog index.
+    pub fn data_len(&self) -> usize {
+        self.data().len()
+    }
+
+    #[inline]
+    fn data(&self) -> &[RevlogEntry] {
+        self.nodemap.changelogi.as_ref()
+    }
+
+    /// Get parent revisions.
+    pub fn parent_revs(&self, rev: u32) -> dag::Result<ParentRevs> {
+        let data_len = self.data_len();
+        if rev >= data_len as u32 {
+            return match self.pending_parent_revs(rev as usize - data_len)? {
+                Some(revs) => Ok(revs),
+                None => Id(rev as _).not_found(),
+            };
+        }
+
+        let data = self.data();
+        let entry = &data[rev as usize];
+        let p1 = i32::from_be(entry.p1);
+        let p2 = i32::from_be(entry.p2);
+        if entry.is_octopus_merge() {
+            let mut parents = Vec::with_capacity(3);
+            if p1 >= 0 {
+                parents.push(p1);
+            }
+            if p2 >= 0 {
+                parents.push(p2);
+            }
+            // Read from the "stepparents" extra.
+            let data = self.raw_data(rev)?;
+            let stepparents = self.get_stepparents(&data)?;
+            parents.extend(stepparents);
+            check_stored_parents(rev, &parents)?;
+            Ok(ParentRevs::from_vec(parents))
+        } else if p1 == -1 && p2 != -1 && self.filelog {
+            check_stored_parents(rev, &[p2])?;
+            Ok(ParentRevs::from_p1p2(p2, -1))
+        } else {
+            check_stored_p1p2(rev, p1, p2)?;
+            Ok(ParentRevs::from_p1p2(p1, p2))
+        }
+    }
+
+    /// Get parent revisions of many revisions at once, in the order of `revs`.
+    /// Fails without partial results if any of `revs` is out of range.
+    pub fn parent_revs_batch(&self, revs: &[u32]) -> dag::Result<Vec<Vec<u32>>> {
+        let len = self.len();
+        if let Some(&rev) = revs.iter().find(|&&rev| rev as usize >= len) {
+            return Id(rev as _).not_found();
+        }
+        revs.iter()
+            .map(|&rev| Ok(self.parent_revs(rev)?.as_revs().to_vec()))
+            .collect()
+    }
+
+    /// Get the extra parents from raw data of a revision.
+    fn get_stepparents(&self, data: &[u8]) -> Result<Vec<i32>> {
+        // `data` format:
+        // <manifest sha1> + '\n'
+        // author + '\n'
+        // date + ' ' + timezone + ' ' + (... + '\0')* + 'stepparents:' + (hexnode + ',')+
+
+        let mut parents = Vec::new();
+
+        #[derive(Copy, Clone)]
+        enum State {
+            ManifestLine,
+            AuthorLine,
+            Date,
+            Timezone,
+            Extras,
+        }
+
+        let mut state: State = State::ManifestLine;
+        let mut extra_start = 0;
+        for (i, b) in data.iter().enumerate() {
+            match (state, b) {
+                (State::ManifestLine, b'\n') => {
+                    state = State::AuthorLine;
+                }
+                (State::AuthorLine, b'\n') => {
+                    state = State::Date;
+                }
+                (State::Date, b' ') => {
+                    state = State::Timezone;
+                }
+                (State::Timezone, b' ') => {
+                    state = State::Extras;
+                    extra_start = i + 1;
+                }
+                (State::Extras, b'\0') | (State::Extras, b'\n') => {
+                    let extra = &data[extra_start..i]; // name:value
+                    if let Some(value) = extra.strip_prefix(b"stepparents:") {
+                        // Parse the stepparents field.
+                        if let Ok(value) = std::str::from_utf8(value) {
+                            for hex_node in value.split(',') {
+                                let node = Vertex::from_hex(hex_node.as_bytes())?;
+                                if let Some(id) = self.nodemap.node_to_rev(node.as_ref())? {
+                                    parents.push(id as i32);
+                                } else {
+                                    return Err(crate::errors::CommitNotFound(node).into());
+                                }
+                            }
+                        }
+                    }
+                    if *b == b'\n' {
+                        break;
+                    } else {
+                        extra_start = i + 1;
+                    }
+                }
+                (_, _) => {}
+            }
+        }
+        Ok(parents)
+    }
+
+    /// Get raw content from a revision.
+    pub fn raw_data(&self, rev: u32) -> Result<Bytes> {
+        if rev as usize >= self.data_len() {
+            let result = &self.pending_raw_data[rev as usize - self.data_len()];
+            return Ok(result.clone());
+        }
+        let entry = self.data()[rev as usize];
+        let offset = if rev == 0 {
+            0
+        } else {
+            u64::from_be(entry.offset_flags) >> 16
+        };
+
+        let compressed_size = i32::from_be(entry.compressed) as usize;
+        let mut chunk = vec![0; compressed_size];
+
+        let mut locked = self.data_handler.lock();
+        if locked.is_none() {
+            let revlog_data_path = self.index_path.with_extension("d");
+            let file = fs::OpenOptions::new().read(true).open(revlog_data_path)?;
+            *locked = Some(file);
+        }
+        let file = locked.as_mut().unwrap();
+
+        file.seek(io::SeekFrom::Start(offset))?;
+        file.read_exact(&mut chunk)?;
+        drop(locked);
+
+        let decompressed = match chunk.get(0) {
+            None => chunk,
+            Some(b'\0') => chunk,
+            Some(b'u') => {
+                chunk.drain(0..1);
+                chunk
+            }
+            Some(b'4') => lz4_pyframe::decompress(&chunk[1..])?,
+            Some(&c) => return unsupported(format!("unsupported header: {:?}", c as char)),
+        };
+
+        let base = i32::from_be(entry.base);
+        let result = if base != rev as i32 && base >= 0 {
+            // Has a delta base. Load it recursively.
+            // PERF: this is very inefficient (no caching, no folding delta chains), but is only
+            // used for ancient data.
+            let base = if self.is_general_delta() {
+                base as u32
+            } else {
+                rev - 1
+            };
+            let base_text = self.raw_data(base)?;
+            apply_deltas(&base_text, &decompressed)?
+        } else {
+            decompressed
+        };
+
+        Ok(Bytes::from(result))
+    }
+
+    /// Parents of the `idx`-th inserted revision.
+    fn pending_parent_revs(&self, idx: usize) -> dag::Result<Option<ParentRevs>> {
+        if idx >= self.spilled_len {
+            return Ok(self.pending_parents.get(idx - self.spilled_len).cloned());
+        }
+        if let Some(revs) = self.spilled_overrides.get(&idx) {
+            return Ok(Some(revs.clone()));
+        }
+        match &self.spill_file {
+            Some(file) => {
+                let revs = file.lock().read(idx).map_err(crate::Error::from)?;
+                Ok(Some(revs))
+            }
+            None => dag::errors::bug("spilled revisions should have a spill file"),
+        }
+    }
+
+    /// Move `pending_parents` to the spill file if there are too many.
+    fn maybe_spill(&mut self) -> Result<()> {
+        let dir = match &self.spill_options {
+            Some(options) if self.pending_parents.len() >= options.threshold.max(1) => {
+                options.dir.clone()
+            }
+            _ => return Ok(()),
+        };
+
+        let file = match &self.spill_file {
+            Some(file) if file.lock().len == self.spilled_len => file.clone(),
+            // No file yet, or `truncate_pending` dropped spilled revisions
+            // that snapshots might still read. Start a new file.
+            old_file => {
+                let mut file = SpillFile::new(&dir)?;
+                if let Some(old_file) = old_file {
+                    let mut old_file = old_file.lock();
+                    let parents = (0..self.spilled_len)
+                        .map(|idx| old_file.read(idx))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    file.append(&parents)?;
+                }
+                Arc::new(Mutex::new(file))
+            }
+        };
+
+        file.lock().append(&self.pending_parents)?;
+        for (i, parent_revs) in self.pending_parents.iter().enumerate() {
+            if let ParentRevs::Octopus(_) = parent_revs {
+                self.spilled_overrides
+                    .insert(self.spilled_len + i, parent_revs.clone());
+            }
+        }
+        self.spilled_len += self.pending_parents.len();
+        self.pending_parents = Vec::new();
+        self.spill_file = Some(file);
+        Ok(())
+    }
+
+    /// Insert a new revision with given parents at the end.
+    ///
+    /// Every parent must be lower than the revision being inserted. Inserting
+    /// an existing node is a no-op.
+    pub fn insert(&mut self, node: Vertex, parents: Vec<u32>, raw_data: Bytes) -> Result<()> {
+        if non_blocking_result(self.contains_vertex_name(&node)).unwrap_or(false) {
+            return Ok(());
+        }
+        check_parents(self.len() as u32, &parents)?;
+        let parent_revs = ParentRevs::from_revs(parents);
+        let idx = self.pending_len();
+        self.pending_parents.push(parent_revs);
+        self.pending_nodes.push(node.clone());
+
+        self.pending_nodes_index.insert(node, idx);
+        *self.snapshot.write() = None;
+        *self.generations.write() = None;
+        *self.children.write() = None;
+
+        self.pending_raw_data.push(raw_data);
+        self.maybe_spill()?;
+
+        self.version.bump();
+        Ok(())
+    }
+
+    /// Replace the parents of a revision that was inserted but not flushed.
+    /// Revisions on disk cannot be modified.
+    pub fn set_inserted_parents(&mut self, rev: u32, parents: Vec<u32>) -> Result<()> {
+        let data_len = self.data_len();
+        if (rev as usize) < data_len {
+            return unsupported(format!("rev {} is on disk and cannot be modified", rev));
+        }
+        let idx = rev as usize - data_len;
+        if idx >= self.pending_len() {
+            return Err(RevNotFound(rev).into());
+        }
+        check_parents(rev, &parents)?;
+
+        let parent_revs = ParentRevs::from_revs(parents);
+        if idx < self.spilled_len {
+            self.spilled_overrides.insert(idx, parent_revs);
+        } else {
+            self.pending_parents[idx - self.spilled_len] = parent_revs;
+        }
+        *self.snapshot.write() = None;
+        *self.generations.write() = None;
+        *self.children.write() = None;
+        self.dag_hashes.write().clear();
+        // This is not an append-only change.
+        self.version = VerLink::new();
+        Ok(())
+    }
+
+    /// Number of inserted revisions that are not flushed to disk.
+    ///
+    /// Can be passed to `truncate_pending` to roll back later inserts.
+    pub fn pending_len(&self) -> usize {
+        self.spilled_len + self.pending_parents.len()
+    }
+
+    /// Drop inserted revisions so only the first `len` are kept.
+    /// Error if `len` is greater than the number of inserted revisions.
+    pub fn truncate_pending(&mut self, len: usize) -> Result<()> {
+        let pending_len = self.pending_len();
+        if len > pending_len {
+            return unsupported(format!(
+                "cannot restore {} inserted revisions (only {} exist)",
+                len, pending_len
+            ));
+        }
+        if len == pending_len {
+            return Ok(());
+        }
+
+        for node in self.pending_nodes.drain(len..) {
+            self.pending_nodes_index.remove(&node);
+        }
+        if len < self.spilled_len {
+            self.spilled_overrides.retain(|&idx, _| idx < len);
+            self.spilled_len = len;
+            self.pending_parents.clear();
+        } else {
+            self.pending_parents.truncate(len - self.spilled_len);
+        }
+        self.pending_raw_data.truncate(len);
+        *self.snapshot.write() = None;
+        *self.generations.write() = None;
+        *self.children.write() = None;
+        self.dag_hashes.write().clear();
+        // This is not an append-only change.
+        self.version = VerLink::new();
+        Ok(())
+    }
+
+    fn pending_parent_map(&self) -> dag::Result<HashMap<Vec<u8>, Vec<Vec<u8>>>> {
+        let mut result = HashMap::new();
+        for i in 0..self.pending_nodes.len() {
+            let parent_revs = self.parent_revs((self.data_len() + i) as u32)?;
+            let parent_nodes = parent_revs
+                .as_revs()
+                .iter()
+                .map(|&rev| {
+                    non_blocking_result(self.vertex_name(Id(rev as _)))
+                        .unwrap()
+                        .as_ref()
+                        .to_vec()
+                })
+                .collect::<Vec<_>>();
+            let rev = self.data_len() + i;
+            let node = non_blocking_result(self.vertex_name(Id(rev as _))).unwrap();
+            result.insert(node.as_ref().to_vec(), parent_nodes);
+        }
+        Ok(result)
+    }
+
+    /// Write pending commits to disk. Return the number of revisions written.
+    /// Pending commits that were written by others are skipped.
+    pub fn flush(&mut self) -> Result<usize> {
+        // Convert parent revs to parent nodes. This is because revs are
+        // easy to get wrong: ex. some nodes already exist in updated revlog.
+        let parent_map = self.pending_parent_map().map_err(|e| {
+            CorruptionError::Generic(format!("cannot calculate pending graph: {}", e))
+        })?;
+
+        let _lock = ScopedDirLock::new(
+            self.index_path
+                .parent()
+                .expect("index_path should not be root dir"),
+        )?;
+
+        let revlog_data_path = self.index_path.with_extension("d");
+        let mut revlog_data = fs::OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .write(true)
+            .open(&revlog_data_path)?;
+        let mut revlog_index = fs::OpenOptions::new()
+            .read(true)
+            .create(true)
+            .write(true)
+            .open(&self.index_path)?;
+
+        let meta_len_path = self.index_path.with_extension("len");
+        let meta_len = read_usize(&meta_len_path)?;
+        let revlog_index_size = match meta_len {
+            // Use the logic length.
+            Some(len) => revlog_index.seek(io::SeekFrom::Start(len as _))? as usize,
+            // No explicit length. Use the physical length of the file.
+            None => revlog_index.seek(io::SeekFrom::End(0))? as usize,
+        };
+
+        let old_rev_len = revlog_index_size / mem::size_of::<RevlogEntry>();
+        let old_offset = revlog_data.seek(io::SeekFrom::End(0))?;
+
+        if revlog_index_size % mem::size_of::<RevlogEntry>() != 0 {
+            return corruption("changelog index length is not a multiple of 64");
+        }
+
+        if old_rev_len < self.data_len() {
+            return corruption("changelog was truncated unexpectedly");
+        }
+
+        // Read from disk about new nodes. Do not write them again.
+        // References to them will use new revs.
+        // `existing_nodes` contain rev -> node relationship not known
+        // to the current revlog snapshot.
+        let mut existing_nodes = HashMap::new();
+        if old_rev_len > self.data_len() {
+            let data = BytesSlice::<RevlogEntry>::from(read_path(
+                &self.index_path,
+                Some(revlog_index_size),
+                Bytes::new(),
+            )?);
+            for (i, entry) in data.as_ref()[self.data_len()..].iter().enumerate() {
+                let rev = (self.data_len() + i) as u32;
+                existing_nodes.insert(entry.node.as_ref().to_vec(), rev);
+            }
+        }
+
+        let get_rev = |existing_nodes: &HashMap<Vec<u8>, u32>, node: &[u8]| -> Result<u32> {
+            match existing_nodes.get(node) {
+                Some(&rev) => Ok(rev),
+                None => Ok(self.nodemap.node_to_rev(node)?.unwrap()),
+            }
+        };
+
+        let mut new_data = Vec::new();
+        let mut new_index = Vec::new();
+        let mut i = 0;
+
+        for (raw, node) in self.pending_raw_data.iter().zip(self.pending_nodes.iter()) {
+            if existing_nodes.contains_key(node.as_ref()) {
+                continue;
+            }
+            let raw_len = raw.len();
+            let compressed = lz4_pyframe::compress(&raw)?;
+            let chunk = if compressed.len() < raw.len() {
+                // Use LZ4 compression ('4' header).
+                let mut chunk = vec![b'4'];
+                chunk.extend(compressed);
+                chunk
+            } else {
+                // Do not use compression ('u' header).
+                let mut chunk = vec![b'u'];
+                chunk.extend(raw.as_ref().to_vec());
+                chunk
+            };
+
+            let offset = old_offset + new_data.len() as u64;
+            let rev = old_rev_len + i;
+            i += 1;
+            existing_nodes.insert(node.as_ref().to_vec(), rev as u32);
+
+            let mut parent_revs: [i32; 2] = [-1, -1];
+            let parents = &parent_map[node.as_ref()];
+            for (p_id, p_node) in parents.iter().take(2).enumerate() {
+                parent_revs[p_id] = get_rev(&existing_nodes, p_node)? as i32;
+            }
+
+            let mut flags = 0;
+            if parents.len() > 2 || find_bytes_in_bytes(&raw, b"stepparents:").is_some() {
+                flags |= REVIDX_OCTOPUS_MERGE;
+            };
+            let entry = RevlogEntry {
+                offset_flags: u64::to_be((offset << 16) | (flags as u64)),
+                compressed: i32::to_be(chunk.len() as i32),
+                len: i32::to_be(raw_len as i32),
+                base: i32::to_be(rev as i32),
+                link: i32::to_be(rev as i32),
+                p1: i32::to_be(parent_revs[0]),
+                p2: i32::to_be(parent_revs[1]),
+                node: <[u8; 20]>::try_from(node.as_ref()).map_err(|_| {
+                    crate::Error::Unsupported(format!("node is not 20-char long: {:?}", &node))
+                })?,
+                _padding: [0u8; 12],
+            };
+
+            let entry_bytes: [u8; 64] = unsafe { std::mem::transmute(entry) };
+
+            new_index.write_all(&entry_bytes)?;
+            new_data.write_all(&chunk)?;
+        }
+
+        // Special case. First 6 bytes of the index file are not the offset but
+        // specify the version and flags.
+        if old_rev_len == 0 && new_index.len() > 4 {
+            new_index[3] = 1; // revlog v1
+        }
+
+        // Write revlog data before revlog index.
+        revlog_data.write_all(&new_data)?;
+        drop(revlog_data);
+
+        // Write revlog index.
+        revlog_index.write_all(&new_index)?;
+        drop(revlog_index);
+
+        // Update meta file with the new logical length.
+        let new_len = revlog_index_size + new_index.len();
+        atomic_write_plain(&meta_len_path, format!("{}", new_len).as_bytes(), false)?;
+
+        // Reload.
+        let interrupt_check = self.interrupt_check.take();
+        let spill_options = self.spill_options.take();
+        let filelog = self.filelog;
+        *self = Self::new(&self.index_path, &self.nodemap_path)?;
+        self.interrupt_check = interrupt_check;
+        self.spill_options = spill_options;
+        self.filelog = filelog;
+
+        Ok(i)
+    }
+
+    /// Create a Arc snapshot of IdConvert trait object on demand.
+    pub fn get_snapshot(&self) -> Arc<Self> {
+        let mut snapshot = self.snapshot.write();
+        // Create snapshot on demand.
+        match snapshot.deref() {
+            Some(s) => s.clone(),
+            None => {
+                let result = Arc::new(self.clone());
+                *snapshot = Some(result.clone());
+                result
+            }
+        }
+    }
+
+    /// Set the check used by `ancestors`, `descendants` and `heads` to abort
+    /// early. Replaces the previous check.
+    pub fn set_interrupt_check(&mut self, check: Option<InterruptCheck>) {
+        self.interrupt_check = check;
+        *self.snapshot.write() = None;
+    }
+
+    /// Treat the index as a filelog. Revisions of filelogs can have p2 without
+    /// p1, to record copy information. Their p2 is their only parent then.
+    pub fn set_filelog(&mut self, filelog: bool) {
+        self.filelog = filelog;
+        *self.snapshot.write() = None;
+        *self.generations.write() = None;
+        *self.children.write() = None;
+        self.dag_hashes.write().clear();
+    }
+
+    /// Run the interrupt check if `rev` is at a check interval.
+    fn check_interrupted(&self, rev: usize) -> dag::Result<()> {
+        if rev % INTERRUPT_CHECK_INTERVAL == 0 {
+            if let Some(check) = &self.interrupt_check {
+                if check() {
+                    return Err(crate::Error::Interrupted.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Clone for RevlogIndex {
+    fn clone(&self) -> Self {
+        Self {
+            pending_parents: self.pending_parents.clone(),
+            pending_nodes: self.pending_nodes.clone(),
+            pending_nodes_index: self.pending_nodes_index.clone(),
+            pending_raw_data: self.pending_raw_data.clone(),
+            snapshot: Default::default(),
+            generations: Default::default(),
+            children: Default::default(),
+            dag_hashes: Default::default(),
+            data_handler: Default::default(),
+            nodemap: self.nodemap.clone(),
+            changelogi_data: self.changelogi_data.clone(),
+            index_path: self.index_path.clone(),
+            nodemap_path: self.nodemap_path.clone(),
+            id: self.id.clone(),
+            version: self.version.clone(),
+            interrupt_check: self.interrupt_check.clone(),
+            filelog: self.filelog,
+            spill_options: self.spill_options.clone(),
+            spill_file: self.spill_file.clone(),
+            spilled_len: self.spilled_len,
+            spilled_overrides: self.spilled_overrides.clone(),
+        }
+    }
+}
+
+/// Check that all `parents` are lower than `rev`.
+fn check_parents(rev: u32, parents: &[u32]) -> Result<()> {
+    if let Some(parent_rev) = parents.iter().find(|&&p| p >= rev) {
+        return unsupported(format!(
+            "parent {} of rev {} must be less than {}",
+            parent_rev, rev, rev
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `parents` of `rev` read from the index are lower than `rev`,
+/// or -1 for missing parents. Traversals rely on that, so a corrupt index
+/// is reported here instead of breaking them.
+fn check_stored_parents(rev: u32, parents: &[i32]) -> Result<()> {
+    if let Some(parent_rev) = parents.iter().find(|&&p| p < -1 || p >= rev as i32) {
+        return corruption(format!(
+            "parent {} of rev {} is out of range",
+            parent_rev, rev
+        ));
+    }
+    Ok(())
+}
+
+/// Like `check_stored_parents`, but also check that p2 is only set if p1 is.
+fn check_stored_p1p2(rev: u32, p1: i32, p2: i32) -> Result<()> {
+    if p1 == -1 && p2 != -1 {
+        return corruption(format!("rev {} has p2 {} but no p1", rev, p2));
+    }
+    check_stored_parents(rev, &[p1, p2])
+}
+
+fn apply_deltas(base_text: &[u8], delta_text: &[u8]) -> Result<Vec<u8>> {
+    struct Delta {
+        start: usize,
+        end: usize,
+        data: Vec<u8>,
+    }
+    let mut deltas = Vec::new();
+    let mut cursor = &delta_text[..];
+    while !cursor.is_empty() {
+        let start = cursor.read_u32::<BE>()?;
+        let end = cursor.read_u32::<BE>()?;
+        let len = cursor.read_u32::<BE>()?;
+        let mut data = vec![0u8; len as usize];
+        cursor.read_exact(&mut data)?;
+        deltas.push(Delta {
+            start: start as _,
+            end: end as _,
+            data,
+        });
+    }
+    let mut result: Vec<u8> = Vec::new();
+    let mut base_text_pos = 0;
+    for delta in deltas {
+        if base_text_pos < delta.start {
+            result.extend_from_slice(&base_text[base_text_pos..delta.start]);
+        }
+        if !delta.data.is_empty() {
+            result.extend(delta.data);
+        }
+        base_text_pos = delta.end;
+    }
+    if base_text_pos < base_text.len() {
+        result.extend_from_slice(&base_text[base_text_pos..]);
+    }
+    Ok(result)
+}
+
+/// Read an integer from a `path`. If the file does not exist, or is empty,
+/// return None.
+fn read_usize(path: &Path) -> io::Result<Option<usize>> {
+    match fs::OpenOptions::new().read(true).open(path) {
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                Ok(None)
+            } else {
+                Err(err)
+            }
+        }
+        Ok(mut file) => {
+            let mut s = String::new();
+            file.read_to_string(&mut s)?;
+            if s.is_empty() {
+                // This might happen with some file systems.
+                // Just treat it as if the file does not exist.
+                Ok(None)
+            } else {
+                s.parse::<usize>()
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+fn read_path(path: &Path, length: Option<usize>, fallback: Bytes) -> io::Result<Bytes> {
+    match fs::OpenOptions::new().read(true).open(path) {
+        Err(err) => {
+            if err.kind() == io::ErrorKind::NotFound {
+                Ok(fallback)
+            } else {
+                Err(err)
+            }
+        }
+        Ok(file) => mmap_bytes(&file, length.map(|size| size as u64)),
+    }
+}
+
+#[async_trait::async_trait]
+impl PrefixLookup for RevlogIndex {
+    async fn vertexes_by_hex_prefix(
+        &self,
+        hex_prefix: &[u8],
+        limit: usize,
+    ) -> dag::Result<Vec<Vertex>> {
+        // Search through the BTreeMap
+        let start = Vertex::from_hex(hex_prefix)?;
+        let mut result = Vec::new();
+        for (vertex, _) in self.pending_nodes_index.range(start..) {
+            if !vertex.to_hex().as_bytes().starts_with(hex_prefix) {
+                break;
+            }
+            result.push(vertex.clone());
+            if result.len() >= limit {
+                break;
+            }
+        }
+        // Search through the NodeRevMap
+        match self.nodemap.hex_prefix_to_node(hex_prefix) {
+            Ok(Some(node)) => {
+                if limit > 0 {
+                    result.push(node.to_vec().into());
+                }
+            }
+            Ok(None) => {}
+            Err(crate::Error::AmbiguousPrefix) => {
+                // Convert AmbiguousPrefix to a non-error with multiple vertex pushed to
+                // result.  That's what the Python code base expects.
+                while result.len() < limit {
+                    result.push(Vertex::from(Bytes::from_static(b"")));
+                }
+                return Ok(result);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(result)
+    }
+}
+
+#[async_trait::async_trait]
+impl IdConvert for RevlogIndex {
+    async fn vertex_id(&self, vertex: Vertex) -> dag::Result<Id> {
+        if let Some(pending_id) = self.pending_nodes_index.get(&vertex) {
+            Ok(Id((pending_id + self.data_len()) as _))
+        } else if let Some(id) = self.nodemap.node_to_rev(vertex.as_ref())? {
+            Ok(Id(id as _))
+        } else {
+            vertex.not_found()
+        }
+    }
+    async fn vertex_id_with_max_group(
+        &self,
+        vertex: &Vertex,
+        _max_group: Group,
+    ) -> dag::Result<Option<Id>> {
+        // RevlogIndex stores everything in the master group. So max_gorup is ignored.
+        if let Some(pending_id) = self.pending_nodes_index.get(vertex) {
+            Ok(Some(Id((pending_id + self.data_len()) as _)))
+        } else if let Some(id) = self.nodemap.node_to_rev(vertex.as_ref())? {
+            Ok(Some(Id(id as _)))
+        } else {
+            Ok(None)
+        }
+    }
+    async fn vertex_name(&self, id: Id) -> dag::Result<Vertex> {
+        let rev = id.0 as usize;
+        if rev < self.data_len() {
+            Ok(Vertex::from(self.data()[rev].node.as_ref().to_vec()))
+        } else {
+            match self.pending_nodes.get(rev - self.data_len()) {
+                Some(node) => Ok(node.clone()),
+                None => id.not_found(),
+            }
+        }
+    }
+    async fn contains_vertex_name(&self, vertex: &Vertex) -> dag::Result<bool> {
+        if let Some(_pending_id) = self.pending_nodes_index.get(vertex) {
+            Ok(true)
+        } else if let Some(_id) = self.nodemap.node_to_rev(vertex.as_ref())? {
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    async fn contains_vertex_id_locally(&self, ids: &[Id]) -> dag::Result<Vec<bool>> {
+        let mut list = Vec::with_capacity(ids.len());
+        for id in ids {
+            let rev = id.0 as usize;
+            list.push(
+                rev < self.data_len() || self.pending_nodes.get(rev - self.data_len()).is_some(),
+            );
+        }
+        Ok(list)
+    }
+    async fn contains_vertex_name_locally(&self, names: &[Vertex]) -> dag::Result<Vec<bool>> {
+        let mut list = Vec::with_capacity(names.len());
+        for name in names {
+            list.push(
+                self.pending_nodes_index.contains_key(name)
+                    || self.nodemap.node_to_rev(name.as_ref())?.is_some(),
+            )
+        }
+        Ok(list)
+    }
+    fn map_id(&self) -> &str {
+        &self.id
+    }
+    fn map_version(&self) -> &VerLink {
+        &self.version
+    }
+}
+
+#[async_trait::async_trait]
+impl DagAlgorithm for RevlogIndex {
+    /// Sort a `Set` topologically.
+    async fn sort(&self, set: &Set) -> dag::Result<Set> {
+        let hints = set.hints();
+        if hints.contains(Flags::TOPO_DESC)
+            && matches!(hints.dag_version(), Some(v) if v <= self.dag_version())
+            && matches!(hints.id_map_version(), Some(v) if v <= self.map_version())
+        {
+            Ok(set.clone())
+        } else {
+            let mut spans = IdSet::empty();
+            for name in set.iter()? {
+                let id = self.vertex_id(name?).await?;
+                spans.push(id);
+            }
+            let result = Set::from_spans_dag(spans, self)?;
+            Ok(result)
+        }
+    }
+
+    /// Get ordered parent vertexes.
+    async fn parent_names(&self, name: Vertex) -> dag::Result<Vec<Vertex>> {
+        let rev = self.vertex_id(name).await?.0 as u32;
+        let parent_revs = self.parent_revs(rev)?;
+        let parent_revs = parent_revs.as_revs();
+        let mut result = Vec::with_capacity(parent_revs.len());
+        for &rev in parent_revs {
+            result.push(self.vertex_name(Id(rev as _)).await?);
+        }
+        Ok(result)
+    }
+
+    /// Returns a set that covers all vertexes tracked by this DAG.
+    async fn all(&self) -> dag::Result<Set> {
+        let id_set = if self.len() == 0 {
+            IdSet::empty()
+        } else {
+            IdSet::from(Id(0)..=Id(self.len() as u64 - 1))
+        };
+        let result = Set::from_spans_dag(id_set, self)?;
+        result.hints().add_flags(Flags::FULL);
+        Ok(result)
+    }
+
+    /// Returns a set that covers all vertexes in the master group.
+    async fn master_group(&self) -> dag::Result<Set> {
+        self.all().await
+    }
+
+    /// Vertexes buffered, not persisted.
+    async fn dirty(&self) -> dag::Result<Set> {
+        let low = Id(self.data_len() as _);
+        let next = Id(self.len() as _);
+        let mut id_set = IdSet::empty();
+        if next > low {
+            id_set.push(low..=(next - 1));
+        }
+        let set = Set::from_spans_dag(id_set, self)?;
+        Ok(set)
+    }
+
+    /// Calculates all ancestors reachable from any name from the given set.
+    async fn ancestors(&self, set: Set) -> dag::Result<Set> {
+        if set.hints().contains(Flags::ANCESTORS) {
+            return Ok(set);
+        }
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let dag = self.get_snapshot();
+        let max_id = id_set.max().unwrap();
+        let max_rev = max_id.0 as u32;
+
+        let mut included = BitVec::from_elem(max_rev as usize + 1, false);
+        for id in id_set.into_iter() {
+            included.set(id.0 as usize, true);
+        }
+
+        let iter = (0..=max_rev).rev().filter_map(move |rev| {
+            if let Err(err) = dag.check_interrupted(rev as usize) {
+                return Some(Err(err));
+            }
+            let should_include = included[rev as usize];
+            if should_include {
+                let parent_revs = match dag.parent_revs(rev) {
+                    Ok(revs) => revs,
+                    Err(e) => return Some(Err(e)),
+                };
+                for &p in parent_revs.as_revs() {
+                    included.set(p as usize, true);
+                }
+            }
+            if should_include {
+                Some(Ok(Id(rev as _)))
+            } else {
+                None
+            }
+        });
+
+        let set = Set::from_id_iter_dag(iter, self)?;
+        set.hints()
+            .add_flags(Flags::ID_DESC | Flags::TOPO_DESC | Flags::ANCESTORS)
+            .set_max_id(max_id);
+        Ok(set)
+    }
+
+    /// Calculates parents.
+    async fn parents(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let max_id = id_set.max().unwrap();
+        let dag = self.get_snapshot();
+        let min_parent = id_set.iter_desc().fold(max_id.0 as u32, |min, id| {
+            let rev = id.0 as u32;
+            if let Ok(parent_revs) = dag.parent_revs(rev) {
+                parent_revs.as_revs().iter().fold(min, |min, &p| p.min(min))
+            } else {
+                min
+            }
+        }) as usize;
+
+        let mut included = BitVec::from_elem(max_id.0 as usize - min_parent + 1, false);
+        for id in id_set {
+            let rev = id.0 as u32;
+            for &p in dag.parent_revs(rev)?.as_revs() {
+                included.set(p as usize - min_parent, true);
+            }
+        }
+
+        // IdSet::push is O(1) if pushed in DESC order, otherwise it's O(N).
+        let mut id_spans: IdSet = IdSet::empty();
+        for rev in (min_parent..=max_id.0 as usize).rev() {
+            if included[rev - min_parent] {
+                id_spans.push(Id(rev as _));
+            }
+        }
+
+        let idmap = dag.clone();
+        let result = Set::from_spans_idmap_dag(id_spans, idmap, dag);
+        Ok(result)
+    }
+
+    /// Calculates children of the given set.
+    async fn children(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let min_id = id_set.min().unwrap();
+        let dag = self.get_snapshot();
+        // Children: scan to the highest Id. Check parents.
+        let iter = ((min_id.0 as u32)..(dag.len() as u32)).filter_map(move |rev| {
+            let parent_revs = match dag.parent_revs(rev) {
+                Ok(revs) => revs,
+                Err(err) => return Some(Err(err)),
+            };
+            let should_include = parent_revs
+                .as_revs()
+                .iter()
+                .any(|&p| id_set.contains(Id(p as _)));
+            if should_include {
+                Some(Ok(Id(rev as _)))
+            } else {
+                None
+            }
+        });
+
+        let set = Set::from_id_iter_dag(iter, self)?;
+        set.hints().add_flags(Flags::ID_ASC).set_min_id(min_id);
+        Ok(set)
+    }
+
+    /// Calculates roots of the given set.
+    async fn roots(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+        let min_id = id_set.min().unwrap();
+        let max_id = id_set.max().unwrap();
+        let dag = self.get_snapshot();
+        // Roots: [x for x in set if (parents(x) & set) is empty]
+        let iter = id_set.clone().into_iter().filter_map(move |i| {
+            let parent_revs = match dag.parent_revs(i.0 as _) {
+                Ok(revs) => revs,
+                Err(err) => return Some(Err(err)),
+            };
+            let should_include = parent_revs
+                .as_revs()
+                .iter()
+                .all(|&p| !id_set.contains(Id(p as _)));
+            if should_include { Some(Ok(i)) } else { None }
+        });
+        let set = Set::from_id_iter_dag(iter, self)?;
+        set.hints()
+            .add_flags(Flags::ID_DESC | Flags::TOPO_DESC)
+            .set_min_id(min_id)
+            .set_max_id(max_id);
+        Ok(set)
+    }
+
+    /// Calculates one "greatest common ancestor" of the given set.
+    ///
+    /// If there are no common ancestors, return None.
+    /// If there are multiple greatest common ancestors, pick one arbitrarily.
+    /// Use `gca_all` to get all of them.
+    async fn gca_one(&self, set: Set) -> dag::Result<Option<Vertex>> {
+        let id_set = self.to_id_set(&set).await?;
+        let mut revs: Vec<u32> = id_set.iter_desc().map(|id| id.0 as u32).collect();
+        while revs.len() > 1 {
+            let mut new_revs = Vec::new();
+            // gca_revs takes at most 6 revs at one.
+            for revs in revs.chunks(6) {
+                let gcas = self.gca_revs(revs, 1)?;
+                if gcas.is_empty() {
+                    return Ok(None);
+                } else {
+                    new_revs.extend(gcas);
+                }
+            }
+            // gca_revs needs de-duplicated revs to work.
+            new_revs.sort_unstable();
+            new_revs.dedup();
+            revs = new_revs;
+        }
+        if revs.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.vertex_name(Id(revs[0] as _)).await?))
+        }
+    }
+
+    /// Calculates all "greatest common ancestor"s of the given set.
+    /// `gca_one` is faster if an arbitrary answer is ok.
+    async fn gca_all(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        // XXX: Limited by gca_revs implementation detail.
+        if id_set.count() > 6 {
+            return Err(Error::Unsupported(format!(
+                "RevlogIndex::gca_all does not support set with > 6 items, got {} items",
+                id_set.count()
+            ))
+            .into());
+        }
+        let revs: Vec<u32> = id_set.iter_desc().map(|id| id.0 as u32).collect();
+        let gcas = self.gca_revs(&revs, usize::max_value())?;
+        let spans = IdSet::from_spans(gcas.into_iter().map(|r| Id(r as _)));
+        let result = Set::from_spans_dag(spans, self)?;
+        Ok(result)
+    }
+
+    /// Tests if `ancestor` is an ancestor of `descendant`.
+    async fn is_ancestor(&self, ancestor: Vertex, descendant: Vertex) -> dag::Result<bool> {
+        let ancestor_rev = self.vertex_id(ancestor).await?.0 as u32;
+        let descendant_rev = self.vertex_id(descendant).await?.0 as u32;
+        if ancestor_rev == descendant_rev {
+            return Ok(true);
+        }
+        Ok(self.gca_revs(&[ancestor_rev, descendant_rev], 1)?.get(0) == Some(&ancestor_rev))
+    }
+
+    /// Calculates "heads" of the ancestors of the given set. That is,
+    /// Find Y, which is the smallest subset of set X, where `ancestors(Y)` is
+    /// `ancestors(X)`.
+    async fn heads_ancestors(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let min_rev = id_set.min().unwrap().0 as usize;
+        let max_rev = id_set.max().unwrap().0 as usize;
+        if max_rev >= self.len() {
+            return Id(max_rev as _).not_found();
+        }
+        let state_len = max_rev - min_rev + 1;
+
+        #[repr(u8)]
+        #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+        enum State {
+            Unspecified,
+            PotentialHead,
+            NotHead,
+        }
+        let mut states = if id_set.count() as usize == state_len {
+            // Fast path: entire range of states are "Unspecified".
+            vec![State::PotentialHead; state_len]
+        } else {
+            let mut states = vec![State::Unspecified; state_len];
+            for id in id_set {
+                states[id.0 as usize - min_rev] = State::PotentialHead;
+            }
+            states
+        };
+
+        let mut result_id_set = IdSet::empty();
+        for i in (0..states.len()).rev() {
+            self.check_interrupted(i + min_rev)?;
+            let state = states[i];
+            match state {
+                State::Unspecified => {}
+                State::PotentialHead | State::NotHead => {
+                    let rev = i + min_rev;
+                    if state == State::PotentialHead {
+                        result_id_set.push(Id(rev as _));
+                    }
+                    for &parent_rev in self.parent_revs(rev as u32)?.as_revs() {
+                        if parent_rev as usize >= min_rev {
+                            states[parent_rev as usize - min_rev] = State::NotHead;
+                        }
+                    }
+                }
+            }
+        }
+        let result = Set::from_spans_dag(result_id_set, self)?;
+        Ok(result)
+    }
+
+    /// Calculate the heads of the set.
+    async fn heads(&self, set: Set) -> dag::Result<Set> {
+        if set.hints().contains(Flags::ANCESTORS) {
+            self.heads_ancestors(set).await
+        } else {
+            Ok(set.clone() - self.parents(set).await?)
+        }
+    }
+
+    /// Calculates the "dag range" - vertexes reachable from both sides.
+    async fn range(&self, roots: Set, heads: Set) -> dag::Result<Set> {
+        let root_ids = self.to_id_set(&roots).await?;
+        let head_ids = self.to_id_set(&heads).await?;
+        let root_revs: Vec<u32> = root_ids.into_iter().map(|i| i.0 as u32).collect();
+        let head_revs: Vec<u32> = head_ids.into_iter().map(|i| i.0 as u32).collect();
+        let result_revs = self.range_revs(&root_revs, &head_revs)?;
+        let result_id_set = IdSet::from_spans(result_revs.into_iter().map(|r| Id(r as _)));
+        let result = Set::from_spans_dag(result_id_set, self)?;
+        Ok(result)
+    }
+
+    /// Calculate `::reachable - ::unreachable`.
+    async fn only(&self, reachable: Set, unreachable: Set) -> dag::Result<Set> {
+        let reachable_ids = self.to_id_set(&reachable).await?;
+        let unreachable_ids = self.to_id_set(&unreachable).await?;
+
+        if reachable_ids.is_empty() {
+            return Ok(Set::empty());
+        } else if unreachable_ids.is_empty() {
+            return self.ancestors(reachable).await;
+        }
+
+        let max_id = reachable_ids
+            .max()
+            .unwrap()
+            .max(unreachable_ids.max().unwrap());
+
+        // bits[i*2]: true if i is reachable from "reachable".
+        // bits[i*2+1]: true if i is reachable from "unreachable".
+        let mut bits = BitVec::from_elem(((max_id.0 + 1) * 2) as usize, false);
+
+        // set "unreachable" heads.
+        for id in unreachable_ids.iter_desc() {
+            bits.set((id.0 * 2 + 1) as usize, true);
+        }
+
+        // alive: count of "id"s that might belong to the result set but haven't
+        // been added to the result set yet.  alive == 0 indicates there is no
+        // need to check more ids.
+        let mut alive = 0;
+        for id in reachable_ids.iter_desc() {
+            if !bits[(id.0 * 2 + 1) as _] {
+                bits.set((id.0 * 2) as usize, true);
+                alive += 1;
+            }
+        }
+
+        let mut result = IdSet::empty();
+        for rev in (0..=max_id.0 as usize).rev() {
+            let is_reachable = bits[rev * 2];
+            let is_unreachable = bits[rev * 2 + 1];
+            if is_unreachable {
+                // Update unreachable's parents to unreachable.
+                for p in self.parent_revs(rev as u32)?.as_revs() {
+                    bits.set((p * 2 + 1) as usize, true);
+                }
+            } else if is_reachable {
+                // Push to result - only reachable from 'reachable'.
+                result.push(Id(rev as _));
+                // Parents might belong to the result set.
+                for p in self.parent_revs(rev as u32)?.as_revs() {
+                    let i = (p * 2) as usize;
+                    if !bits[i] && !bits[i + 1] {
+                        bits.set(i, true);
+                        alive += 1;
+                    }
+                }
+            }
+            if is_reachable {
+                // This rev is processed. It's no longer a "potential".
+                alive -= 1;
+            }
+            if alive == 0 {
+                break;
+            }
+        }
+
+        let result = Set::from_spans_dag(result, self)?;
+        Ok(result)
+    }
+
+    /// Calculate `::reachable - ::unreachable` and `::unreachable`.
+    async fn only_both(&self, reachable: Set, unreachable: Set) -> dag::Result<(Set, Set)> {
+        let hints_ancestors_of_unreachable = unreachable.hints().clone();
+        hints_ancestors_of_unreachable.update_flags_with(|f| f | Flags::ANCESTORS | Flags::ID_DESC);
+
+        let reachable_ids = self.to_id_set(&reachable).await?;
+        let unreachable_ids = self.to_id_set(&unreachable).await?;
+        let reachable_revs = reachable_ids.into_iter().map(|i| i.0 as u32);
+        let unreachable_revs = unreachable_ids.into_iter().map(|i| i.0 as u32);
+
+        // Used internally. Different from "phases.py".
+        #[repr(u8)]
+        #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
+        enum Phase {
+            Unspecified,
+            Draft,
+            Public,
+        }
+        impl Phase {
+            fn max(self, other: Phase) -> Phase {
+                if self > other { self } else { other }
+            }
+        }
+
+        struct OnlyBothState {
+            // Track the last_rev so we can resume from that location.
+            last_rev: u64,
+            // Track how many draft commits are remaining so we can stop processing after we've
+            // seen them. (Unless the limit rev indicates we should go farther).
+            remaining_draft: u64,
+            reachable_set: IdSet,
+            unreachable_set: IdSet,
+            phases: Vec<Phase>,
+            dag: Arc<RevlogIndex>,
+        }
+        let mut state = OnlyBothState {
+            last_rev: self.len() as u64,
+            remaining_draft: 0,
+            reachable_set: IdSet::empty(),
+            unreachable_set: IdSet::empty(),
+            phases: vec![Phase::Unspecified; self.len()],
+            dag: self.get_snapshot(),
+        };
+
+        for rev in reachable_revs {
+            state.phases[rev as usize] = Phase::Draft;
+            state.remaining_draft += 1;
+        }
+        for rev in unreachable_revs {
+            if state.phases[rev as usize] == Phase::Draft {
+                state.remaining_draft -= 1;
+            }
+            state.phases[rev as usize] = Phase::Public;
+        }
+
+        let arc_state = Arc::new(Mutex::new(state));
+
+        let evaluate = Arc::new(
+            move |limit_rev: u64| -> dag::Result<Arc<Mutex<OnlyBothState>>> {
+                let mut guard = arc_state.lock();
+                let state = &mut guard;
+
+                // If we've already processed the requested rev, and we don't have any draft
+                // commits remaining, exit early.
+                if limit_rev >= state.last_rev && state.remaining_draft == 0 {
+                    return Ok(arc_state.clone());
+                }
+
+                // Track the spans of public commits manually, instead of relying on constantly adding them
+                // to a span. This is a hotpath and this optimization can save 100+ms.
+                let mut start_public: i64 = -1;
+                for rev in (0..state.last_rev).rev() {
+                    state.last_rev = rev;
+
+                    let phase = state.phases[rev as usize];
+                    match phase {
+                        Phase::Public => {
+                            // Record the start of a public span.
+                            if start_public == -1 {
+                                start_public = rev as i64;
+                            }
+                        }
+                        _ => {
+                            // Record the end of a public span.
+                            if start_public != -1 {
+                                // Start is the end of the range because we're iterating in reverse order. So
+                                // start is the later revision.
+                                let span = Id(rev + 1)..=Id(start_public as u64);
+                                state.unreachable_set.push(span);
+                                start_public = -1;
+                            }
+                            if phase == Phase::Draft {
+                                state.remaining_draft -= 1;
+                                state.reachable_set.push(Id(rev));
+                            }
+                        }
+                    }
+                    for &parent_rev in state.dag.parent_revs(rev as u32)?.as_revs() {
+                        // Propagate phases from this rev to its parents, tracking changes to the number of
+                        // remaining drafts as we go.
+                        let old_parent_phase = state.phases[parent_rev as usize];
+                        let new_parent_phase = old_parent_phase.max(phase);
+                        if new_parent_phase == Phase::Draft && old_parent_phase != Phase::Draft {
+                            state.remaining_draft += 1;
+                        }
+                        if new_parent_phase == Phase::Public && old_parent_phase == Phase::Draft {
+                            state.remaining_draft -= 1;
+                        }
+                        state.phases[parent_rev as usize] = new_parent_phase;
+                    }
+
+                    if rev <= limit_rev && state.remaining_draft == 0 {
+                        break;
+                    }
+                }
+
+                // Record any final public spans
+                if start_public != -1 {
+                    let last_rev = state.last_rev;
+                    state
+                        .unreachable_set
+                        .push(Id(last_rev)..=Id(start_public as u64));
+                }
+
+                Ok(arc_state.clone())
+            },
+        );
+
+        let dag = self.get_snapshot();
+
+        // Kick off an initial evaluate to process all the draft commits.
+        let state = (evaluate)(self.len() as u64)?;
+        let reachable_set =
+            Set::from_spans_idmap_dag(state.lock().reachable_set.clone(), dag.clone(), dag.clone());
+
+        let eval_contains = evaluate.clone();
+        let is_public = move |_: &MetaSet, v: &Vertex| -> dag::Result<bool> {
+            let id = match non_blocking_result(dag.vertex_id(v.clone())) {
+                Ok(id) => id,
+                Err(DagError::VertexNotFound(_)) => return Ok(false),
+                Err(e) => {
+                    return Err(e);
+                }
+            };
+            Ok((eval_contains)(id.0)?.lock().phases[id.0 as usize] == Phase::Public)
+        };
+        let unreachable_set = Set::from_evaluate_contains(
+            move || {
+                let state = (evaluate)(0)?;
+                let guard = state.lock();
+
+                Ok(Set::from_spans_idmap_dag(
+                    guard.unreachable_set.clone(),
+                    guard.dag.clone(),
+                    guard.dag.clone(),
+                ))
+            },
+            is_public,
+            hints_ancestors_of_unreachable,
+        );
+        Ok((reachable_set, unreachable_set))
+    }
+
+    /// Calculates the descendants of the given set.
+    async fn descendants(&self, set: Set) -> dag::Result<Set> {
+        let id_set = self.to_id_set(&set).await?;
+        if id_set.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let min_id = id_set.min().unwrap();
+        let min_rev = min_id.0 as u32;
+        let dag = self.get_snapshot();
+        let mut included = BitVec::from_elem(dag.len() - min_id.0 as usize, false);
+        for id in id_set.into_iter() {
+            included.set(id.0 as usize - min_id.0 as usize, true);
+        }
+
+        let iter = (min_rev..(dag.len() as u32)).filter_map(move |rev| {
+            if let Err(err) = dag.check_interrupted(rev as usize) {
+                return Some(Err(err));
+            }
+            let should_include = included[(rev - min_rev) as usize] || {
+                let parent_revs = match dag.parent_revs(rev) {
+                    Ok(revs) => revs,
+                    Err(err) => return Some(Err(err)),
+                };
+                parent_revs
+                    .as_revs()
+                    .iter()
+                    .any(|&prev| prev >= min_rev && included[(prev - min_rev) as usize])
+            };
+            if should_include {
+                included.set((rev - min_rev) as usize, true);
+                Some(Ok(Id(rev as _)))
+            } else {
+                None
+            }
+        });
+
+        let set = Set::from_id_iter_dag(iter, self)?;
+        set.hints().add_flags(Flags::ID_ASC).set_min_id(min_id);
+        Ok(set)
+    }
+
+    async fn reachable_roots(&self, roots: Set, heads: Set) -> dag::Result<Set> {
+        let id_roots = self.to_id_set(&roots).await?;
+        if id_roots.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let id_heads = self.to_id_set(&heads).await?;
+        if id_heads.is_empty() {
+            return Ok(Set::empty());
+        }
+
+        let max_rev = id_heads.max().unwrap().0;
+        let min_rev = id_roots.min().unwrap().0;
+        if max_rev < min_rev {
+            return Ok(Set::empty());
+        }
+        let mut reachable = BitVec::from_elem((max_rev + 1 - min_rev) as usize, false);
+        let mut is_root = BitVec::from_elem((max_rev + 1 - min_rev) as usize, false);
+        let mut result = IdSet::empty();
+
+        // alive: count of "id"s that have unexplored parents.
+        // alive == 0 indicates all parents are checked and iteration can be stopped.
+        let mut alive = 0;
+        for rev in id_heads.iter_desc() {
+            let rev = rev.0;
+            if rev <= max_rev && rev >= min_rev {
+                reachable.set((rev - min_rev) as _, true);
+                alive += 1;
+            }
+        }
+        for rev in id_roots.iter_desc() {
+            let rev = rev.0;
+            if rev <= max_rev && rev >= min_rev {
+                is_root.set((rev - min_rev) as _, true);
+            }
+        }
+
+        for rev in (min_rev..=max_rev).rev() {
+            if alive == 0 {
+                break;
+            }
+            if !reachable[(rev - min_rev) as _] {
+                continue;
+            }
+            alive -= 1;
+            if is_root[(rev - min_rev) as _] {
+                result.push(Id(rev as _));
+                continue;
+            }
+            let parent_revs = self.parent_revs(rev as _)?;
+            for parent_rev in parent_revs.as_revs() {
+                let parent_rev = *parent_rev as u64;
+                if parent_rev >= min_rev as _ && parent_rev <= max_rev as _ {
+                    let idx = (parent_rev - min_rev) as usize;
+                    if !reachable[idx] {
+                        reachable.set(idx, true);
+                        alive += 1;
+                    }
+                }
+            }
+        }
+
+        let result = Set::from_spans_dag(result, self)?;
+        Ok(result)
+    }
+
+    fn is_vertex_lazy(&self) -> bool {
+        false
+    }
+
+    fn dag_snapshot(&self) -> dag::Result<Arc<dyn DagAlgorithm + Send + Sync>> {
+        Ok(self.get_snapshot())
+    }
+
+    fn dag_id(&self) -> &str {
+        &self.id
+    }
+
+    fn dag_version(&self) -> &VerLink {
+        &self.version
+    }
+}
+
+impl IdMapSnapshot for RevlogIndex {
+    fn id_map_snapshot(&self) -> dag::Result<Arc<dyn IdConvert + Send + Sync>> {
+        Ok(self.get_snapshot())
+    }
+}
+
+impl RevlogIndex {
+    fn add_heads_for_testing(
+        &mut self,
+        parents_func: &dyn Parents,
+        heads: &VertexListWithOptions,
+    ) -> dag::Result<bool> {
+        if !cfg!(test) {
+            panic!(
+                "add_heads should only works for testing \
+                   because it uses dummy commit message and \
+                   revlog does not support separating commit \
+                   messages from the graph"
+            );
+        }
+
+        let mut updated = false;
+        // Update IdMap. Keep track of what heads are added.
+        for head in heads.vertexes() {
+            if !non_blocking_result(self.contains_vertex_name(&head))? {
+                let parents = non_blocking_result(parents_func.parent_names(head.clone()))?;
+                for parent in parents.iter() {
+                    self.add_heads_for_testing(parents_func, &vec![parent.clone()].into())?;
+                }
+                if !non_blocking_result(self.contains_vertex_name(&head))? {
+                    let parent_revs: Vec<u32> = parents
+                        .iter()
+                        .map(|p| non_blocking_result(self.vertex_id(p.clone())).unwrap().0 as u32)
+                        .collect();
+                    if parent_revs.len() > 2 {
+                        return Err(Error::Unsupported(format!(
+                            "revlog does not support > 2 parents (when inserting {:?})",
+                            &head
+                        ))
+                        .into());
+                    }
+                    let text = Bytes::from_static(b"DUMMY COMMIT MESSAGE FOR TESTING");
+                    self.insert(head.clone(), parent_revs, text)?;
+                    updated = true;
+                }
+            }
+        }
+
+        Ok(updated)
+    }
+}
+
+#[async_trait::async_trait]
+impl DagAddHeads for RevlogIndex {
+    async fn add_heads(
+        &mut self,
+        parents_func: &dyn Parents,
+        heads: &VertexListWithOptions,
+    ) -> dag::Result<bool> {
+        self.add_heads_for_testing(parents_func, heads)
+    }
+}
+
+#[async_trait::async_trait]
+impl CheckIntegrity for RevlogIndex {
+    async fn check_universal_ids(&self) -> dag::Result<Vec<Id>> {
+        unsupported_dag_error()
+    }
+
+    async fn check_segments(&self) -> dag::Result<Vec<String>> {
+        unsupported_dag_error()
+    }
+
+    async fn check_isomorphic_graph(
+        &self,
+        other: &dyn DagAlgorithm,
+        heads: dag::NameSet,
+    ) -> dag::Result<Vec<String>> {
+        let _ = (other, heads);
+        unsupported_dag_error()
+    }
+}
+
+fn unsupported_dag_error<T>() -> dag::Result<T> {
+    Err(dag::errors::BackendError::Generic("unsupported by revlog index".to_string()).into())
+}
+
+fn find_bytes_in_bytes(bytes: &[u8], to_find: &[u8]) -> Option<usize> {
+    bytes.windows(to_find.len()).position(|b| b == to_find)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+
+    use anyhow::Result;
+    use nonblocking::non_blocking_result as r;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_simple_3_commits() -> Result<()> {
+        // 00changelog.i and 00changelog.d are created by real hg commands.
+        let changelog_i = from_xxd(
+            r#"
+0000000: 0000 0001 0000 0000 0000 002c 0000 0048  ...........,...H
+0000010: 0000 0000 0000 0000 ffff ffff ffff ffff  ................
+0000020: 785b 4df8 9f24 f67f fdd3 eec2 2cc4 c51f  x[M..$......,...
+0000030: ca90 cc44 0000 0000 0000 0000 0000 0000  ...D............
+0000040: 0000 0000 002c 0000 0000 0044 0000 0083  .....,.....D....
+0000050: 0000 0001 0000 0001 0000 0000 ffff ffff  ................
+0000060: 4028 e6d9 355e 415f ef6b 7a2b f8cf 4b53  @(..5^A_.kz+..KS
+0000070: 0d66 2906 0000 0000 0000 0000 0000 0000  .f).............
+0000080: 0000 0000 0070 0000 0000 004b 0000 004a  .....p.....K...J
+0000090: 0000 0002 0000 0002 0000 0001 ffff ffff  ................
+00000a0: ab7a efa7 ec2d 5e85 0295 6b2c 8fb4 2590  .z...-^...k,..%.
+00000b0: 9c57 9822 0000 0000 0000 0000 0000 0000  .W."............
+"#,
+        );
+        let changelog_d = from_xxd(
+            r#"
+0000000: 3448 0000 001f 3001 0014 f011 0a74 6573  4H....0......tes
+0000010: 740a 3135 3936 3038 3335 3534 2032 3532  t.1596083554 252
+0000020: 3030 0a0a 636f 6d6d 6974 2031 3483 0000  00..commit 14...
+0000030: 001f 3001 0014 ff14 0a74 6573 740a 3135  ..0......test.15
+0000040: 3936 3038 3335 3834 2032 3532 3030 0a0a  96083584 25200..
+0000050: 636f 6d6d 6974 2032 3a20 3201 0015 f001  commit 2: 2.....
+0000060: 2069 7320 636f 6d70 7265 7373 6962 6c65   is compressible
+0000070: 7538 3531 3564 3462 6664 6137 3638 6530  u8515d4bfda768e0
+0000080: 3461 6634 6331 3361 3639 6137 3265 3238  4af4c13a69a72e28
+0000090: 6337 6566 6662 6561 370a 7465 7374 0a31  c7effbea7.test.1
+00000a0: 3539 3630 3833 3631 3420 3235 3230 300a  596083614 25200.
+00000b0: 610a 0a63 6f6d 6d69 7420 33              a..commit 3
+"#,
+        );
+
+        let dir = tempdir()?;
+        let dir = dir.path();
+        let changelog_i_path = dir.join("00changelog.i");
+        fs::write(&changelog_i_path, changelog_i)?;
+        let changelog_d_path = dir.join("00changelog.d");
+        fs::write(&changelog_d_path, changelog_d)?;
+        let nodemap_path = dir.join("00changelog.nodemap");
+        let index = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+
+        // Read parents.
+        assert_eq!(index.parent_revs(0)?, ParentRevs::Compact([-1, -1]));
+        assert_eq!(index.parent_revs(1)?, ParentRevs::Compact([0, -1]));
+        assert_eq!(index.parent_revs(2)?, ParentRevs::Compact([1, -1]));
+
+        // Read commit data.
+        let read = |rev: u32| -> Result<String> {
+            Ok(std::str::from_utf8(&index.raw_data(rev)?)?.to_string())
+        };
+        assert_eq!(
+            read(0)?,
+            "0000000000000000000000000000000000000000\ntest\n1596083554 25200\n\ncommit 1"
+        );
+        assert_eq!(
+            read(1)?,
+            r#"0000000000000000000000000000000000000000
+test
+1596083584 25200
+
+commit 2: 22222222222222222222222222222222222222222 is compressible"#
+        );
+        // commit 3 is not lz4 compressed.
+        assert_eq!(
+            read(2)?,
+            r#"8515d4bfda768e04af4c13a69a72e28c7effbea7
+test
+1596083614 25200
+a
+
+commit 3"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delta_application() -> Result<()> {
+        // util.py.i and util.py.d come from mercurial/util.py cloned from
+        // https://www.mercurial-scm.org/repo/hg with lz4revlog enabled.
+        // Truncated to first 3 revs.
+        let util_py_i = from_xxd(
+            r#"
+0000000: 0002 0001 0000 0000 0000 016c 0000 0199  ...........l....
+0000010: 0000 0000 0000 01a3 ffff ffff ffff ffff  ................
+0000020: 83c9 892d 2313 c88d c0ae d198 5930 91ea  ...-#.......Y0..
+0000030: 4c33 4f5f 0000 0000 0000 0000 0000 0000  L3O_............
+0000040: 0000 0000 016c 0000 0000 0071 0000 0230  .....l.....q...0
+0000050: 0000 0000 0000 01a5 0000 0000 ffff ffff  ................
+0000060: fa42 82a9 8aaf 93c9 0c4c 89d0 e835 336f  .B.......L...53o
+0000070: 875e f671 0000 0000 0000 0000 0000 0000  .^.q............
+0000080: 0000 0000 01dd 0000 0000 00d4 0000 0393  ................
+0000090: 0000 0001 0000 01a6 0000 0001 ffff ffff  ................
+00000a0: 3326 e39d 9781 4b34 7c5b 4542 fc55 65c9  3&....K4|[EB.Ue.
+00000b0: 7e6f b53d 0000 0000 0000 0000 0000 0000  ~o.=............
+00000c0: 0000 0000 02b1 0000 0000 013e 0000 05c5  ...........>....
+"#,
+        );
+        let util_py_d = from_xxd(
+            r#"
+0000000: 3499 0100 00b1 2320 7574 696c 2e70 7920  4.....# util.py 
+0000010: 2d0a 00f1 1e69 7479 2066 756e 6374 696f  -....ity functio
+0000020: 6e73 2061 6e64 2070 6c61 7466 6f72 6d20  ns and platform 
+0000030: 7370 6563 6669 6320 696d 706c 656d 656e  specfic implemen
+0000040: 7461 2500 f217 0a23 0a23 2043 6f70 7972  ta%....#.# Copyr
+0000050: 6967 6874 2032 3030 3520 4b2e 2054 6861  ight 2005 K. Tha
+0000060: 6e61 6e63 6861 7961 6e20 3c74 0e00 c16b  nanchayan <t...k
+0000070: 4079 6168 6f6f 2e63 6f6d 3e38 00f1 0a54  @yahoo.com>8...T
+0000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
+0000090: 2062 6520 7573 6564 7b00 9064 6973 7472   be used{..distr
+00000a0: 6962 7574 1000 f10b 6363 6f72 6469 6e67  ibut....ccording
+00000b0: 2074 6f20 7468 6520 7465 726d 730a 2320   to the terms.# 
+00000c0: 6f66 0f00 f016 474e 5520 4765 6e65 7261  of....GNU Genera
+00000d0: 6c20 5075 626c 6963 204c 6963 656e 7365  l Public License
+00000e0: 2c20 696e 636f 7270 6f72 6149 00f2 3c68  , incorporaI..<h
+00000f0: 6572 6569 6e20 6279 2072 6566 6572 656e  erein by referen
+0000100: 6365 2e0a 0a69 6d70 6f72 7420 6f73 0a0a  ce...import os..
+0000110: 6966 206f 732e 6e61 6d65 203d 3d20 276e  if os.name == 'n
+0000120: 7427 3a0a 2020 2020 6465 6620 7063 6f6e  t':.    def pcon
+0000130: 7665 7274 2870 6174 6829 1800 0001 0070  vert(path).....p
+0000140: 7265 7475 726e 2016 00ff 092e 7265 706c  return .....repl
+0000150: 6163 6528 225c 5c22 2c20 222f 2229 0a65  ace("\\", "/").e
+0000160: 6c73 6545 0017 5061 7468 0a0a 34a3 0000  lseE..Path..4...
+0000170: 0042 0000 0113 0400 f210 0097 6465 6620  .B..........def 
+0000180: 7265 6e61 6d65 2873 7263 2c20 6473 7429  rename(src, dst)
+0000190: 3a0a 2020 2020 7472 7909 0000 0100 3c6f  :.    try.....<o
+00001a0: 732e 2600 011c 0069 6578 6365 7074 2800  s.&....iexcept(.
+00001b0: 7575 6e6c 696e 6b28 2300 0f3f 0005 f00e  uunlink(#..?....
+00001c0: 0a23 2050 6c61 7466 6f72 2073 7065 6369  .# Platfor speci
+00001d0: 6669 6320 7661 7269 656e 7473 0a34 7b01  fic varients.4{.
+00001e0: 0000 4200 0001 fd04 00f3 1700 da0a 2020  ..B...........  
+00001f0: 2020 6465 6620 6d61 6b65 6c6f 636b 2869    def makelock(i
+0000200: 6e66 6f2c 2070 6174 686e 616d 6529 3a0a  nfo, pathname):.
+0000210: 2001 00d4 6c64 203d 206f 732e 6f70 656e   ...ld = os.open
+0000220: 2820 0010 2c12 0092 4f5f 4352 4541 5420  ( ..,...O_CREAT 
+0000230: 7c0d 0064 5752 4f4e 4c59 0e00 5545 5843  |..dWRONLY..UEXC
+0000240: 4c29 4500 d06f 732e 7772 6974 6528 6c64  L)E..os.write(ld
+0000250: 2c20 6b00 091b 0040 636c 6f73 1b00 2529  , k....@clos..%)
+0000260: 0a98 0041 7265 6164 9800 0f92 0000 b672  ...Aread.......r
+0000270: 6574 7572 6e20 6669 6c65 1f00 102e 3200  eturn file....2.
+0000280: 7228 290a 0000 0230 0400 2f00 89e5 0017  r()....0../.....
+0000290: 9d6f 732e 7379 6d6c 696e 2400 0f93 001a  .os.symlin$.....
+00002a0: 216f 7387 0001 4800 0326 0050 6529 0a0a  !os...H..&.Pe)..
+00002b0: 0a34 4a02 0000 4200 0001 be04 00f3 0e00  .4J...B.........
+00002c0: 5c20 2020 2064 6566 2069 735f 6578 6563  \    def is_exec
+"#,
+        );
+
+        let dir = tempdir()?;
+        let dir = dir.path();
+        let i_path = dir.join("util.py.i");
+        fs::write(&i_path, util_py_i)?;
+        let d_path = dir.join("util.py.d");
+        fs::write(&d_path, util_py_d)?;
+        let nodemap_path = dir.join("util.py.nodemap");
+        let index = RevlogIndex::new(&i_path, &nodemap_path)?;
+        let read = |rev: u32| index.raw_data(rev);
+
+        assert_eq!(
+            to_xxd(&read(0)?),
+            r#"
+00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
+00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
+00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
+00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
+00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
+00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
+00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
+00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
+00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
+00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
+000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
+000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
+000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
+000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
+000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
+000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
+00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
+00000110: 730a 0a69 6620 6f73 2e6e 616d 6520 3d3d  s..if os.name ==
+00000120: 2027 6e74 273a 0a20 2020 2064 6566 2070   'nt':.    def p
+00000130: 636f 6e76 6572 7428 7061 7468 293a 0a20  convert(path):. 
+00000140: 2020 2020 2020 2072 6574 7572 6e20 7061         return pa
+00000150: 7468 2e72 6570 6c61 6365 2822 5c5c 222c  th.replace("\\",
+00000160: 2022 2f22 290a 656c 7365 3a0a 2020 2020   "/").else:.    
+00000170: 6465 6620 7063 6f6e 7665 7274 2870 6174  def pconvert(pat
+00000180: 6829 3a0a 2020 2020 2020 2020 7265 7475  h):.        retu
+00000190: 726e 2070 6174 680a 0a                   rn path..
+"#
+        );
+        assert_eq!(
+            to_xxd(&read(1)?),
+            r#"
+00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
+00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
+00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
+00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
+00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
+00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
+00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
+00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
+00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
+00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
+000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
+000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
+000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
+000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
+000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
+000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
+00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
+00000110: 730a 0a64 6566 2072 656e 616d 6528 7372  s..def rename(sr
+00000120: 632c 2064 7374 293a 0a20 2020 2074 7279  c, dst):.    try
+00000130: 3a0a 2020 2020 2020 2020 6f73 2e72 656e  :.        os.ren
+00000140: 616d 6528 7372 632c 2064 7374 290a 2020  ame(src, dst).  
+00000150: 2020 6578 6365 7074 3a0a 2020 2020 2020    except:.      
+00000160: 2020 6f73 2e75 6e6c 696e 6b28 6473 7429    os.unlink(dst)
+00000170: 0a20 2020 2020 2020 206f 732e 7265 6e61  .        os.rena
+00000180: 6d65 2873 7263 2c20 6473 7429 0a0a 2320  me(src, dst)..# 
+00000190: 506c 6174 666f 7220 7370 6563 6966 6963  Platfor specific
+000001a0: 2076 6172 6965 6e74 730a 6966 206f 732e   varients.if os.
+000001b0: 6e61 6d65 203d 3d20 276e 7427 3a0a 2020  name == 'nt':.  
+000001c0: 2020 6465 6620 7063 6f6e 7665 7274 2870    def pconvert(p
+000001d0: 6174 6829 3a0a 2020 2020 2020 2020 7265  ath):.        re
+000001e0: 7475 726e 2070 6174 682e 7265 706c 6163  turn path.replac
+000001f0: 6528 225c 5c22 2c20 222f 2229 0a65 6c73  e("\\", "/").els
+00000200: 653a 0a20 2020 2064 6566 2070 636f 6e76  e:.    def pconv
+00000210: 6572 7428 7061 7468 293a 0a20 2020 2020  ert(path):.     
+00000220: 2020 2072 6574 7572 6e20 7061 7468 0a0a     return path..
+"#
+        );
+        assert_eq!(
+            to_xxd(&read(2)?),
+            r#"
+00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
+00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
+00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
+00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
+00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
+00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
+00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
+00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
+00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
+00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
+000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
+000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
+000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
+000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
+000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
+000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
+00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
+00000110: 730a 0a64 6566 2072 656e 616d 6528 7372  s..def rename(sr
+00000120: 632c 2064 7374 293a 0a20 2020 2074 7279  c, dst):.    try
+00000130: 3a0a 2020 2020 2020 2020 6f73 2e72 656e  :.        os.ren
+00000140: 616d 6528 7372 632c 2064 7374 290a 2020  ame(src, dst).  
+00000150: 2020 6578 6365 7074 3a0a 2020 2020 2020    except:.      
+00000160: 2020 6f73 2e75 6e6c 696e 6b28 6473 7429    os.unlink(dst)
+00000170: 0a20 2020 2020 2020 206f 732e 7265 6e61  .        os.rena
+00000180: 6d65 2873 7263 2c20 6473 7429 0a0a 2320  me(src, dst)..# 
+00000190: 506c 6174 666f 7220 7370 6563 6966 6963  Platfor specific
+000001a0: 2076 6172 6965 6e74 730a 6966 206f 732e   varients.if os.
+000001b0: 6e61 6d65 203d 3d20 276e 7427 3a0a 2020  name == 'nt':.  
+000001c0: 2020 6465 6620 7063 6f6e 7665 7274 2870    def pconvert(p
+000001d0: 6174 6829 3a0a 2020 2020 2020 2020 7265  ath):.        re
+000001e0: 7475 726e 2070 6174 682e 7265 706c 6163  turn path.replac
+000001f0: 6528 225c 5c22 2c20 222f 2229 0a0a 2020  e("\\", "/")..  
+00000200: 2020 6465 6620 6d61 6b65 6c6f 636b 2869    def makelock(i
+00000210: 6e66 6f2c 2070 6174 686e 616d 6529 3a0a  nfo, pathname):.
+00000220: 2020 2020 2020 2020 6c64 203d 206f 732e          ld = os.
+00000230: 6f70 656e 2870 6174 686e 616d 652c 206f  open(pathname, o
+00000240: 732e 4f5f 4352 4541 5420 7c20 6f73 2e4f  s.O_CREAT | os.O
+00000250: 5f57 524f 4e4c 5920 7c20 6f73 2e4f 5f45  _WRONLY | os.O_E
+00000260: 5843 4c29 0a20 2020 2020 2020 206f 732e  XCL).        os.
+00000270: 7772 6974 6528 6c64 2c20 696e 666f 290a  write(ld, info).
+00000280: 2020 2020 2020 2020 6f73 2e63 6c6f 7365          os.close
+00000290: 286c 6429 0a0a 2020 2020 6465 6620 7265  (ld)..    def re
+000002a0: 6164 6c6f 636b 2870 6174 686e 616d 6529  adlock(pathname)
+000002b0: 3a0a 2020 2020 2020 2020 7265 7475 726e  :.        return
+000002c0: 2066 696c 6528 7061 7468 6e61 6d65 292e   file(pathname).
+000002d0: 7265 6164 2829 0a65 6c73 653a 0a20 2020  read().else:.   
+000002e0: 2064 6566 2070 636f 6e76 6572 7428 7061   def pconvert(pa
+000002f0: 7468 293a 0a20 2020 2020 2020 2072 6574  th):.        ret
+00000300: 7572 6e20 7061 7468 0a0a 2020 2020 6465  urn path..    de
+00000310: 6620 6d61 6b65 6c6f 636b 2869 6e66 6f2c  f makelock(info,
+00000320: 2070 6174 686e 616d 6529 3a0a 2020 2020   pathname):.    
+00000330: 2020 2020 6f73 2e73 796d 6c69 6e6b 2869      os.symlink(i
+00000340: 6e66 6f2c 2070 6174 686e 616d 6529 0a0a  nfo, pathname)..
+00000350: 2020 2020 6465 6620 7265 6164 6c6f 636b      def readlock
+00000360: 2870 6174 686e 616d 6529 3a0a 2020 2020  (pathname):.    
+00000370: 2020 2020 7265 7475 726e 206f 732e 7265      return os.re
+00000380: 6164 6c69 6e6b 2870 6174 686e 616d 6529  adlink(pathname)
+00000390: 0a0a 0a                                  ...
+"#
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_octopus_merge() {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let changelog_i_path = dir.join("00changelog.i");
+        let nodemap_path = dir.join("00changelog.nodemap");
+
+        let mut rlog = RevlogIndex::new(&changelog_i_path, &nodemap_path).unwrap();
+        rlog.insert(v(0), vec![], b"A".to_vec().into()).unwrap(); // rev 0
+        rlog.insert(v(1), vec![], b"B".to_vec().into()).unwrap(); // rev 1
+        rlog.insert(v(2), vec![], b"C".to_vec().into()).unwrap(); // rev 2
+        rlog.insert(v(3), vec![], b"D".to_vec().into()).unwrap(); // rev 3
+        rlog.insert(
+            v(4),
+            vec![0, 3],
+            format!(
+                concat!(
+                    "deadbeef000000000000\n",
+                    "test <test@example.com>\n",
+                    "100 300 bar:foo\0stepparents:{},{}\0foo:bar\n",
+                    "foobar"
+                ),
+                v(2).to_hex(),
+                v(1).to_hex()
+            )
+            .as_bytes()
+            .to_vec()
+            .into(),
+        )
+        .unwrap(); // rev 4
+        rlog.flush().unwrap();
+
+        let rlog = RevlogIndex::new(&changelog_i_path, &nodemap_path).unwrap();
+        assert_eq!(rlog.parent_revs(4).unwrap().as_revs(), [0, 3, 2, 1]);
     }
 
-    /// Calculate `::reachable - ::unreachable`.
-    async fn only(&self, reachable: Set, unreachable: Set) -> dag::Result<Set> {
-        let reachable_ids = self.to_id_set(&reachable).await?;
-        let unreachable_ids = self.to_id_set(&unreachable).await?;
+    #[test]
+    fn test_flush() -> Result<()> {
+        let dir = tempdir()?;
+        let dir = dir.path();
+        let changelog_i_path = dir.join("00changelog.i");
+        let nodemap_path = dir.join("00changelog.nodemap");
 
-        if reachable_ids.is_empty() {
-            return Ok(Set::empty());
-        } else if unreachable_ids.is_empty() {
-            return self.ancestors(reachable).await;
-        }
+        let mut revlog1 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        let mut revlog2 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
 
-        let max_id = reachable_ids
-            .max()
-            .unwrap()
-            .max(unreachable_ids.max().unwrap());
+        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into())?; // rev 0
 
-        // bits[i*2]: true if i is reachable from "reachable".
-        // bits[i*2+1]: true if i is reachable from "unreachable".
-        let mut bits = BitVec::from_elem(((max_id.0 + 1) * 2) as usize, false);
+        // commit 2 is lz4-friendly.
+        let text = b"commit 2 (............................................)";
+        revlog1.insert(v(2), vec![0], text.to_vec().into())?; // rev 1, parent rev 0
 
-        // set "unreachable" heads.
-        for id in unreachable_ids.iter_desc() {
-            bits.set((id.0 * 2 + 1) as usize, true);
-        }
+        revlog2.insert(v(3), vec![], b"commit 3".to_vec().into())?; // rev 2, local 0
+        revlog2.insert(v(1), vec![], b"commit 1".to_vec().into())?; // duplicate with revlog1, local 1, rev 0
+        revlog2.insert(v(4), vec![0], b"commit 4".to_vec().into())?; // rev 3, local 2
+        revlog2.insert(v(5), vec![1, 0], b"commit 5".to_vec().into())?; // rev 4, local 3
 
-        // alive: count of "id"s that might belong to the result set but haven't
-        // been added to the result set yet.  alive == 0 indicates there is no
-        // need to check more ids.
-        let mut alive = 0;
-        for id in reachable_ids.iter_desc() {
-            if !bits[(id.0 * 2 + 1) as _] {
-                bits.set((id.0 * 2) as usize, true);
-                alive += 1;
-            }
-        }
+        // Inserting an existing node is ignored.
+        let old_len = revlog1.len();
+        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into())?; // rev 0
+        revlog1.insert(v(2), vec![0], text.to_vec().into())?; // rev 1
+        assert_eq!(revlog1.len(), old_len);
 
-        let mut result = IdSet::empty();
-        for rev in (0..=max_id.0 as usize).rev() {
-            let is_reachable = bits[rev * 2];
-            let is_unreachable = bits[rev * 2 + 1];
-            if is_unreachable {
-                // Update unreachable's parents to unreachable.
-                for p in self.parent_revs(rev as u32)?.as_revs() {
-                    bits.set((p * 2 + 1) as usize, true);
-                }
-            } else if is_reachable {
-                // Push to result - only reachable from 'reachable'.
-                result.push(Id(rev as _));
-                // Parents might belong to the result set.
-                for p in self.parent_revs(rev as u32)?.as_revs() {
-                    let i = (p * 2) as usize;
-                    if !bits[i] && !bits[i + 1] {
-                        bits.set(i, true);
-                        alive += 1;
-                    }
-                }
-            }
-            if is_reachable {
-                // This rev is processed. It's no longer a "potential".
-                alive -= 1;
-            }
-            if alive == 0 {
-                break;
-            }
-        }
+        assert_eq!(revlog1.flush()?, 2);
+        // "commit 1" was written by revlog1.
+        assert_eq!(revlog2.flush()?, 3);
 
-        let result = Set::from_spans_dag(result, self)?;
-        Ok(result)
-    }
+        // The second flush reloads data, without writing new data.
+        assert_eq!(revlog1.flush()?, 0);
+        assert_eq!(revlog2.flush()?, 0);
 
-    /// Calculate `::reachable - ::unreachable` and `::unreachable`.
-    async fn only_both(&self, reachable: Set, unreachable: Set) -> dag::Result<(Set, Set)> {
-        let hints_ancestors_of_unreachable = unreachable.hints().clone();
-        hints_ancestors_of_unreachable.update_flags_with(|f| f | Flags::ANCESTORS | Flags::ID_DESC);
+        // Read the flushed data into revlog3.
+        let revlog3 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
 
-        let reachable_ids = self.to_id_set(&reachable).await?;
-        let unreachable_ids = self.to_id_set(&unreachable).await?;
-        let reachable_revs = reachable_ids.into_iter().map(|i| i.0 as u32);
-        let unreachable_revs = unreachable_ids.into_iter().map(|i| i.0 as u32);
+        // Read parents.
+        assert_eq!(revlog3.parent_revs(0)?, ParentRevs::Compact([-1, -1]));
+        assert_eq!(revlog3.parent_revs(1)?, ParentRevs::Compact([0, -1]));
+        assert_eq!(revlog3.parent_revs(2)?, ParentRevs::Compact([-1, -1]));
+        assert_eq!(revlog3.parent_revs(3)?, ParentRevs::Compact([2, -1]));
+        assert_eq!(revlog3.parent_revs(4)?, ParentRevs::Compact([0, 2])); // 0: "commit 1"
 
-        // Used internally. Different from "phases.py".
-        #[repr(u8)]
-        #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-        enum Phase {
-            Unspecified,
-            Draft,
-            Public,
-        }
-        impl Phase {
-            fn max(self, other: Phase) -> Phase {
-                if self > other { self } else { other }
-            }
-        }
+        // Prefix lookup.
+        assert_eq!(r(revlog3.vertexes_by_hex_prefix(b"0303", 2))?, vec![v(3)]);
 
-        struct OnlyBothState {
-            // Track the last_rev so we can resume from that location.
-            last_rev: u64,
-            // Track how many draft commits are remaining so we can stop processing after we've
-            // seen them. (Unless the limit rev indicates we should go farther).
-            remaining_draft: u64,
-            reachable_set: IdSet,
-            unreachable_set: IdSet,
-            phases: Vec<Phase>,
-            dag: Arc<RevlogIndex>,
-        }
-        let mut state = OnlyBothState {
-            last_rev: self.len() as u64,
-            remaining_draft: 0,
-            reachable_set: IdSet::empty(),
-            unreachable_set: IdSet::empty(),
-            phases: vec![Phase::Unspecified; self.len()],
-            dag: self.get_snapshot(),
-        };
+        // Id - Vertex.
+        assert_eq!(r(revlog3.vertex_name(Id(2)))?, v(3));
+        assert_eq!(r(revlog3.vertex_id(v(3)))?, Id(2));
 
-        for rev in reachable_revs {
-            state.phases[rev as usize] = Phase::Draft;
-            state.remaining_draft += 1;
-        }
-        for rev in unreachable_revs {
-            if state.phases[rev as usize] == Phase::Draft {
-                state.remaining_draft -= 1;
+        // Read commit data.
+        let read = |rev: u32| -> Result<String> {
+            let raw = revlog3.raw_data(rev)?;
+            for index in vec![&revlog1, &revlog2] {
+                assert!(index.raw_data(rev)? == raw, "index read mismatch");
             }
-            state.phases[rev as usize] = Phase::Public;
-        }
+            Ok(std::str::from_utf8(&raw)?.to_string())
+        };
 
-        let arc_state = Arc::new(Mutex::new(state));
+        assert_eq!(read(0)?, "commit 1");
+        assert_eq!(
+            read(1)?,
+            "commit 2 (............................................)"
+        );
+        assert_eq!(read(2)?, "commit 3");
+        assert_eq!(read(3)?, "commit 4");
+        assert_eq!(read(4)?, "commit 5");
 
-        let evaluate = Arc::new(
-            move |limit_rev: u64| -> dag::Result<Arc<Mutex<OnlyBothState>>> {
-                let mut guard = arc_state.lock();
-                let state = &mut guard;
+        Ok(())
+    }
 
-                // If we've already processed the requested rev, and we don't have any draft
-                // commits remaining, exit early.
-                if limit_rev >= state.last_rev && state.remaining_draft == 0 {
-                    return Ok(arc_state.clone());
-                }
+    #[test]
+    fn test_phasesets() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 3 - 4
+        //      \
+        //       5 - 6
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[2], &[3], &[1], &[5]]);
+        let (public, draft, secret) = revlog.phasesets(vec![1], vec![3], vec![4, 6])?;
+        assert_eq!(format!("{:?}", public), "0 1");
+        assert_eq!(format!("{:?}", draft), "2 3");
+        assert_eq!(format!("{:?}", secret), "4 5 6");
+
+        // The most public phase wins.
+        let (public, draft, secret) = revlog.phasesets(vec![], vec![4], vec![4, 6])?;
+        assert!(public.is_empty());
+        assert_eq!(format!("{:?}", draft), "0..=4");
+        assert_eq!(format!("{:?}", secret), "5 6");
+        Ok(())
+    }
 
-                // Track the spans of public commits manually, instead of relying on constantly adding them
-                // to a span. This is a hotpath and this optimization can save 100+ms.
-                let mut start_public: i64 = -1;
-                for rev in (0..state.last_rev).rev() {
-                    state.last_rev = rev;
+    #[test]
+    fn test_phasesets_nodes() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 3
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[2]]);
+        revlog.flush()?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+
+        let (public, draft, secret) = revlog.phasesets_nodes(vec![1], vec![3], vec![4])?;
+        assert_eq!(public, vec![v(0), v(1)]);
+        assert_eq!(draft, vec![v(2), v(3)]);
+        assert_eq!(secret, vec![v(4)]);
+        Ok(())
+    }
 
-                    let phase = state.phases[rev as usize];
-                    match phase {
-                        Phase::Public => {
-                            // Record the start of a public span.
-                            if start_public == -1 {
-                                start_public = rev as i64;
-                            }
-                        }
-                        _ => {
-                            // Record the end of a public span.
-                            if start_public != -1 {
-                                // Start is the end of the range because we're iterating in reverse order. So
-                                // start is the later revision.
-                                let span = Id(rev + 1)..=Id(start_public as u64);
-                                state.unreachable_set.push(span);
-                                start_public = -1;
-                            }
-                            if phase == Phase::Draft {
-                                state.remaining_draft -= 1;
-                                state.reachable_set.push(Id(rev));
-                            }
-                        }
-                    }
-                    for &parent_rev in state.dag.parent_revs(rev as u32)?.as_revs() {
-                        // Propagate phases from this rev to its parents, tracking changes to the number of
-                        // remaining drafts as we go.
-                        let old_parent_phase = state.phases[parent_rev as usize];
-                        let new_parent_phase = old_parent_phase.max(phase);
-                        if new_parent_phase == Phase::Draft && old_parent_phase != Phase::Draft {
-                            state.remaining_draft += 1;
-                        }
-                        if new_parent_phase == Phase::Public && old_parent_phase == Phase::Draft {
-                            state.remaining_draft -= 1;
-                        }
-                        state.phases[parent_rev as usize] = new_parent_phase;
-                    }
+    #[test]
+    fn test_phasesets_from_roots() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 3 - 4
+        //      \         /
+        //       5 ----- 6 - 7
+        let revlog = revlog_from_parents(
+            dir.path(),
+            &[&[], &[0], &[1], &[2], &[3, 6], &[1], &[5], &[6]],
+        );
+        let (draft, secret) = revlog.phasesets_from_roots(vec![2], vec![6])?;
+        assert_eq!(format!("{:?}", draft), "2 3");
+        assert_eq!(format!("{:?}", secret), "4 6 7");
+
+        let (draft, secret) = revlog.phasesets_from_roots(vec![5], vec![])?;
+        assert_eq!(format!("{:?}", draft), "4..=7");
+        assert!(secret.is_empty());
+
+        let (draft, secret) = revlog.phasesets_from_roots(vec![], vec![])?;
+        assert!(draft.is_empty());
+        assert!(secret.is_empty());
+        assert!(revlog.phasesets_from_roots(vec![], vec![8]).is_err());
+        Ok(())
+    }
 
-                    if rev <= limit_rev && state.remaining_draft == 0 {
-                        break;
-                    }
-                }
+    #[test]
+    fn test_reachableroots() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5   6
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4], &[]]);
+
+        let reachable = revlog.reachableroots(vec![1, 4, 6], vec![3, 5], false)?;
+        assert_eq!(format!("{:?}", reachable), "1 4");
+        let reachable = revlog.reachableroots(vec![1, 4, 6], vec![3, 5], true)?;
+        assert_eq!(format!("{:?}", reachable), "1 3 4 5");
+        let reachable = revlog.reachableroots(vec![0], vec![3], true)?;
+        assert_eq!(format!("{:?}", reachable), "0..=3");
+
+        assert!(revlog.reachableroots(vec![5], vec![3], false)?.is_empty());
+        assert!(revlog.reachableroots(vec![], vec![3], true)?.is_empty());
+        assert!(revlog.reachableroots(vec![0], vec![7], false).is_err());
+        Ok(())
+    }
 
-                // Record any final public spans
-                if start_public != -1 {
-                    let last_rev = state.last_rev;
-                    state
-                        .unreachable_set
-                        .push(Id(last_rev)..=Id(start_public as u64));
-                }
+    #[test]
+    fn test_findmissingcommon() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 3 (local)
+        //      \
+        //       4 - 5 (remote)
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[2], &[1], &[4]]);
+        let (common, missing) = revlog.findmissingcommon(vec![3], vec![5])?;
+        assert_eq!(format!("{:?}", common), "0 1");
+        assert_eq!(format!("{:?}", missing), "4 5");
+
+        // Fully shared.
+        let (common, missing) = revlog.findmissingcommon(vec![3], vec![2])?;
+        assert_eq!(format!("{:?}", common), "0 1 2");
+        assert!(missing.is_empty());
+
+        // Fully disjoint.
+        let (common, missing) = revlog.findmissingcommon(vec![], vec![5])?;
+        assert!(common.is_empty());
+        assert_eq!(format!("{:?}", missing), "0 1 4 5");
+
+        // No remote heads.
+        let (common, missing) = revlog.findmissingcommon(vec![3], vec![])?;
+        assert!(common.is_empty());
+        assert!(missing.is_empty());
 
-                Ok(arc_state.clone())
-            },
-        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_headrevs() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5   6
+        let mut revlog =
+            revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4], &[]]);
+        revlog.flush()?;
+        assert_eq!(revlog.headrevs()?, vec![3, 5, 6]);
+
+        revlog.insert(v(7), vec![5], Bytes::from_static(b"commit 7"))?;
+        assert_eq!(revlog.headrevs()?, vec![3, 6, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parentsofset() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[1], &[3], &[2, 4]]);
+        assert_eq!(format!("{:?}", revlog.parentsofset(vec![5])?), "2 4");
+        assert_eq!(format!("{:?}", revlog.parentsofset(vec![2, 3, 4, 5])?), "1");
+        assert_eq!(format!("{:?}", revlog.parentsofset(vec![0])?), "");
+        assert!(revlog.parentsofset(vec![]).unwrap().is_empty());
+        assert!(revlog.parentsofset(vec![6]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ancestorsofset() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4   6
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[1], &[3], &[2, 4], &[]]);
+        assert_eq!(format!("{:?}", revlog.ancestorsofset(vec![5])?), "0..=5");
+        assert_eq!(format!("{:?}", revlog.ancestorsofset(vec![4, 2])?), "0..=4");
+        let ancestors = revlog.ancestorsofset(vec![3, 6])?;
+        assert_eq!(format!("{:?}", ancestors), "0 1 3 6");
+        assert!(revlog.ancestorsofset(vec![]).unwrap().is_empty());
+        assert!(revlog.ancestorsofset(vec![7]).is_err());
+        Ok(())
+    }
 
-        let dag = self.get_snapshot();
+    #[test]
+    fn test_descendantsofset() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4   6
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[1], &[3], &[2, 4], &[]]);
+        assert_eq!(format!("{:?}", revlog.descendantsofset(vec![0])?), "0..=5");
+        let descendants = revlog.descendantsofset(vec![4, 2])?;
+        assert_eq!(format!("{:?}", descendants), "2 4 5");
+        let descendants = revlog.descendantsofset(vec![3, 6])?;
+        assert_eq!(format!("{:?}", descendants), "3..=6");
+        assert!(revlog.descendantsofset(vec![]).unwrap().is_empty());
+        assert!(revlog.descendantsofset(vec![7]).is_err());
+        Ok(())
+    }
 
-        // Kick off an initial evaluate to process all the draft commits.
-        let state = (evaluate)(self.len() as u64)?;
-        let reachable_set =
-            Set::from_spans_idmap_dag(state.lock().reachable_set.clone(), dag.clone(), dag.clone());
+    #[test]
+    fn test_lowestcommonancestor_fast() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4 - 6
+        //
+        // 7 (disjoint)
+        let revlog = revlog_from_parents(
+            dir.path(),
+            &[&[], &[0], &[1], &[1], &[3], &[2, 4], &[4], &[]],
+        );
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![5, 6])?, Some(4));
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![2, 6])?, Some(1));
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![2, 5, 6])?, Some(1));
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![5])?, Some(5));
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![5, 7])?, None);
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![])?, None);
+        assert!(revlog.lowestcommonancestor_fast(vec![8]).is_err());
+        Ok(())
+    }
 
-        let eval_contains = evaluate.clone();
-        let is_public = move |_: &MetaSet, v: &Vertex| -> dag::Result<bool> {
-            let id = match non_blocking_result(dag.vertex_id(v.clone())) {
-                Ok(id) => id,
-                Err(DagError::VertexNotFound(_)) => return Ok(false),
-                Err(e) => {
-                    return Err(e);
-                }
-            };
-            Ok((eval_contains)(id.0)?.lock().phases[id.0 as usize] == Phase::Public)
-        };
-        let unreachable_set = Set::from_evaluate_contains(
-            move || {
-                let state = (evaluate)(0)?;
-                let guard = state.lock();
+    #[test]
+    fn test_set_inserted_parents() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+
+        revlog.set_inserted_parents(4, vec![0, 2])?;
+        assert_eq!(revlog.parent_revs(4)?.as_revs(), &[0, 2]);
+        assert_eq!(r(revlog.parent_names(v(4)))?, vec![v(0), v(2)]);
+
+        // On-disk revisions are immutable.
+        assert!(revlog.set_inserted_parents(2, vec![0]).is_err());
+        // Parents must be lower than the revision.
+        assert!(revlog.set_inserted_parents(4, vec![4]).is_err());
+        assert!(revlog.set_inserted_parents(3, vec![4]).is_err());
+        // Unknown revision.
+        assert!(revlog.set_inserted_parents(5, vec![0]).is_err());
+
+        revlog.flush()?;
+        assert_eq!(revlog.parent_revs(4)?.as_revs(), &[0, 2]);
+        Ok(())
+    }
 
-                Ok(Set::from_spans_idmap_dag(
-                    guard.unreachable_set.clone(),
-                    guard.dag.clone(),
-                    guard.dag.clone(),
-                ))
-            },
-            is_public,
-            hints_ancestors_of_unreachable,
+    #[test]
+    fn test_descendant_of_any() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4 - 6
+        let mut revlog =
+            revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[1], &[3], &[2, 4], &[4]]);
+        revlog.flush()?;
+        // 7 is pending.
+        revlog.insert(v(7), vec![6], Bytes::from_static(b"commit 7"))?;
+
+        let candidates = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(
+            revlog.descendant_of_any(candidates.clone(), vec![3])?,
+            vec![false, false, false, true, true, true, true, true]
         );
-        Ok((reachable_set, unreachable_set))
+        assert_eq!(
+            revlog.descendant_of_any(candidates.clone(), vec![2, 6])?,
+            vec![false, false, true, false, false, true, true, true]
+        );
+        assert_eq!(
+            revlog.descendant_of_any(candidates, vec![])?,
+            vec![false; 8]
+        );
+        assert!(revlog.descendant_of_any(vec![8], vec![0]).is_err());
+        assert!(revlog.descendant_of_any(vec![0], vec![8]).is_err());
+        Ok(())
     }
 
-    /// Calculates the descendants of the given set.
-    async fn descendants(&self, set: Set) -> dag::Result<Set> {
-        let id_set = self.to_id_set(&set).await?;
-        if id_set.is_empty() {
-            return Ok(Set::empty());
-        }
+    #[test]
+    fn test_insert_rejects_invalid_parents() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0]]);
 
-        let min_id = id_set.min().unwrap();
-        let min_rev = min_id.0 as u32;
-        let dag = self.get_snapshot();
-        let mut included = BitVec::from_elem(dag.len() - min_id.0 as usize, false);
-        for id in id_set.into_iter() {
-            included.set(id.0 as usize - min_id.0 as usize, true);
-        }
+        let text = Bytes::from_static(b"commit 2");
 
-        let iter = (min_rev..(dag.len() as u32)).filter_map(move |rev| {
-            let should_include = included[(rev - min_rev) as usize] || {
-                let parent_revs = match dag.parent_revs(rev) {
-                    Ok(revs) => revs,
-                    Err(err) => return Some(Err(err)),
-                };
-                parent_revs
-                    .as_revs()
-                    .iter()
-                    .any(|&prev| prev >= min_rev && included[(prev - min_rev) as usize])
-            };
-            if should_include {
-                included.set((rev - min_rev) as usize, true);
-                Some(Ok(Id(rev as _)))
-            } else {
-                None
-            }
-        });
+        // Parent referring to the revision being inserted.
+        assert!(revlog.insert(v(2), vec![2], text.clone()).is_err());
+        // Parent referring to a revision that does not exist yet.
+        assert!(revlog.insert(v(2), vec![0, 5], text.clone()).is_err());
+        assert_eq!(revlog.len(), 2);
 
-        let set = Set::from_id_iter_dag(iter, self)?;
-        set.hints().add_flags(Flags::ID_ASC).set_min_id(min_id);
-        Ok(set)
+        revlog.insert(v(2), vec![1, 0], text)?;
+        assert_eq!(revlog.len(), 3);
+        assert_eq!(revlog.parent_revs(2)?.as_revs(), &[1, 0]);
+        Ok(())
     }
 
-    async fn reachable_roots(&self, roots: Set, heads: Set) -> dag::Result<Set> {
-        let id_roots = self.to_id_set(&roots).await?;
-        if id_roots.is_empty() {
-            return Ok(Set::empty());
-        }
-
-        let id_heads = self.to_id_set(&heads).await?;
-        if id_heads.is_empty() {
-            return Ok(Set::empty());
-        }
+    #[test]
+    fn test_truncate_pending() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0]]);
+        revlog.flush()?;
+        revlog.insert(v(2), vec![1], Bytes::from_static(b"commit 2"))?;
+        let marker = revlog.pending_len();
+        assert_eq!(marker, 1);
+
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+        assert_eq!(revlog.len(), 5);
+        assert_eq!(r(r(revlog.all())?.count())?, 5);
+        assert!(revlog.truncate_pending(4).is_err());
+
+        revlog.truncate_pending(marker)?;
+        assert_eq!(revlog.len(), 3);
+        assert_eq!(r(r(revlog.all())?.count())?, 3);
+        assert!(!r(revlog.contains_vertex_name(&v(3)))?);
+        assert!(!r(revlog.contains_vertex_name(&v(4)))?);
+        assert_eq!(r(revlog.vertex_id(v(2)))?, Id(2));
+
+        // Dropped nodes can be inserted again.
+        revlog.insert(v(4), vec![1], Bytes::from_static(b"commit 4"))?;
+        assert_eq!(r(revlog.vertex_id(v(4)))?, Id(3));
+        Ok(())
+    }
 
-        let max_rev = id_heads.max().unwrap().0;
-        let min_rev = id_roots.min().unwrap().0;
-        if max_rev < min_rev {
-            return Ok(Set::empty());
-        }
-        let mut reachable = BitVec::from_elem((max_rev + 1 - min_rev) as usize, false);
-        let mut is_root = BitVec::from_elem((max_rev + 1 - min_rev) as usize, false);
-        let mut result = IdSet::empty();
+    #[test]
+    fn test_ancestors_or_equal() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 5
+        //      \     /
+        //       3 - 4 - 6
+        let mut revlog =
+            revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[1], &[3], &[2, 4], &[4]]);
+        revlog.flush()?;
+        // 7 is pending.
+        revlog.insert(v(7), vec![6], Bytes::from_static(b"commit 7"))?;
+
+        let candidates = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        assert_eq!(
+            revlog.ancestors_or_equal(5, candidates.clone())?,
+            vec![true, true, true, true, true, true, false, false]
+        );
+        assert_eq!(
+            revlog.ancestors_or_equal(7, candidates)?,
+            vec![true, true, false, true, true, false, true, true]
+        );
+        assert_eq!(
+            revlog.ancestors_or_equal(2, vec![6, 3, 2])?,
+            vec![false, false, true]
+        );
+        assert_eq!(
+            revlog.ancestors_or_equal(2, vec![5, 6])?,
+            vec![false, false]
+        );
+        assert!(revlog.ancestors_or_equal(2, vec![])?.is_empty());
+        assert!(revlog.ancestors_or_equal(2, vec![8]).is_err());
+        assert!(revlog.ancestors_or_equal(8, vec![0]).is_err());
+        Ok(())
+    }
 
-        // alive: count of "id"s that have unexplored parents.
-        // alive == 0 indicates all parents are checked and iteration can be stopped.
-        let mut alive = 0;
-        for rev in id_heads.iter_desc() {
-            let rev = rev.0;
-            if rev <= max_rev && rev >= min_rev {
-                reachable.set((rev - min_rev) as _, true);
-                alive += 1;
-            }
-        }
-        for rev in id_roots.iter_desc() {
-            let rev = rev.0;
-            if rev <= max_rev && rev >= min_rev {
-                is_root.set((rev - min_rev) as _, true);
-            }
-        }
+    #[test]
+    fn test_ancestornodes() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2
+        //  \
+        //   3 - 4
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1], &[0], &[3]]);
+        revlog.flush()?;
+        // 5 is pending.
+        revlog.insert(v(5), vec![2, 4], Bytes::from_static(b"commit 5"))?;
+
+        assert_eq!(revlog.ancestornodes(vec![2])?, vec![v(2), v(1), v(0)]);
+        assert_eq!(
+            revlog.ancestornodes(vec![4, 1])?,
+            vec![v(4), v(3), v(1), v(0)]
+        );
+        assert_eq!(
+            revlog.ancestornodes(vec![5])?,
+            vec![v(5), v(4), v(3), v(2), v(1), v(0)]
+        );
+        assert!(revlog.ancestornodes(vec![])?.is_empty());
+        assert!(revlog.ancestornodes(vec![6]).is_err());
+        Ok(())
+    }
 
-        for rev in (min_rev..=max_rev).rev() {
-            if alive == 0 {
-                break;
-            }
-            if !reachable[(rev - min_rev) as _] {
-                continue;
-            }
-            alive -= 1;
-            if is_root[(rev - min_rev) as _] {
-                result.push(Id(rev as _));
-                continue;
-            }
-            let parent_revs = self.parent_revs(rev as _)?;
-            for parent_rev in parent_revs.as_revs() {
-                let parent_rev = *parent_rev as u64;
-                if parent_rev >= min_rev as _ && parent_rev <= max_rev as _ {
-                    let idx = (parent_rev - min_rev) as usize;
-                    if !reachable[idx] {
-                        reachable.set(idx, true);
-                        alive += 1;
-                    }
-                }
-            }
-        }
+    #[test]
+    fn test_reachability_matrix() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
 
-        let result = Set::from_spans_dag(result, self)?;
-        Ok(result)
+        assert_eq!(
+            revlog.reachability_matrix(vec![3, 2, 1, 5])?,
+            vec![
+                vec![true, false, false, false],
+                vec![true, true, false, true],
+                vec![true, false, true, false],
+                vec![false, false, false, true],
+            ]
+        );
+        assert!(revlog.reachability_matrix(vec![])?.is_empty());
+        assert!(revlog.reachability_matrix(vec![6]).is_err());
+        let too_many = vec![0; MAX_REACHABILITY_MATRIX_REVS + 1];
+        assert!(revlog.reachability_matrix(too_many).is_err());
+        Ok(())
     }
 
-    fn is_vertex_lazy(&self) -> bool {
-        false
+    #[test]
+    fn test_empty_revlog() -> Result<()> {
+        let dir = tempdir()?;
+        let revlog = revlog_from_parents(dir.path(), &[]);
+        assert_eq!(revlog.len(), 0);
+
+        let (public, draft, secret) = revlog.phasesets(vec![], vec![], vec![])?;
+        assert!(public.is_empty());
+        assert!(draft.is_empty());
+        assert!(secret.is_empty());
+        assert!(revlog.phasesets(vec![0], vec![], vec![]).is_err());
+        assert!(revlog.phasesets(vec![], vec![0], vec![]).is_err());
+        assert!(revlog.phasesets(vec![], vec![], vec![0]).is_err());
+
+        assert!(revlog.headsancestors(vec![])?.is_empty());
+        assert!(revlog.headsancestors(vec![0]).is_err());
+
+        let (common, missing) = revlog.findmissingcommon(vec![], vec![])?;
+        assert!(common.is_empty());
+        assert!(missing.is_empty());
+        assert!(revlog.findmissingcommon(vec![], vec![0]).is_err());
+
+        assert!(revlog.parentsofset(vec![])?.is_empty());
+        assert_eq!(revlog.lowestcommonancestor_fast(vec![])?, None);
+        assert!(revlog.descendant_of_any(vec![], vec![])?.is_empty());
+        assert!(revlog.ancestornodes(vec![])?.is_empty());
+        assert!(revlog.reachability_matrix(vec![])?.is_empty());
+        assert!(revlog.gca_revs(&[], 1)?.is_empty());
+        assert!(revlog.range_revs(&[], &[])?.is_empty());
+
+        assert_eq!(r(r(revlog.all())?.count())?, 0);
+        assert_eq!(r(r(revlog.ancestors(Set::empty()))?.count())?, 0);
+        assert_eq!(r(r(revlog.descendants(Set::empty()))?.count())?, 0);
+        assert_eq!(r(r(revlog.heads(Set::empty()))?.count())?, 0);
+        assert_eq!(r(r(revlog.heads_ancestors(Set::empty()))?.count())?, 0);
+        Ok(())
     }
 
-    fn dag_snapshot(&self) -> dag::Result<Arc<dyn DagAlgorithm + Send + Sync>> {
-        Ok(self.get_snapshot())
-    }
+    #[test]
+    fn test_interrupt_check() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        let count = Arc::new(AtomicUsize::new(0));
+        let check_count = count.clone();
+        revlog.set_interrupt_check(Some(Arc::new(move || {
+            check_count.fetch_add(1, SeqCst);
+            true
+        })));
+        let set = |revlog: &RevlogIndex, revs: &[u64]| {
+            Set::from_spans_dag(IdSet::from_spans(revs.iter().map(|&rev| Id(rev))), revlog)
+        };
 
-    fn dag_id(&self) -> &str {
-        &self.id
+        assert!(revlog.headsancestors(vec![2]).is_err());
+        assert!(r(r(revlog.ancestors(set(&revlog, &[2])?))?.count()).is_err());
+        assert!(r(r(revlog.descendants(set(&revlog, &[0])?))?.count()).is_err());
+        assert!(r(revlog.heads_ancestors(set(&revlog, &[0, 1])?)).is_err());
+        assert!(revlog.lowestcommonancestor_fast(vec![1, 2]).is_err());
+        assert!(revlog.ancestornodes(vec![2]).is_err());
+        assert!(revlog.reachability_matrix(vec![0, 2]).is_err());
+        assert!(revlog.onlyfirst(vec![2], vec![]).is_err());
+        assert!(revlog.bisect_next(vec![0], vec![2]).is_err());
+        assert!(revlog.branchpoints(vec![]).is_err());
+        assert!(count.load(SeqCst) >= 10);
+
+        revlog.set_interrupt_check(None);
+        assert_eq!(revlog.headsancestors(vec![2])?, vec![2]);
+        assert_eq!(r(r(revlog.ancestors(set(&revlog, &[2])?))?.count())?, 3);
+        Ok(())
     }
 
-    fn dag_version(&self) -> &VerLink {
-        &self.version
+    #[test]
+    fn test_inducedsubgraph() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(
+            revlog.inducedsubgraph(vec![5, 3, 1, 4, 3])?,
+            vec![
+                (1, vec![], vec![0]),
+                (3, vec![1], vec![2]),
+                (4, vec![], vec![2]),
+                (5, vec![4], vec![]),
+            ]
+        );
+        assert_eq!(
+            revlog.inducedsubgraph(vec![0, 2])?,
+            vec![(0, vec![], vec![]), (2, vec![0], vec![])]
+        );
+        assert!(revlog.inducedsubgraph(vec![])?.is_empty());
+        assert!(revlog.inducedsubgraph(vec![6]).is_err());
+        Ok(())
     }
-}
 
-impl IdMapSnapshot for RevlogIndex {
-    fn id_map_snapshot(&self) -> dag::Result<Arc<dyn IdConvert + Send + Sync>> {
-        Ok(self.get_snapshot())
+    #[test]
+    fn test_ancestordistance() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(revlog.ancestordistance(5, 5)?, Some(0));
+        assert_eq!(revlog.ancestordistance(0, 5)?, Some(3));
+        assert_eq!(revlog.ancestordistance(0, 3)?, Some(2));
+        assert_eq!(revlog.ancestordistance(2, 3)?, Some(1));
+        assert_eq!(revlog.ancestordistance(1, 5)?, None);
+        assert_eq!(revlog.ancestordistance(5, 0)?, None);
+        assert!(revlog.ancestordistance(0, 6).is_err());
+        Ok(())
     }
-}
 
-impl RevlogIndex {
-    fn add_heads_for_testing(
-        &mut self,
-        parents_func: &dyn Parents,
-        heads: &VertexListWithOptions,
-    ) -> dag::Result<bool> {
-        if !cfg!(test) {
-            panic!(
-                "add_heads should only works for testing \
-                   because it uses dummy commit message and \
-                   revlog does not support separating commit \
-                   messages from the graph"
-            );
-        }
+    #[test]
+    fn test_isancestor() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert!(revlog.isancestor(5, 5)?);
+        assert!(revlog.isancestor(0, 5)?);
+        assert!(revlog.isancestor(1, 3)?);
+        assert!(revlog.isancestor(2, 3)?);
+        assert!(!revlog.isancestor(1, 5)?);
+        assert!(!revlog.isancestor(3, 4)?);
+        assert!(!revlog.isancestor(5, 0)?);
+        assert!(revlog.isancestor(0, 6).is_err());
+        Ok(())
+    }
 
-        let mut updated = false;
-        // Update IdMap. Keep track of what heads are added.
-        for head in heads.vertexes() {
-            if !non_blocking_result(self.contains_vertex_name(&head))? {
-                let parents = non_blocking_result(parents_func.parent_names(head.clone()))?;
-                for parent in parents.iter() {
-                    self.add_heads_for_testing(parents_func, &vec![parent.clone()].into())?;
-                }
-                if !non_blocking_result(self.contains_vertex_name(&head))? {
-                    let parent_revs: Vec<u32> = parents
-                        .iter()
-                        .map(|p| non_blocking_result(self.vertex_id(p.clone())).unwrap().0 as u32)
-                        .collect();
-                    if parent_revs.len() > 2 {
-                        return Err(Error::Unsupported(format!(
-                            "revlog does not support > 2 parents (when inserting {:?})",
-                            &head
-                        ))
-                        .into());
-                    }
-                    let text = Bytes::from_static(b"DUMMY COMMIT MESSAGE FOR TESTING");
-                    self.insert(head.clone(), parent_revs, text);
-                    updated = true;
-                }
-            }
+    #[test]
+    fn test_spill_pending() -> Result<()> {
+        let dir = tempdir()?;
+        let changelog_i_path = dir.path().join("00changelog.i");
+        let nodemap_path = dir.path().join("00changelog.nodemap");
+        let spill = SpillOptions {
+            threshold: 2,
+            dir: dir.path().to_path_buf(),
+        };
+        let mut revlog = RevlogIndex::new_with_spill(&changelog_i_path, &nodemap_path, spill)?;
+        let parents: &[&[u32]] = &[&[], &[0], &[0], &[2, 1], &[0, 1, 3], &[4]];
+        for (i, p) in parents.iter().enumerate() {
+            let text = format!("commit {}", i);
+            revlog.insert(v(i as u8), p.to_vec(), text.into_bytes().into())?;
         }
-
-        Ok(updated)
+        assert_eq!(revlog.spilled_len, 6);
+        assert!(revlog.pending_parents.is_empty());
+        assert_eq!(revlog.len(), 6);
+        for (rev, p) in parents.iter().enumerate() {
+            assert_eq!(revlog.parent_revs(rev as u32)?.as_revs(), *p);
+        }
+        assert!(revlog.parent_revs(6).is_err());
+
+        // Spilled parents can be changed.
+        let snapshot = revlog.get_snapshot();
+        revlog.set_inserted_parents(2, vec![1])?;
+        assert_eq!(revlog.parent_revs(2)?.as_revs(), &[1]);
+        assert_eq!(snapshot.parent_revs(2)?.as_revs(), &[0]);
+
+        // Truncating below the spilled revisions starts a new spill file, so
+        // the snapshot still reads the old parents.
+        revlog.truncate_pending(3)?;
+        assert_eq!(revlog.len(), 3);
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+        assert_eq!(revlog.spilled_len, 5);
+        assert_eq!(revlog.parent_revs(2)?.as_revs(), &[1]);
+        assert_eq!(revlog.parent_revs(3)?.as_revs(), &[2]);
+        assert_eq!(revlog.parent_revs(4)?.as_revs(), &[3]);
+        assert_eq!(snapshot.parent_revs(3)?.as_revs(), &[2, 1]);
+        assert_eq!(snapshot.parent_revs(4)?.as_revs(), &[0, 1, 3]);
+
+        revlog.flush()?;
+        let revlog = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        assert_eq!(revlog.len(), 5);
+        assert_eq!(revlog.parent_revs(2)?.as_revs(), &[1]);
+        assert_eq!(revlog.parent_revs(4)?.as_revs(), &[3]);
+        Ok(())
     }
-}
 
-#[async_trait::async_trait]
-impl DagAddHeads for RevlogIndex {
-    async fn add_heads(
-        &mut self,
-        parents_func: &dyn Parents,
-        heads: &VertexListWithOptions,
-    ) -> dag::Result<bool> {
-        self.add_heads_for_testing(parents_func, heads)
+    #[test]
+    fn test_nth_ancestor() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(revlog.nth_ancestor(5, 0)?, Some(5));
+        assert_eq!(revlog.nth_ancestor(5, 2)?, Some(2));
+        assert_eq!(revlog.nth_ancestor(5, 3)?, Some(0));
+        assert_eq!(revlog.nth_ancestor(5, 4)?, None);
+        // Merges follow the first parent.
+        assert_eq!(revlog.nth_ancestor(3, 1)?, Some(1));
+        assert!(revlog.nth_ancestor(6, 0).is_err());
+        Ok(())
     }
-}
 
-#[async_trait::async_trait]
-impl CheckIntegrity for RevlogIndex {
-    async fn check_universal_ids(&self) -> dag::Result<Vec<Id>> {
-        unsupported_dag_error()
+    #[test]
+    fn test_bisect_next() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 2 - 3 - 4 - 5 - 6 - 7 - 8
+        let parents: Vec<Vec<u32>> = (0..9u32)
+            .map(|rev| if rev == 0 { vec![] } else { vec![rev - 1] })
+            .collect();
+        let parents: Vec<&[u32]> = parents.iter().map(|p| p.as_slice()).collect();
+        let revlog = revlog_from_parents(dir.path(), &parents);
+
+        assert_eq!(revlog.bisect_next(vec![0], vec![8])?, Some(4));
+        assert_eq!(revlog.bisect_next(vec![4], vec![8])?, Some(6));
+        assert_eq!(revlog.bisect_next(vec![6], vec![8])?, Some(7));
+        assert_eq!(revlog.bisect_next(vec![7], vec![8])?, Some(8));
+        assert_eq!(revlog.bisect_next(vec![8], vec![8])?, None);
+        assert_eq!(revlog.bisect_next(vec![0], vec![])?, None);
+        assert!(revlog.bisect_next(vec![9], vec![8]).is_err());
+
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let dir = tempdir()?;
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+        // Candidates are 1, 2, 3. Ancestors of 1 or 2 take 1 of 3.
+        assert_eq!(revlog.bisect_next(vec![0], vec![3])?, Some(1));
+        // Candidates are 2, 4, 5. 2 and 4 split them equally, the lower one
+        // is picked.
+        assert_eq!(revlog.bisect_next(vec![1], vec![5])?, Some(2));
+        Ok(())
     }
 
-    async fn check_segments(&self) -> dag::Result<Vec<String>> {
-        unsupported_dag_error()
+    #[test]
+    fn test_since() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![1, 2], Bytes::from_static(b"commit 3"))?;
+
+        assert_eq!(
+            revlog.since(0)?,
+            vec![(1, vec![0]), (2, vec![0]), (3, vec![1, 2])]
+        );
+        assert_eq!(revlog.since(2)?, vec![(3, vec![1, 2])]);
+        assert!(revlog.since(3)?.is_empty());
+        assert!(revlog.since(u32::MAX)?.is_empty());
+        Ok(())
     }
 
-    async fn check_isomorphic_graph(
-        &self,
-        other: &dyn DagAlgorithm,
-        heads: dag::NameSet,
-    ) -> dag::Result<Vec<String>> {
-        let _ = (other, heads);
-        unsupported_dag_error()
+    #[test]
+    fn test_revs_by_linkrev_range() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
+
+        assert_eq!(revlog.revs_by_linkrev_range(0, 3), vec![0, 1, 2, 3]);
+        assert_eq!(revlog.revs_by_linkrev_range(1, 2), vec![1, 2]);
+        assert_eq!(revlog.revs_by_linkrev_range(3, 10), vec![3]);
+        assert!(revlog.revs_by_linkrev_range(2, 1).is_empty());
+        assert!(revlog.revs_by_linkrev_range(-5, -1).is_empty());
+        Ok(())
     }
-}
 
-fn unsupported_dag_error<T>() -> dag::Result<T> {
-    Err(dag::errors::BackendError::Generic("unsupported by revlog index".to_string()).into())
-}
+    #[test]
+    fn test_onlyfirst() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
 
-fn find_bytes_in_bytes(bytes: &[u8], to_find: &[u8]) -> Option<usize> {
-    bytes.windows(to_find.len()).position(|b| b == to_find)
-}
+        let onlyfirst = |a: Vec<u32>, b: Vec<u32>| -> dag::Result<Vec<u64>> {
+            Ok(revlog.onlyfirst(a, b)?.iter_desc().map(|id| id.0).collect())
+        };
+        assert_eq!(onlyfirst(vec![3], vec![5])?, vec![3, 1]);
+        assert_eq!(onlyfirst(vec![5], vec![3])?, vec![5, 4]);
+        assert_eq!(onlyfirst(vec![3, 5], vec![])?, vec![5, 4, 3, 2, 1, 0]);
+        assert_eq!(onlyfirst(vec![2], vec![3])?, Vec::<u64>::new());
+        assert_eq!(onlyfirst(vec![], vec![3])?, Vec::<u64>::new());
+        assert!(revlog.onlyfirst(vec![6], vec![]).is_err());
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::atomic::AtomicUsize;
-    use std::sync::atomic::Ordering::SeqCst;
+    #[test]
+    fn test_ancestors_symmetric_difference() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        let difference = |a: Vec<u32>, b: Vec<u32>| -> dag::Result<Vec<u64>> {
+            Ok(revlog
+                .ancestors_symmetric_difference(a, b)?
+                .iter_desc()
+                .map(|id| id.0)
+                .collect())
+        };
+        assert_eq!(difference(vec![3], vec![5])?, vec![5, 4, 3, 1]);
+        assert_eq!(difference(vec![5], vec![3])?, vec![5, 4, 3, 1]);
+        assert_eq!(difference(vec![3], vec![])?, vec![3, 2, 1, 0]);
+        assert_eq!(difference(vec![2], vec![3])?, vec![3, 1]);
+        assert_eq!(difference(vec![3, 5], vec![5, 3])?, Vec::<u64>::new());
+        assert!(revlog
+            .ancestors_symmetric_difference(vec![], vec![6])
+            .is_err());
+        Ok(())
+    }
 
-    use anyhow::Result;
-    use nonblocking::non_blocking_result as r;
-    use tempfile::tempdir;
+    #[test]
+    fn test_misaligned_index() -> Result<()> {
+        let dir = tempdir()?;
+        let changelog_i_path = dir.path().join("00changelog.i");
+        let nodemap_path = dir.path().join("00changelog.nodemap");
+        fs::write(&changelog_i_path, vec![0u8; 64 + 10])?;
+
+        let err = RevlogIndex::new(&changelog_i_path, &nodemap_path)
+            .err()
+            .expect("misaligned index should be rejected");
+        let message = err.to_string();
+        assert!(message.contains("entry size 64"), "{}", message);
+        assert!(message.contains("10 extra bytes"), "{}", message);
+        Ok(())
+    }
 
-    use super::*;
+    #[test]
+    fn test_corrupt_parents() -> Result<()> {
+        let dir = tempdir()?;
+        let changelog_i_path = dir.path().join("00changelog.i");
+        let nodemap_path = dir.path().join("00changelog.nodemap");
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        drop(revlog);
+
+        // p1 and p2 are at offsets 24 and 28 of each 64-byte entry.
+        let mut data = fs::read(&changelog_i_path)?;
+        data[64 + 24..64 + 32].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        data[128 + 24..128 + 28].copy_from_slice(&5i32.to_be_bytes());
+        fs::write(&changelog_i_path, data)?;
+
+        let revlog = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        assert!(revlog.parent_revs(0)?.as_revs().is_empty());
+        let message = revlog.parent_revs(1).unwrap_err().to_string();
+        assert!(message.contains("rev 1 has p2 0 but no p1"), "{}", message);
+        let message = revlog.parent_revs(2).unwrap_err().to_string();
+        assert!(message.contains("parent 5 of rev 2"), "{}", message);
+        assert!(revlog.headsancestors(vec![2]).is_err());
+        Ok(())
+    }
 
     #[test]
-    fn test_simple_3_commits() -> Result<()> {
-        // 00changelog.i and 00changelog.d are created by real hg commands.
-        let changelog_i = from_xxd(
-            r#"
-0000000: 0000 0001 0000 0000 0000 002c 0000 0048  ...........,...H
-0000010: 0000 0000 0000 0000 ffff ffff ffff ffff  ................
-0000020: 785b 4df8 9f24 f67f fdd3 eec2 2cc4 c51f  x[M..$......,...
-0000030: ca90 cc44 0000 0000 0000 0000 0000 0000  ...D............
-0000040: 0000 0000 002c 0000 0000 0044 0000 0083  .....,.....D....
-0000050: 0000 0001 0000 0001 0000 0000 ffff ffff  ................
-0000060: 4028 e6d9 355e 415f ef6b 7a2b f8cf 4b53  @(..5^A_.kz+..KS
-0000070: 0d66 2906 0000 0000 0000 0000 0000 0000  .f).............
-0000080: 0000 0000 0070 0000 0000 004b 0000 004a  .....p.....K...J
-0000090: 0000 0002 0000 0002 0000 0001 ffff ffff  ................
-00000a0: ab7a efa7 ec2d 5e85 0295 6b2c 8fb4 2590  .z...-^...k,..%.
-00000b0: 9c57 9822 0000 0000 0000 0000 0000 0000  .W."............
-"#,
-        );
-        let changelog_d = from_xxd(
-            r#"
-0000000: 3448 0000 001f 3001 0014 f011 0a74 6573  4H....0......tes
-0000010: 740a 3135 3936 3038 3335 3534 2032 3532  t.1596083554 252
-0000020: 3030 0a0a 636f 6d6d 6974 2031 3483 0000  00..commit 14...
-0000030: 001f 3001 0014 ff14 0a74 6573 740a 3135  ..0......test.15
-0000040: 3936 3038 3335 3834 2032 3532 3030 0a0a  96083584 25200..
-0000050: 636f 6d6d 6974 2032 3a20 3201 0015 f001  commit 2: 2.....
-0000060: 2069 7320 636f 6d70 7265 7373 6962 6c65   is compressible
-0000070: 7538 3531 3564 3462 6664 6137 3638 6530  u8515d4bfda768e0
-0000080: 3461 6634 6331 3361 3639 6137 3265 3238  4af4c13a69a72e28
-0000090: 6337 6566 6662 6561 370a 7465 7374 0a31  c7effbea7.test.1
-00000a0: 3539 3630 3833 3631 3420 3235 3230 300a  596083614 25200.
-00000b0: 610a 0a63 6f6d 6d69 7420 33              a..commit 3
-"#,
-        );
+    fn test_parent_revs_batch() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2]]);
+        revlog.flush()?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+
+        let parents = revlog.parent_revs_batch(&[4, 0, 3, 1])?;
+        assert_eq!(parents, vec![vec![3], vec![], vec![1, 2], vec![0]]);
+        assert!(revlog.parent_revs_batch(&[])?.is_empty());
+        let err = revlog.parent_revs_batch(&[1, 5, 2]).unwrap_err();
+        assert!(err.to_string().contains('5'), "{}", err);
+        Ok(())
+    }
 
+    #[test]
+    fn test_filelog_copy_parents() -> Result<()> {
         let dir = tempdir()?;
-        let dir = dir.path();
-        let changelog_i_path = dir.join("00changelog.i");
-        fs::write(&changelog_i_path, changelog_i)?;
-        let changelog_d_path = dir.join("00changelog.d");
-        fs::write(&changelog_d_path, changelog_d)?;
-        let nodemap_path = dir.join("00changelog.nodemap");
-        let index = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        let changelog_i_path = dir.path().join("00changelog.i");
+        let nodemap_path = dir.path().join("00changelog.nodemap");
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        drop(revlog);
+
+        // Rev 1 records a copy: p1 is -1 and p2 is 0.
+        let mut data = fs::read(&changelog_i_path)?;
+        data[64 + 24..64 + 32].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        fs::write(&changelog_i_path, data)?;
+
+        let mut revlog = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        assert!(revlog.parent_revs(1).is_err());
+        revlog.set_filelog(true);
+        assert_eq!(revlog.parent_revs(1)?.as_revs(), &[0]);
+        assert_eq!(revlog.parent_revs(2)?.as_revs(), &[1]);
+        assert_eq!(revlog.headsancestors(vec![2])?, vec![2]);
+        Ok(())
+    }
 
-        // Read parents.
-        assert_eq!(index.parent_revs(0)?, ParentRevs::Compact([-1, -1]));
-        assert_eq!(index.parent_revs(1)?, ParentRevs::Compact([0, -1]));
-        assert_eq!(index.parent_revs(2)?, ParentRevs::Compact([1, -1]));
+    #[test]
+    fn test_linkrev() -> Result<()> {
+        let dir = tempdir()?;
+        let changelog_i_path = dir.path().join("00changelog.i");
+        let nodemap_path = dir.path().join("00changelog.nodemap");
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        drop(revlog);
+
+        // link is at offset 20 of each 64-byte entry.
+        let mut data = fs::read(&changelog_i_path)?;
+        data[64 + 20..64 + 24].copy_from_slice(&(-1i32).to_be_bytes());
+        fs::write(&changelog_i_path, data)?;
+
+        let mut revlog = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
+        assert_eq!(revlog.linkrev(0)?, Some(0));
+        assert_eq!(revlog.linkrev(1)?, None);
+        assert_eq!(revlog.linkrev(2)?, Some(2));
+        let message = revlog.linkrev(3).unwrap_err().to_string();
+        assert!(message.contains("before flush"), "{}", message);
+        assert!(revlog.linkrev(4).is_err());
+        Ok(())
+    }
 
-        // Read commit data.
-        let read = |rev: u32| -> Result<String> {
-            Ok(std::str::from_utf8(&index.raw_data(rev)?)?.to_string())
-        };
-        assert_eq!(
-            read(0)?,
-            "0000000000000000000000000000000000000000\ntest\n1596083554 25200\n\ncommit 1"
-        );
-        assert_eq!(
-            read(1)?,
-            r#"0000000000000000000000000000000000000000
-test
-1596083584 25200
+    #[test]
+    fn test_verifynode() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0]]);
+        revlog.flush()?;
+        revlog.insert(v(2), vec![1], Bytes::from_static(b"commit 2"))?;
+
+        assert!(revlog.verifynode(0, v(0).as_ref())?);
+        assert!(!revlog.verifynode(0, v(1).as_ref())?);
+        assert!(revlog.verifynode(2, v(2).as_ref())?);
+        assert!(!revlog.verifynode(2, &[2; 19])?);
+        assert!(revlog.verifynode(3, v(3).as_ref()).is_err());
+        Ok(())
+    }
 
-commit 2: 22222222222222222222222222222222222222222 is compressible"#
-        );
-        // commit 3 is not lz4 compressed.
-        assert_eq!(
-            read(2)?,
-            r#"8515d4bfda768e04af4c13a69a72e28c7effbea7
-test
-1596083614 25200
-a
+    #[test]
+    fn test_branchpoints() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(revlog.branchpoints(vec![])?, vec![0, 2]);
+        assert_eq!(revlog.branchpoints(vec![2, 1, 2])?, vec![2]);
+        assert!(revlog.branchpoints(vec![3, 4, 5])?.is_empty());
+        assert!(revlog.branchpoints(vec![6]).is_err());
+
+        revlog.flush()?;
+        revlog.insert(v(6), vec![4], Bytes::from_static(b"commit 6"))?;
+        assert_eq!(revlog.branchpoints(vec![])?, vec![0, 2, 4]);
+        Ok(())
+    }
 
-commit 3"#
-        );
+    #[test]
+    fn test_external_leaves() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(revlog.external_leaves(vec![5, 3, 1])?, vec![3, 5]);
+        // 2 is a head of the set, but has a child outside it.
+        assert_eq!(revlog.external_leaves(vec![1, 2, 3])?, vec![3]);
+        assert!(revlog.external_leaves(vec![0, 1, 2])?.is_empty());
+        assert!(revlog.external_leaves(vec![])?.is_empty());
+        assert!(revlog.external_leaves(vec![6]).is_err());
+
+        revlog.flush()?;
+        revlog.insert(v(6), vec![5], Bytes::from_static(b"commit 6"))?;
+        assert_eq!(revlog.external_leaves(vec![3, 5, 6])?, vec![3, 6]);
+        Ok(())
+    }
 
+    #[test]
+    fn test_parent_nodes() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![1, 2], Bytes::from_static(b"commit 3"))?;
+        revlog.insert(v(4), vec![3], Bytes::from_static(b"commit 4"))?;
+
+        assert!(revlog.parent_nodes(0)?.is_empty());
+        assert_eq!(revlog.parent_nodes(3)?, vec![v(1), v(2)]);
+        assert_eq!(revlog.parent_nodes(4)?, vec![v(3)]);
+        assert!(revlog.parent_nodes(5).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_delta_application() -> Result<()> {
-        // util.py.i and util.py.d come from mercurial/util.py cloned from
-        // https://www.mercurial-scm.org/repo/hg with lz4revlog enabled.
-        // Truncated to first 3 revs.
-        let util_py_i = from_xxd(
-            r#"
-0000000: 0002 0001 0000 0000 0000 016c 0000 0199  ...........l....
-0000010: 0000 0000 0000 01a3 ffff ffff ffff ffff  ................
-0000020: 83c9 892d 2313 c88d c0ae d198 5930 91ea  ...-#.......Y0..
-0000030: 4c33 4f5f 0000 0000 0000 0000 0000 0000  L3O_............
-0000040: 0000 0000 016c 0000 0000 0071 0000 0230  .....l.....q...0
-0000050: 0000 0000 0000 01a5 0000 0000 ffff ffff  ................
-0000060: fa42 82a9 8aaf 93c9 0c4c 89d0 e835 336f  .B.......L...53o
-0000070: 875e f671 0000 0000 0000 0000 0000 0000  .^.q............
-0000080: 0000 0000 01dd 0000 0000 00d4 0000 0393  ................
-0000090: 0000 0001 0000 01a6 0000 0001 ffff ffff  ................
-00000a0: 3326 e39d 9781 4b34 7c5b 4542 fc55 65c9  3&....K4|[EB.Ue.
-00000b0: 7e6f b53d 0000 0000 0000 0000 0000 0000  ~o.=............
-00000c0: 0000 0000 02b1 0000 0000 013e 0000 05c5  ...........>....
-"#,
-        );
-        let util_py_d = from_xxd(
-            r#"
-0000000: 3499 0100 00b1 2320 7574 696c 2e70 7920  4.....# util.py 
-0000010: 2d0a 00f1 1e69 7479 2066 756e 6374 696f  -....ity functio
-0000020: 6e73 2061 6e64 2070 6c61 7466 6f72 6d20  ns and platform 
-0000030: 7370 6563 6669 6320 696d 706c 656d 656e  specfic implemen
-0000040: 7461 2500 f217 0a23 0a23 2043 6f70 7972  ta%....#.# Copyr
-0000050: 6967 6874 2032 3030 3520 4b2e 2054 6861  ight 2005 K. Tha
-0000060: 6e61 6e63 6861 7961 6e20 3c74 0e00 c16b  nanchayan <t...k
-0000070: 4079 6168 6f6f 2e63 6f6d 3e38 00f1 0a54  @yahoo.com>8...T
-0000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
-0000090: 2062 6520 7573 6564 7b00 9064 6973 7472   be used{..distr
-00000a0: 6962 7574 1000 f10b 6363 6f72 6469 6e67  ibut....ccording
-00000b0: 2074 6f20 7468 6520 7465 726d 730a 2320   to the terms.# 
-00000c0: 6f66 0f00 f016 474e 5520 4765 6e65 7261  of....GNU Genera
-00000d0: 6c20 5075 626c 6963 204c 6963 656e 7365  l Public License
-00000e0: 2c20 696e 636f 7270 6f72 6149 00f2 3c68  , incorporaI..<h
-00000f0: 6572 6569 6e20 6279 2072 6566 6572 656e  erein by referen
-0000100: 6365 2e0a 0a69 6d70 6f72 7420 6f73 0a0a  ce...import os..
-0000110: 6966 206f 732e 6e61 6d65 203d 3d20 276e  if os.name == 'n
-0000120: 7427 3a0a 2020 2020 6465 6620 7063 6f6e  t':.    def pcon
-0000130: 7665 7274 2870 6174 6829 1800 0001 0070  vert(path).....p
-0000140: 7265 7475 726e 2016 00ff 092e 7265 706c  return .....repl
-0000150: 6163 6528 225c 5c22 2c20 222f 2229 0a65  ace("\\", "/").e
-0000160: 6c73 6545 0017 5061 7468 0a0a 34a3 0000  lseE..Path..4...
-0000170: 0042 0000 0113 0400 f210 0097 6465 6620  .B..........def 
-0000180: 7265 6e61 6d65 2873 7263 2c20 6473 7429  rename(src, dst)
-0000190: 3a0a 2020 2020 7472 7909 0000 0100 3c6f  :.    try.....<o
-00001a0: 732e 2600 011c 0069 6578 6365 7074 2800  s.&....iexcept(.
-00001b0: 7575 6e6c 696e 6b28 2300 0f3f 0005 f00e  uunlink(#..?....
-00001c0: 0a23 2050 6c61 7466 6f72 2073 7065 6369  .# Platfor speci
-00001d0: 6669 6320 7661 7269 656e 7473 0a34 7b01  fic varients.4{.
-00001e0: 0000 4200 0001 fd04 00f3 1700 da0a 2020  ..B...........  
-00001f0: 2020 6465 6620 6d61 6b65 6c6f 636b 2869    def makelock(i
-0000200: 6e66 6f2c 2070 6174 686e 616d 6529 3a0a  nfo, pathname):.
-0000210: 2001 00d4 6c64 203d 206f 732e 6f70 656e   ...ld = os.open
-0000220: 2820 0010 2c12 0092 4f5f 4352 4541 5420  ( ..,...O_CREAT 
-0000230: 7c0d 0064 5752 4f4e 4c59 0e00 5545 5843  |..dWRONLY..UEXC
-0000240: 4c29 4500 d06f 732e 7772 6974 6528 6c64  L)E..os.write(ld
-0000250: 2c20 6b00 091b 0040 636c 6f73 1b00 2529  , k....@clos..%)
-0000260: 0a98 0041 7265 6164 9800 0f92 0000 b672  ...Aread.......r
-0000270: 6574 7572 6e20 6669 6c65 1f00 102e 3200  eturn file....2.
-0000280: 7228 290a 0000 0230 0400 2f00 89e5 0017  r()....0../.....
-0000290: 9d6f 732e 7379 6d6c 696e 2400 0f93 001a  .os.symlin$.....
-00002a0: 216f 7387 0001 4800 0326 0050 6529 0a0a  !os...H..&.Pe)..
-00002b0: 0a34 4a02 0000 4200 0001 be04 00f3 0e00  .4J...B.........
-00002c0: 5c20 2020 2064 6566 2069 735f 6578 6563  \    def is_exec
-"#,
-        );
+    fn test_tip() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[]);
+        assert_eq!(revlog.tip(), None);
+        assert_eq!(revlog.tip_node()?, None);
+
+        revlog.insert(v(0), vec![], Bytes::from_static(b"commit 0"))?;
+        revlog.insert(v(1), vec![0], Bytes::from_static(b"commit 1"))?;
+        assert_eq!(revlog.tip(), Some(1));
+        assert_eq!(revlog.tip_node()?, Some(v(1)));
+        Ok(())
+    }
 
+    #[test]
+    fn test_ancestors_visible() -> Result<()> {
         let dir = tempdir()?;
-        let dir = dir.path();
-        let i_path = dir.join("util.py.i");
-        fs::write(&i_path, util_py_i)?;
-        let d_path = dir.join("util.py.d");
-        fs::write(&d_path, util_py_d)?;
-        let nodemap_path = dir.join("util.py.nodemap");
-        let index = RevlogIndex::new(&i_path, &nodemap_path)?;
-        let read = |rev: u32| index.raw_data(rev);
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        let ancestors_visible = |heads: Vec<u32>, hidden: Vec<u32>| -> dag::Result<Vec<u64>> {
+            Ok(revlog
+                .ancestors_visible(heads, hidden)?
+                .iter_desc()
+                .map(|id| id.0)
+                .collect())
+        };
+        assert_eq!(ancestors_visible(vec![3], vec![])?, vec![3, 2, 1, 0]);
+        // Hidden revisions don't hide their ancestors.
+        assert_eq!(ancestors_visible(vec![5], vec![4])?, vec![5, 2, 0]);
+        assert_eq!(ancestors_visible(vec![5], vec![5, 4, 2])?, vec![0]);
+        assert_eq!(ancestors_visible(vec![3, 4], vec![1, 5])?, vec![4, 3, 2, 0]);
+        assert!(ancestors_visible(vec![], vec![1])?.is_empty());
+        assert!(ancestors_visible(vec![6], vec![]).is_err());
+        assert!(ancestors_visible(vec![1], vec![6]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ancestors_in_window() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        let ancestors_in_window = |heads: Vec<u32>, lo: u32, hi: u32| -> dag::Result<Vec<u64>> {
+            Ok(revlog
+                .ancestors_in_window(heads, lo, hi)?
+                .iter_desc()
+                .map(|id| id.0)
+                .collect())
+        };
+        assert_eq!(ancestors_in_window(vec![3], 0, 5)?, vec![3, 2, 1, 0]);
+        // Heads above the window are ignored.
+        assert!(ancestors_in_window(vec![5], 2, 4)?.is_empty());
+        assert_eq!(ancestors_in_window(vec![5], 2, 5)?, vec![5, 4, 2]);
+        assert_eq!(ancestors_in_window(vec![3, 5], 2, 4)?, vec![3, 2]);
+        assert_eq!(ancestors_in_window(vec![4], 3, 4)?, vec![4]);
+        assert!(ancestors_in_window(vec![1], 2, 5)?.is_empty());
+        assert!(ancestors_in_window(vec![3], 4, 2)?.is_empty());
+        assert!(ancestors_in_window(vec![6], 0, 10).is_err());
+        Ok(())
+    }
 
-        assert_eq!(
-            to_xxd(&read(0)?),
-            r#"
-00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
-00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
-00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
-00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
-00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
-00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
-00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
-00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
-00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
-00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
-000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
-000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
-000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
-000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
-000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
-000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
-00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
-00000110: 730a 0a69 6620 6f73 2e6e 616d 6520 3d3d  s..if os.name ==
-00000120: 2027 6e74 273a 0a20 2020 2064 6566 2070   'nt':.    def p
-00000130: 636f 6e76 6572 7428 7061 7468 293a 0a20  convert(path):. 
-00000140: 2020 2020 2020 2072 6574 7572 6e20 7061         return pa
-00000150: 7468 2e72 6570 6c61 6365 2822 5c5c 222c  th.replace("\\",
-00000160: 2022 2f22 290a 656c 7365 3a0a 2020 2020   "/").else:.    
-00000170: 6465 6620 7063 6f6e 7665 7274 2870 6174  def pconvert(pat
-00000180: 6829 3a0a 2020 2020 2020 2020 7265 7475  h):.        retu
-00000190: 726e 2070 6174 680a 0a                   rn path..
-"#
+    #[test]
+    fn test_ancestors_merge_bounded() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3       6
+        //  /   /       /
+        // 0 - 2 - 4 - 5 - 7
+        //
+        // 7 is a merge of 3 and 5.
+        let revlog = revlog_from_parents(
+            dir.path(),
+            &[&[], &[0], &[0], &[1, 2], &[2], &[4], &[5], &[5, 3]],
         );
+
+        let ancestors_merge_bounded = |heads: Vec<u32>, max_merges: u32| -> dag::Result<Vec<u64>> {
+            Ok(revlog
+                .ancestors_merge_bounded(heads, max_merges)?
+                .iter_desc()
+                .map(|id| id.0)
+                .collect())
+        };
+        assert_eq!(ancestors_merge_bounded(vec![7], 0)?, vec![7]);
+        // 2 is reached through 5 without crossing the merge 3.
+        assert_eq!(ancestors_merge_bounded(vec![7], 1)?, vec![7, 5, 4, 3, 2, 0]);
         assert_eq!(
-            to_xxd(&read(1)?),
-            r#"
-00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
-00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
-00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
-00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
-00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
-00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
-00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
-00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
-00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
-00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
-000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
-000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
-000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
-000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
-000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
-000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
-00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
-00000110: 730a 0a64 6566 2072 656e 616d 6528 7372  s..def rename(sr
-00000120: 632c 2064 7374 293a 0a20 2020 2074 7279  c, dst):.    try
-00000130: 3a0a 2020 2020 2020 2020 6f73 2e72 656e  :.        os.ren
-00000140: 616d 6528 7372 632c 2064 7374 290a 2020  ame(src, dst).  
-00000150: 2020 6578 6365 7074 3a0a 2020 2020 2020    except:.      
-00000160: 2020 6f73 2e75 6e6c 696e 6b28 6473 7429    os.unlink(dst)
-00000170: 0a20 2020 2020 2020 206f 732e 7265 6e61  .        os.rena
-00000180: 6d65 2873 7263 2c20 6473 7429 0a0a 2320  me(src, dst)..# 
-00000190: 506c 6174 666f 7220 7370 6563 6966 6963  Platfor specific
-000001a0: 2076 6172 6965 6e74 730a 6966 206f 732e   varients.if os.
-000001b0: 6e61 6d65 203d 3d20 276e 7427 3a0a 2020  name == 'nt':.  
-000001c0: 2020 6465 6620 7063 6f6e 7665 7274 2870    def pconvert(p
-000001d0: 6174 6829 3a0a 2020 2020 2020 2020 7265  ath):.        re
-000001e0: 7475 726e 2070 6174 682e 7265 706c 6163  turn path.replac
-000001f0: 6528 225c 5c22 2c20 222f 2229 0a65 6c73  e("\\", "/").els
-00000200: 653a 0a20 2020 2064 6566 2070 636f 6e76  e:.    def pconv
-00000210: 6572 7428 7061 7468 293a 0a20 2020 2020  ert(path):.     
-00000220: 2020 2072 6574 7572 6e20 7061 7468 0a0a     return path..
-"#
+            ancestors_merge_bounded(vec![7], 2)?,
+            vec![7, 5, 4, 3, 2, 1, 0]
         );
+        assert_eq!(ancestors_merge_bounded(vec![6], 0)?, vec![6, 5, 4, 2, 0]);
         assert_eq!(
-            to_xxd(&read(2)?),
-            r#"
-00000000: 2320 7574 696c 2e70 7920 2d20 7574 696c  # util.py - util
-00000010: 6974 7920 6675 6e63 7469 6f6e 7320 616e  ity functions an
-00000020: 6420 706c 6174 666f 726d 2073 7065 6366  d platform specf
-00000030: 6963 2069 6d70 6c65 6d65 6e74 6174 696f  ic implementatio
-00000040: 6e73 0a23 0a23 2043 6f70 7972 6967 6874  ns.#.# Copyright
-00000050: 2032 3030 3520 4b2e 2054 6861 6e61 6e63   2005 K. Thananc
-00000060: 6861 7961 6e20 3c74 6861 6e61 6e63 6b40  hayan <thananck@
-00000070: 7961 686f 6f2e 636f 6d3e 0a23 0a23 2054  yahoo.com>.#.# T
-00000080: 6869 7320 736f 6674 7761 7265 206d 6179  his software may
-00000090: 2062 6520 7573 6564 2061 6e64 2064 6973   be used and dis
-000000a0: 7472 6962 7574 6564 2061 6363 6f72 6469  tributed accordi
-000000b0: 6e67 2074 6f20 7468 6520 7465 726d 730a  ng to the terms.
-000000c0: 2320 6f66 2074 6865 2047 4e55 2047 656e  # of the GNU Gen
-000000d0: 6572 616c 2050 7562 6c69 6320 4c69 6365  eral Public Lice
-000000e0: 6e73 652c 2069 6e63 6f72 706f 7261 7465  nse, incorporate
-000000f0: 6420 6865 7265 696e 2062 7920 7265 6665  d herein by refe
-00000100: 7265 6e63 652e 0a0a 696d 706f 7274 206f  rence...import o
-00000110: 730a 0a64 6566 2072 656e 616d 6528 7372  s..def rename(sr
-00000120: 632c 2064 7374 293a 0a20 2020 2074 7279  c, dst):.    try
-00000130: 3a0a 2020 2020 2020 2020 6f73 2e72 656e  :.        os.ren
-00000140: 616d 6528 7372 632c 2064 7374 290a 2020  ame(src, dst).  
-00000150: 2020 6578 6365 7074 3a0a 2020 2020 2020    except:.      
-00000160: 2020 6f73 2e75 6e6c 696e 6b28 6473 7429    os.unlink(dst)
-00000170: 0a20 2020 2020 2020 206f 732e 7265 6e61  .        os.rena
-00000180: 6d65 2873 7263 2c20 6473 7429 0a0a 2320  me(src, dst)..# 
-00000190: 506c 6174 666f 7220 7370 6563 6966 6963  Platfor specific
-000001a0: 2076 6172 6965 6e74 730a 6966 206f 732e   varients.if os.
-000001b0: 6e61 6d65 203d 3d20 276e 7427 3a0a 2020  name == 'nt':.  
-000001c0: 2020 6465 6620 7063 6f6e 7665 7274 2870    def pconvert(p
-000001d0: 6174 6829 3a0a 2020 2020 2020 2020 7265  ath):.        re
-000001e0: 7475 726e 2070 6174 682e 7265 706c 6163  turn path.replac
-000001f0: 6528 225c 5c22 2c20 222f 2229 0a0a 2020  e("\\", "/")..  
-00000200: 2020 6465 6620 6d61 6b65 6c6f 636b 2869    def makelock(i
-00000210: 6e66 6f2c 2070 6174 686e 616d 6529 3a0a  nfo, pathname):.
-00000220: 2020 2020 2020 2020 6c64 203d 206f 732e          ld = os.
-00000230: 6f70 656e 2870 6174 686e 616d 652c 206f  open(pathname, o
-00000240: 732e 4f5f 4352 4541 5420 7c20 6f73 2e4f  s.O_CREAT | os.O
-00000250: 5f57 524f 4e4c 5920 7c20 6f73 2e4f 5f45  _WRONLY | os.O_E
-00000260: 5843 4c29 0a20 2020 2020 2020 206f 732e  XCL).        os.
-00000270: 7772 6974 6528 6c64 2c20 696e 666f 290a  write(ld, info).
-00000280: 2020 2020 2020 2020 6f73 2e63 6c6f 7365          os.close
-00000290: 286c 6429 0a0a 2020 2020 6465 6620 7265  (ld)..    def re
-000002a0: 6164 6c6f 636b 2870 6174 686e 616d 6529  adlock(pathname)
-000002b0: 3a0a 2020 2020 2020 2020 7265 7475 726e  :.        return
-000002c0: 2066 696c 6528 7061 7468 6e61 6d65 292e   file(pathname).
-000002d0: 7265 6164 2829 0a65 6c73 653a 0a20 2020  read().else:.   
-000002e0: 2064 6566 2070 636f 6e76 6572 7428 7061   def pconvert(pa
-000002f0: 7468 293a 0a20 2020 2020 2020 2072 6574  th):.        ret
-00000300: 7572 6e20 7061 7468 0a0a 2020 2020 6465  urn path..    de
-00000310: 6620 6d61 6b65 6c6f 636b 2869 6e66 6f2c  f makelock(info,
-00000320: 2070 6174 686e 616d 6529 3a0a 2020 2020   pathname):.    
-00000330: 2020 2020 6f73 2e73 796d 6c69 6e6b 2869      os.symlink(i
-00000340: 6e66 6f2c 2070 6174 686e 616d 6529 0a0a  nfo, pathname)..
-00000350: 2020 2020 6465 6620 7265 6164 6c6f 636b      def readlock
-00000360: 2870 6174 686e 616d 6529 3a0a 2020 2020  (pathname):.    
-00000370: 2020 2020 7265 7475 726e 206f 732e 7265      return os.re
-00000380: 6164 6c69 6e6b 2870 6174 686e 616d 6529  adlink(pathname)
-00000390: 0a0a 0a                                  ...
-"#
+            ancestors_merge_bounded(vec![6, 3], 0)?,
+            vec![6, 5, 4, 3, 2, 0]
         );
-
+        assert_eq!(ancestors_merge_bounded(vec![7], u32::MAX)?.len(), 7);
+        assert!(ancestors_merge_bounded(vec![], 1)?.is_empty());
+        assert!(ancestors_merge_bounded(vec![8], 1).is_err());
         Ok(())
     }
 
     #[test]
-    fn test_octopus_merge() {
-        let dir = tempdir().unwrap();
-        let dir = dir.path();
-        let changelog_i_path = dir.join("00changelog.i");
-        let nodemap_path = dir.join("00changelog.nodemap");
+    fn test_shallowbase() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        let shallowbase = |heads: Vec<u32>, depth: u32| -> dag::Result<(Vec<u64>, Vec<u64>)> {
+            let (base, included) = revlog.shallowbase(heads, depth)?;
+            Ok((
+                base.iter_desc().map(|id| id.0).collect(),
+                included.iter_desc().map(|id| id.0).collect(),
+            ))
+        };
+        assert_eq!(shallowbase(vec![5], 0)?, (vec![4], vec![5]));
+        assert_eq!(shallowbase(vec![5], 1)?, (vec![2], vec![5, 4]));
+        // 2 is one hop from 3, even though it is two hops from 5.
+        assert_eq!(shallowbase(vec![3, 5], 1)?, (vec![0], vec![5, 4, 3, 2, 1]));
+        assert_eq!(shallowbase(vec![3], 5)?, (vec![], vec![3, 2, 1, 0]));
+        assert_eq!(shallowbase(vec![3], u32::MAX)?, (vec![], vec![3, 2, 1, 0]));
+        assert_eq!(shallowbase(vec![], 1)?, (vec![], vec![]));
+        assert!(shallowbase(vec![6], 1).is_err());
+        Ok(())
+    }
 
-        let mut rlog = RevlogIndex::new(&changelog_i_path, &nodemap_path).unwrap();
-        rlog.insert(v(0), vec![], b"A".to_vec().into()); // rev 0
-        rlog.insert(v(1), vec![], b"B".to_vec().into()); // rev 1
-        rlog.insert(v(2), vec![], b"C".to_vec().into()); // rev 2
-        rlog.insert(v(3), vec![], b"D".to_vec().into()); // rev 3
-        rlog.insert(
-            v(4),
-            vec![0, 3],
-            format!(
-                concat!(
-                    "deadbeef000000000000\n",
-                    "test <test@example.com>\n",
-                    "100 300 bar:foo\0stepparents:{},{}\0foo:bar\n",
-                    "foobar"
-                ),
-                v(2).to_hex(),
-                v(1).to_hex()
-            )
-            .as_bytes()
-            .to_vec()
-            .into(),
-        ); // rev 4
-        rlog.flush().unwrap();
+    #[test]
+    fn test_parents2() -> Result<()> {
+        let dir = tempdir()?;
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[0, 1, 2]]);
 
-        let rlog = RevlogIndex::new(&changelog_i_path, &nodemap_path).unwrap();
-        assert_eq!(rlog.parent_revs(4).unwrap().as_revs(), [0, 3, 2, 1]);
+        assert_eq!(revlog.parents2(0)?, (-1, -1));
+        assert_eq!(revlog.parents2(1)?, (0, -1));
+        assert_eq!(revlog.parents2(3)?, (1, 2));
+        assert!(revlog.parents2(4).is_err());
+        assert!(revlog.parents2(5).is_err());
+        Ok(())
     }
 
     #[test]
-    fn test_flush() -> Result<()> {
+    fn test_generations() -> Result<()> {
         let dir = tempdir()?;
-        let dir = dir.path();
-        let changelog_i_path = dir.join("00changelog.i");
-        let nodemap_path = dir.join("00changelog.nodemap");
-
-        let mut revlog1 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
-        let mut revlog2 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+        assert_eq!(*revlog.generations()?, vec![0, 1, 1, 2, 2, 3]);
+
+        // The cache is updated for inserted revisions.
+        revlog.insert(v(6), vec![3, 5], Bytes::from_static(b"commit 6"))?;
+        revlog.insert(v(7), vec![], Bytes::from_static(b"commit 7"))?;
+        assert_eq!(*revlog.generations()?, vec![0, 1, 1, 2, 2, 3, 4, 0]);
+        revlog.set_inserted_parents(7, vec![6])?;
+        assert_eq!(*revlog.generations()?, vec![0, 1, 1, 2, 2, 3, 4, 5]);
+        assert_eq!(revlog.generation(0)?, 0);
+        assert_eq!(revlog.generation(7)?, 5);
+        assert!(revlog.generation(8).is_err());
+        Ok(())
+    }
 
-        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into()); // rev 0
+    #[test]
+    fn test_child_revs() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+        assert_eq!(revlog.child_revs(0)?, vec![1, 2]);
+        assert_eq!(revlog.child_revs(2)?, vec![3, 4]);
+        assert!(revlog.child_revs(5)?.is_empty());
+        assert!(revlog.child_revs(6).is_err());
+
+        // The cache is updated for inserted revisions.
+        revlog.insert(v(6), vec![3, 5], Bytes::from_static(b"commit 6"))?;
+        revlog.insert(v(7), vec![], Bytes::from_static(b"commit 7"))?;
+        assert_eq!(revlog.child_revs(5)?, vec![6]);
+        assert!(revlog.child_revs(6)?.is_empty());
+        revlog.set_inserted_parents(7, vec![6])?;
+        assert_eq!(revlog.child_revs(3)?, vec![6]);
+        assert_eq!(revlog.child_revs(6)?, vec![7]);
+        Ok(())
+    }
 
-        // commit 2 is lz4-friendly.
-        let text = b"commit 2 (............................................)";
-        revlog1.insert(v(2), vec![0], text.to_vec().into()); // rev 1, parent rev 0
+    #[test]
+    fn test_rev_of_node() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
 
-        revlog2.insert(v(3), vec![], b"commit 3".to_vec().into()); // rev 2, local 0
-        revlog2.insert(v(1), vec![], b"commit 1".to_vec().into()); // duplicate with revlog1, local 1, rev 0
-        revlog2.insert(v(4), vec![0], b"commit 4".to_vec().into()); // rev 3, local 2
-        revlog2.insert(v(5), vec![1, 0], b"commit 5".to_vec().into()); // rev 4, local 3
+        for rev in 0..4 {
+            assert_eq!(revlog.rev_of_node(&v(rev))?, Some(rev as u32));
+        }
+        assert_eq!(revlog.rev_of_node(&v(4))?, None);
+        Ok(())
+    }
 
-        // Inserting an existing node is ignored.
-        let old_len = revlog1.len();
-        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into()); // rev 0
-        revlog1.insert(v(2), vec![0], text.to_vec().into()); // rev 1
-        assert_eq!(revlog1.len(), old_len);
+    #[test]
+    fn test_node_of_rev() -> Result<()> {
+        let dir = tempdir()?;
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[1]]);
+        revlog.flush()?;
+        revlog.insert(v(3), vec![2], Bytes::from_static(b"commit 3"))?;
 
-        revlog1.flush()?;
-        revlog2.flush()?;
+        for rev in 0..4 {
+            assert_eq!(revlog.node_of_rev(rev as u32)?, v(rev));
+        }
+        assert!(revlog.node_of_rev(4).is_err());
+        Ok(())
+    }
 
-        // The second flush reloads data, without writing new data.
-        revlog1.flush()?;
-        revlog2.flush()?;
+    #[test]
+    fn test_dag_hash() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let mut revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
+
+        assert_eq!(revlog.dag_hash(1)?, revlog.dag_hash(2)?);
+        assert_ne!(revlog.dag_hash(0)?, revlog.dag_hash(1)?);
+        assert_ne!(revlog.dag_hash(3)?, revlog.dag_hash(4)?);
+        assert_ne!(revlog.dag_hash(4)?, revlog.dag_hash(5)?);
+        assert!(revlog.dag_hash(6).is_err());
+
+        // The same shape at other revision numbers has the same hash.
+        let dir2 = tempdir()?;
+        let other = revlog_from_parents(dir2.path(), &[&[], &[], &[1], &[2]]);
+        assert_eq!(other.dag_hash(3)?, revlog.dag_hash(4)?);
+
+        // A diamond and a graph without the shared root have different
+        // hashes, even though their merges have parents of the same shape.
+        let dir3 = tempdir()?;
+        let diamond = revlog_from_parents(dir3.path(), &[&[], &[0], &[0], &[1, 2]]);
+        let dir4 = tempdir()?;
+        let unshared = revlog_from_parents(dir4.path(), &[&[], &[], &[0], &[1], &[2, 3]]);
+        assert_ne!(diamond.dag_hash(3)?, unshared.dag_hash(4)?);
+
+        // Hashes are memoized, and inserting revisions keeps them.
+        let hash = revlog.dag_hash(5)?;
+        revlog.flush()?;
+        assert_eq!(revlog.dag_hash(5)?, hash);
+        assert_eq!(revlog.dag_hashes.read().get(&5), Some(&hash));
+        revlog.insert(v(6), vec![3, 5], Bytes::from_static(b"commit 6"))?;
+        assert_eq!(revlog.dag_hashes.read().get(&5), Some(&hash));
+        assert_eq!(revlog.dag_hash(5)?, hash);
+        assert_ne!(revlog.dag_hash(6)?, hash);
+        assert!(revlog.dag_hashes.read().contains_key(&6));
+
+        revlog.truncate_pending(0)?;
+        assert!(revlog.dag_hashes.read().is_empty());
+        assert!(revlog.dag_hash(6).is_err());
+        assert_eq!(revlog.dag_hash(5)?, hash);
+        Ok(())
+    }
 
-        // Read the flushed data into revlog3.
-        let revlog3 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
+    #[test]
+    fn test_commonancestors_multi() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 3 - 5
+        //  \    X
+        //   2 - 4 - 6   7 - 8
+        let revlog = revlog_from_parents(
+            dir.path(),
+            &[&[], &[0], &[0], &[1, 2], &[1, 2], &[3], &[4], &[], &[7]],
+        );
 
-        // Read parents.
-        assert_eq!(revlog3.parent_revs(0)?, ParentRevs::Compact([-1, -1]));
-        assert_eq!(revlog3.parent_revs(1)?, ParentRevs::Compact([0, -1]));
-        assert_eq!(revlog3.parent_revs(2)?, ParentRevs::Compact([-1, -1]));
-        assert_eq!(revlog3.parent_revs(3)?, ParentRevs::Compact([2, -1]));
-        assert_eq!(revlog3.parent_revs(4)?, ParentRevs::Compact([0, 2])); // 0: "commit 1"
+        assert_eq!(revlog.commonancestors_multi(vec![5, 6])?, vec![2, 1]);
+        assert_eq!(revlog.commonancestors_multi(vec![3, 4, 5])?, vec![2, 1]);
+        assert_eq!(revlog.commonancestors_multi(vec![5, 3, 5])?, vec![3]);
+        assert_eq!(revlog.commonancestors_multi(vec![1, 2, 5, 6])?, vec![0]);
+        assert_eq!(revlog.commonancestors_multi(vec![5])?, vec![5]);
+        assert!(revlog.commonancestors_multi(vec![5, 8])?.is_empty());
+        assert!(revlog.commonancestors_multi(vec![])?.is_empty());
+        assert!(revlog.commonancestors_multi(vec![9]).is_err());
+        Ok(())
+    }
 
-        // Prefix lookup.
-        assert_eq!(r(revlog3.vertexes_by_hex_prefix(b"0303", 2))?, vec![v(3)]);
+    #[test]
+    fn test_gca() -> Result<()> {
+        let dir = tempdir()?;
+        // 0 - 1 - 3 - 5
+        //  \    X
+        //   2 - 4 - 6   7
+        let revlog = revlog_from_parents(
+            dir.path(),
+            &[&[], &[0], &[0], &[1, 2], &[1, 2], &[3], &[4], &[]],
+        );
 
-        // Id - Vertex.
-        assert_eq!(r(revlog3.vertex_name(Id(2)))?, v(3));
-        assert_eq!(r(revlog3.vertex_id(v(3)))?, Id(2));
+        assert_eq!(revlog.gca(5, 6)?, vec![2, 1]);
+        assert_eq!(revlog.gca(3, 4)?, vec![2, 1]);
+        assert_eq!(revlog.gca(1, 6)?, vec![1]);
+        assert_eq!(revlog.gca(5, 5)?, vec![5]);
+        assert_eq!(revlog.gca(1, 2)?, vec![0]);
+        assert!(revlog.gca(5, 7)?.is_empty());
+        assert!(revlog.gca(5, 8).is_err());
+        Ok(())
+    }
 
-        // Read commit data.
-        let read = |rev: u32| -> Result<String> {
-            let raw = revlog3.raw_data(rev)?;
-            for index in vec![&revlog1, &revlog2] {
-                assert!(index.raw_data(rev)? == raw, "index read mismatch");
-            }
-            Ok(std::str::from_utf8(&raw)?.to_string())
-        };
+    #[test]
+    fn test_toposort_with_key() -> Result<()> {
+        let dir = tempdir()?;
+        //   1 - 3
+        //  /   /
+        // 0 - 2 - 4 - 5
+        let revlog = revlog_from_parents(dir.path(), &[&[], &[0], &[0], &[1, 2], &[2], &[4]]);
 
-        assert_eq!(read(0)?, "commit 1");
+        let revs = vec![0, 1, 2, 3, 4, 5];
+        let keys = vec![0, 10, 1, 2, 20, 5];
         assert_eq!(
-            read(1)?,
-            "commit 2 (............................................)"
+            revlog.toposort_with_key(revs, keys)?,
+            vec![5, 4, 3, 1, 2, 0]
         );
-        assert_eq!(read(2)?, "commit 3");
-        assert_eq!(read(3)?, "commit 4");
-        assert_eq!(read(4)?, "commit 5");
-
+        // 0 is an ancestor of 5 through revisions that are not sorted, so
+        // it comes last despite its key.
+        assert_eq!(
+            revlog.toposort_with_key(vec![0, 5, 1], vec![100, 0, 50])?,
+            vec![1, 5, 0]
+        );
+        // Equal keys are ordered by revision number.
+        assert_eq!(
+            revlog.toposort_with_key(vec![1, 4, 2], vec![0, 0, 0])?,
+            vec![4, 2, 1]
+        );
+        assert!(revlog.toposort_with_key(vec![], vec![])?.is_empty());
+        assert!(revlog.toposort_with_key(vec![1], vec![]).is_err());
+        assert!(revlog.toposort_with_key(vec![6], vec![0]).is_err());
         Ok(())
     }
 
+    /// Construct a RevlogIndex with pending revisions. `parents[i]` are the
+    /// parent revs of rev `i`.
+    fn revlog_from_parents(dir: &Path, parents: &[&[u32]]) -> RevlogIndex {
+        let changelog_i_path = dir.join("00changelog.i");
+        let nodemap_path = dir.join("00changelog.nodemap");
+        let mut revlog = RevlogIndex::new(&changelog_i_path, &nodemap_path).unwrap();
+        for (i, parents) in parents.iter().enumerate() {
+            let text = format!("commit {}", i);
+            revlog
+                .insert(v(i as u8), parents.to_vec(), text.into_bytes().into())
+                .unwrap();
+        }
+        revlog
+    }
+
     /// Quickly construct a Vertex from a byte.
     fn v(byte: u8) -> Vertex {
         Vertex::from(vec![byte; 20])
@@ -2420,7 +5550,7 @@ commit 3"#
         let nodemap_path = dir.join("00changelog.nodemap");
 
         let mut revlog1 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
-        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into());
+        revlog1.insert(v(1), vec![], b"commit 1".to_vec().into())?;
         revlog1.flush()?;
 
         // Trigger nodemap build.
@@ -2445,7 +5575,7 @@ commit 3"#
         let revlog2 = RevlogIndex::new(&changelog_i_path, &nodemap_path)?;
 
         // Make nodemap lagged.
-        revlog1.insert(v(0xff), vec![], b"commit 1".to_vec().into());
+        revlog1.insert(v(0xff), vec![], b"commit 1".to_vec().into())?;
         revlog1.flush()?;
 
         // Trigger nodemap build while keeping the mmap.
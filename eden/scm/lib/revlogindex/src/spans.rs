@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Compact serialization of [`IdSet`]s, so computed sets like phases can be
+//! cached on disk.
+//!
+//! The format is a version byte followed by one pair of VLQ integers per
+//! span, in ascending order: the gap since the end of the previous span, and
+//! the length of the span minus one.
+
+use dag::Id;
+use dag::IdSet;
+use vlqencoding::VLQDecodeAt;
+use vlqencoding::VLQEncode;
+
+use crate::errors::corruption;
+use crate::Result;
+
+const VERSION: u8 = 0;
+
+/// Serialize `set` into run-length encoded bytes.
+pub fn serialize_spans(set: &IdSet) -> Vec<u8> {
+    let mut buf = vec![VERSION];
+    let mut next = 0;
+    for span in set.iter_span_asc() {
+        let high = span.nth(0).unwrap().0;
+        let low = high + 1 - span.count();
+        // Writing to a Vec does not fail.
+        buf.write_vlq(low - next).unwrap();
+        buf.write_vlq(high - low).unwrap();
+        next = high + 1;
+    }
+    buf
+}
+
+/// Deserialize bytes produced by [`serialize_spans`].
+pub fn deserialize_spans(data: &[u8]) -> Result<IdSet> {
+    match data.first() {
+        Some(&VERSION) => {}
+        Some(version) => return corruption(format!("unsupported spans version {}", version)),
+        None => return corruption("empty spans data"),
+    }
+
+    let mut spans = Vec::new();
+    let mut offset = 1;
+    let mut next: u64 = 0;
+    while offset < data.len() {
+        let (gap, len): (u64, usize) = data.read_vlq_at(offset)?;
+        offset += len;
+        let (extra, len): (u64, usize) = data.read_vlq_at(offset)?;
+        offset += len;
+        let low = next.checked_add(gap);
+        let high = low.and_then(|low| low.checked_add(extra));
+        match (low, high) {
+            (Some(low), Some(high)) if high <= Id::MAX.0 => {
+                spans.push(Id(low)..=Id(high));
+                next = high + 1;
+            }
+            _ => return corruption("span in spans data overflows"),
+        }
+    }
+    spans.reverse();
+    Ok(IdSet::from_sorted_spans(spans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let sets = vec![
+            IdSet::empty(),
+            IdSet::from_spans(vec![Id(0)..=Id(0)]),
+            IdSet::from_spans(vec![Id(0)..=Id(10), Id(12)..=Id(12), Id(100)..=Id(1000)]),
+            IdSet::from_spans(vec![Id(5)..=Id::MAX]),
+        ];
+        for set in sets {
+            let data = serialize_spans(&set);
+            assert_eq!(deserialize_spans(&data)?.as_spans(), set.as_spans());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() {
+        let set = IdSet::from_spans(vec![Id(0)..=Id(100000), Id(100002)..=Id(100002)]);
+        assert_eq!(serialize_spans(&set).len(), 7);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(deserialize_spans(b"").is_err());
+        assert!(deserialize_spans(b"\x01").is_err());
+        // Truncated VLQ integer.
+        assert!(deserialize_spans(b"\x00\x80").is_err());
+    }
+}